@@ -0,0 +1,161 @@
+//! Building blocks shared by several channels: the volume envelope and the length counter.
+
+use sm83::state::{SaveState, StateError, StateReader, StateWriter};
+
+use crate::bounded;
+
+/// Volume envelope of the pulse and noise channels, configured through NRx2.
+pub struct Envelope {
+    initial_volume: u8,
+    increase: bool,
+    period: u8,
+    timer: u8,
+    volume: u8,
+}
+
+impl Envelope {
+    pub const fn new() -> Self {
+        Self {
+            initial_volume: 0,
+            increase: false,
+            period: 0,
+            timer: 0,
+            volume: 0,
+        }
+    }
+
+    /// Returns the current volume, from 0 to 15
+    pub fn volume(&self) -> u8 {
+        self.volume
+    }
+
+    /// Returns true if the DAC of the channel is powered, which happens when the envelope is
+    /// configured with a non-zero volume or in increase mode.
+    pub fn dac_enabled(&self) -> bool {
+        self.initial_volume != 0 || self.increase
+    }
+
+    /// Restarts the envelope from the initial volume
+    pub fn trigger(&mut self) {
+        self.timer = self.period;
+        self.volume = self.initial_volume;
+    }
+
+    /// Clocked at 64 Hz by the frame sequencer.
+    pub fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increase && self.volume < 0xF {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+
+    /// Reads the NRx2 register
+    pub fn read(&self) -> u8 {
+        (self.initial_volume << 4) | ((self.increase as u8) << 3) | self.period
+    }
+
+    /// Writes the NRx2 register
+    pub fn write(&mut self, value: u8) {
+        self.initial_volume = value >> 4;
+        self.increase = value & 0x08 != 0;
+        self.period = value & 0x7;
+    }
+}
+
+impl SaveState for Envelope {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.initial_volume);
+        state.write_bool(self.increase);
+        state.write_u8(self.period);
+        state.write_u8(self.timer);
+        state.write_u8(self.volume);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.initial_volume = bounded(state.read_u8()?, 0xF)?;
+        self.increase = state.read_bool()?;
+        self.period = bounded(state.read_u8()?, 0x7)?;
+        self.timer = bounded(state.read_u8()?, 0x7)?;
+        self.volume = bounded(state.read_u8()?, 0xF)?;
+        Ok(())
+    }
+}
+
+impl Default for Envelope {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Length counter, which silences a channel after a programmable amount of time.
+pub struct LengthCounter {
+    max: u16,
+    length: u16,
+    enabled: bool,
+}
+
+impl LengthCounter {
+    /// Creates a length counter that counts down from up to `max` clocks.
+    pub const fn new(max: u16) -> Self {
+        Self {
+            max,
+            length: 0,
+            enabled: false,
+        }
+    }
+
+    /// Loads the counter with the value written to the length register. The channel plays for
+    /// `max - value` clocks.
+    pub fn load(&mut self, value: u8) {
+        self.length = self.max - value as u16;
+    }
+
+    /// Returns true if the counter silences the channel when it expires (NRx4 bit 6)
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// Triggering a channel with an expired counter reloads it with the maximum length.
+    pub fn trigger(&mut self) {
+        if self.length == 0 {
+            self.length = self.max;
+        }
+    }
+
+    /// Clocked at 256 Hz by the frame sequencer. Returns true if the counter just expired, which
+    /// disables the channel.
+    pub fn clock(&mut self) -> bool {
+        if self.enabled && self.length != 0 {
+            self.length -= 1;
+            return self.length == 0;
+        }
+        false
+    }
+}
+
+/// The maximum length is fixed for each channel, so it is not part of the state.
+impl SaveState for LengthCounter {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.length);
+        state.write_bool(self.enabled);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.length = bounded(state.read_u16()?, self.max)?;
+        self.enabled = state.read_bool()?;
+        Ok(())
+    }
+}
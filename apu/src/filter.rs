@@ -0,0 +1,80 @@
+//! Output filters of the APU
+
+/// Charge factor of the capacitor in the DMG output stage for a 44.1 KHz sample rate.
+pub const DMG_HIGHPASS_COEFFICIENT: f32 = 0.996;
+
+/// The Game Boy outputs audio through a capacitor that removes the DC offset of the signal. This
+/// models it as a first order high-pass filter.
+pub struct HighPassFilter {
+    enabled: bool,
+    coefficient: f32,
+    capacitor: f32,
+}
+
+impl HighPassFilter {
+    /// Creates an enabled filter with the DMG coefficient.
+    pub const fn new() -> Self {
+        Self {
+            enabled: true,
+            coefficient: DMG_HIGHPASS_COEFFICIENT,
+            capacitor: 0.0,
+        }
+    }
+
+    /// Enables or disables the filter. The coefficient is the fraction of the charge that the
+    /// capacitor keeps after each sample, which depends on the sample rate.
+    pub fn configure(&mut self, enabled: bool, coefficient: f32) {
+        self.enabled = enabled;
+        self.coefficient = coefficient;
+        self.capacitor = 0.0;
+    }
+
+    /// Filters a single sample.
+    pub fn apply(&mut self, input: f32) -> f32 {
+        if !self.enabled {
+            return input;
+        }
+
+        let output = input - self.capacitor;
+        self.capacitor = input - output * self.coefficient;
+        output
+    }
+}
+
+impl Default for HighPassFilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn constant_input_decays_to_zero() {
+        let mut filter = HighPassFilter::new();
+
+        let first = filter.apply(1.0);
+        assert_eq!(first, 1.0);
+
+        let mut last = first;
+        for _ in 0..4096 {
+            let output = filter.apply(1.0);
+            assert!(output <= last);
+            assert!(output >= 0.0);
+            last = output;
+        }
+        assert!(last < 0.001);
+    }
+
+    #[test]
+    fn disabled_filter_keeps_offset() {
+        let mut filter = HighPassFilter::new();
+        filter.configure(false, DMG_HIGHPASS_COEFFICIENT);
+
+        for _ in 0..4096 {
+            assert_eq!(filter.apply(1.0), 1.0);
+        }
+    }
+}
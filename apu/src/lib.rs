@@ -0,0 +1,572 @@
+//! The Audio Processing Unit of the Game Boy.
+//!
+//! The APU can be driven on its own, without the rest of the system, which is handy for audio
+//! tools:
+//!
+//! ```rust
+//! use apu::Apu;
+//! use sm83::core::Cycles;
+//!
+//! let mut apu = Apu::new();
+//! // Power on, full volume on both outputs, and play channel 2 on both of them
+//! apu.write_register(0xFF26, 0x80);
+//! apu.write_register(0xFF24, 0x77);
+//! apu.write_register(0xFF25, 0x22);
+//! // 50% duty, full volume, and trigger at roughly 440 Hz
+//! apu.write_register(0xFF16, 0x80);
+//! apu.write_register(0xFF17, 0xF0);
+//! apu.write_register(0xFF18, 0xD6);
+//! apu.write_register(0xFF19, 0x86);
+//!
+//! apu.step(Cycles::new(4194304 / 60));
+//! let samples = apu.take_samples();
+//! assert!(samples.iter().any(|(left, _)| *left != 0.0));
+//! ```
+#![no_std]
+
+extern crate alloc;
+
+pub mod envelope;
+pub mod filter;
+pub mod noise;
+pub mod pulse;
+pub mod wave;
+
+use alloc::vec::Vec;
+use filter::HighPassFilter;
+use noise::NoiseChannel;
+use pulse::PulseChannel;
+use sm83::{
+    core::Cycles,
+    state::{SaveState, StateError, StateReader, StateWriter},
+};
+use wave::WaveChannel;
+
+/// Frequency of the clock that drives the APU
+const CLOCK_HZ: usize = 4194304;
+
+/// The frame sequencer runs at 512 Hz, clocking the length counters, envelopes and sweep.
+const FRAME_SEQUENCER_PERIOD: usize = CLOCK_HZ / 512;
+const FRAME_SEQUENCER_STEPS: usize = 8;
+
+/// Default rate of the generated samples
+pub const DEFAULT_SAMPLE_RATE: usize = 44100;
+
+/// Maximum number of samples kept until they are taken. Newer samples are dropped once the buffer
+/// is full.
+const MAX_BUFFERED_SAMPLES: usize = 1 << 16;
+
+const NUM_CHANNELS: usize = 4;
+
+/// The hardware model to emulate. Some quirks of the APU are only present in the DMG.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Model {
+    #[default]
+    Dmg,
+    Cgb,
+}
+
+/// The Audio Processing Unit
+pub struct Apu {
+    model: Model,
+    powered: bool,
+    pulse1: PulseChannel,
+    pulse2: PulseChannel,
+    wave: WaveChannel,
+    noise: NoiseChannel,
+    highpass: [HighPassFilter; 2],
+
+    /// NR50
+    master_volume: u8,
+    /// NR51
+    panning: u8,
+
+    frame_sequencer_timer: usize,
+    frame_sequencer_step: usize,
+
+    sample_rate: usize,
+    /// Fraction of the current sample period that has elapsed, scaled by `CLOCK_HZ`
+    sample_phase: usize,
+    samples: Vec<(f32, f32)>,
+}
+
+impl Apu {
+    /// Constructs an APU instance
+    pub const fn new() -> Self {
+        Self {
+            model: Model::Dmg,
+            powered: false,
+            pulse1: PulseChannel::new_with_sweep(),
+            pulse2: PulseChannel::new(),
+            wave: WaveChannel::new(),
+            noise: NoiseChannel::new(),
+            highpass: [HighPassFilter::new(), HighPassFilter::new()],
+            master_volume: 0,
+            panning: 0,
+            frame_sequencer_timer: FRAME_SEQUENCER_PERIOD,
+            frame_sequencer_step: 0,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            sample_phase: 0,
+            samples: Vec::new(),
+        }
+    }
+
+    /// Restores the APU to its power-on state, keeping the model, the sample rate and the output
+    /// stage configuration. Buffered samples are discarded.
+    pub fn reset(&mut self) {
+        *self = Self {
+            model: self.model,
+            highpass: core::mem::take(&mut self.highpass),
+            sample_rate: self.sample_rate,
+            ..Self::new()
+        };
+    }
+
+    /// Selects the hardware model to emulate. Defaults to the DMG.
+    pub fn set_model(&mut self, model: Model) {
+        self.model = model;
+    }
+
+    /// Selects the rate of the samples returned by `take_samples`, in Hz. Defaults to
+    /// [`DEFAULT_SAMPLE_RATE`].
+    pub fn set_sample_rate(&mut self, sample_rate: usize) {
+        assert!(
+            sample_rate > 0 && sample_rate <= CLOCK_HZ,
+            "Invalid sample rate {sample_rate}"
+        );
+        self.sample_rate = sample_rate;
+        self.sample_phase = 0;
+    }
+
+    /// Runs the APU for the given number of cycles, generating samples along the way.
+    pub fn step(&mut self, cycles: Cycles) {
+        let mut cycles: usize = cycles.into();
+        while cycles != 0 {
+            // Advance up to the next event, which is either a frame sequencer step or a sample
+            let until_sample = (CLOCK_HZ - self.sample_phase).div_ceil(self.sample_rate);
+            let chunk = cycles.min(self.frame_sequencer_timer).min(until_sample);
+
+            self.pulse1.step(Cycles::new(chunk));
+            self.pulse2.step(Cycles::new(chunk));
+            self.wave.step(Cycles::new(chunk));
+            self.noise.step(Cycles::new(chunk));
+            cycles -= chunk;
+
+            self.frame_sequencer_timer -= chunk;
+            if self.frame_sequencer_timer == 0 {
+                self.frame_sequencer_timer = FRAME_SEQUENCER_PERIOD;
+                self.clock_frame_sequencer();
+            }
+
+            self.sample_phase += chunk * self.sample_rate;
+            if self.sample_phase >= CLOCK_HZ {
+                self.sample_phase -= CLOCK_HZ;
+                let (left, right) = self.mix();
+                let sample = self.output_stage(left, right);
+                if self.samples.len() < MAX_BUFFERED_SAMPLES {
+                    self.samples.push(sample);
+                }
+            }
+        }
+    }
+
+    fn clock_frame_sequencer(&mut self) {
+        let step = self.frame_sequencer_step;
+        self.frame_sequencer_step = (step + 1) % FRAME_SEQUENCER_STEPS;
+
+        if step & 1 == 0 {
+            self.pulse1.clock_length();
+            self.pulse2.clock_length();
+            self.wave.clock_length();
+            self.noise.clock_length();
+        }
+        if step == 2 || step == 6 {
+            self.pulse1.clock_sweep();
+        }
+        if step == 7 {
+            self.pulse1.clock_envelope();
+            self.pulse2.clock_envelope();
+            self.noise.clock_envelope();
+        }
+    }
+
+    /// Mixes the output of all channels into a stereo sample, in the range -1.0 to 1.0.
+    fn mix(&self) -> (f32, f32) {
+        if !self.powered {
+            return (0.0, 0.0);
+        }
+
+        // The DACs convert the digital output from 0 to 15 into an analog level from 1.0 to
+        // -1.0. Channels with their DAC off don't contribute to the mix.
+        let dac = |enabled: bool, output: u8| -> f32 {
+            if enabled {
+                1.0 - output as f32 / 7.5
+            } else {
+                0.0
+            }
+        };
+        let channels: [f32; NUM_CHANNELS] = [
+            dac(self.pulse1.dac_enabled(), self.pulse1.output()),
+            dac(self.pulse2.dac_enabled(), self.pulse2.output()),
+            dac(self.wave.dac_enabled(), self.wave.output()),
+            dac(self.noise.dac_enabled(), self.noise.output()),
+        ];
+
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for (index, level) in channels.iter().enumerate() {
+            if self.panning & (1 << index) != 0 {
+                right += level;
+            }
+            if self.panning & (0x10 << index) != 0 {
+                left += level;
+            }
+        }
+
+        // NR50 scales each side from 1/8 to 8/8
+        let left_volume = (((self.master_volume >> 4) & 0x7) + 1) as f32 / 8.0;
+        let right_volume = ((self.master_volume & 0x7) + 1) as f32 / 8.0;
+        (
+            left * left_volume / NUM_CHANNELS as f32,
+            right * right_volume / NUM_CHANNELS as f32,
+        )
+    }
+
+    /// Returns the stereo samples generated since the last call, as (left, right) pairs in the
+    /// range -1.0 to 1.0.
+    pub fn take_samples(&mut self) -> Vec<(f32, f32)> {
+        core::mem::take(&mut self.samples)
+    }
+
+    /// Drains the oldest samples into `out` as interleaved 16-bit stereo samples (left first).
+    /// Returns the number of stereo samples written, which is limited by the length of `out`.
+    pub fn samples(&mut self, out: &mut [i16]) -> usize {
+        let count = self.samples.len().min(out.len() / 2);
+        let to_i16 = |level: f32| (level.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        for (out, (left, right)) in out.chunks_exact_mut(2).zip(self.samples.drain(..count)) {
+            out[0] = to_i16(left);
+            out[1] = to_i16(right);
+        }
+        count
+    }
+
+    fn channel_status(&self) -> u8 {
+        (self.pulse1.enabled() as u8)
+            | ((self.pulse2.enabled() as u8) << 1)
+            | ((self.wave.enabled() as u8) << 2)
+            | ((self.noise.enabled() as u8) << 3)
+    }
+
+    fn power_off(&mut self) {
+        self.powered = false;
+        self.pulse1 = PulseChannel::new_with_sweep();
+        self.pulse2 = PulseChannel::new();
+        self.noise = NoiseChannel::new();
+        self.master_volume = 0;
+        self.panning = 0;
+
+        // Wave RAM is not affected by the power of the APU
+        let mut wave = WaveChannel::new();
+        for address in 0xFF30..=0xFF3F {
+            wave.write(address, self.wave.read(address), false);
+        }
+        self.wave = wave;
+    }
+
+    /// Reads a register of the APU, from 0xFF10 to 0xFF3F.
+    pub fn read_register(&self, address: sm83::memory::Address) -> u8 {
+        match address {
+            0xFF10..=0xFF14 => self.pulse1.read((address - 0xFF10) as usize),
+            0xFF15..=0xFF19 => self.pulse2.read((address - 0xFF15) as usize),
+            0xFF1A..=0xFF1E | 0xFF30..=0xFF3F => self.wave.read(address),
+            0xFF1F..=0xFF23 => self.noise.read((address - 0xFF1F) as usize),
+            0xFF24 => self.master_volume,
+            0xFF25 => self.panning,
+            0xFF26 => ((self.powered as u8) << 7) | 0x70 | self.channel_status(),
+            _ => 0xFF,
+        }
+    }
+
+    /// Writes a register of the APU, from 0xFF10 to 0xFF3F. While the APU is powered off, only
+    /// NR52 and wave RAM can be written.
+    pub fn write_register(&mut self, address: sm83::memory::Address, value: u8) {
+        if !self.powered && !matches!(address, 0xFF26 | 0xFF30..=0xFF3F) {
+            return;
+        }
+
+        match address {
+            0xFF10..=0xFF14 => self.pulse1.write((address - 0xFF10) as usize, value),
+            0xFF15..=0xFF19 => self.pulse2.write((address - 0xFF15) as usize, value),
+            0xFF1A..=0xFF1E | 0xFF30..=0xFF3F => {
+                self.wave.write(address, value, self.model == Model::Dmg)
+            }
+            0xFF1F..=0xFF23 => self.noise.write((address - 0xFF1F) as usize, value),
+            0xFF24 => self.master_volume = value,
+            0xFF25 => self.panning = value,
+            0xFF26 => {
+                let powered = value & 0x80 != 0;
+                if self.powered && !powered {
+                    self.power_off();
+                } else if !self.powered && powered {
+                    self.powered = true;
+                    self.frame_sequencer_step = 0;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Configures the high-pass filter of the output stage, which removes the DC offset of the
+    /// mixed signal. It is enabled by default with [`filter::DMG_HIGHPASS_COEFFICIENT`].
+    pub fn set_highpass(&mut self, enabled: bool, coefficient: f32) {
+        self.highpass
+            .iter_mut()
+            .for_each(|filter| filter.configure(enabled, coefficient));
+    }
+
+    /// Runs a mixed stereo sample through the output stage.
+    pub fn output_stage(&mut self, left: f32, right: f32) -> (f32, f32) {
+        (self.highpass[0].apply(left), self.highpass[1].apply(right))
+    }
+}
+
+/// Returns the value if it is not larger than `max`, or an error for states with values that the
+/// APU can never reach.
+fn bounded<T: PartialOrd>(value: T, max: T) -> Result<T, StateError> {
+    if value > max {
+        return Err(StateError::Invalid);
+    }
+    Ok(value)
+}
+
+/// Saves the registers and the internal state of the channels. The model, the sample rate and the
+/// output stage are configured by the frontend, so they are not part of the state, and buffered
+/// samples are kept on load.
+impl SaveState for Apu {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.powered);
+        self.pulse1.save_state(state);
+        self.pulse2.save_state(state);
+        self.wave.save_state(state);
+        self.noise.save_state(state);
+        state.write_u8(self.master_volume);
+        state.write_u8(self.panning);
+        state.write_u16(self.frame_sequencer_timer as u16);
+        state.write_u8(self.frame_sequencer_step as u8);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.powered = state.read_bool()?;
+        self.pulse1.load_state(state)?;
+        self.pulse2.load_state(state)?;
+        self.wave.load_state(state)?;
+        self.noise.load_state(state)?;
+        self.master_volume = state.read_u8()?;
+        self.panning = state.read_u8()?;
+        let frame_sequencer_timer = state.read_u16()? as usize;
+        if !(1..=FRAME_SEQUENCER_PERIOD).contains(&frame_sequencer_timer) {
+            return Err(StateError::Invalid);
+        }
+        self.frame_sequencer_timer = frame_sequencer_timer;
+        self.frame_sequencer_step = bounded(state.read_u8()? as usize, FRAME_SEQUENCER_STEPS - 1)?;
+        Ok(())
+    }
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn standalone_pulse_channel() {
+        let mut apu = Apu::new();
+        apu.write_register(0xFF26, 0x80);
+        apu.write_register(0xFF24, 0x77);
+        // Channel 1 on the left output only
+        apu.write_register(0xFF25, 0x10);
+        // 50% duty, full volume, and trigger with a period of 64 cycles per duty step
+        apu.write_register(0xFF11, 0x80);
+        apu.write_register(0xFF12, 0xF0);
+        apu.write_register(0xFF13, 0xF0);
+        apu.write_register(0xFF14, 0x87);
+        assert_eq!(apu.read_register(0xFF26), 0xF1);
+
+        apu.step(Cycles::new(CLOCK_HZ / 100));
+        let samples = apu.take_samples();
+        assert_eq!(
+            samples.len(),
+            CLOCK_HZ / 100 * DEFAULT_SAMPLE_RATE / CLOCK_HZ
+        );
+        assert!(apu.take_samples().is_empty());
+
+        let max = samples.iter().map(|(l, _)| *l).fold(f32::MIN, f32::max);
+        let min = samples.iter().map(|(l, _)| *l).fold(f32::MAX, f32::min);
+        assert!(max > 0.1, "max level {max}");
+        assert!(min < -0.1, "min level {min}");
+        assert!(samples.iter().all(|(_, r)| r.abs() < 0.001));
+    }
+
+    #[test]
+    fn powered_off_apu_ignores_writes() {
+        let mut apu = Apu::new();
+        apu.write_register(0xFF12, 0xF0);
+        apu.write_register(0xFF30, 0x12);
+        assert_eq!(apu.read_register(0xFF12), 0x00);
+        assert_eq!(apu.read_register(0xFF30), 0x12);
+        assert_eq!(apu.read_register(0xFF26), 0x70);
+
+        apu.step(Cycles::new(CLOCK_HZ / 100));
+        assert!(apu
+            .take_samples()
+            .iter()
+            .all(|(l, r)| *l == 0.0 && *r == 0.0));
+    }
+
+    #[test]
+    fn length_counters_disable_channels() {
+        let mut apu = Apu::new();
+        apu.write_register(0xFF26, 0x80);
+        // A length of 1 for every channel, with the length counter enabled on trigger
+        apu.write_register(0xFF16, 0x3F);
+        apu.write_register(0xFF17, 0xF0);
+        apu.write_register(0xFF19, 0xC0);
+        apu.write_register(0xFF1A, 0x80);
+        apu.write_register(0xFF1B, 0xFF);
+        apu.write_register(0xFF1E, 0xC0);
+        apu.write_register(0xFF20, 0x3F);
+        apu.write_register(0xFF21, 0xF0);
+        apu.write_register(0xFF23, 0xC0);
+        assert_eq!(apu.read_register(0xFF26), 0xFE);
+
+        // The first length clock happens on the first frame sequencer step
+        apu.step(Cycles::new(FRAME_SEQUENCER_PERIOD - 4));
+        assert_eq!(apu.read_register(0xFF26), 0xFE);
+        apu.step(Cycles::new(4));
+        assert_eq!(apu.read_register(0xFF26), 0xF0);
+    }
+
+    #[test]
+    fn length_counter_ignored_when_disabled() {
+        let mut apu = Apu::new();
+        apu.write_register(0xFF26, 0x80);
+        apu.write_register(0xFF16, 0x3F);
+        apu.write_register(0xFF17, 0xF0);
+        apu.write_register(0xFF19, 0x80);
+        apu.step(Cycles::new(FRAME_SEQUENCER_PERIOD * FRAME_SEQUENCER_STEPS));
+        assert_eq!(apu.read_register(0xFF26), 0xF2);
+
+        // Enabling the length counter later on makes it expire on the next length clock
+        apu.write_register(0xFF19, 0x40);
+        apu.step(Cycles::new(FRAME_SEQUENCER_PERIOD));
+        assert_eq!(apu.read_register(0xFF26), 0xF0);
+    }
+
+    #[test]
+    fn envelope_timing() {
+        let mut apu = Apu::new();
+        apu.write_register(0xFF26, 0x80);
+        // Initial volume 8, decreasing with a period of 1 for channel 2, and 0, increasing with a
+        // period of 2 for the noise channel
+        apu.write_register(0xFF17, 0x81);
+        apu.write_register(0xFF19, 0x80);
+        apu.write_register(0xFF21, 0x0A);
+        apu.write_register(0xFF23, 0x80);
+        assert_eq!(apu.pulse2.volume(), 8);
+        assert_eq!(apu.noise.volume(), 0);
+
+        // The envelope is clocked on the last step of the frame sequencer, at 64 Hz
+        let envelope_period = FRAME_SEQUENCER_PERIOD * FRAME_SEQUENCER_STEPS;
+        apu.step(Cycles::new(envelope_period - 4));
+        assert_eq!(apu.pulse2.volume(), 8);
+        apu.step(Cycles::new(4));
+        assert_eq!(apu.pulse2.volume(), 7);
+        assert_eq!(apu.noise.volume(), 0);
+
+        apu.step(Cycles::new(envelope_period));
+        assert_eq!(apu.pulse2.volume(), 6);
+        assert_eq!(apu.noise.volume(), 1);
+
+        apu.step(Cycles::new(envelope_period * 10));
+        assert_eq!(apu.pulse2.volume(), 0);
+        assert_eq!(apu.noise.volume(), 6);
+    }
+
+    #[test]
+    fn samples_are_drained_as_interleaved_stereo() {
+        let mut apu = Apu::new();
+        apu.step(Cycles::new(CLOCK_HZ / 1000));
+        let available = CLOCK_HZ / 1000 * DEFAULT_SAMPLE_RATE / CLOCK_HZ;
+
+        let mut out = [1i16; 9];
+        assert_eq!(apu.samples(&mut out), 4);
+        assert_eq!(&out[..8], &[0; 8]);
+        assert_eq!(out[8], 1);
+
+        let mut out = [1i16; 1024];
+        assert_eq!(apu.samples(&mut out), available - 4);
+        assert_eq!(apu.samples(&mut out), 0);
+    }
+
+    #[test]
+    fn save_state_round_trip() {
+        let mut apu = Apu::new();
+        apu.write_register(0xFF26, 0x80);
+        apu.write_register(0xFF24, 0x77);
+        apu.write_register(0xFF25, 0xFF);
+        apu.write_register(0xFF11, 0x80);
+        apu.write_register(0xFF12, 0xF1);
+        apu.write_register(0xFF14, 0x87);
+        for address in 0xFF30..=0xFF3F {
+            apu.write_register(address, address as u8);
+        }
+        apu.write_register(0xFF1A, 0x80);
+        apu.write_register(0xFF1C, 0x20);
+        apu.write_register(0xFF1E, 0x87);
+        apu.write_register(0xFF21, 0xF0);
+        apu.write_register(0xFF22, 0x11);
+        apu.write_register(0xFF23, 0x80);
+        apu.step(Cycles::new(FRAME_SEQUENCER_PERIOD * 3 + 123));
+        apu.take_samples();
+
+        let mut state = StateWriter::new();
+        apu.save_state(&mut state);
+        let state = state.into_bytes();
+        // The mixed output follows the channels, regardless of the sample rate and output stage
+        let mixed_output = |apu: &mut Apu| -> Vec<(f32, f32)> {
+            (0..1000)
+                .map(|_| {
+                    apu.step(Cycles::new(37));
+                    apu.mix()
+                })
+                .collect()
+        };
+        let expected_output = mixed_output(&mut apu);
+        let expected_registers: Vec<u8> = (0xFF10..=0xFF3F)
+            .map(|address| apu.read_register(address))
+            .collect();
+
+        // Changes made after saving are undone by loading the state
+        apu.write_register(0xFF12, 0x00);
+        apu.write_register(0xFF24, 0x00);
+        apu.write_register(0xFF30, 0xAA);
+        apu.write_register(0xFF1A, 0x00);
+        let mut reader = StateReader::new(&state);
+        apu.load_state(&mut reader).unwrap();
+        assert!(reader.is_empty());
+
+        assert_eq!(mixed_output(&mut apu), expected_output);
+        let registers: Vec<u8> = (0xFF10..=0xFF3F)
+            .map(|address| apu.read_register(address))
+            .collect();
+        assert_eq!(registers, expected_registers);
+
+        let mut reader = StateReader::new(&state[..state.len() - 1]);
+        assert_eq!(apu.load_state(&mut reader), Err(StateError::Truncated));
+    }
+}
@@ -0,0 +1,269 @@
+//! Channel 4 of the APU, which plays pseudo-random noise generated by a linear feedback shift
+//! register (LFSR).
+
+use sm83::{
+    core::Cycles,
+    state::{SaveState, StateError, StateReader, StateWriter},
+};
+
+use crate::{
+    bounded,
+    envelope::{Envelope, LengthCounter},
+};
+
+const MAX_LENGTH: u16 = 64;
+
+/// Divisors selected by the lower 3 bits of NR43, in clock cycles
+const DIVISORS: [usize; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+/// The LFSR is 15 bits wide, and starts with all bits set when the channel is triggered
+const LFSR_INITIAL: u16 = 0x7FFF;
+
+/// The noise channel. Registers are addressed by their index within the channel, from 1 (NR41)
+/// to 4 (NR44).
+pub struct NoiseChannel {
+    enabled: bool,
+    length: LengthCounter,
+    envelope: Envelope,
+
+    /// NR43
+    polynomial: u8,
+    lfsr: u16,
+    timer: usize,
+}
+
+impl NoiseChannel {
+    pub const fn new() -> Self {
+        Self {
+            enabled: false,
+            length: LengthCounter::new(MAX_LENGTH),
+            envelope: Envelope::new(),
+            polynomial: 0,
+            lfsr: LFSR_INITIAL,
+            timer: 0,
+        }
+    }
+
+    /// Returns true while the channel is playing
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Returns true if the DAC of the channel is powered
+    pub fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    /// Returns the current volume of the envelope
+    pub fn volume(&self) -> u8 {
+        self.envelope.volume()
+    }
+
+    /// Returns the 4-bit sample that the channel is currently outputting
+    pub fn output(&self) -> u8 {
+        // The output is high when bit 0 of the LFSR is clear
+        if self.enabled && self.lfsr & 1 == 0 {
+            self.envelope.volume()
+        } else {
+            0
+        }
+    }
+
+    fn period(&self) -> usize {
+        let shift = self.polynomial >> 4;
+        DIVISORS[(self.polynomial & 0x7) as usize] << shift
+    }
+
+    fn short_mode(&self) -> bool {
+        self.polynomial & 0x08 != 0
+    }
+
+    fn clock_lfsr(&mut self) {
+        let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+        self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+        if self.short_mode() {
+            // In 7-bit mode, the feedback is also copied into bit 6
+            self.lfsr = (self.lfsr & !(1 << 6)) | (feedback << 6);
+        }
+    }
+
+    pub fn step(&mut self, cycles: Cycles) {
+        if !self.enabled {
+            return;
+        }
+
+        let mut cycles: usize = cycles.into();
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.clock_lfsr();
+        }
+        self.timer -= cycles;
+    }
+
+    /// Clocked at 256 Hz by the frame sequencer.
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    /// Clocked at 64 Hz by the frame sequencer.
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled();
+        self.length.trigger();
+        self.timer = self.period();
+        self.envelope.trigger();
+        self.lfsr = LFSR_INITIAL;
+    }
+
+    pub fn read(&self, register: usize) -> u8 {
+        match register {
+            2 => self.envelope.read(),
+            3 => self.polynomial,
+            4 => ((self.length.enabled() as u8) << 6) | 0xBF,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, register: usize, value: u8) {
+        match register {
+            1 => self.length.load(value & 0x3F),
+            2 => {
+                self.envelope.write(value);
+                if !self.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.polynomial = value,
+            4 => {
+                self.length.set_enabled(value & 0x40 != 0);
+                if value & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl SaveState for NoiseChannel {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        self.length.save_state(state);
+        self.envelope.save_state(state);
+        state.write_u8(self.polynomial);
+        state.write_u16(self.lfsr);
+        state.write_u32(self.timer as u32);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.length.load_state(state)?;
+        self.envelope.load_state(state)?;
+        self.polynomial = state.read_u8()?;
+        self.lfsr = bounded(state.read_u16()?, LFSR_INITIAL)?;
+        // The timer keeps counting with the previous period after NR43 changes
+        self.timer = bounded(state.read_u32()? as usize, DIVISORS[7] << 15)?;
+        Ok(())
+    }
+}
+
+impl Default for NoiseChannel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn playing_channel(polynomial: u8) -> NoiseChannel {
+        let mut channel = NoiseChannel::new();
+        channel.write(2, 0xF0);
+        channel.write(3, polynomial);
+        channel.write(4, 0x80);
+        channel
+    }
+
+    /// Collects the output of the channel after each LFSR clock
+    fn waveform<const N: usize>(channel: &mut NoiseChannel) -> [u8; N] {
+        let period = channel.period();
+        core::array::from_fn(|_| {
+            channel.step(Cycles::new(period));
+            channel.output()
+        })
+    }
+
+    #[test]
+    fn lfsr_sequence() {
+        // Divisor of 8 cycles, 15-bit mode
+        let mut channel = playing_channel(0x00);
+        assert_eq!(channel.period(), 8);
+        assert_eq!(channel.output(), 0);
+
+        // All bits are set initially, so the first 14 clocks shift in zeros
+        let outputs: [u8; 16] = waveform(&mut channel);
+        assert_eq!(&outputs[..14], &[0; 14]);
+        assert_eq!(outputs[14], 0xF);
+        assert_eq!(outputs[15], 0xF);
+
+        // The 15-bit LFSR repeats every 2^15 - 1 clocks
+        let mut channel = playing_channel(0x00);
+        let first: [u8; 64] = waveform(&mut channel);
+        channel.step(Cycles::new(8 * (0x7FFF - 64)));
+        let repeated: [u8; 64] = waveform(&mut channel);
+        assert_eq!(first, repeated);
+    }
+
+    #[test]
+    fn short_mode_repeats_every_127_clocks() {
+        let mut channel = playing_channel(0x08);
+        let first: [u8; 127] = waveform(&mut channel);
+        let second: [u8; 127] = waveform(&mut channel);
+        assert_eq!(first, second);
+        assert!(first.iter().any(|output| *output != 0));
+    }
+
+    #[test]
+    fn clock_shift_and_divisor() {
+        let mut channel = NoiseChannel::new();
+        channel.write(3, 0x35);
+        assert_eq!(channel.period(), 80 << 3);
+    }
+
+    #[test]
+    fn length_counter_disables_channel() {
+        let mut channel = NoiseChannel::new();
+        // Length of 3 clocks
+        channel.write(1, 61);
+        channel.write(2, 0xF0);
+        channel.write(4, 0xC0);
+
+        channel.clock_length();
+        channel.clock_length();
+        assert!(channel.enabled());
+        channel.clock_length();
+        assert!(!channel.enabled());
+    }
+
+    #[test]
+    fn envelope_increases_volume() {
+        let mut channel = NoiseChannel::new();
+        // Volume 14, increasing every clock
+        channel.write(2, 0xE9);
+        channel.write(4, 0x80);
+        assert_eq!(channel.volume(), 14);
+
+        channel.clock_envelope();
+        assert_eq!(channel.volume(), 15);
+        // The volume saturates at 15
+        channel.clock_envelope();
+        assert_eq!(channel.volume(), 15);
+    }
+}
@@ -0,0 +1,364 @@
+//! Channels 1 and 2 of the APU, which play square waves with a selectable duty cycle. Channel 1
+//! can also sweep its frequency.
+
+use sm83::{
+    core::Cycles,
+    state::{SaveState, StateError, StateReader, StateWriter},
+};
+
+use crate::{
+    bounded,
+    envelope::{Envelope, LengthCounter},
+};
+
+/// Waveforms for each duty cycle (12.5%, 25%, 50% and 75%), played from the LSB to the MSB.
+const DUTY_PATTERNS: [u8; 4] = [0b1000_0000, 0b1000_0001, 0b1110_0001, 0b0111_1110];
+const DUTY_STEPS: usize = 8;
+
+const MAX_LENGTH: u16 = 64;
+const MAX_FREQUENCY: u16 = 2047;
+
+/// A pulse channel. Registers are addressed by their index within the channel, from 0 (NRx0) to
+/// 4 (NRx4).
+pub struct PulseChannel {
+    has_sweep: bool,
+    enabled: bool,
+
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_timer: u8,
+    sweep_enabled: bool,
+    shadow_frequency: u16,
+
+    duty: u8,
+    length: LengthCounter,
+    envelope: Envelope,
+
+    frequency: u16,
+    timer: usize,
+    duty_position: usize,
+}
+
+impl PulseChannel {
+    /// Creates channel 2, which has no frequency sweep.
+    pub const fn new() -> Self {
+        Self {
+            has_sweep: false,
+            enabled: false,
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_timer: 0,
+            sweep_enabled: false,
+            shadow_frequency: 0,
+            duty: 0,
+            length: LengthCounter::new(MAX_LENGTH),
+            envelope: Envelope::new(),
+            frequency: 0,
+            timer: 0,
+            duty_position: 0,
+        }
+    }
+
+    /// Creates channel 1, which has a frequency sweep unit.
+    pub const fn new_with_sweep() -> Self {
+        Self {
+            has_sweep: true,
+            ..Self::new()
+        }
+    }
+
+    /// Returns true while the channel is playing
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Returns true if the DAC of the channel is powered, which happens when the envelope is
+    /// configured with a non-zero volume or in increase mode.
+    pub fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    /// Returns the current volume of the envelope
+    pub fn volume(&self) -> u8 {
+        self.envelope.volume()
+    }
+
+    /// Returns the 4-bit sample that the channel is currently outputting
+    pub fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        let high = DUTY_PATTERNS[self.duty as usize] & (1 << self.duty_position) != 0;
+        if high {
+            self.envelope.volume()
+        } else {
+            0
+        }
+    }
+
+    fn period(&self) -> usize {
+        (2048 - self.frequency as usize) * 4
+    }
+
+    pub fn step(&mut self, cycles: Cycles) {
+        if !self.enabled {
+            return;
+        }
+
+        let mut cycles: usize = cycles.into();
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.duty_position = (self.duty_position + 1) % DUTY_STEPS;
+        }
+        self.timer -= cycles;
+    }
+
+    /// Clocked at 256 Hz by the frame sequencer.
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    /// Clocked at 64 Hz by the frame sequencer.
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    /// Computes the next frequency of the sweep, disabling the channel if it overflows.
+    fn next_sweep_frequency(&mut self) -> u16 {
+        let delta = self.shadow_frequency >> self.sweep_shift;
+        let frequency = if self.sweep_negate {
+            self.shadow_frequency - delta
+        } else {
+            self.shadow_frequency + delta
+        };
+
+        if frequency > MAX_FREQUENCY {
+            self.enabled = false;
+        }
+        frequency
+    }
+
+    /// Clocked at 128 Hz by the frame sequencer.
+    pub fn clock_sweep(&mut self) {
+        if !self.has_sweep {
+            return;
+        }
+
+        self.sweep_timer = self.sweep_timer.saturating_sub(1);
+        if self.sweep_timer != 0 {
+            return;
+        }
+
+        self.sweep_timer = if self.sweep_period == 0 {
+            8
+        } else {
+            self.sweep_period
+        };
+
+        if self.sweep_enabled && self.sweep_period != 0 {
+            let frequency = self.next_sweep_frequency();
+            if frequency <= MAX_FREQUENCY && self.sweep_shift != 0 {
+                self.frequency = frequency;
+                self.shadow_frequency = frequency;
+                // The overflow check runs again with the new frequency
+                self.next_sweep_frequency();
+            }
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled();
+        self.length.trigger();
+        self.timer = self.period();
+        self.envelope.trigger();
+
+        if self.has_sweep {
+            self.shadow_frequency = self.frequency;
+            self.sweep_timer = if self.sweep_period == 0 {
+                8
+            } else {
+                self.sweep_period
+            };
+            self.sweep_enabled = self.sweep_period != 0 || self.sweep_shift != 0;
+            if self.sweep_shift != 0 {
+                self.next_sweep_frequency();
+            }
+        }
+    }
+
+    pub fn read(&self, register: usize) -> u8 {
+        match register {
+            0 if self.has_sweep => {
+                (self.sweep_period << 4)
+                    | ((self.sweep_negate as u8) << 3)
+                    | self.sweep_shift
+                    | 0x80
+            }
+            1 => (self.duty << 6) | 0x3F,
+            2 => self.envelope.read(),
+            4 => ((self.length.enabled() as u8) << 6) | 0xBF,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, register: usize, value: u8) {
+        match register {
+            0 if self.has_sweep => {
+                self.sweep_period = (value >> 4) & 0x7;
+                self.sweep_negate = value & 0x08 != 0;
+                self.sweep_shift = value & 0x7;
+            }
+            1 => {
+                self.duty = value >> 6;
+                self.length.load(value & 0x3F);
+            }
+            2 => {
+                self.envelope.write(value);
+                if !self.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.frequency = (self.frequency & 0x700) | value as u16,
+            4 => {
+                self.frequency = (self.frequency & 0xFF) | (((value & 0x7) as u16) << 8);
+                self.length.set_enabled(value & 0x40 != 0);
+                if value & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Whether the channel has a sweep unit is fixed, so it is not part of the state.
+impl SaveState for PulseChannel {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_u8(self.sweep_period);
+        state.write_bool(self.sweep_negate);
+        state.write_u8(self.sweep_shift);
+        state.write_u8(self.sweep_timer);
+        state.write_bool(self.sweep_enabled);
+        state.write_u16(self.shadow_frequency);
+        state.write_u8(self.duty);
+        self.length.save_state(state);
+        self.envelope.save_state(state);
+        state.write_u16(self.frequency);
+        state.write_u16(self.timer as u16);
+        state.write_u8(self.duty_position as u8);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.sweep_period = bounded(state.read_u8()?, 0x7)?;
+        self.sweep_negate = state.read_bool()?;
+        self.sweep_shift = bounded(state.read_u8()?, 0x7)?;
+        self.sweep_timer = bounded(state.read_u8()?, 8)?;
+        self.sweep_enabled = state.read_bool()?;
+        self.shadow_frequency = bounded(state.read_u16()?, MAX_FREQUENCY)?;
+        self.duty = bounded(state.read_u8()?, 3)?;
+        self.length.load_state(state)?;
+        self.envelope.load_state(state)?;
+        self.frequency = bounded(state.read_u16()?, MAX_FREQUENCY)?;
+        // The timer keeps counting with the previous period after a frequency change
+        self.timer = bounded(state.read_u16()? as usize, 2048 * 4)?;
+        self.duty_position = bounded(state.read_u8()? as usize, DUTY_STEPS - 1)?;
+        Ok(())
+    }
+}
+
+impl Default for PulseChannel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Frequency with a period of 16 cycles per duty step
+    const FREQUENCY: u16 = 2044;
+    const PERIOD: usize = 16;
+
+    fn playing_channel(channel: &mut PulseChannel, duty: u8, envelope: u8) {
+        channel.write(1, duty << 6);
+        channel.write(2, envelope);
+        channel.write(3, FREQUENCY as u8);
+        channel.write(4, 0x80 | (FREQUENCY >> 8) as u8);
+    }
+
+    #[test]
+    fn plays_duty_cycle() {
+        let mut channel = PulseChannel::new();
+        // 25% duty at full volume
+        playing_channel(&mut channel, 1, 0xF0);
+
+        let mut waveform = [0; DUTY_STEPS];
+        for sample in waveform.iter_mut() {
+            *sample = channel.output();
+            channel.step(Cycles::new(PERIOD));
+        }
+        assert_eq!(waveform, [0xF, 0, 0, 0, 0, 0, 0, 0xF]);
+    }
+
+    #[test]
+    fn length_counter_disables_channel() {
+        let mut channel = PulseChannel::new();
+        // Length of 2 clocks
+        channel.write(1, 62);
+        channel.write(2, 0xF0);
+        channel.write(4, 0xC0);
+        assert!(channel.enabled());
+
+        channel.clock_length();
+        assert!(channel.enabled());
+        channel.clock_length();
+        assert!(!channel.enabled());
+        assert_eq!(channel.output(), 0);
+    }
+
+    #[test]
+    fn envelope_decreases_volume() {
+        let mut channel = PulseChannel::new();
+        // Volume 2, decreasing every 2 clocks, 75% duty so the first step is high
+        playing_channel(&mut channel, 3, 0x22);
+        channel.step(Cycles::new(PERIOD));
+        assert_eq!(channel.output(), 2);
+
+        channel.clock_envelope();
+        assert_eq!(channel.output(), 2);
+        channel.clock_envelope();
+        assert_eq!(channel.output(), 1);
+        channel.clock_envelope();
+        channel.clock_envelope();
+        assert_eq!(channel.output(), 0);
+
+        // The volume doesn't wrap around
+        channel.clock_envelope();
+        channel.clock_envelope();
+        assert_eq!(channel.output(), 0);
+    }
+
+    #[test]
+    fn sweep_overflow_disables_channel() {
+        let mut channel = PulseChannel::new_with_sweep();
+        // Sweep up every clock, with a shift of 1
+        channel.write(0, 0x11);
+        channel.write(2, 0xF0);
+        channel.write(3, 0x00);
+        channel.write(4, 0x85);
+        assert!(channel.enabled());
+
+        // 0x500 -> 0x780, and the next one (0xB40) overflows
+        channel.clock_sweep();
+        assert_eq!(channel.frequency, 0x780);
+        assert!(!channel.enabled());
+    }
+}
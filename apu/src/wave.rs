@@ -0,0 +1,294 @@
+//! Channel 3 of the APU, which plays back arbitrary 4-bit samples stored in wave RAM.
+
+use sm83::{
+    core::Cycles,
+    state::{SaveState, StateError, StateReader, StateWriter},
+};
+
+use crate::{bounded, envelope::LengthCounter};
+
+/// Number of bytes of wave RAM. Each byte holds two 4-bit samples.
+pub const WAVE_RAM_SIZE: usize = 16;
+const NUM_SAMPLES: usize = WAVE_RAM_SIZE * 2;
+
+const MAX_LENGTH: u16 = 256;
+
+/// The channel takes a few cycles to start reading samples after being triggered.
+const TRIGGER_DELAY: usize = 6;
+
+/// The wave channel
+pub struct WaveChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    length: LengthCounter,
+    volume: u8,
+    frequency: u16,
+    wave_ram: [u8; WAVE_RAM_SIZE],
+    position: usize,
+    timer: usize,
+    sample: u8,
+}
+
+impl WaveChannel {
+    pub const fn new() -> Self {
+        Self {
+            enabled: false,
+            dac_enabled: false,
+            length: LengthCounter::new(MAX_LENGTH),
+            volume: 0,
+            frequency: 0,
+            wave_ram: [0; WAVE_RAM_SIZE],
+            position: 0,
+            timer: 0,
+            sample: 0,
+        }
+    }
+
+    /// Returns true while the channel is playing
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Returns the 4-bit sample that the channel is currently outputting
+    pub fn sample(&self) -> u8 {
+        self.sample
+    }
+
+    /// Returns true if the DAC of the channel is powered (NR30 bit 7)
+    pub fn dac_enabled(&self) -> bool {
+        self.dac_enabled
+    }
+
+    /// Returns the current sample after applying the output level of the channel
+    pub fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        match self.volume {
+            0 => 0,
+            volume => self.sample >> (volume - 1),
+        }
+    }
+
+    fn period(&self) -> usize {
+        (2048 - self.frequency as usize) * 2
+    }
+
+    pub fn step(&mut self, cycles: Cycles) {
+        if !self.enabled {
+            return;
+        }
+
+        let mut cycles: usize = cycles.into();
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.position = (self.position + 1) % NUM_SAMPLES;
+
+            let byte = self.wave_ram[self.position / 2];
+            self.sample = if self.position & 1 == 0 {
+                byte >> 4
+            } else {
+                byte & 0xF
+            };
+        }
+        self.timer -= cycles;
+    }
+
+    /// Clocked at 256 Hz by the frame sequencer.
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    /// Triggers the channel. On DMG, triggering the channel while it is about to read wave RAM
+    /// corrupts the first bytes of wave RAM.
+    fn trigger(&mut self, dmg_quirks: bool) {
+        if dmg_quirks && self.enabled && self.timer <= 2 {
+            let offset = ((self.position + 1) % NUM_SAMPLES) / 2;
+            if offset < 4 {
+                self.wave_ram[0] = self.wave_ram[offset];
+            } else {
+                let base = offset & !3;
+                self.wave_ram.copy_within(base..base + 4, 0);
+            }
+        }
+
+        self.enabled = self.dac_enabled;
+        self.length.trigger();
+        self.position = 0;
+        self.timer = self.period() + TRIGGER_DELAY;
+    }
+
+    pub fn read(&self, address: sm83::memory::Address) -> u8 {
+        match address {
+            0xFF1A => ((self.dac_enabled as u8) << 7) | 0x7F,
+            0xFF1B => 0xFF,
+            0xFF1C => (self.volume << 5) | 0x9F,
+            0xFF1D => 0xFF,
+            0xFF1E => ((self.length.enabled() as u8) << 6) | 0xBF,
+            0xFF30..=0xFF3F => self.wave_ram[address as usize - 0xFF30],
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, address: sm83::memory::Address, value: u8, dmg_quirks: bool) {
+        match address {
+            0xFF1A => {
+                self.dac_enabled = value & 0x80 != 0;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            0xFF1B => self.length.load(value),
+            0xFF1C => self.volume = (value >> 5) & 0x3,
+            0xFF1D => self.frequency = (self.frequency & 0x700) | value as u16,
+            0xFF1E => {
+                self.frequency = (self.frequency & 0xFF) | (((value & 0x7) as u16) << 8);
+                self.length.set_enabled(value & 0x40 != 0);
+                if value & 0x80 != 0 {
+                    self.trigger(dmg_quirks);
+                }
+            }
+            0xFF30..=0xFF3F => self.wave_ram[address as usize - 0xFF30] = value,
+            _ => {}
+        }
+    }
+}
+
+impl SaveState for WaveChannel {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_bool(self.dac_enabled);
+        self.length.save_state(state);
+        state.write_u8(self.volume);
+        state.write_u16(self.frequency);
+        state.write_bytes(&self.wave_ram);
+        state.write_u8(self.position as u8);
+        state.write_u16(self.timer as u16);
+        state.write_u8(self.sample);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.dac_enabled = state.read_bool()?;
+        self.length.load_state(state)?;
+        self.volume = bounded(state.read_u8()?, 3)?;
+        self.frequency = bounded(state.read_u16()?, 0x7FF)?;
+        state.read_bytes(&mut self.wave_ram)?;
+        self.position = bounded(state.read_u8()? as usize, NUM_SAMPLES - 1)?;
+        // The timer keeps counting with the previous period after a frequency change
+        self.timer = bounded(state.read_u16()? as usize, 2048 * 2 + TRIGGER_DELAY)?;
+        self.sample = bounded(state.read_u8()?, 0xF)?;
+        Ok(())
+    }
+}
+
+impl Default for WaveChannel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WAVE: [u8; WAVE_RAM_SIZE] = [
+        0x01, 0x23, 0x45, 0x67, 0x89, 0xAB, 0xCD, 0xEF, 0xFE, 0xDC, 0xBA, 0x98, 0x76, 0x54, 0x32,
+        0x10,
+    ];
+
+    // Frequency with a period of 16 cycles
+    const FREQUENCY: u16 = 2040;
+    const PERIOD: usize = 16;
+
+    fn playing_channel() -> WaveChannel {
+        let mut channel = WaveChannel::new();
+        for (i, byte) in WAVE.iter().enumerate() {
+            channel.write(0xFF30 + i as u16, *byte, true);
+        }
+        channel.write(0xFF1A, 0x80, true);
+        channel.write(0xFF1D, FREQUENCY as u8, true);
+        channel.write(0xFF1E, 0x80 | (FREQUENCY >> 8) as u8, true);
+        channel
+    }
+
+    fn wave_ram(channel: &WaveChannel) -> [u8; WAVE_RAM_SIZE] {
+        core::array::from_fn(|i| channel.read(0xFF30 + i as u16))
+    }
+
+    /// Runs the channel until it is 2 cycles away from reading the given sample.
+    fn run_until_read_of(channel: &mut WaveChannel, position: usize) {
+        channel.step(Cycles::new(TRIGGER_DELAY + position * PERIOD - 2));
+    }
+
+    #[test]
+    fn plays_wave_ram() {
+        let mut channel = playing_channel();
+        channel.step(Cycles::new(PERIOD + TRIGGER_DELAY));
+        assert_eq!(channel.sample(), 0x1);
+        channel.step(Cycles::new(PERIOD));
+        assert_eq!(channel.sample(), 0x2);
+        channel.step(Cycles::new(PERIOD));
+        assert_eq!(channel.sample(), 0x3);
+    }
+
+    #[test]
+    fn length_counter_disables_channel() {
+        let mut channel = playing_channel();
+        // Length of 2 clocks
+        channel.write(0xFF1B, 254, true);
+        channel.write(0xFF1E, 0xC0 | (FREQUENCY >> 8) as u8, true);
+
+        channel.clock_length();
+        assert!(channel.enabled());
+        channel.clock_length();
+        assert!(!channel.enabled());
+
+        // Triggering again with an expired counter plays the full length
+        channel.write(0xFF1E, 0xC0 | (FREQUENCY >> 8) as u8, true);
+        for _ in 0..MAX_LENGTH - 1 {
+            channel.clock_length();
+        }
+        assert!(channel.enabled());
+        channel.clock_length();
+        assert!(!channel.enabled());
+    }
+
+    #[test]
+    fn dmg_trigger_during_read_corrupts_wave_ram() {
+        // Reading a sample from the first 4 bytes only copies the byte being read
+        let mut channel = playing_channel();
+        run_until_read_of(&mut channel, 5);
+        channel.write(0xFF1E, 0x80 | (FREQUENCY >> 8) as u8, true);
+        let mut expected = WAVE;
+        expected[0] = WAVE[2];
+        assert_eq!(wave_ram(&channel), expected);
+
+        // Otherwise, the aligned block of 4 bytes being read is copied
+        let mut channel = playing_channel();
+        run_until_read_of(&mut channel, 19);
+        channel.write(0xFF1E, 0x80 | (FREQUENCY >> 8) as u8, true);
+        let mut expected = WAVE;
+        expected[..4].copy_from_slice(&WAVE[8..12]);
+        assert_eq!(wave_ram(&channel), expected);
+    }
+
+    #[test]
+    fn trigger_away_from_read_keeps_wave_ram() {
+        let mut channel = playing_channel();
+        channel.step(Cycles::new(TRIGGER_DELAY + 19 * PERIOD - 8));
+        channel.write(0xFF1E, 0x80 | (FREQUENCY >> 8) as u8, true);
+        assert_eq!(wave_ram(&channel), WAVE);
+    }
+
+    #[test]
+    fn cgb_trigger_during_read_keeps_wave_ram() {
+        let mut channel = playing_channel();
+        run_until_read_of(&mut channel, 19);
+        channel.write(0xFF1E, 0x80 | (FREQUENCY >> 8) as u8, false);
+        assert_eq!(wave_ram(&channel), WAVE);
+    }
+}
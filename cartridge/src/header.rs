@@ -2,6 +2,13 @@
 //! 0x150 and determines the type of mapper IC used by the cartridge, the size of the ROM, size of
 //! the RAM (if any) and the title of the game, among other data.
 
+/// The logo that the boot ROM expects in every cartridge
+pub const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
 /// The licensee of the game
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum Licensee {
@@ -11,6 +18,37 @@ pub enum Licensee {
     New([u8; 2]),
 }
 
+/// The region where the game is intended to be sold
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Destination {
+    /// Japan (and possibly overseas)
+    Japan,
+    /// Overseas only
+    Overseas,
+    /// Unknown destination code. This variant is not found in comercial games.
+    Unknown(u8),
+}
+
+impl From<u8> for Destination {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Destination::Japan,
+            1 => Destination::Overseas,
+            v => Destination::Unknown(v),
+        }
+    }
+}
+
+impl core::fmt::Display for Destination {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Destination::Japan => write!(f, "Japan"),
+            Destination::Overseas => write!(f, "Overseas"),
+            Destination::Unknown(v) => write!(f, "Unknown ({})", v),
+        }
+    }
+}
+
 /// Represents the RAM size in a game
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum RamSize {
@@ -167,6 +205,42 @@ impl From<u8> for CartridgeType {
     }
 }
 
+impl From<CartridgeType> for u8 {
+    fn from(value: CartridgeType) -> Self {
+        match value {
+            CartridgeType::RomOnly => 0x00,
+            CartridgeType::Mbc1 => 0x01,
+            CartridgeType::Mbc1Ram => 0x02,
+            CartridgeType::Mbc1RamBattery => 0x03,
+            CartridgeType::Mbc2 => 0x05,
+            CartridgeType::Mbc2Battery => 0x06,
+            CartridgeType::RomRam => 0x08,
+            CartridgeType::RomRamBattery => 0x09,
+            CartridgeType::Mmm01 => 0x0B,
+            CartridgeType::Mmm01Ram => 0x0C,
+            CartridgeType::Mmm01RamBattery => 0x0D,
+            CartridgeType::Mbc3TimerBattery => 0x0F,
+            CartridgeType::Mbc3TimerRamBattery => 0x10,
+            CartridgeType::Mbc3 => 0x11,
+            CartridgeType::Mbc3Ram => 0x12,
+            CartridgeType::Mbc3RamBattery => 0x13,
+            CartridgeType::Mbc5 => 0x19,
+            CartridgeType::Mbc5Ram => 0x1A,
+            CartridgeType::Mbc5RamBattery => 0x1B,
+            CartridgeType::Mbc5Rumble => 0x1C,
+            CartridgeType::Mbc5RumbleRam => 0x1D,
+            CartridgeType::Mbc5RumbleRamBattery => 0x1E,
+            CartridgeType::Mbc6 => 0x20,
+            CartridgeType::Mbc7SensorRumbleRamBattery => 0x22,
+            CartridgeType::PocketCamera => 0xFC,
+            CartridgeType::BandaiTama5 => 0xFD,
+            CartridgeType::Huc3 => 0xFE,
+            CartridgeType::Huc1RamBattery => 0xFF,
+            CartridgeType::Unknown(v) => v,
+        }
+    }
+}
+
 impl core::fmt::Display for CartridgeType {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{:?}", self)
@@ -186,10 +260,17 @@ pub enum Error {
 
 impl core::fmt::Display for Error {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            Error::NoHeader => write!(f, "ROM is too small to contain a header"),
+            Error::InvalidTitle => write!(f, "Title is not valid UTF-8"),
+            Error::InvalidRamSize => write!(f, "Unknown RAM size code"),
+        }
     }
 }
 
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
 /// The cartridge contains a header with an invalid RAM size
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct CartridgeHeader<'a> {
@@ -211,9 +292,25 @@ pub struct CartridgeHeader<'a> {
     pub ram_size: RamSize,
     /// The type of cartridge
     pub cartridge_type: CartridgeType,
+    /// The region where the game is intended to be sold
+    pub destination: Destination,
+    /// The version number of the game, usually 0
+    pub rom_version: u8,
 }
 
 impl<'a> CartridgeHeader<'a> {
+    /// Returns true if the cartridge contains the logo expected by the boot ROM. Dumps without it
+    /// are likely corrupted or not Game Boy ROMs at all.
+    pub fn has_valid_logo(&self) -> bool {
+        self.logo == NINTENDO_LOGO
+    }
+
+    /// Returns true if the game uses the features of the Game Boy Color, either because it only
+    /// runs on it (CGB flag 0xC0) or because it is enhanced when available (CGB flag 0x80).
+    pub fn supports_cgb(&self) -> bool {
+        self.cgb_flag.is_some_and(|flag| flag & 0x80 != 0)
+    }
+
     /// Attempts to construct a cartridge header from the raw contents of the cartridge
     pub fn try_new(data: &'a [u8]) -> Result<Self, Error> {
         if data.len() < 0x150 {
@@ -244,8 +341,14 @@ impl<'a> CartridgeHeader<'a> {
 
         let ty: CartridgeType = data[0x147].into();
 
-        let rom_size = (32 * 1024) << data[0x148] as usize;
+        // The largest valid ROM size is 8 MiB. Invalid sizes are reported as 0 bytes.
+        let rom_size = match data[0x148] {
+            v @ 0..=8 => (32 * 1024) << v as usize,
+            _ => 0,
+        };
         let ram_size = data[0x149].into();
+        let destination = data[0x14A].into();
+        let rom_version = data[0x14C];
 
         Ok(CartridgeHeader {
             entrypoint: &data[0x100..0x104],
@@ -257,6 +360,60 @@ impl<'a> CartridgeHeader<'a> {
             rom_size,
             ram_size,
             cartridge_type: ty,
+            destination,
+            rom_version,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern crate alloc;
+    use alloc::vec;
+
+    #[test]
+    fn destination_and_rom_version() {
+        let mut data = vec![0; 0x150];
+        data[0x134..0x138].copy_from_slice(b"GAME");
+
+        let header = CartridgeHeader::try_new(&data).unwrap();
+        assert_eq!(header.destination, Destination::Japan);
+        assert_eq!(header.rom_version, 0);
+
+        data[0x14A] = 0x01;
+        data[0x14C] = 0x02;
+        let header = CartridgeHeader::try_new(&data).unwrap();
+        assert_eq!(header.title, "GAME");
+        assert_eq!(header.destination, Destination::Overseas);
+        assert_eq!(header.rom_version, 2);
+
+        data[0x14A] = 0x05;
+        let header = CartridgeHeader::try_new(&data).unwrap();
+        assert_eq!(header.destination, Destination::Unknown(5));
+    }
+
+    #[test]
+    fn cgb_flag() {
+        let mut data = vec![0; 0x150];
+        data[0x134..0x13F].copy_from_slice(b"COLOR  GAME");
+
+        let header = CartridgeHeader::try_new(&data).unwrap();
+        assert_eq!(header.cgb_flag, Some(0));
+        assert!(!header.supports_cgb());
+
+        for flag in [0x80, 0xC0] {
+            data[0x143] = flag;
+            let header = CartridgeHeader::try_new(&data).unwrap();
+            assert_eq!(header.cgb_flag, Some(flag));
+            assert!(header.supports_cgb());
+        }
+
+        // Long titles use the byte of the flag
+        data[0x134..0x144].copy_from_slice(b"A VERY LONG GAME");
+        let header = CartridgeHeader::try_new(&data).unwrap();
+        assert_eq!(header.cgb_flag, None);
+        assert!(!header.supports_cgb());
+    }
+}
@@ -29,11 +29,17 @@ pub mod header;
 pub mod mappers;
 
 extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
 
 use alloc::boxed::Box;
+use alloc::string::String;
 use alloc::vec::Vec;
 use header::CartridgeHeader;
-use mappers::Mapper;
+use mappers::{BankEvent, Mapper};
+use sm83::state::{SaveState, StateError, StateReader, StateWriter};
+
+pub use mappers::is_supported;
 
 use self::header::CartridgeType;
 
@@ -57,6 +63,22 @@ pub enum Error {
 
     /// The given mapper is not supported
     UnsupportedMapper(header::CartridgeType),
+
+    /// The size of the ROM is not a power of 2 or exceeds the maximum supported by the mapper.
+    InvalidRomSize(usize),
+
+    /// The cartridge doesn't contain the Nintendo logo, so it is probably not a Game Boy ROM or it
+    /// is corrupted.
+    LogoMismatch,
+
+    /// The size of the ROM doesn't match the size declared in the header, which usually means
+    /// that the dump is truncated or overdumped.
+    RomSizeMismatch {
+        /// ROM size declared in the header, in bytes
+        declared: usize,
+        /// Actual size of the ROM, in bytes
+        actual: usize,
+    },
 }
 
 impl From<header::Error> for Error {
@@ -67,21 +89,77 @@ impl From<header::Error> for Error {
 
 impl core::fmt::Display for Error {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(f, "{self:?}")
+        match self {
+            Error::UnsupportedMapper(CartridgeType::Unknown(code)) => {
+                write!(f, "Unknown mapper type {code:#04x}")
+            }
+            Error::UnsupportedMapper(ty) => {
+                write!(f, "Unsupported mapper type {:#04x} ({ty})", u8::from(*ty))
+            }
+            Error::InvalidHeader(_) => write!(f, "Invalid cartridge header"),
+            Error::LogoMismatch => write!(f, "Nintendo logo mismatch"),
+            Error::RomSizeMismatch { declared, actual } => write!(
+                f,
+                "Declared ROM size ({declared} bytes) doesn't match the file ({actual} bytes)"
+            ),
+            _ => write!(f, "{self:?}"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::InvalidHeader(error) => Some(error),
+            _ => None,
+        }
     }
 }
 
+/// Callback invoked whenever a write to the mapper changes the selected ROM or RAM bank
+pub type BankListener = Box<dyn FnMut(BankEvent)>;
+
 /// Represents a game cartridge
 pub struct Cartridge {
     mapper: Box<dyn Mapper>,
+    rom_hash: u32,
+    bank_listener: Option<BankListener>,
+}
+
+/// 32-bit FNV-1a hash. Simple and stable across platforms and releases, which is all that is
+/// needed to tell ROMs apart.
+fn fnv1a(data: &[u8]) -> u32 {
+    const OFFSET_BASIS: u32 = 0x811c_9dc5;
+    const PRIME: u32 = 0x0100_0193;
+    data.iter().fold(OFFSET_BASIS, |hash, byte| {
+        (hash ^ *byte as u32).wrapping_mul(PRIME)
+    })
 }
 
 impl Cartridge {
     /// Constructs a new cartridge using the given ROM data. Can fail if there was a problem
     /// reading the header.
     pub fn try_new(rom_data: Vec<u8>) -> Result<Self, Error> {
-        let mapper = mappers::new_mapper(rom_data)?;
-        Ok(Self { mapper })
+        let rom_hash = fnv1a(&rom_data);
+        let mapper = mappers::new_mapper(rom_data, None)?;
+        Ok(Self {
+            mapper,
+            rom_hash,
+            bank_listener: None,
+        })
+    }
+
+    /// Constructs a new cartridge using the given ROM data, ignoring the RAM size declared in the
+    /// header and using `ram_size` bytes of RAM instead.
+    pub fn try_new_with_ram_size(rom_data: Vec<u8>, ram_size: usize) -> Result<Self, Error> {
+        let rom_hash = fnv1a(&rom_data);
+        let mapper = mappers::new_mapper(rom_data, Some(ram_size))?;
+        Ok(Self {
+            mapper,
+            rom_hash,
+            bank_listener: None,
+        })
     }
 
     /// Returns the header of the current cartridge. Note that the header keeps borrowed data of
@@ -90,6 +168,50 @@ impl Cartridge {
         self.mapper.header().unwrap()
     }
 
+    /// Returns the whole ROM image of the cartridge. Useful for tools like disassemblers or
+    /// patchers that need access to every bank.
+    pub fn rom(&self) -> &[u8] {
+        self.mapper.rom()
+    }
+
+    /// Decodes the header of the current cartridge, returning an error instead of panicking if it
+    /// is malformed.
+    pub fn try_header(&self) -> Result<CartridgeHeader<'_>, header::Error> {
+        self.mapper.header()
+    }
+
+    /// Returns a key that identifies the cartridge, meant to be used as the name of its save
+    /// file. It combines the title of the game, restricted to characters that are safe in file
+    /// names, with a short hash of the ROM, so that different ROMs with the same title don't share
+    /// their saves.
+    pub fn save_key(&self) -> String {
+        let title: String = self
+            .header()
+            .title
+            .trim()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        let title = if title.is_empty() { "untitled" } else { &title };
+        alloc::format!("{title}-{:08x}", self.rom_hash)
+    }
+
+    /// Returns a hash of the title of the game, which is stored in save states to detect when
+    /// they are loaded with a different cartridge.
+    pub fn title_hash(&self) -> u32 {
+        fnv1a(self.header().title.as_bytes())
+    }
+
+    /// Decodes the effect that writing `value` to the given address of the mapper control region
+    /// (0x0000 to 0x7FFF) would have. Must be called before performing the write.
+    pub fn decode_control_write(
+        &self,
+        address: sm83::memory::Address,
+        value: u8,
+    ) -> mappers::ControlWrite {
+        self.mapper.decode_control_write(address, value)
+    }
+
     /// Returns true if the cartridge has a battery to keep RAM powered while the GameBoy is off.
     pub fn has_battery(&self) -> bool {
         match self.header().cartridge_type {
@@ -120,15 +242,235 @@ impl Cartridge {
         self.mapper.restore_battery_backed_ram(ram)
     }
 
+    /// Advances the real-time clock of the cartridge by the given number of seconds, if it has
+    /// one.
+    pub fn tick_rtc(&mut self, seconds: u64) {
+        self.mapper.tick_rtc(seconds)
+    }
+
+    /// Stores the given UNIX time in the save of the real-time clock, if the cartridge has one.
+    /// Call it right before saving the battery-backed RAM.
+    pub fn stamp_rtc(&mut self, now: u64) {
+        self.mapper.stamp_rtc(now)
+    }
+
+    /// Advances the real-time clock, if the cartridge has one, by the time elapsed between the
+    /// timestamp of the restored save and the given UNIX time.
+    pub fn catch_up_rtc(&mut self, now: u64) {
+        self.mapper.catch_up_rtc(now)
+    }
+
+    /// Restores the banking registers of the mapper to their power-on state. The contents of the
+    /// cartridge RAM are preserved.
+    pub fn reset(&mut self) {
+        self.mapper.reset()
+    }
+
     /// Reads the given memory-mapped address of the cartridge. Panics if the address does not
     /// belong the address space of the cartridge (0x0000 to 0x8000 or 0xA000 to 0xC000).
     pub fn read(&self, address: sm83::memory::Address) -> u8 {
         self.mapper.read(address)
     }
 
+    /// Returns the ROM bank mapped at 0x4000 to 0x7FFF.
+    pub fn selected_rom_bank(&self) -> usize {
+        self.mapper.selected_rom_bank()
+    }
+
+    /// Returns the RAM bank mapped at 0xA000 to 0xBFFF.
+    pub fn selected_ram_bank(&self) -> usize {
+        self.mapper.selected_ram_bank()
+    }
+
+    /// Registers a callback that observes the changes of the selected ROM and RAM banks caused by
+    /// writes to the mapper, replacing the previous one, if any. Useful to debug banking issues.
+    pub fn on_bank_switch(&mut self, listener: Option<BankListener>) {
+        self.bank_listener = listener;
+    }
+
     /// Writes the given memory-mapped address of the cartridge with the given value. Panics if
     /// the address does not belong the address space of the cartridge (0x0000 to 0x8000 or 0xA000 to 0xC000).
     pub fn write(&mut self, address: sm83::memory::Address, value: u8) {
-        self.mapper.write(address, value)
+        let Some(listener) = self.bank_listener.as_mut().filter(|_| address < 0x8000) else {
+            self.mapper.write(address, value);
+            return;
+        };
+
+        let old_rom_bank = self.mapper.selected_rom_bank();
+        let old_ram_bank = self.mapper.selected_ram_bank();
+        self.mapper.write(address, value);
+        let new_rom_bank = self.mapper.selected_rom_bank();
+        let new_ram_bank = self.mapper.selected_ram_bank();
+
+        if new_rom_bank != old_rom_bank {
+            listener(BankEvent::Rom {
+                old: old_rom_bank,
+                new: new_rom_bank,
+            });
+        }
+        if new_ram_bank != old_ram_bank {
+            listener(BankEvent::Ram {
+                old: old_ram_bank,
+                new: new_ram_bank,
+            });
+        }
+    }
+}
+
+/// Saves the banking registers and the RAM of the cartridge. The ROM is not part of the state.
+impl SaveState for Cartridge {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.mapper.save_state(state)
+    }
+
+    fn load_state(&mut self, state: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.mapper.load_state(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use alloc::{format, vec};
+
+    fn rom_with_title(title: &[u8]) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x134..0x134 + title.len()].copy_from_slice(title);
+        rom
+    }
+
+    #[test]
+    fn supported_mappers() {
+        assert!(is_supported(CartridgeType::RomOnly));
+        assert!(is_supported(CartridgeType::Mbc5RamBattery));
+        assert!(!is_supported(CartridgeType::Huc1RamBattery));
+
+        let mut rom = rom_with_title(b"HUC1");
+        rom[0x147] = 0xFF;
+        assert!(matches!(
+            Cartridge::try_new(rom),
+            Err(Error::UnsupportedMapper(CartridgeType::Huc1RamBattery))
+        ));
+    }
+
+    #[test]
+    fn save_key_sanitizes_title() {
+        let cartridge = Cartridge::try_new(rom_with_title(b"POKEMON RED")).unwrap();
+        let key = cartridge.save_key();
+        assert!(key.starts_with("POKEMON_RED-"));
+        assert_eq!(key.len(), "POKEMON_RED-".len() + 8);
+
+        let cartridge = Cartridge::try_new(rom_with_title(b"")).unwrap();
+        assert!(cartridge.save_key().starts_with("untitled-"));
+    }
+
+    #[test]
+    fn save_key_differs_for_roms_with_same_title() {
+        let rom_a = rom_with_title(b"TETRIS");
+        let mut rom_b = rom_with_title(b"TETRIS");
+        rom_b[0x4000] = 0x01;
+
+        let cartridge_a = Cartridge::try_new(rom_a.clone()).unwrap();
+        let cartridge_b = Cartridge::try_new(rom_b).unwrap();
+        assert_ne!(cartridge_a.save_key(), cartridge_b.save_key());
+
+        // The key is stable for the same ROM
+        let cartridge_a2 = Cartridge::try_new(rom_a).unwrap();
+        assert_eq!(cartridge_a.save_key(), cartridge_a2.save_key());
+    }
+
+    fn rom_with_logo(cartridge_type: u8, rom_size: u8, len: usize) -> Vec<u8> {
+        let mut rom = rom_with_title(b"DIAG");
+        rom.resize(len, 0);
+        rom[0x104..0x134].copy_from_slice(&header::NINTENDO_LOGO);
+        rom[0x147] = cartridge_type;
+        rom[0x148] = rom_size;
+        rom
+    }
+
+    #[test]
+    fn diagnose_logo_mismatch() {
+        let mut rom = rom_with_title(b"GARBAGE");
+        rom[0x147] = 0x42;
+        assert!(matches!(Cartridge::try_new(rom), Err(Error::LogoMismatch)));
+
+        // With the logo, the unknown mapper is reported instead
+        let rom = rom_with_logo(0x42, 0, 0x8000);
+        let Err(error) = Cartridge::try_new(rom) else {
+            panic!("The cartridge should not load");
+        };
+        assert!(matches!(
+            error,
+            Error::UnsupportedMapper(CartridgeType::Unknown(0x42))
+        ));
+        assert_eq!(format!("{error}"), "Unknown mapper type 0x42");
+    }
+
+    #[test]
+    fn diagnose_rom_size_mismatch() {
+        // MBC1 declaring 64 KiB of ROM, but truncated to 24 KiB
+        let rom = rom_with_logo(0x01, 0x01, 0x6000);
+        let Err(error) = Cartridge::try_new(rom) else {
+            panic!("The cartridge should not load");
+        };
+        assert!(matches!(
+            error,
+            Error::RomSizeMismatch {
+                declared: 0x10000,
+                actual: 0x6000
+            }
+        ));
+        assert_eq!(
+            format!("{error}"),
+            "Declared ROM size (65536 bytes) doesn't match the file (24576 bytes)"
+        );
+    }
+
+    #[test]
+    fn diagnose_unsupported_mapper() {
+        let rom = rom_with_logo(0x20, 0, 0x8000);
+        let Err(error) = Cartridge::try_new(rom) else {
+            panic!("The cartridge should not load");
+        };
+        assert!(matches!(
+            error,
+            Error::UnsupportedMapper(CartridgeType::Mbc6)
+        ));
+        assert_eq!(format!("{error}"), "Unsupported mapper type 0x20 (Mbc6)");
+    }
+
+    #[test]
+    fn bank_switch_events() {
+        // MBC5 + RAM, 128 KiB ROM, 32 KiB RAM
+        let mut rom = rom_with_logo(0x1A, 0x02, 0x20000);
+        rom[0x149] = 0x03;
+        let mut cartridge = Cartridge::try_new(rom).unwrap();
+
+        let events = alloc::rc::Rc::new(core::cell::RefCell::new(Vec::new()));
+        let listener_events = events.clone();
+        cartridge.on_bank_switch(Some(Box::new(move |event| {
+            listener_events.borrow_mut().push(event)
+        })));
+
+        cartridge.write(0x2000, 5);
+        // Selecting the same bank again is not reported
+        cartridge.write(0x2000, 5);
+        cartridge.write(0x2000, 2);
+        cartridge.write(0x4000, 3);
+        // Neither are writes that don't change the banks
+        cartridge.write(0x0000, 0x0A);
+        cartridge.write(0xA000, 0x42);
+
+        assert_eq!(
+            *events.borrow(),
+            [
+                BankEvent::Rom { old: 0, new: 5 },
+                BankEvent::Rom { old: 5, new: 2 },
+                BankEvent::Ram { old: 0, new: 3 },
+            ]
+        );
+        assert_eq!(cartridge.selected_rom_bank(), 2);
+        assert_eq!(cartridge.selected_ram_bank(), 3);
     }
 }
@@ -7,8 +7,10 @@ use crate::header::{self, CartridgeHeader, CartridgeType};
 extern crate alloc;
 use alloc::boxed::Box;
 use alloc::vec::Vec;
+use sm83::state::{StateError, StateReader, StateWriter};
 
 mod mbc1;
+mod mbc2;
 mod mbc3;
 mod mbc5;
 mod rom_only;
@@ -20,6 +22,9 @@ pub trait Mapper {
     /// for the header or the title of the game is not a valid string.
     fn header<'a>(&'a self) -> Result<CartridgeHeader<'a>, header::Error>;
 
+    /// Returns the whole ROM image of the cartridge, regardless of the selected banks.
+    fn rom(&self) -> &[u8];
+
     /// Reads the given memory-mapped address of the cartridge. Panics if the address does not
     /// belong the address space of the cartridge (0x0000 to 0x8000 or 0xA000 to 0xC000).
     fn read(&self, address: sm83::memory::Address) -> u8;
@@ -28,43 +33,240 @@ pub trait Mapper {
     /// the address does not belong the address space of the cartridge (0x0000 to 0x8000 or 0xA000 to 0xC000).
     fn write(&mut self, address: sm83::memory::Address, value: u8);
 
+    /// Decodes the effect that writing `value` to the given address in the mapper control region
+    /// (0x0000 to 0x7FFF) would have, given the current state of the mapper. Must be called
+    /// before performing the write. This is meant for debugging banking issues.
+    fn decode_control_write(&self, _address: sm83::memory::Address, _value: u8) -> ControlWrite {
+        ControlWrite::Ignored
+    }
+
+    /// Returns the ROM bank mapped at 0x4000 to 0x7FFF.
+    fn selected_rom_bank(&self) -> usize {
+        1
+    }
+
+    /// Returns the RAM bank mapped at 0xA000 to 0xBFFF. On MBC3 cartridges, values from 0x08 to
+    /// 0x0C select an RTC register instead.
+    fn selected_ram_bank(&self) -> usize {
+        0
+    }
+
     /// Returns a slice of the RAM that is battery-backed in the cartridge.
     /// Not all cartridge types have this memory.
     fn battery_backed_ram(&self) -> Option<&[u8]> {
         None
     }
 
+    /// Advances the real-time clock of the cartridge by the given number of seconds. Frontends
+    /// call this to keep the clock in sync with the wall-clock time. Cartridges without a clock
+    /// ignore it.
+    fn tick_rtc(&mut self, _seconds: u64) {}
+
+    /// Stores the given UNIX time in the save as the time it was made, so that the clock can catch
+    /// up when the save is restored. Cartridges without a clock ignore it.
+    fn stamp_rtc(&mut self, _now: u64) {}
+
+    /// Advances the real-time clock by the time elapsed from the timestamp of the restored save
+    /// to the given UNIX time. Cartridges without a clock ignore it.
+    fn catch_up_rtc(&mut self, _now: u64) {}
+
+    /// Restores the banking registers to their power-on state, disabling RAM. The contents of the
+    /// RAM are preserved.
+    fn reset(&mut self) {}
+
     /// Attempts to restore the battery-backed RAM from the given slice. This may be used to restore
     /// RAM from a save file after the emulator starts up.
     fn restore_battery_backed_ram(&mut self, _ram: &[u8]) -> Result<(), crate::Error> {
         Err(crate::Error::CartridgeHasNoRam)
     }
+
+    /// Serializes the volatile state of the mapper, which are its banking registers and the
+    /// contents of the RAM. Mappers without any state don't write anything.
+    fn save_state(&self, _state: &mut StateWriter) {}
+
+    /// Restores the state serialized with `save_state`.
+    fn load_state(&mut self, _state: &mut StateReader<'_>) -> Result<(), StateError> {
+        Ok(())
+    }
+}
+
+/// The decoded effect of a write to the control registers of a mapper.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlWrite {
+    /// Enables or disables access to the cartridge RAM (and RTC, if any)
+    RamEnable(bool),
+    /// Selects the ROM bank mapped at 0x4000 to 0x7FFF
+    RomBank(usize),
+    /// Selects the RAM bank mapped at 0xA000 to 0xBFFF
+    RamBank(usize),
+    /// Selects an RTC register to be mapped at 0xA000 to 0xBFFF
+    RtcRegister(usize),
+    /// Selects the banking mode of MBC1 mappers
+    BankingMode(u8),
+    /// Writes to the RTC latch register
+    RtcLatch(u8),
+    /// The write has no effect on this mapper
+    Ignored,
+}
+
+impl core::fmt::Display for ControlWrite {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ControlWrite::RamEnable(true) => write!(f, "RAM enabled"),
+            ControlWrite::RamEnable(false) => write!(f, "RAM disabled"),
+            ControlWrite::RomBank(bank) => write!(f, "ROM bank {bank:#x} selected"),
+            ControlWrite::RamBank(bank) => write!(f, "RAM bank {bank:#x} selected"),
+            ControlWrite::RtcRegister(reg) => write!(f, "RTC register {reg:#x} selected"),
+            ControlWrite::BankingMode(mode) => write!(f, "banking mode {mode} selected"),
+            ControlWrite::RtcLatch(value) => write!(f, "RTC latch write {value:#x}"),
+            ControlWrite::Ignored => write!(f, "ignored"),
+        }
+    }
+}
+
+/// A change of the banks selected by the mapper, caused by a write to its control registers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BankEvent {
+    /// The ROM bank mapped at 0x4000 to 0x7FFF changed
+    Rom {
+        /// The previously selected bank
+        old: usize,
+        /// The newly selected bank
+        new: usize,
+    },
+    /// The RAM bank mapped at 0xA000 to 0xBFFF changed
+    Ram {
+        /// The previously selected bank
+        old: usize,
+        /// The newly selected bank
+        new: usize,
+    },
+}
+
+/// Returns true if cartridges of the given type are supported by the emulator. Frontends may use
+/// this to check whether a ROM can run before attempting to boot it.
+pub fn is_supported(cartridge_type: CartridgeType) -> bool {
+    matches!(
+        cartridge_type,
+        CartridgeType::RomOnly
+            | CartridgeType::Mbc1
+            | CartridgeType::Mbc1Ram
+            | CartridgeType::Mbc1RamBattery
+            | CartridgeType::Mbc2
+            | CartridgeType::Mbc2Battery
+            | CartridgeType::Mbc3
+            | CartridgeType::Mbc3Ram
+            | CartridgeType::Mbc3RamBattery
+            | CartridgeType::Mbc3TimerBattery
+            | CartridgeType::Mbc3TimerRamBattery
+            | CartridgeType::Mbc5
+            | CartridgeType::Mbc5Ram
+            | CartridgeType::Mbc5RamBattery
+    )
+}
+
+/// Banked mappers require the ROM size to be a power of 2 within the addressable range.
+fn check_rom_size(data: &[u8], max_size: usize) -> Result<(), super::Error> {
+    if data.len().is_power_of_two() && data.len() <= max_size {
+        Ok(())
+    } else {
+        Err(super::Error::InvalidRomSize(data.len()))
+    }
+}
+
+/// Inspects the header of a ROM to find a more specific reason why it can't be loaded than the
+/// error reported while creating the mapper.
+fn diagnose(data: &[u8]) -> Option<super::Error> {
+    let header = CartridgeHeader::try_new(data).ok()?;
+    if !header.has_valid_logo() {
+        return Some(super::Error::LogoMismatch);
+    }
+    if header.rom_size != data.len() {
+        return Some(super::Error::RomSizeMismatch {
+            declared: header.rom_size,
+            actual: data.len(),
+        });
+    }
+    None
 }
 
 /// Creates a new mapper from the given ROM. The rom header is parsed to determine the required
 /// mapper type. A boxed Mapper type implementing the mapper type indicated by the cartridge header
-/// is returned. This method panics
-pub fn new_mapper(data: Vec<u8>) -> Result<Box<dyn Mapper>, super::Error> {
+/// is returned.
+///
+/// The RAM size declared in the header can be overridden by passing `ram_size_override`. This is
+/// useful for dumps that declare the wrong RAM size or homebrew using non-standard RAM sizes.
+///
+/// When the mapper can't be created, the header is inspected to report a more specific error,
+/// such as a missing logo or a ROM size that doesn't match the header.
+pub fn new_mapper(
+    data: Vec<u8>,
+    ram_size_override: Option<usize>,
+) -> Result<Box<dyn Mapper>, super::Error> {
+    let diagnostic = diagnose(&data);
+    build_mapper(data, ram_size_override).map_err(|error| match (error, diagnostic) {
+        // A known but unsupported cartridge type is specific enough on its own
+        (error @ super::Error::UnsupportedMapper(ty), _)
+            if !matches!(ty, CartridgeType::Unknown(_)) =>
+        {
+            error
+        }
+        (_, Some(diagnostic)) => diagnostic,
+        (error, None) => error,
+    })
+}
+
+fn build_mapper(
+    data: Vec<u8>,
+    ram_size_override: Option<usize>,
+) -> Result<Box<dyn Mapper>, super::Error> {
     let header = CartridgeHeader::try_new(&data)?;
     let cartridge_type = header.cartridge_type;
-    let ram_size = header
-        .ram_size
-        .into_usize()
-        .ok_or(super::Error::InvalidHeader(header::Error::InvalidRamSize))?;
+    if !is_supported(cartridge_type) {
+        return Err(crate::Error::UnsupportedMapper(cartridge_type));
+    }
+    let ram_size = match ram_size_override {
+        Some(ram_size) => {
+            log::warn!(
+                "Overriding cartridge RAM size declared in header ({}) with {} bytes",
+                header.ram_size,
+                ram_size
+            );
+            ram_size
+        }
+        None => header
+            .ram_size
+            .into_usize()
+            .ok_or(super::Error::InvalidHeader(header::Error::InvalidRamSize))?,
+    };
 
     Ok(match cartridge_type {
         CartridgeType::RomOnly => Box::new(rom_only::RomOnly::new(data)),
         CartridgeType::Mbc1 | CartridgeType::Mbc1Ram | CartridgeType::Mbc1RamBattery => {
+            check_rom_size(&data, mbc1::MAX_ROM_SIZE)?;
             Box::new(mbc1::Mbc1::new(data, ram_size))
         }
+        CartridgeType::Mbc2 | CartridgeType::Mbc2Battery => {
+            check_rom_size(&data, mbc2::MAX_ROM_SIZE)?;
+            if ram_size_override.is_some() {
+                log::warn!("MBC2 cartridges have built-in RAM, ignoring the RAM size override");
+            }
+            Box::new(mbc2::Mbc2::new(data))
+        }
         CartridgeType::Mbc3 | CartridgeType::Mbc3Ram | CartridgeType::Mbc3RamBattery => {
-            Box::new(mbc3::Mbc3::new(data, ram_size))
+            check_rom_size(&data, mbc3::MAX_ROM_SIZE)?;
+            Box::new(mbc3::Mbc3::new(data, ram_size, false))
+        }
+        CartridgeType::Mbc3TimerBattery | CartridgeType::Mbc3TimerRamBattery => {
+            check_rom_size(&data, mbc3::MAX_ROM_SIZE)?;
+            Box::new(mbc3::Mbc3::new(data, ram_size, true))
         }
         CartridgeType::Mbc5 | CartridgeType::Mbc5Ram | CartridgeType::Mbc5RamBattery => {
+            check_rom_size(&data, mbc5::MAX_ROM_SIZE)?;
             Box::new(mbc5::Mbc5::new(data, ram_size))
         }
         v => {
-            // Other cartridge types are currently unsupported
+            // Must be kept in sync with `is_supported`
             return Err(crate::Error::UnsupportedMapper(v));
         }
     })
@@ -5,19 +5,23 @@ extern crate alloc;
 use alloc::vec;
 use alloc::vec::Vec;
 
-use super::Mapper;
+use super::{ControlWrite, Mapper};
+use sm83::state::{StateError, StateReader, StateWriter};
 
+// Max size of MBC1 roms is 2 MiB
+pub const MAX_ROM_SIZE: usize = 2048 * 1024;
 const ROM_BANK_SIZE: usize = 16 * 1024;
-const ROM_BANK_SELECT_MASK: usize = 0x1F;
+const ROM_BANK_LOW_SELECT_MASK: usize = 0x1F;
+const ROM_BANK_HIGH_SELECT_OFFSET: usize = 5;
 
 const RAM_BASE: usize = 0xA000;
-const RAM_BANK_SIZE: usize = 4 * 1024;
-const RAM_BANK_SELECT_MASK: usize = 0x03;
+const RAM_BANK_SIZE: usize = 8 * 1024;
+const BANK_HIGH_SELECT_MASK: usize = 0x03;
 
 const BANK_MODE_SELECT_MASK: usize = 0x01;
 
 #[repr(u8)]
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum Mode {
     Simple = 0,
     Advanced = 1,
@@ -26,46 +30,83 @@ enum Mode {
 pub struct Mbc1 {
     rom: Vec<u8>,
     ram: Vec<u8>,
+    ram_mask: usize,
     ram_enabled: bool,
-    selected_rom_bank: usize,
-    selected_ram_bank: usize,
+    /// 5-bit register at 0x2000 to 0x3FFF. Never 0, as writing 0 selects bank 1.
+    bank_low: usize,
+    /// 2-bit register at 0x4000 to 0x5FFF. Selects the RAM bank, or bits 5 and 6 of the ROM bank
+    /// in ROMs of 1 MiB or more.
+    bank_high: usize,
     mode: Mode,
 }
 
 impl Mbc1 {
     pub fn new(rom: Vec<u8>, ram_size: usize) -> Self {
         assert!(rom.len().count_ones() == 1); // ROM size must be a power of 2
-        assert!(rom.len() < 512 * 1024); // ROMs larger than 512 KiB are currently unsupported
+        assert!(rom.len() <= MAX_ROM_SIZE);
+
+        // RAM sizes that are not a power of 2 are only reachable through a RAM size override.
+        // Addresses are decoded as if the RAM had the next power of 2 size.
+        let ram_mask = ram_size.next_power_of_two().saturating_sub(1);
 
         Self {
             rom,
             ram: vec![0; ram_size],
+            ram_mask,
             ram_enabled: false,
-            selected_rom_bank: 1,
-            selected_ram_bank: 0,
+            bank_low: 1,
+            bank_high: 0,
             mode: Mode::Simple,
         }
     }
 
-    fn read_rom(&self, address: usize) -> u8 {
+    /// Bank mapped at 0x0000 to 0x3FFF. It is always bank 0 in simple mode, but in advanced mode
+    /// the high bank register selects bank 0x00, 0x20, 0x40 or 0x60.
+    fn rom_bank_0(&self) -> usize {
+        match self.mode {
+            Mode::Simple => 0,
+            Mode::Advanced => self.bank_high << ROM_BANK_HIGH_SELECT_OFFSET,
+        }
+    }
+
+    /// Bank mapped at 0x4000 to 0x7FFF. Because the low bank register can't be 0, banks 0x00,
+    /// 0x20, 0x40 and 0x60 can't be mapped here, selecting the following bank instead.
+    fn rom_bank_n(&self) -> usize {
+        (self.bank_high << ROM_BANK_HIGH_SELECT_OFFSET) | self.bank_low
+    }
+
+    fn ram_bank(&self) -> usize {
+        match self.mode {
+            Mode::Simple => 0,
+            Mode::Advanced => self.bank_high,
+        }
+    }
+
+    fn read_rom(&self, bank: usize, offset: usize) -> u8 {
         // Banks that don't exist need to read the wrapped address value.
-        // TODO: This mod operation is in a hot path, move it to bank selection
-        //       or make sure it is always implemented as a shift
-        let address = address % self.rom.len();
+        let address = (bank * ROM_BANK_SIZE + offset) & (self.rom.len() - 1);
         self.rom[address]
     }
 
-    fn read_ram(&self, address: usize) -> u8 {
-        if address < self.ram.len() && self.ram_enabled {
-            self.ram[address]
-        } else {
-            0
+    fn ram_address(&self, address: sm83::memory::Address) -> usize {
+        (self.ram_bank() * RAM_BANK_SIZE + address as usize - RAM_BASE) & self.ram_mask
+    }
+
+    fn read_ram(&self, address: sm83::memory::Address) -> u8 {
+        if !self.ram_enabled {
+            return 0xff;
         }
+        let address = self.ram_address(address);
+        self.ram.get(address).copied().unwrap_or(0xff)
     }
 
-    fn write_ram(&mut self, address: usize, value: u8) {
-        if address < self.ram.len() && self.ram_enabled {
-            self.ram[address] = value
+    fn write_ram(&mut self, address: sm83::memory::Address, value: u8) {
+        if !self.ram_enabled {
+            return;
+        }
+        let address = self.ram_address(address);
+        if let Some(byte) = self.ram.get_mut(address) {
+            *byte = value;
         }
     }
 }
@@ -75,29 +116,23 @@ impl Mapper for Mbc1 {
         CartridgeHeader::try_new(&self.rom)
     }
 
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
     fn read(&self, address: sm83::memory::Address) -> u8 {
         match address {
             0x0000..=0x3FFF => {
-                // ROM bank 0 (16 KiB)
-                // Note that for roms > 512 KiB, this bank is switched if is Mode::Advanced.
-                // However, this is currently an unsupported ROM type.
-                self.read_rom(address as usize)
+                // ROM bank 0, 0x20, 0x40 or 0x60 (16 KiB)
+                self.read_rom(self.rom_bank_0(), address as usize)
             }
             0x4000..=0x7FFF => {
-                // ROM bank 1 to 0x1F (16 KiB each)
-                debug_assert!(self.selected_rom_bank < 0x20);
-                debug_assert_ne!(self.selected_rom_bank, 0);
-                let address = (self.selected_rom_bank - 1) * ROM_BANK_SIZE + address as usize;
-                self.read_rom(address)
+                // ROM bank 1 to 0x7F (16 KiB each)
+                self.read_rom(self.rom_bank_n(), address as usize - ROM_BANK_SIZE)
             }
             0xA000..=0xBFFF => {
-                // RAM bank
-                let offset = if self.mode == Mode::Advanced {
-                    self.selected_ram_bank * RAM_BANK_SIZE
-                } else {
-                    0
-                };
-                self.read_ram(address as usize - RAM_BASE + offset)
+                // RAM bank 0 to 3 (8 KiB each)
+                self.read_ram(address)
             }
             _ => unimplemented!(),
         }
@@ -110,18 +145,13 @@ impl Mapper for Mbc1 {
                 self.ram_enabled = value & 0xF == 0x0A;
             }
             0x2000..=0x3FFF => {
-                // ROM Bank number
-                let value = ROM_BANK_SELECT_MASK & (value as usize);
-                if value == 0 {
-                    self.selected_rom_bank = 1;
-                } else {
-                    self.selected_rom_bank = value;
-                }
+                // Lower 5 bits of the ROM Bank number
+                let value = ROM_BANK_LOW_SELECT_MASK & (value as usize);
+                self.bank_low = if value == 0 { 1 } else { value };
             }
             0x4000..=0x5FFF => {
-                // RAM Bank number
-                let value = RAM_BANK_SELECT_MASK & (value as usize);
-                self.selected_ram_bank = value;
+                // RAM Bank number or upper 2 bits of the ROM bank number
+                self.bank_high = BANK_HIGH_SELECT_MASK & (value as usize);
             }
             0x6000..=0x7FFF => {
                 // Banking mode selection
@@ -134,17 +164,50 @@ impl Mapper for Mbc1 {
             }
             0xA000..=0xBFFF => {
                 // RAM bank
-                let offset = if self.mode == Mode::Advanced {
-                    self.selected_ram_bank * RAM_BANK_SIZE
-                } else {
-                    0
-                };
-                self.write_ram(address as usize - RAM_BASE + offset, value)
+                self.write_ram(address, value)
             }
             _ => unimplemented!(),
         }
     }
 
+    fn decode_control_write(&self, address: sm83::memory::Address, value: u8) -> ControlWrite {
+        match address {
+            0x0000..=0x1FFF => ControlWrite::RamEnable(value & 0xF == 0x0A),
+            0x2000..=0x3FFF => {
+                let value = ROM_BANK_LOW_SELECT_MASK & (value as usize);
+                let bank_low = if value == 0 { 1 } else { value };
+                ControlWrite::RomBank((self.bank_high << ROM_BANK_HIGH_SELECT_OFFSET) | bank_low)
+            }
+            0x4000..=0x5FFF => {
+                let value = BANK_HIGH_SELECT_MASK & (value as usize);
+                if self.rom.len() > 32 * ROM_BANK_SIZE {
+                    ControlWrite::RomBank((value << ROM_BANK_HIGH_SELECT_OFFSET) | self.bank_low)
+                } else {
+                    ControlWrite::RamBank(value)
+                }
+            }
+            0x6000..=0x7FFF => {
+                ControlWrite::BankingMode((BANK_MODE_SELECT_MASK & (value as usize)) as u8)
+            }
+            _ => ControlWrite::Ignored,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.ram_enabled = false;
+        self.bank_low = 1;
+        self.bank_high = 0;
+        self.mode = Mode::Simple;
+    }
+
+    fn selected_rom_bank(&self) -> usize {
+        self.rom_bank_n()
+    }
+
+    fn selected_ram_bank(&self) -> usize {
+        self.ram_bank()
+    }
+
     fn battery_backed_ram(&self) -> Option<&[u8]> {
         Some(&self.ram)
     }
@@ -162,4 +225,205 @@ impl Mapper for Mbc1 {
             .for_each(|(d, s)| *d = *s);
         Ok(())
     }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.ram_enabled);
+        state.write_u8(self.bank_low as u8);
+        state.write_u8(self.bank_high as u8);
+        state.write_u8(self.mode as u8);
+        state.write_bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.ram_enabled = state.read_bool()?;
+        let bank_low = state.read_u8()? as usize;
+        let bank_high = state.read_u8()? as usize;
+        if bank_low == 0 || bank_low > ROM_BANK_LOW_SELECT_MASK || bank_high > BANK_HIGH_SELECT_MASK
+        {
+            return Err(StateError::Invalid);
+        }
+        self.bank_low = bank_low;
+        self.bank_high = bank_high;
+        self.mode = match state.read_u8()? {
+            0 => Mode::Simple,
+            1 => Mode::Advanced,
+            _ => return Err(StateError::Invalid),
+        };
+        state.read_bytes(&mut self.ram)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mappers::new_mapper;
+
+    const ROM_SIZE: usize = 512 * 1024;
+
+    /// An MBC1 ROM where the first byte of each bank contains the bank number
+    fn mbc1_rom(rom_size: usize) -> Vec<u8> {
+        let mut rom = vec![0; rom_size];
+        for (bank, data) in rom.chunks_mut(ROM_BANK_SIZE).enumerate() {
+            data[0] = bank as u8;
+        }
+        // MBC1 + RAM + Battery, 32 KiB RAM
+        rom[0x147] = 0x03;
+        rom[0x148] = (rom_size / (32 * 1024)).trailing_zeros() as u8;
+        rom[0x149] = 0x03;
+        rom
+    }
+
+    #[test]
+    fn rom_bank_switching() {
+        let mut mapper = new_mapper(mbc1_rom(ROM_SIZE), None).unwrap();
+        assert_eq!(mapper.read(0x0000), 0);
+        assert_eq!(mapper.read(0x4000), 1);
+
+        for bank in 1..0x20 {
+            mapper.write(0x2000, bank);
+            assert_eq!(mapper.read(0x4000), bank);
+            assert_eq!(mapper.read(0x0000), 0);
+        }
+
+        // Bank 0 can't be mapped at 0x4000
+        mapper.write(0x2000, 0);
+        assert_eq!(mapper.read(0x4000), 1);
+
+        // Only the lower 5 bits of the register are used
+        mapper.write(0x2000, 0xE3);
+        assert_eq!(mapper.read(0x4000), 3);
+    }
+
+    #[test]
+    fn large_rom_bank_switching() {
+        let mut mapper = Mbc1::new(mbc1_rom(MAX_ROM_SIZE), 0);
+
+        mapper.write(0x4000, 2);
+        mapper.write(0x2000, 0x05);
+        assert_eq!(mapper.read(0x4000), 0x45);
+        assert_eq!(mapper.read(0x0000), 0);
+
+        // Banks 0x20, 0x40 and 0x60 map to the next bank
+        for high in 0..4 {
+            mapper.write(0x4000, high);
+            mapper.write(0x2000, 0);
+            assert_eq!(mapper.read(0x4000), (high << 5) + 1);
+        }
+
+        // In advanced mode, the upper bits also switch the bank at 0x0000
+        mapper.write(0x6000, 1);
+        mapper.write(0x4000, 3);
+        assert_eq!(mapper.read(0x0000), 0x60);
+        mapper.write(0x6000, 0);
+        assert_eq!(mapper.read(0x0000), 0);
+    }
+
+    #[test]
+    fn banks_wrap_around_rom_size() {
+        let mut mapper = Mbc1::new(mbc1_rom(ROM_SIZE), 0);
+
+        // 512 KiB only has 32 banks, so the upper bits are ignored for the ROM
+        mapper.write(0x4000, 1);
+        mapper.write(0x2000, 0x07);
+        assert_eq!(mapper.read(0x4000), 0x07);
+
+        mapper.write(0x6000, 1);
+        assert_eq!(mapper.read(0x0000), 0);
+    }
+
+    #[test]
+    fn ram_banking() {
+        let mut mapper = Mbc1::new(mbc1_rom(ROM_SIZE), 32 * 1024);
+
+        // RAM is disabled by default
+        mapper.write(0xA000, 0x42);
+        assert_eq!(mapper.read(0xA000), 0xff);
+
+        mapper.write(0x0000, 0x0A);
+        mapper.write(0x6000, 1);
+        for bank in 0..4 {
+            mapper.write(0x4000, bank);
+            mapper.write(0xA000, 0x10 + bank);
+        }
+        for bank in 0..4 {
+            mapper.write(0x4000, bank);
+            assert_eq!(mapper.read(0xA000), 0x10 + bank);
+        }
+
+        // In simple mode, only bank 0 is accessible
+        mapper.write(0x6000, 0);
+        assert_eq!(mapper.read(0xA000), 0x10);
+
+        let ram = mapper.battery_backed_ram().unwrap();
+        for bank in 0..4 {
+            assert_eq!(ram[bank * RAM_BANK_SIZE], 0x10 + bank as u8);
+        }
+    }
+
+    #[test]
+    fn ram_enable_gating() {
+        let mut mapper = Mbc1::new(mbc1_rom(ROM_SIZE), 8 * 1024);
+
+        // Writes while RAM is disabled are dropped
+        mapper.write(0xA000, 0x42);
+        assert_eq!(mapper.read(0xA000), 0xff);
+        assert_eq!(mapper.battery_backed_ram().unwrap()[0], 0x00);
+
+        // Only 0x0A in the lower nibble enables RAM
+        mapper.write(0x0000, 0x0B);
+        assert_eq!(mapper.read(0xA000), 0xff);
+        mapper.write(0x1FFF, 0xFA);
+        assert_eq!(mapper.read(0xA000), 0x00);
+        mapper.write(0xA000, 0x42);
+        mapper.write(0xBFFF, 0x24);
+
+        // Disabling RAM hides the contents, which are still there when enabled again
+        mapper.write(0x0000, 0x00);
+        assert_eq!(mapper.read(0xA000), 0xff);
+        mapper.write(0xA000, 0x99);
+        mapper.write(0x0000, 0x0A);
+        assert_eq!(mapper.read(0xA000), 0x42);
+        assert_eq!(mapper.read(0xBFFF), 0x24);
+    }
+
+    #[test]
+    fn ram_enable_without_ram() {
+        let mut mapper = Mbc1::new(mbc1_rom(ROM_SIZE), 0);
+        assert_eq!(mapper.read(0xA000), 0xff);
+
+        // Enabling RAM doesn't make any memory appear
+        mapper.write(0x0000, 0x0A);
+        mapper.write(0xA000, 0x42);
+        assert_eq!(mapper.read(0xA000), 0xff);
+        assert_eq!(mapper.read(0xBFFF), 0xff);
+        assert_eq!(mapper.battery_backed_ram(), Some(&[][..]));
+    }
+
+    #[test]
+    fn save_state_round_trip() {
+        let mut mapper = Mbc1::new(mbc1_rom(MAX_ROM_SIZE), 32 * 1024);
+        mapper.write(0x0000, 0x0A);
+        mapper.write(0x2000, 0x05);
+        mapper.write(0x4000, 0x02);
+        mapper.write(0x6000, 0x01);
+        mapper.write(0xA000, 0x42);
+
+        let mut state = StateWriter::new();
+        mapper.save_state(&mut state);
+        let state = state.into_bytes();
+
+        let mut restored = Mbc1::new(mbc1_rom(MAX_ROM_SIZE), 32 * 1024);
+        restored.load_state(&mut StateReader::new(&state)).unwrap();
+        assert_eq!(restored.read(0x0000), 0x40);
+        assert_eq!(restored.read(0x4000), 0x45);
+        assert_eq!(restored.read(0xA000), 0x42);
+
+        // Bank 0 can't be selected through the low bank register
+        let mut corrupted = state.clone();
+        corrupted[1] = 0;
+        assert_eq!(
+            restored.load_state(&mut StateReader::new(&corrupted)),
+            Err(StateError::Invalid)
+        );
+    }
 }
@@ -0,0 +1,233 @@
+use crate::header::{self, CartridgeHeader};
+
+extern crate alloc;
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::{ControlWrite, Mapper};
+use sm83::state::{StateError, StateReader, StateWriter};
+
+// Max size of MBC2 roms is 256 KiB
+pub const MAX_ROM_SIZE: usize = 256 * 1024;
+const ROM_BANK_SIZE: usize = 16 * 1024;
+const ROM_BANK_SELECT_MASK: usize = 0x0F;
+
+/// Bit 8 of the address selects between the RAM enable and ROM bank registers
+const REGISTER_SELECT_BIT: u16 = 0x0100;
+
+const RAM_BASE: usize = 0xA000;
+/// The built-in RAM has 512 cells of 4 bits. Each cell is stored in its own byte.
+pub const RAM_SIZE: usize = 512;
+const RAM_VALUE_MASK: u8 = 0x0F;
+/// Only the lower 4 bits of the data bus are driven by the RAM, the upper ones read as 1.
+const RAM_OPEN_BUS: u8 = 0xF0;
+
+pub struct Mbc2 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    selected_rom_bank: usize,
+}
+
+impl Mbc2 {
+    pub fn new(rom: Vec<u8>) -> Self {
+        assert!(rom.len().count_ones() == 1); // ROM size must be a power of 2
+        assert!(rom.len() <= MAX_ROM_SIZE);
+
+        Self {
+            rom,
+            ram: vec![0; RAM_SIZE],
+            ram_enabled: false,
+            selected_rom_bank: 1,
+        }
+    }
+
+    fn read_rom(&self, address: usize) -> u8 {
+        // Banks that don't exist need to read the wrapped address value.
+        let address = address & (self.rom.len() - 1);
+        self.rom[address]
+    }
+
+    fn ram_address(address: sm83::memory::Address) -> usize {
+        // The RAM is mirrored across the whole 0xA000 to 0xBFFF region
+        (address as usize - RAM_BASE) & (RAM_SIZE - 1)
+    }
+}
+
+impl Mapper for Mbc2 {
+    fn header<'a>(&'a self) -> Result<CartridgeHeader<'a>, header::Error> {
+        CartridgeHeader::try_new(&self.rom)
+    }
+
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn read(&self, address: sm83::memory::Address) -> u8 {
+        match address {
+            0x0000..=0x3FFF => {
+                // ROM bank 0 (16 KiB)
+                self.read_rom(address as usize)
+            }
+            0x4000..=0x7FFF => {
+                // ROM bank 1 to 0xF (16 KiB each)
+                self.read_rom((self.selected_rom_bank - 1) * ROM_BANK_SIZE + address as usize)
+            }
+            0xA000..=0xBFFF => {
+                if !self.ram_enabled {
+                    return 0xff;
+                }
+                RAM_OPEN_BUS | self.ram[Self::ram_address(address)]
+            }
+            _ => unimplemented!(),
+        }
+    }
+
+    fn write(&mut self, address: sm83::memory::Address, value: u8) {
+        match address {
+            0x0000..=0x3FFF if address & REGISTER_SELECT_BIT == 0 => {
+                // Ram is enabled if lower nibble is A
+                self.ram_enabled = value & 0xF == 0x0A;
+            }
+            0x0000..=0x3FFF => {
+                // ROM Bank number
+                let value = ROM_BANK_SELECT_MASK & (value as usize);
+                self.selected_rom_bank = if value == 0 { 1 } else { value };
+            }
+            0x4000..=0x7FFF => {}
+            0xA000..=0xBFFF => {
+                if self.ram_enabled {
+                    self.ram[Self::ram_address(address)] = value & RAM_VALUE_MASK;
+                }
+            }
+            _ => unimplemented!(),
+        }
+    }
+
+    fn decode_control_write(&self, address: sm83::memory::Address, value: u8) -> ControlWrite {
+        match address {
+            0x0000..=0x3FFF if address & REGISTER_SELECT_BIT == 0 => {
+                ControlWrite::RamEnable(value & 0xF == 0x0A)
+            }
+            0x0000..=0x3FFF => {
+                let value = ROM_BANK_SELECT_MASK & (value as usize);
+                ControlWrite::RomBank(if value == 0 { 1 } else { value })
+            }
+            _ => ControlWrite::Ignored,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.ram_enabled = false;
+        self.selected_rom_bank = 1;
+    }
+
+    fn selected_rom_bank(&self) -> usize {
+        self.selected_rom_bank
+    }
+
+    fn battery_backed_ram(&self) -> Option<&[u8]> {
+        Some(&self.ram)
+    }
+
+    fn restore_battery_backed_ram(&mut self, ram: &[u8]) -> Result<(), crate::Error> {
+        if ram.len() != self.ram.len() {
+            return Err(crate::Error::UnexpectedRamSize {
+                expected: self.ram.len(),
+                actual: ram.len(),
+            });
+        }
+        self.ram
+            .iter_mut()
+            .zip(ram.iter())
+            .for_each(|(d, s)| *d = *s & RAM_VALUE_MASK);
+        Ok(())
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.ram_enabled);
+        state.write_u8(self.selected_rom_bank as u8);
+        state.write_bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.ram_enabled = state.read_bool()?;
+        let bank = state.read_u8()? as usize;
+        if bank == 0 || bank > ROM_BANK_SELECT_MASK {
+            return Err(StateError::Invalid);
+        }
+        self.selected_rom_bank = bank;
+        state.read_bytes(&mut self.ram)?;
+        self.ram
+            .iter_mut()
+            .for_each(|value| *value &= RAM_VALUE_MASK);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mappers::new_mapper;
+
+    fn mbc2_rom() -> Vec<u8> {
+        let mut rom = vec![0; 256 * 1024];
+        for (bank, data) in rom.chunks_mut(ROM_BANK_SIZE).enumerate() {
+            data[0] = bank as u8;
+        }
+        // MBC2 + Battery, 256 KiB ROM
+        rom[0x147] = 0x06;
+        rom[0x148] = 0x03;
+        rom
+    }
+
+    #[test]
+    fn ram_stores_nibbles() {
+        let mut mapper = new_mapper(mbc2_rom(), None).unwrap();
+        mapper.write(0x0000, 0x0A);
+
+        mapper.write(0xA000, 0xFF);
+        mapper.write(0xA001, 0x35);
+        assert_eq!(mapper.read(0xA000), 0xFF);
+        assert_eq!(mapper.read(0xA001), 0xF5);
+
+        let ram = mapper.battery_backed_ram().unwrap();
+        assert_eq!(ram.len(), RAM_SIZE);
+        assert_eq!(ram[..2], [0x0F, 0x05]);
+
+        // The RAM is mirrored every 512 bytes
+        assert_eq!(mapper.read(0xA201), 0xF5);
+        assert_eq!(mapper.read(0xBE01), 0xF5);
+
+        // Disabled RAM is not accessible
+        mapper.write(0x0000, 0x00);
+        mapper.write(0xA001, 0x0A);
+        assert_eq!(mapper.read(0xA001), 0xFF);
+        mapper.write(0x0000, 0x0A);
+        assert_eq!(mapper.read(0xA001), 0xF5);
+    }
+
+    #[test]
+    fn register_selected_by_address_bit_8() {
+        let mut mapper = new_mapper(mbc2_rom(), None).unwrap();
+        assert_eq!(mapper.read(0x4000), 1);
+
+        // Bit 8 clear writes the RAM enable register, not the ROM bank
+        mapper.write(0x0000, 0x0A);
+        assert_eq!(mapper.read(0x4000), 1);
+
+        mapper.write(0x2100, 0x05);
+        assert_eq!(mapper.read(0x4000), 5);
+        mapper.write(0x0100, 0x0F);
+        assert_eq!(mapper.read(0x4000), 0x0F);
+
+        // Bank 0 maps to bank 1 and only 4 bits are used
+        mapper.write(0x3F00, 0x10);
+        assert_eq!(mapper.read(0x4000), 1);
+
+        // The RAM is still enabled after selecting banks
+        mapper.write(0xA000, 0x03);
+        assert_eq!(mapper.read(0xA000), 0xF3);
+    }
+}
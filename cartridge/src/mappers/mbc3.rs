@@ -5,34 +5,148 @@ extern crate alloc;
 use alloc::vec;
 use alloc::vec::Vec;
 
-use super::Mapper;
+use super::{ControlWrite, Mapper};
+use sm83::state::{StateError, StateReader, StateWriter};
 
+// Max size of MBC3 roms is 2 MiB
+pub const MAX_ROM_SIZE: usize = 2048 * 1024;
 const ROM_BANK_SIZE: usize = 16 * 1024;
 const ROM_BANK_SELECT_MASK: usize = 0x7F;
 
 const RAM_BASE: usize = 0xA000;
-const RAM_BANK_SIZE: usize = 4 * 1024;
+const RAM_BANK_SIZE: usize = 8 * 1024;
 const RAM_BANK_SELECT_MASK: usize = 0x0f;
 
+/// Number of RTC registers: seconds, minutes, hours, day counter low and day counter high.
+const RTC_REGS: usize = 5;
+const RTC_REG_BASE: usize = 0x08;
+/// Valid bits of each RTC register. Bit 0 of the day counter high register is bit 8 of the day
+/// counter, bit 6 halts the clock and bit 7 is the day counter carry.
+const RTC_REG_MASKS: [u8; RTC_REGS] = [0x3F, 0x3F, 0x1F, 0xFF, 0xC1];
+const RTC_SECONDS: usize = 0;
+const RTC_MINUTES: usize = 1;
+const RTC_HOURS: usize = 2;
+const RTC_DAY_LOW: usize = 3;
+const RTC_DAY_HIGH: usize = 4;
+const RTC_DAY_MSB: u8 = 0x01;
+const RTC_HALT: u8 = 0x40;
+const RTC_DAY_CARRY: u8 = 0x80;
+/// The day counter is 9 bits wide
+const RTC_DAYS: u64 = 512;
+
+/// Size of the RTC trailer appended to the RAM in save files, in the layout used by VBA and BGB:
+/// - The current seconds, minutes, hours, day low and day high registers, as 32-bit little
+///   endian values.
+/// - The latched registers, in the same order and format.
+/// - The UNIX timestamp of the save, as a 64-bit little endian value.
+pub const RTC_SAVE_LEN: usize = 2 * RTC_REGS * 4 + 8;
+/// Older versions of the format store the UNIX timestamp as a 32-bit value.
+const RTC_SAVE_LEN_32BIT_TIMESTAMP: usize = 2 * RTC_REGS * 4 + 4;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Rtc {
+    current: [u8; RTC_REGS],
+    latched: [u8; RTC_REGS],
+    timestamp: u64,
+    latch_armed: bool,
+}
+
+impl Rtc {
+    fn parse(data: &[u8]) -> Self {
+        let reg = |index: usize| {
+            let offset = index * 4;
+            let value = u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+            value as u8 & RTC_REG_MASKS[index % RTC_REGS]
+        };
+
+        let timestamp = &data[2 * RTC_REGS * 4..];
+        let timestamp = if timestamp.len() == 8 {
+            u64::from_le_bytes(timestamp.try_into().unwrap())
+        } else {
+            u32::from_le_bytes(timestamp.try_into().unwrap()) as u64
+        };
+
+        Self {
+            current: core::array::from_fn(reg),
+            latched: core::array::from_fn(|index| reg(RTC_REGS + index)),
+            timestamp,
+            latch_armed: false,
+        }
+    }
+
+    /// Advances the clock by the given number of seconds, unless it is halted. Each counter
+    /// carries into the next one, and the day counter sets the carry bit when it overflows.
+    fn tick(&mut self, seconds: u64) {
+        let day_high = self.current[RTC_DAY_HIGH];
+        if day_high & RTC_HALT != 0 {
+            return;
+        }
+
+        // Carry whole minutes right away, so that no counter overflows for any elapsed time
+        let minutes = seconds / 60;
+        let seconds = self.current[RTC_SECONDS] as u64 + seconds % 60;
+        let minutes = self.current[RTC_MINUTES] as u64 + minutes + seconds / 60;
+        let hours = self.current[RTC_HOURS] as u64 + minutes / 60;
+        let day = ((day_high & RTC_DAY_MSB) as u64) << 8 | self.current[RTC_DAY_LOW] as u64;
+        let days = day + hours / 24;
+
+        self.current[RTC_SECONDS] = (seconds % 60) as u8;
+        self.current[RTC_MINUTES] = (minutes % 60) as u8;
+        self.current[RTC_HOURS] = (hours % 24) as u8;
+        self.current[RTC_DAY_LOW] = (days % RTC_DAYS) as u8;
+        let carry = if days >= RTC_DAYS {
+            RTC_DAY_CARRY
+        } else {
+            day_high & RTC_DAY_CARRY
+        };
+        self.current[RTC_DAY_HIGH] = carry | ((days % RTC_DAYS) >> 8) as u8;
+    }
+
+    fn serialize(&self, data: &mut [u8]) {
+        let regs = self.current.iter().chain(self.latched.iter());
+        for (dest, reg) in data.chunks_exact_mut(4).zip(regs) {
+            dest.copy_from_slice(&(*reg as u32).to_le_bytes());
+        }
+        data[2 * RTC_REGS * 4..].copy_from_slice(&self.timestamp.to_le_bytes());
+    }
+}
+
 pub struct Mbc3 {
     rom: Vec<u8>,
+    /// Cartridge RAM, followed by the serialized RTC state if the cartridge has a clock, so that
+    /// it can be saved as a whole.
     ram: Vec<u8>,
+    ram_size: usize,
+    rtc: Option<Rtc>,
     ram_and_rtc_enabled: bool,
     selected_rom_bank: usize,
     selected_ram_bank: usize,
 }
 
 impl Mbc3 {
-    pub fn new(rom: Vec<u8>, ram_size: usize) -> Self {
+    pub fn new(rom: Vec<u8>, ram_size: usize, has_rtc: bool) -> Self {
         assert!(rom.len().count_ones() == 1); // ROM size must be a power of 2
-        assert!(rom.len() < 2048 * 1024); // Max size of MB3 roms is 2 MiB
+        assert!(rom.len() <= MAX_ROM_SIZE);
 
-        Self {
+        let rtc = has_rtc.then(Rtc::default);
+        let save_size = ram_size + if has_rtc { RTC_SAVE_LEN } else { 0 };
+        let mut mbc3 = Self {
             rom,
-            ram: vec![0; ram_size],
+            ram: vec![0; save_size],
+            ram_size,
+            rtc,
             ram_and_rtc_enabled: false,
             selected_rom_bank: 1,
             selected_ram_bank: 0,
+        };
+        mbc3.sync_rtc_save();
+        mbc3
+    }
+
+    /// Keeps the serialized RTC state at the end of the save up to date.
+    fn sync_rtc_save(&mut self) {
+        if let Some(rtc) = &self.rtc {
+            rtc.serialize(&mut self.ram[self.ram_size..]);
         }
     }
 
@@ -43,11 +157,29 @@ impl Mbc3 {
     }
 
     fn read_ram(&self, address: usize) -> u8 {
-        self.ram[address]
+        self.ram[..self.ram_size]
+            .get(address)
+            .copied()
+            .unwrap_or(0xff)
     }
 
     fn write_ram(&mut self, address: usize, value: u8) {
-        self.ram[address] = value
+        if let Some(byte) = self.ram[..self.ram_size].get_mut(address) {
+            *byte = value;
+        }
+    }
+
+    fn latch_rtc(&mut self, value: u8) {
+        let Some(rtc) = &mut self.rtc else {
+            return;
+        };
+
+        // Writing 0 and then 1 latches the current time into the RTC registers
+        if rtc.latch_armed && value == 0x01 {
+            rtc.latched = rtc.current;
+        }
+        rtc.latch_armed = value == 0x00;
+        self.sync_rtc_save();
     }
 }
 
@@ -56,6 +188,10 @@ impl Mapper for Mbc3 {
         CartridgeHeader::try_new(&self.rom)
     }
 
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
     fn read(&self, address: sm83::memory::Address) -> u8 {
         match address {
             0x0000..=0x3FFF => {
@@ -78,11 +214,14 @@ impl Mapper for Mbc3 {
                         let offset = self.selected_ram_bank * RAM_BANK_SIZE;
                         self.read_ram(address as usize - RAM_BASE + offset)
                     }
-                    0x08..=0x0C => {
-                        // TODO: access RTC
-                        0
+                    0x08..=0x0C => match &self.rtc {
+                        Some(rtc) => rtc.latched[self.selected_ram_bank - RTC_REG_BASE],
+                        None => 0xff,
+                    },
+                    _ => {
+                        // Unmapped bank, the bus is not driven
+                        0xff
                     }
-                    _ => unimplemented!(),
                 }
             }
             _ => unimplemented!(),
@@ -105,9 +244,7 @@ impl Mapper for Mbc3 {
                 let value = RAM_BANK_SELECT_MASK & (value as usize);
                 self.selected_ram_bank = value;
             }
-            0x6000..=0x7FFF => {
-                // TODO: latch rtc regs
-            }
+            0x6000..=0x7FFF => self.latch_rtc(value),
             0xA000..=0xBFFF => {
                 if !self.ram_and_rtc_enabled {
                     return;
@@ -120,30 +257,351 @@ impl Mapper for Mbc3 {
                         self.write_ram(address as usize - RAM_BASE + offset, value)
                     }
                     0x08..=0x0C => {
-                        // TODO: access RTC
+                        let index = self.selected_ram_bank - RTC_REG_BASE;
+                        if let Some(rtc) = &mut self.rtc {
+                            rtc.current[index] = value & RTC_REG_MASKS[index];
+                        }
+                        self.sync_rtc_save();
+                    }
+                    _ => {
+                        // Unmapped bank, writes are ignored
                     }
-                    _ => unimplemented!(),
                 }
             }
             _ => unimplemented!(),
         }
     }
 
+    fn decode_control_write(&self, address: sm83::memory::Address, value: u8) -> ControlWrite {
+        match address {
+            0x0000..=0x1FFF => ControlWrite::RamEnable(value & 0xF == 0x0A),
+            0x2000..=0x3FFF => {
+                let value = ROM_BANK_SELECT_MASK & (value as usize);
+                ControlWrite::RomBank(if value == 0 { 1 } else { value })
+            }
+            0x4000..=0x5FFF => match RAM_BANK_SELECT_MASK & (value as usize) {
+                reg @ 0x08..=0x0C => ControlWrite::RtcRegister(reg),
+                bank => ControlWrite::RamBank(bank),
+            },
+            0x6000..=0x7FFF => ControlWrite::RtcLatch(value),
+            _ => ControlWrite::Ignored,
+        }
+    }
+
+    fn tick_rtc(&mut self, seconds: u64) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.tick(seconds);
+        }
+        self.sync_rtc_save();
+    }
+
+    fn stamp_rtc(&mut self, now: u64) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.timestamp = now;
+        }
+        self.sync_rtc_save();
+    }
+
+    /// Saves without a timestamp, which is stored as 0, don't advance the clock.
+    fn catch_up_rtc(&mut self, now: u64) {
+        if let Some(rtc) = &mut self.rtc {
+            if rtc.timestamp != 0 {
+                rtc.tick(now.saturating_sub(rtc.timestamp));
+                rtc.timestamp = now;
+            }
+        }
+        self.sync_rtc_save();
+    }
+
+    fn reset(&mut self) {
+        self.ram_and_rtc_enabled = false;
+        self.selected_rom_bank = 1;
+        self.selected_ram_bank = 0;
+    }
+
+    fn selected_rom_bank(&self) -> usize {
+        self.selected_rom_bank
+    }
+
+    fn selected_ram_bank(&self) -> usize {
+        self.selected_ram_bank
+    }
+
     fn battery_backed_ram(&self) -> Option<&[u8]> {
         Some(&self.ram)
     }
 
+    /// Saves of cartridges with a clock contain the RAM followed by the RTC state, in the layout
+    /// described in `RTC_SAVE_LEN`. Saves without the RTC state are also accepted, in which
+    /// case the clock is left untouched.
     fn restore_battery_backed_ram(&mut self, ram: &[u8]) -> Result<(), crate::Error> {
-        if ram.len() != self.ram.len() {
+        let rtc_len = ram.len().wrapping_sub(self.ram_size);
+        let valid_rtc_len = match self.rtc {
+            Some(_) => [0, RTC_SAVE_LEN, RTC_SAVE_LEN_32BIT_TIMESTAMP].contains(&rtc_len),
+            None => rtc_len == 0,
+        };
+        if !valid_rtc_len {
             return Err(crate::Error::UnexpectedRamSize {
                 expected: self.ram.len(),
                 actual: ram.len(),
             });
         }
-        self.ram
-            .iter_mut()
-            .zip(ram.iter())
-            .for_each(|(d, s)| *d = *s);
+
+        let (ram, rtc) = ram.split_at(self.ram_size);
+        self.ram[..self.ram_size].copy_from_slice(ram);
+        if !rtc.is_empty() {
+            self.rtc = Some(Rtc::parse(rtc));
+            self.sync_rtc_save();
+        }
         Ok(())
     }
+
+    /// The RTC registers are part of the RAM, as they are kept serialized after it.
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.ram_and_rtc_enabled);
+        state.write_u8(self.selected_rom_bank as u8);
+        state.write_u8(self.selected_ram_bank as u8);
+        state.write_bool(self.rtc.as_ref().is_some_and(|rtc| rtc.latch_armed));
+        state.write_bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.ram_and_rtc_enabled = state.read_bool()?;
+        let rom_bank = state.read_u8()? as usize;
+        let ram_bank = state.read_u8()? as usize;
+        if rom_bank == 0 || rom_bank > ROM_BANK_SELECT_MASK || ram_bank > RAM_BANK_SELECT_MASK {
+            return Err(StateError::Invalid);
+        }
+        self.selected_rom_bank = rom_bank;
+        self.selected_ram_bank = ram_bank;
+        let latch_armed = state.read_bool()?;
+        state.read_bytes(&mut self.ram)?;
+        if let Some(rtc) = &mut self.rtc {
+            *rtc = Rtc::parse(&self.ram[self.ram_size..]);
+            rtc.latch_armed = latch_armed;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mbc3_rom() -> Vec<u8> {
+        let mut rom = vec![0; 64 * 1024];
+        // MBC3 + Timer + RAM + Battery, 64 KiB ROM, 8 KiB RAM
+        rom[0x147] = 0x10;
+        rom[0x148] = 0x01;
+        rom[0x149] = 0x02;
+        rom
+    }
+
+    #[test]
+    fn ram_banks_do_not_overlap() {
+        let mut mapper = Mbc3::new(mbc3_rom(), 32 * 1024, false);
+        mapper.write(0x0000, 0x0A);
+        for bank in 0..4u8 {
+            mapper.write(0x4000, bank);
+            mapper.write(0xA000, bank + 1);
+            mapper.write(0xBFFF, bank + 0x11);
+        }
+
+        for bank in 0..4u8 {
+            mapper.write(0x4000, bank);
+            assert_eq!(mapper.read(0xA000), bank + 1);
+            assert_eq!(mapper.read(0xBFFF), bank + 0x11);
+        }
+
+        // Each bank takes 8 KiB of the save
+        let save = mapper.battery_backed_ram().unwrap();
+        assert_eq!(save[3 * 8 * 1024], 4);
+        assert_eq!(save[4 * 8 * 1024 - 1], 0x14);
+    }
+
+    fn rtc_save_fixture(timestamp_len: usize) -> Vec<u8> {
+        let mut save = vec![0; 8 * 1024];
+        save[0] = 0x42;
+        // Current: 10:15:30, day 0x12A with the day carry set
+        for reg in [30u32, 15, 10, 0x2A, 0x81] {
+            save.extend_from_slice(&reg.to_le_bytes());
+        }
+        // Latched: 10:15:29, day 0x12A
+        for reg in [29u32, 15, 10, 0x2A, 0x01] {
+            save.extend_from_slice(&reg.to_le_bytes());
+        }
+        save.extend_from_slice(&0x6543_2100u64.to_le_bytes()[..timestamp_len]);
+        save
+    }
+
+    fn read_rtc_regs(mapper: &mut Mbc3) -> [u8; RTC_REGS] {
+        core::array::from_fn(|index| {
+            mapper.write(0x4000, (RTC_REG_BASE + index) as u8);
+            mapper.read(0xA000)
+        })
+    }
+
+    #[test]
+    fn import_vba_rtc_save() {
+        for timestamp_len in [8, 4] {
+            let mut mapper = Mbc3::new(mbc3_rom(), 8 * 1024, true);
+            mapper
+                .restore_battery_backed_ram(&rtc_save_fixture(timestamp_len))
+                .unwrap();
+
+            mapper.write(0x0000, 0x0A);
+            assert_eq!(mapper.read(0xA000), 0x42);
+            assert_eq!(read_rtc_regs(&mut mapper), [29, 15, 10, 0x2A, 0x01]);
+
+            // Latching exposes the current time
+            mapper.write(0x6000, 0x00);
+            mapper.write(0x6000, 0x01);
+            assert_eq!(read_rtc_regs(&mut mapper), [30, 15, 10, 0x2A, 0x81]);
+        }
+    }
+
+    #[test]
+    fn export_vba_rtc_save() {
+        let mut mapper = Mbc3::new(mbc3_rom(), 8 * 1024, true);
+        let save = rtc_save_fixture(8);
+        mapper.restore_battery_backed_ram(&save).unwrap();
+        assert_eq!(mapper.battery_backed_ram(), Some(&save[..]));
+
+        // Register writes are reflected in the save
+        mapper.write(0x0000, 0x0A);
+        mapper.write(0x4000, 0x09);
+        mapper.write(0xA000, 0x3B);
+        let save = mapper.battery_backed_ram().unwrap();
+        assert_eq!(save.len(), 8 * 1024 + RTC_SAVE_LEN);
+        assert_eq!(save[8 * 1024 + 4..8 * 1024 + 8], [0x3B, 0, 0, 0]);
+    }
+
+    fn latch(mapper: &mut Mbc3) {
+        mapper.write(0x6000, 0x00);
+        mapper.write(0x6000, 0x01);
+    }
+
+    #[test]
+    fn tick_and_latch() {
+        let mut mapper = Mbc3::new(mbc3_rom(), 8 * 1024, true);
+        mapper.write(0x0000, 0x0A);
+
+        // 1 day, 2 hours, 3 minutes and 4 seconds
+        mapper.tick_rtc(86400 + 2 * 3600 + 3 * 60 + 4);
+
+        // The registers only change when latched
+        assert_eq!(read_rtc_regs(&mut mapper), [0; RTC_REGS]);
+        latch(&mut mapper);
+        assert_eq!(read_rtc_regs(&mut mapper), [4, 3, 2, 1, 0]);
+
+        mapper.tick_rtc(57);
+        assert_eq!(read_rtc_regs(&mut mapper), [4, 3, 2, 1, 0]);
+        latch(&mut mapper);
+        assert_eq!(read_rtc_regs(&mut mapper), [1, 4, 2, 1, 0]);
+
+        // Ticks are stored in the save as well
+        let save = mapper.battery_backed_ram().unwrap();
+        assert_eq!(save[8 * 1024..8 * 1024 + 4], [1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn rtc_day_carry() {
+        let mut mapper = Mbc3::new(mbc3_rom(), 8 * 1024, true);
+        mapper.write(0x0000, 0x0A);
+
+        // Day 0x1FF, 23:59:59
+        for (reg, value) in [
+            (0x08, 59),
+            (0x09, 59),
+            (0x0A, 23),
+            (0x0B, 0xFF),
+            (0x0C, 0x01),
+        ] {
+            mapper.write(0x4000, reg);
+            mapper.write(0xA000, value);
+        }
+        mapper.tick_rtc(1);
+        latch(&mut mapper);
+        assert_eq!(read_rtc_regs(&mut mapper), [0, 0, 0, 0, RTC_DAY_CARRY]);
+
+        // The carry stays set until cleared by the game
+        mapper.tick_rtc(86400);
+        latch(&mut mapper);
+        assert_eq!(read_rtc_regs(&mut mapper), [0, 0, 0, 1, RTC_DAY_CARRY]);
+    }
+
+    #[test]
+    fn tick_rtc_by_max_seconds() {
+        let mut mapper = Mbc3::new(mbc3_rom(), 8 * 1024, true);
+        mapper.write(0x0000, 0x0A);
+        mapper.write(0x4000, 0x08);
+        mapper.write(0xA000, 59);
+
+        // u64::MAX seconds are a whole number of minutes and 15 seconds
+        mapper.tick_rtc(u64::MAX);
+        latch(&mut mapper);
+        let regs = read_rtc_regs(&mut mapper);
+        assert_eq!(regs[RTC_SECONDS], 14);
+        assert_ne!(regs[RTC_DAY_HIGH] & RTC_DAY_CARRY, 0);
+    }
+
+    #[test]
+    fn halted_rtc() {
+        let mut mapper = Mbc3::new(mbc3_rom(), 8 * 1024, true);
+        mapper.write(0x0000, 0x0A);
+        mapper.write(0x4000, 0x0C);
+        mapper.write(0xA000, RTC_HALT);
+
+        mapper.tick_rtc(3600);
+        latch(&mut mapper);
+        assert_eq!(read_rtc_regs(&mut mapper), [0, 0, 0, 0, RTC_HALT]);
+
+        mapper.write(0x4000, 0x0C);
+        mapper.write(0xA000, 0);
+        mapper.tick_rtc(3600);
+        latch(&mut mapper);
+        assert_eq!(read_rtc_regs(&mut mapper), [0, 0, 1, 0, 0]);
+    }
+
+    #[test]
+    fn rtc_catches_up_with_save_timestamp() {
+        let mut mapper = Mbc3::new(mbc3_rom(), 8 * 1024, true);
+        mapper.tick_rtc(30);
+        mapper.stamp_rtc(1_000_000);
+        let save = mapper.battery_backed_ram().unwrap().to_vec();
+        assert_eq!(
+            save[8 * 1024 + 2 * RTC_REGS * 4..],
+            1_000_000u64.to_le_bytes()
+        );
+
+        // 1 hour and 45 seconds later
+        let mut mapper = Mbc3::new(mbc3_rom(), 8 * 1024, true);
+        mapper.restore_battery_backed_ram(&save).unwrap();
+        mapper.catch_up_rtc(1_000_000 + 3645);
+        mapper.write(0x0000, 0x0A);
+        latch(&mut mapper);
+        assert_eq!(read_rtc_regs(&mut mapper), [15, 1, 1, 0, 0]);
+
+        // Catching up again only applies the time elapsed since the last time
+        mapper.catch_up_rtc(1_000_000 + 3650);
+        latch(&mut mapper);
+        assert_eq!(read_rtc_regs(&mut mapper), [20, 1, 1, 0, 0]);
+
+        // Saves without a timestamp keep the clock as it was
+        mapper.stamp_rtc(0);
+        let save = mapper.battery_backed_ram().unwrap().to_vec();
+        mapper.restore_battery_backed_ram(&save).unwrap();
+        mapper.catch_up_rtc(1_000_000);
+        latch(&mut mapper);
+        assert_eq!(read_rtc_regs(&mut mapper), [20, 1, 1, 0, 0]);
+    }
+
+    #[test]
+    fn save_without_rtc() {
+        let mut mapper = Mbc3::new(mbc3_rom(), 8 * 1024, false);
+        assert_eq!(mapper.battery_backed_ram().unwrap().len(), 8 * 1024);
+        assert!(mapper
+            .restore_battery_backed_ram(&rtc_save_fixture(8))
+            .is_err());
+    }
 }
@@ -5,8 +5,11 @@ extern crate alloc;
 use alloc::vec;
 use alloc::vec::Vec;
 
-use super::Mapper;
+use super::{ControlWrite, Mapper};
+use sm83::state::{StateError, StateReader, StateWriter};
 
+// Max size of MBC5 roms is 8 MiB
+pub const MAX_ROM_SIZE: usize = 8192 * 1024;
 const ROM_BANK_SIZE: usize = 16 * 1024;
 const ROM_BANK_MSB_SELECT_MASK: usize = 0x0100;
 const ROM_BANK_MSB_SELECT_OFFSET: usize = 8;
@@ -19,6 +22,7 @@ const RAM_BANK_SELECT_MASK: usize = 0x0f;
 pub struct Mbc5 {
     rom: Vec<u8>,
     ram: Vec<u8>,
+    ram_mask: usize,
     ram_enabled: bool,
     selected_rom_bank: usize,
     selected_ram_bank: usize,
@@ -27,11 +31,16 @@ pub struct Mbc5 {
 impl Mbc5 {
     pub fn new(rom: Vec<u8>, ram_size: usize) -> Self {
         assert!(rom.len().count_ones() == 1); // ROM size must be a power of 2
-        assert!(rom.len() < 8192 * 1024); // Max size of MB5 roms is 8 MiB
+        assert!(rom.len() <= MAX_ROM_SIZE);
+
+        // RAM sizes that are not a power of 2 are only reachable through a RAM size override.
+        // Addresses are decoded as if the RAM had the next power of 2 size.
+        let ram_mask = ram_size.next_power_of_two().saturating_sub(1);
 
         Self {
             rom,
             ram: vec![0; ram_size],
+            ram_mask,
             ram_enabled: false,
             selected_rom_bank: 0,
             selected_ram_bank: 0,
@@ -45,13 +54,15 @@ impl Mbc5 {
     }
 
     fn read_ram(&self, address: usize) -> u8 {
-        let address = address & (self.ram.len() - 1);
-        self.ram[address]
+        let address = address & self.ram_mask;
+        self.ram.get(address).copied().unwrap_or(0xff)
     }
 
     fn write_ram(&mut self, address: usize, value: u8) {
-        let address = address & (self.ram.len() - 1);
-        self.ram[address] = value
+        let address = address & self.ram_mask;
+        if let Some(byte) = self.ram.get_mut(address) {
+            *byte = value;
+        }
     }
 }
 
@@ -60,6 +71,10 @@ impl Mapper for Mbc5 {
         CartridgeHeader::try_new(&self.rom)
     }
 
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
     fn read(&self, address: sm83::memory::Address) -> u8 {
         match address {
             0x0000..=0x3FFF => {
@@ -132,6 +147,36 @@ impl Mapper for Mbc5 {
         }
     }
 
+    fn decode_control_write(&self, address: sm83::memory::Address, value: u8) -> ControlWrite {
+        let value = value as usize;
+        match address {
+            0x0000..=0x1FFF => ControlWrite::RamEnable(value & 0xF == 0x0A),
+            0x2000..=0x2FFF => {
+                ControlWrite::RomBank((self.selected_rom_bank & ROM_BANK_MSB_SELECT_MASK) | value)
+            }
+            0x3000..=0x3FFF => ControlWrite::RomBank(
+                (self.selected_rom_bank & ROM_BANK_LSB_SELECT_MASK)
+                    | (value << ROM_BANK_MSB_SELECT_OFFSET),
+            ),
+            0x4000..=0x5FFF => ControlWrite::RamBank(RAM_BANK_SELECT_MASK & value),
+            _ => ControlWrite::Ignored,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.ram_enabled = false;
+        self.selected_rom_bank = 0;
+        self.selected_ram_bank = 0;
+    }
+
+    fn selected_rom_bank(&self) -> usize {
+        self.selected_rom_bank
+    }
+
+    fn selected_ram_bank(&self) -> usize {
+        self.selected_ram_bank
+    }
+
     fn battery_backed_ram(&self) -> Option<&[u8]> {
         Some(&self.ram)
     }
@@ -149,4 +194,117 @@ impl Mapper for Mbc5 {
             .for_each(|(d, s)| *d = *s);
         Ok(())
     }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.ram_enabled);
+        state.write_u16(self.selected_rom_bank as u16);
+        state.write_u8(self.selected_ram_bank as u8);
+        state.write_bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.ram_enabled = state.read_bool()?;
+        let rom_bank = state.read_u16()? as usize;
+        let ram_bank = state.read_u8()? as usize;
+        if rom_bank > (ROM_BANK_MSB_SELECT_MASK | ROM_BANK_LSB_SELECT_MASK)
+            || ram_bank > RAM_BANK_SELECT_MASK
+        {
+            return Err(StateError::Invalid);
+        }
+        self.selected_rom_bank = rom_bank;
+        self.selected_ram_bank = ram_bank;
+        state.read_bytes(&mut self.ram)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mappers::new_mapper;
+
+    fn mbc5_rom() -> Vec<u8> {
+        let mut rom = vec![0; 64 * 1024];
+        // MBC5 + RAM + Battery, 64 KiB ROM, 8 KiB RAM
+        rom[0x147] = 0x1B;
+        rom[0x148] = 0x01;
+        rom[0x149] = 0x02;
+        rom
+    }
+
+    #[test]
+    fn ram_size_override() {
+        let mut mapper = new_mapper(mbc5_rom(), Some(32 * 1024)).unwrap();
+        assert_eq!(mapper.battery_backed_ram().unwrap().len(), 32 * 1024);
+
+        // Enable RAM and write a different value to each of the 4 banks
+        mapper.write(0x0000, 0x0A);
+        for bank in 0..4 {
+            mapper.write(0x4000, bank);
+            mapper.write(0xA000, 0x10 + bank);
+        }
+
+        for bank in 0..4 {
+            mapper.write(0x4000, bank);
+            assert_eq!(mapper.read(0xA000), 0x10 + bank);
+        }
+
+        // Banks beyond the overridden size wrap around
+        mapper.write(0x4000, 4);
+        assert_eq!(mapper.read(0xA000), 0x10);
+
+        let ram = mapper.battery_backed_ram().unwrap();
+        for bank in 0..4 {
+            assert_eq!(ram[bank * RAM_BANK_SIZE], 0x10 + bank as u8);
+        }
+    }
+
+    #[test]
+    fn rom_accessor() {
+        let mut rom = vec![0; 1024 * 1024];
+        rom[0x147] = 0x19;
+        rom[0x148] = 0x05;
+        rom[0xFFFFF] = 0x5A;
+        let mapper = new_mapper(rom, None).unwrap();
+
+        let rom = mapper.rom();
+        assert_eq!(rom.len(), 1024 * 1024);
+        assert_eq!(rom[0xFFFFF], 0x5A);
+        assert_eq!(rom[0x147], 0x19);
+    }
+
+    #[test]
+    fn reset_restores_banking_and_keeps_ram() {
+        let mut rom = mbc5_rom();
+        rom[ROM_BANK_SIZE] = 0x11;
+        rom[3 * ROM_BANK_SIZE] = 0x33;
+        let mut mapper = Mbc5::new(rom, 32 * 1024);
+
+        mapper.write(0x0000, 0x0A);
+        mapper.write(0x2000, 3);
+        mapper.write(0x4000, 2);
+        mapper.write(0xA000, 0x42);
+        assert_eq!(mapper.read(0x4000), 0x33);
+
+        mapper.reset();
+        assert_eq!(mapper.selected_rom_bank, 0);
+        assert_eq!(mapper.selected_ram_bank, 0);
+        assert!(!mapper.ram_enabled);
+        assert_eq!(mapper.read(0xA000), 0xFF);
+
+        // Bank 0 is mapped to the switchable region after reset
+        assert_eq!(mapper.read(0x4000), 0x00);
+        mapper.write(0x2000, 1);
+        assert_eq!(mapper.read(0x4000), 0x11);
+
+        mapper.write(0x0000, 0x0A);
+        mapper.write(0x4000, 2);
+        assert_eq!(mapper.read(0xA000), 0x42);
+    }
+
+    #[test]
+    fn ram_mask_follows_ram_size() {
+        assert_eq!(Mbc5::new(mbc5_rom(), 32 * 1024).ram_mask, 0x7FFF);
+        assert_eq!(Mbc5::new(mbc5_rom(), 8 * 1024).ram_mask, 0x1FFF);
+        assert_eq!(Mbc5::new(mbc5_rom(), 0).ram_mask, 0);
+    }
 }
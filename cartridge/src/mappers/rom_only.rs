@@ -21,6 +21,10 @@ impl Mapper for RomOnly {
         CartridgeHeader::try_new(&self.data)
     }
 
+    fn rom(&self) -> &[u8] {
+        &self.data
+    }
+
     fn read(&self, address: sm83::memory::Address) -> u8 {
         if (address as usize) < self.data.len() {
             self.data[address as usize]
@@ -0,0 +1,129 @@
+//! Implements the color palette memories of the Game Boy Color, which are accessed through an
+//! index register and a data register.
+
+use crate::{PaletteIndex, Rgb555};
+use sm83::state::{SaveState, StateError, StateReader, StateWriter};
+
+/// The number of palettes in each palette memory
+pub const NUM_COLOR_PALETTES: usize = 8;
+
+/// The number of colors in each palette
+const COLORS_PER_PALETTE: usize = 4;
+
+/// The size of each palette memory in bytes. Colors take 2 bytes, in little endian order.
+pub const PALETTE_MEMORY_SIZE: usize = NUM_COLOR_PALETTES * COLORS_PER_PALETTE * 2;
+
+const INDEX_MASK: u8 = PALETTE_MEMORY_SIZE as u8 - 1;
+const AUTO_INCREMENT: u8 = 0x80;
+const UNUSED_INDEX_BITS: u8 = !(INDEX_MASK | AUTO_INCREMENT);
+
+/// A palette memory of the Game Boy Color, either for the background and window (BCPS and BCPD)
+/// or for objects (OCPS and OCPD)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColorPalettes {
+    data: [u8; PALETTE_MEMORY_SIZE],
+    /// Byte of the palette memory accessed through the data register
+    index: u8,
+    /// Writes to the data register move the index to the next byte
+    auto_increment: bool,
+}
+
+impl ColorPalettes {
+    /// Constructs a palette memory where all colors are white, like the boot ROM leaves the
+    /// background palettes.
+    pub const fn new() -> Self {
+        Self {
+            data: [0xFF; PALETTE_MEMORY_SIZE],
+            index: 0,
+            auto_increment: false,
+        }
+    }
+
+    /// Reads the index register. The unused bit 6 always reads as 1.
+    pub fn read_index(&self) -> u8 {
+        let auto_increment = if self.auto_increment {
+            AUTO_INCREMENT
+        } else {
+            0
+        };
+        auto_increment | UNUSED_INDEX_BITS | self.index
+    }
+
+    pub fn write_index(&mut self, value: u8) {
+        self.index = value & INDEX_MASK;
+        self.auto_increment = value & AUTO_INCREMENT != 0;
+    }
+
+    /// Reads the byte of the palette memory selected by the index register. Reads never move the
+    /// index, even with auto-increment enabled.
+    pub fn read_data(&self) -> u8 {
+        self.data[self.index as usize]
+    }
+
+    /// Writes the byte of the palette memory selected by the index register, and moves on to the
+    /// next byte when auto-increment is enabled. The index wraps around at the end of the memory.
+    pub fn write_data(&mut self, value: u8) {
+        self.data[self.index as usize] = value;
+        if self.auto_increment {
+            self.index = (self.index + 1) & INDEX_MASK;
+        }
+    }
+
+    /// Returns the color of the given palette (from 0 to `NUM_COLOR_PALETTES`) for a palette
+    /// index. The unused bit 15 is ignored.
+    pub fn color(&self, palette: usize, index: PaletteIndex) -> Rgb555 {
+        let offset = (palette * COLORS_PER_PALETTE + index as usize) * 2;
+        let value = u16::from_le_bytes([self.data[offset], self.data[offset + 1]]);
+        Rgb555(value & 0x7FFF)
+    }
+}
+
+impl Default for ColorPalettes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SaveState for ColorPalettes {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.data);
+        state.write_u8(self.read_index());
+    }
+
+    fn load_state(&mut self, state: &mut StateReader<'_>) -> Result<(), StateError> {
+        state.read_bytes(&mut self.data)?;
+        self.write_index(state.read_u8()?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auto_increment_index() {
+        let mut palettes = ColorPalettes::new();
+        assert_eq!(palettes.read_index(), 0x40);
+
+        // Second color of palette 7, which is followed by the end of the memory
+        palettes.write_index(0x80 | 0x3E);
+        assert_eq!(palettes.read_index(), 0xFE);
+        palettes.write_data(0x1F);
+        palettes.write_data(0x7C);
+        assert_eq!(palettes.read_index(), 0xC0);
+        assert_eq!(palettes.color(7, PaletteIndex::Id3), Rgb555(0x7C1F));
+
+        // Reads don't move the index
+        assert_eq!(palettes.read_data(), 0xFF);
+        assert_eq!(palettes.read_index(), 0xC0);
+
+        // Without auto-increment, writes overwrite the same byte
+        palettes.write_index(0x02);
+        palettes.write_data(0x12);
+        palettes.write_data(0x34);
+        assert_eq!(palettes.read_index(), 0x42);
+        assert_eq!(palettes.read_data(), 0x34);
+        assert_eq!(palettes.color(0, PaletteIndex::Id1), Rgb555(0x7F34));
+    }
+}
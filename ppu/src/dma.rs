@@ -1,6 +1,7 @@
 use sm83::{
     core::Cycles,
     memory::{Address, Memory},
+    state::{SaveState, StateError, StateReader, StateWriter},
 };
 
 use crate::oam::OAM_SIZE;
@@ -27,13 +28,14 @@ impl DmaEngine {
     }
 
     pub fn run<T: Memory>(&mut self, mut cycles: Cycles, memory: &mut T) {
-        const NO_CYCLES: Cycles = Cycles::new(0);
-        while cycles != NO_CYCLES && self.active {
+        // Each byte takes a machine cycle to transfer
+        const BYTE_CYCLES: Cycles = Cycles::from_machine_cycles(1);
+        while cycles >= BYTE_CYCLES && self.active {
             let src_address = self.base_address + self.current_element;
             let dest_address = 0xFE00 + self.current_element;
             let value = memory.read(src_address);
             memory.write(dest_address, value);
-            cycles = cycles - Cycles::new(4);
+            cycles = cycles - BYTE_CYCLES;
 
             self.current_element += 1;
             if self.current_element as usize == OAM_SIZE {
@@ -42,3 +44,25 @@ impl DmaEngine {
         }
     }
 }
+
+impl SaveState for DmaEngine {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.active);
+        state.write_u16(self.base_address);
+        state.write_u16(self.current_element);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader<'_>) -> Result<(), StateError> {
+        let active = state.read_bool()?;
+        let base_address = state.read_u16()?;
+        let current_element = state.read_u16()?;
+        if current_element as usize > OAM_SIZE {
+            return Err(StateError::Invalid);
+        }
+
+        self.active = active;
+        self.base_address = base_address;
+        self.current_element = current_element;
+        Ok(())
+    }
+}
@@ -3,13 +3,18 @@
 extern crate alloc;
 
 use alloc::boxed::Box;
+use core::ops::RangeInclusive;
 
+pub mod color_palettes;
 pub mod dma;
 pub mod modes;
 pub mod oam;
 pub mod regs;
 pub mod vram;
 
+mod tile_cache;
+
+use color_palettes::ColorPalettes;
 use dma::DmaEngine;
 use modes::Mode;
 use oam::Oam;
@@ -17,19 +22,117 @@ use regs::Registers;
 use sm83::{
     core::Cycles,
     interrupts::{Interrupt, Interrupts},
+    state::{SaveState, StateError, StateReader, StateWriter},
 };
+use tile_cache::TileCache;
+#[cfg(any(test, feature = "debug"))]
+use tock_registers::interfaces::Writeable;
 use tock_registers::interfaces::{ReadWriteable, Readable};
+use tock_registers::LocalRegisterCopy;
 use vram::Vram;
 
 use regs::STAT;
-use vram::{TILE_HEIGHT, TILE_WIDTH};
+use vram::{TileMap, BG_MAP_ATTRS, NUM_TILES, TILE_HEIGHT, TILE_WIDTH};
+
+/// A color with 5 bits per channel, as stored in the palettes of the Game Boy Color. Red is in
+/// the lowest bits, followed by green and blue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Rgb555(pub u16);
+
+impl Rgb555 {
+    pub const fn red(&self) -> u8 {
+        (self.0 & 0x1F) as u8
+    }
+
+    pub const fn green(&self) -> u8 {
+        ((self.0 >> 5) & 0x1F) as u8
+    }
+
+    pub const fn blue(&self) -> u8 {
+        ((self.0 >> 10) & 0x1F) as u8
+    }
+
+    /// Returns the red, green and blue components, scaled to 8 bits each.
+    pub const fn to_rgb888(&self) -> [u8; 3] {
+        const fn expand(channel: u8) -> u8 {
+            (channel << 3) | (channel >> 2)
+        }
+        [
+            expand(self.red()),
+            expand(self.green()),
+            expand(self.blue()),
+        ]
+    }
+}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Color {
-    White = 0,
-    LightGrey = 1,
-    DarkGrey = 2,
-    Black = 3,
+    White,
+    LightGrey,
+    DarkGrey,
+    Black,
+    /// A color of the palettes of the Game Boy Color. Frames only contain these in CGB mode.
+    Rgb(Rgb555),
+}
+
+impl Color {
+    /// Returns the red, green and blue components, with 8 bits each. The shades of the DMG are
+    /// evenly spaced greys.
+    pub const fn to_rgb888(&self) -> [u8; 3] {
+        match self {
+            Color::White => [255; 3],
+            Color::LightGrey => [170; 3],
+            Color::DarkGrey => [85; 3],
+            Color::Black => [0; 3],
+            Color::Rgb(color) => color.to_rgb888(),
+        }
+    }
+
+    /// Returns the red, green, blue and alpha components, with 8 bits each. Alpha is always 0xFF.
+    pub const fn to_rgba8888(&self) -> [u8; 4] {
+        let [r, g, b] = self.to_rgb888();
+        [r, g, b, 0xFF]
+    }
+
+    /// Returns the brightness of the color, from 0 (black) to 255 (white). The shades of the DMG
+    /// are evenly spaced.
+    pub const fn to_grayscale8(&self) -> u8 {
+        let [r, g, b] = self.to_rgb888();
+        ((r as u32 * 299 + g as u32 * 587 + b as u32 * 114) / 1000) as u8
+    }
+
+    /// Returns the DMG shade that is closest to the color, from 0 (white) to 3 (black), which is
+    /// handy for frontends with monochrome screens.
+    pub fn shade(&self) -> u8 {
+        match self {
+            Color::White => 0,
+            Color::LightGrey => 1,
+            Color::DarkGrey => 2,
+            Color::Black => 3,
+            Color::Rgb(_) => ((255 - self.to_grayscale8() as u32 + 42) / 85) as u8,
+        }
+    }
+
+    /// Returns a value that uniquely identifies the color. DMG shades map to their shade, and
+    /// colors of the Game Boy Color have bit 15 set.
+    fn bits(&self) -> u16 {
+        match self {
+            Color::Rgb(color) => 0x8000 | color.0,
+            _ => self.shade() as u16,
+        }
+    }
+
+    /// Reverses `bits`, returning `None` for values that don't identify any color.
+    fn from_bits(bits: u16) -> Option<Self> {
+        match bits {
+            0 => Some(Color::White),
+            1 => Some(Color::LightGrey),
+            2 => Some(Color::DarkGrey),
+            3 => Some(Color::Black),
+            0x8000..=0xFFFF => Some(Color::Rgb(Rgb555(bits & 0x7FFF))),
+            _ => None,
+        }
+    }
 }
 
 /// An index into the palette, which resolves to a specific color
@@ -82,39 +185,179 @@ pub enum PpuResult {
 
 pub type Frame = [[Color; DISPLAY_WIDTH]; DISPLAY_HEIGHT];
 
+/// A frame produced by the PPU. Derefs into a [`Frame`], and can be compared and hashed, which is
+/// handy to detect changes between frames.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameBuf(pub Frame);
+
+impl core::hash::Hash for FrameBuf {
+    fn hash<H: core::hash::Hasher>(&self, state: &mut H) {
+        // Hash a whole line at a time instead of each pixel individually
+        for line in self.0.iter() {
+            let mut bytes = [0u8; DISPLAY_WIDTH * 2];
+            bytes
+                .chunks_mut(2)
+                .zip(line.iter())
+                .for_each(|(bytes, color)| bytes.copy_from_slice(&color.bits().to_le_bytes()));
+            state.write(&bytes);
+        }
+    }
+}
+
+impl core::ops::Deref for FrameBuf {
+    type Target = Frame;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl core::ops::DerefMut for FrameBuf {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
 /// The Picture Processing Unit
 pub struct Ppu {
     vram: Vram,
+    tile_cache: TileCache,
     regs: Registers,
     oam: Oam,
 
     mode: Mode,
     cycles: Cycles,
     line: usize,
+    /// Length of mode 3 in the current line, in cycles
+    drawing_pixels_len: usize,
+    /// Cycles into the current frame while the LCD is off. The PPU is parked at the start of
+    /// line 0, but frames keep completing so that frontends keep presenting them.
+    disabled_cycles: Cycles,
 
     stat_irq: bool,
 
+    /// Cycles spent in each mode of the current line, checked in debug builds
+    #[cfg(debug_assertions)]
+    line_timing: LineTiming,
+
+    /// Internal line counter of the window. It only advances on lines where the window is shown,
+    /// so hiding the window for a few lines doesn't skip any line of its map.
+    window_line: usize,
+
+    /// The first frame after the LCD is turned on is not displayed. The screen stays blank until
+    /// the next frame starts.
+    blank_frame: bool,
+
     // Vector of indexes into OAM entries
     selected_oam_entries: heapless::Vec<usize, MAX_SELECTED_OBJECTS>,
 
     /// Origin of coordinates is top-left pixel.
-    framebuffer: Box<Frame>,
+    framebuffer: Box<FrameBuf>,
+
+    /// Range of lines of the framebuffer that changed since the last call to `take_dirty_lines`
+    dirty_lines: Option<RangeInclusive<usize>>,
+
+    /// Layers that are drawn when enabled in LCDC. Only ever changed for debugging.
+    layers: LayerVisibility,
+
+    /// Follows the semantics of the Game Boy Color for LCDC bit 0
+    cgb_mode: bool,
+
+    /// Uses the color palettes, the second VRAM bank and the map attributes of the Game Boy Color
+    color_mode: bool,
+    bg_color_palettes: ColorPalettes,
+    obj_color_palettes: ColorPalettes,
+}
+
+/// A pixel of the background or the window of the Game Boy Color
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct CgbBgPixel {
+    index: PaletteIndex,
+    palette: usize,
+    /// Color indexes 1-3 are drawn over objects
+    priority: bool,
+}
+
+impl CgbBgPixel {
+    const BLANK: Self = Self {
+        index: PaletteIndex::Id0,
+        palette: 0,
+        priority: false,
+    };
+}
+
+/// Selects which layers of the picture are drawn, regardless of LCDC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct LayerVisibility {
+    background: bool,
+    window: bool,
+    objects: bool,
+}
+
+impl LayerVisibility {
+    const ALL: Self = Self {
+        background: true,
+        window: true,
+        objects: true,
+    };
 }
 
 const OAM_SCAN_LEN: usize = 80;
+/// Shortest length of mode 3, without any penalties. HBlank takes the rest of the line.
 const DRAWING_PIXELS_LEN: usize = 172;
+const MAX_DRAWING_PIXELS_LEN: usize = 289;
 const HBLANK_LEN: usize = 204;
 const LINE_LENGTH: usize = OAM_SCAN_LEN + DRAWING_PIXELS_LEN + HBLANK_LEN;
 const NUM_LINES: usize = 154;
-const MAX_SELECTED_OBJECTS: usize = 10;
-const OBJ_OFFSET_Y: usize = 16;
-const OBJ_OFFSET_X: usize = 8;
+/// The number of objects that the OAM scan selects for each line
+pub const MAX_SELECTED_OBJECTS: usize = 10;
+
+// Mode 3 penalties, in cycles
+const WINDOW_PENALTY: usize = 6;
+const OBJECT_PENALTY: usize = 6;
+const MAX_OBJECT_TILE_PENALTY: usize = 5;
+
+const FRAME_LENGTH: usize = LINE_LENGTH * NUM_LINES;
+
+/// Offset between WX and the screen X coordinate of the left column of the window
+const WX_OFFSET: usize = 7;
+/// Largest WX that shows the window on screen
+const WX_MAX: u8 = 166;
+
+static_assertions::const_assert_eq!(70224, FRAME_LENGTH);
+static_assertions::const_assert!(OAM_SCAN_LEN + MAX_DRAWING_PIXELS_LEN < LINE_LENGTH);
+
+/// Cycles that the PPU spends in each mode of a line, indexed by mode
+#[cfg(debug_assertions)]
+#[derive(Debug, Clone, Copy)]
+struct LineTiming {
+    mode_cycles: [usize; 4],
+    /// Largest number of cycles of a single step within the line
+    largest_step: usize,
+    /// False if the PPU was not stepped from the start of the line, e.g. after loading a state
+    complete: bool,
+    /// Cycles spent in each mode of the last complete line
+    last_line: Option<[usize; 4]>,
+}
 
-static_assertions::const_assert_eq!(70224, LINE_LENGTH * NUM_LINES);
+#[cfg(debug_assertions)]
+impl LineTiming {
+    const fn new() -> Self {
+        Self {
+            mode_cycles: [0; 4],
+            largest_step: 0,
+            complete: false,
+            last_line: None,
+        }
+    }
+}
 
 #[cfg_attr(feature = "profile", inline(never))]
-fn mode_for_current_cycle_count(line_cycles: Cycles, line: usize) -> Mode {
-    // This is a simplified implementation that considers a fixed time mode 3.
+fn mode_for_current_cycle_count(
+    line_cycles: Cycles,
+    line: usize,
+    drawing_pixels_len: usize,
+) -> Mode {
     let line_cycles: usize = line_cycles.into();
     const VBLANK_START_LINE: usize = 144;
 
@@ -122,7 +365,7 @@ fn mode_for_current_cycle_count(line_cycles: Cycles, line: usize) -> Mode {
         Mode::Vblank
     } else if line_cycles < OAM_SCAN_LEN {
         Mode::OamScan
-    } else if line_cycles < (OAM_SCAN_LEN + DRAWING_PIXELS_LEN) {
+    } else if line_cycles < (OAM_SCAN_LEN + drawing_pixels_len) {
         Mode::DrawingPixels
     } else {
         Mode::Hblank
@@ -143,23 +386,296 @@ impl Ppu {
 
         Self {
             vram: Vram::new(),
+            tile_cache: TileCache::new(),
             regs: Registers::new(),
             oam: Oam::new(),
 
             mode: Mode::OamScan,
             cycles: Cycles::new(0),
             line: 0,
+            drawing_pixels_len: DRAWING_PIXELS_LEN,
+            disabled_cycles: Cycles::new(0),
 
             stat_irq: false,
+            #[cfg(debug_assertions)]
+            line_timing: LineTiming::new(),
+            window_line: 0,
+            blank_frame: false,
             selected_oam_entries: heapless::Vec::new(),
-            framebuffer: Box::new(unsafe { core::mem::transmute::<_, Frame>(framebuffer) }),
+            framebuffer: Box::new(FrameBuf(unsafe {
+                core::mem::transmute::<_, Frame>(framebuffer)
+            })),
+            dirty_lines: None,
+            layers: LayerVisibility::ALL,
+            cgb_mode: false,
+            color_mode: false,
+            bg_color_palettes: ColorPalettes::new(),
+            obj_color_palettes: ColorPalettes::new(),
         }
     }
 
+    /// Restores the PPU to its power-on state. The framebuffer allocation is reused, and its
+    /// contents are cleared.
+    pub fn reset(&mut self) {
+        // A new tile cache matches the contents of a new VRAM
+        self.vram = Vram::new();
+        self.tile_cache = TileCache::new();
+        self.bg_color_palettes = ColorPalettes::new();
+        self.obj_color_palettes = ColorPalettes::new();
+        self.regs = Registers::new();
+        self.oam = Oam::new();
+
+        self.mode = Mode::OamScan;
+        self.cycles = Cycles::new(0);
+        self.line = 0;
+        self.drawing_pixels_len = DRAWING_PIXELS_LEN;
+        self.disabled_cycles = Cycles::new(0);
+
+        self.stat_irq = false;
+        #[cfg(debug_assertions)]
+        {
+            self.line_timing = LineTiming::new();
+        }
+        self.window_line = 0;
+        self.blank_frame = false;
+        self.selected_oam_entries.clear();
+        for line in self.framebuffer.iter_mut() {
+            line.fill(Color::Black);
+        }
+        self.dirty_lines = Some(0..=DISPLAY_HEIGHT - 1);
+    }
+
+    /// Selects whether the PPU follows the behavior of the Game Boy Color. On CGB, clearing LCDC
+    /// bit 0 doesn't blank the background and the window, but makes objects always draw on top
+    /// of them.
+    pub fn set_cgb_mode(&mut self, enabled: bool) {
+        self.cgb_mode = enabled;
+    }
+
+    /// Selects whether the PPU renders like a Game Boy Color running a game made for it. This
+    /// maps VBK and the color palette registers, draws the background and the window with the
+    /// attributes of the second VRAM bank, and produces frames with `Color::Rgb` colors. It also
+    /// sets the CGB semantics of LCDC bit 0, see `set_cgb_mode`.
+    pub fn set_color_mode(&mut self, enabled: bool) {
+        self.color_mode = enabled;
+        self.cgb_mode = enabled;
+    }
+
+    /// Returns whether the PPU renders like a Game Boy Color, see `set_color_mode`.
+    pub fn color_mode(&self) -> bool {
+        self.color_mode
+    }
+
+    /// Returns whether the background and window are drawn according to LCDC bit 0.
+    fn bg_and_window_drawn(&self) -> bool {
+        self.cgb_mode || self.regs.lcdc.read(regs::LCDC::BG_AND_WINDOW_ENABLE) != 0
+    }
+
+    /// Returns whether objects can be drawn behind the background and window according to LCDC
+    /// bit 0.
+    fn bg_and_window_priority(&self) -> bool {
+        self.regs.lcdc.read(regs::LCDC::BG_AND_WINDOW_ENABLE) != 0
+    }
+
     pub fn mode(&self) -> Mode {
         self.mode
     }
 
+    /// Returns true if the CPU can access VRAM. Accesses are blocked while pixels are drawn, and
+    /// allowed again on the cycle mode 0 begins, together with the mode reported in STAT. VRAM is
+    /// always accessible while the LCD is off.
+    pub fn vram_accessible(&self) -> bool {
+        !self.lcd_enabled() || self.mode != Mode::DrawingPixels
+    }
+
+    /// Returns true if the CPU can access OAM. Accesses are blocked during the OAM scan and while
+    /// pixels are drawn, and allowed again on the cycle mode 0 begins.
+    pub fn oam_accessible(&self) -> bool {
+        !self.lcd_enabled() || !matches!(self.mode, Mode::OamScan | Mode::DrawingPixels)
+    }
+
+    /// Returns the number of cycles until the PPU changes its mode or moves on to the next line,
+    /// whatever happens first.
+    pub fn mode_remaining(&self) -> Cycles {
+        if !self.lcd_enabled() {
+            return Cycles::new(FRAME_LENGTH) - self.disabled_cycles;
+        }
+
+        let line_cycles: usize = self.cycles.into();
+        let boundary = match self.mode_for_current_cycle_count() {
+            Mode::OamScan => OAM_SCAN_LEN,
+            Mode::DrawingPixels => OAM_SCAN_LEN + self.drawing_pixels_len,
+            Mode::Hblank | Mode::Vblank => LINE_LENGTH,
+        };
+        Cycles::new(boundary - line_cycles)
+    }
+
+    fn mode_for_current_cycle_count(&self) -> Mode {
+        mode_for_current_cycle_count(self.cycles, self.line, self.drawing_pixels_len)
+    }
+
+    /// Computes the length of mode 3 for the current line. Drawing takes longer when the
+    /// background is scrolled by a fraction of a tile, when the window is shown, and for every
+    /// object on the line, which stalls the pixel fetcher depending on its alignment with the
+    /// background tiles. The tiles of the window are not taken into account for the alignment.
+    fn compute_drawing_pixels_len(&self) -> usize {
+        let fine_scroll = self.regs.scx as usize & 7;
+
+        let window_penalty = if self.window_on_line() {
+            WINDOW_PENALTY
+        } else {
+            0
+        };
+
+        let mut objects_penalty = 0;
+        if self.regs.lcdc.read(regs::LCDC::OBJ_ENABLE) != 0 {
+            // The fetcher only stalls once per background tile, for as long as the leftmost
+            // object within the tile requires.
+            const NUM_TILES: usize = (DISPLAY_WIDTH + 2 * TILE_WIDTH) / TILE_WIDTH;
+            let mut tile_penalties = [0; NUM_TILES];
+            for index in self.selected_oam_entries.iter() {
+                // OAM X coordinate, where 8 is the left column of the screen
+                let oam_x = (self.oam.decoded(*index).x + TILE_WIDTH as i16) as usize;
+                if oam_x >= DISPLAY_WIDTH + TILE_WIDTH {
+                    continue;
+                }
+
+                let position = oam_x + fine_scroll;
+                let offset_in_tile = position & (TILE_WIDTH - 1);
+                let tile_penalty = &mut tile_penalties[position / TILE_WIDTH];
+                *tile_penalty =
+                    (*tile_penalty).max(MAX_OBJECT_TILE_PENALTY.saturating_sub(offset_in_tile));
+                objects_penalty += OBJECT_PENALTY;
+            }
+            objects_penalty += tile_penalties.iter().sum::<usize>();
+        }
+
+        (DRAWING_PIXELS_LEN + fine_scroll + window_penalty + objects_penalty)
+            .min(MAX_DRAWING_PIXELS_LEN)
+    }
+
+    /// Length of mode 0 in the current line, in cycles. HBlank takes the rest of the line after
+    /// mode 3, so it is shorter when mode 3 is longer.
+    fn hblank_len(&self) -> usize {
+        LINE_LENGTH - OAM_SCAN_LEN - self.drawing_pixels_len
+    }
+
+    /// Returns the number of cycles that each mode of the current line should take, indexed by
+    /// mode, or `None` for the first line after turning on the LCD, which has no OAM scan.
+    #[cfg(debug_assertions)]
+    fn expected_mode_cycles(&self) -> Option<[usize; 4]> {
+        let mut mode_cycles = [0; 4];
+        if self.line >= DISPLAY_HEIGHT {
+            mode_cycles[Mode::Vblank as usize] = LINE_LENGTH;
+        } else if self.blank_frame && self.line == 0 {
+            return None;
+        } else {
+            mode_cycles[Mode::OamScan as usize] = OAM_SCAN_LEN;
+            mode_cycles[Mode::DrawingPixels as usize] = self.drawing_pixels_len;
+            mode_cycles[Mode::Hblank as usize] = self.hblank_len();
+        }
+        Some(mode_cycles)
+    }
+
+    /// Accounts the cycles of a step to the mode the PPU was in, and checks in debug builds that
+    /// the modes of each line add up to its length when it ends. Each mode must last as long as
+    /// expected, give or take the cycles of a step, since the PPU only changes modes between
+    /// steps. Returns the cycles of the step that belong to the next line.
+    #[cfg(debug_assertions)]
+    fn record_line_timing(&mut self, cycles: usize) -> usize {
+        let line_remaining = LINE_LENGTH - usize::from(self.cycles);
+        let timing = &mut self.line_timing;
+        let in_line = cycles.min(line_remaining);
+        timing.mode_cycles[self.mode as usize] += in_line;
+        timing.largest_step = timing.largest_step.max(in_line);
+        if cycles < line_remaining {
+            return 0;
+        }
+
+        let expected = self.expected_mode_cycles();
+        let timing = &mut self.line_timing;
+        if timing.complete {
+            let mode_cycles = timing.mode_cycles;
+            debug_assert_eq!(
+                mode_cycles.iter().sum::<usize>(),
+                LINE_LENGTH,
+                "Modes of line {} take {mode_cycles:?} cycles",
+                self.line
+            );
+            if let Some(expected) = expected {
+                let tolerance = timing.largest_step - 1;
+                debug_assert!(
+                    mode_cycles
+                        .iter()
+                        .zip(expected)
+                        .all(|(cycles, expected)| cycles.abs_diff(expected) <= tolerance),
+                    "Modes of line {} take {mode_cycles:?} cycles instead of {expected:?}",
+                    self.line
+                );
+            }
+            timing.last_line = Some(mode_cycles);
+        }
+        *timing = LineTiming {
+            complete: true,
+            last_line: timing.last_line,
+            ..LineTiming::new()
+        };
+        cycles - line_remaining
+    }
+
+    /// Returns whether the window is shown on the current line: it is enabled, the line is below
+    /// WY, and WX places at least one column of it on screen.
+    fn window_on_line(&self) -> bool {
+        self.regs.lcdc.read(regs::LCDC::WINDOW_ENABLE) != 0
+            && self.line >= self.regs.wy as usize
+            && self.regs.wx <= WX_MAX
+    }
+
+    /// Moves the internal line counter of the window to the next line if the window was shown on
+    /// the current one.
+    fn advance_window_line(&mut self) {
+        if self.window_on_line() {
+            self.window_line += 1;
+        }
+    }
+
+    /// Returns whether the LCD and the PPU are turned on (bit 7 of LCDC).
+    pub fn lcd_enabled(&self) -> bool {
+        self.regs.lcdc.read(regs::LCDC::ENABLE) != 0
+    }
+
+    /// Returns the raw value of the background palette register (BGP).
+    pub fn bg_palette(&self) -> u8 {
+        self.regs.bg_palette.into()
+    }
+
+    /// Returns the raw value of the first object palette register (OBP0).
+    pub fn obj_palette0(&self) -> u8 {
+        self.regs.obj_palette0.into()
+    }
+
+    /// Returns the raw value of the second object palette register (OBP1).
+    pub fn obj_palette1(&self) -> u8 {
+        self.regs.obj_palette1.into()
+    }
+
+    /// Returns the line the PPU is currently processing, which is also the value of LY
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// Returns how far the PPU is into the current frame, from 0.0 at the start of line 0 to
+    /// almost 1.0 at the end of the last VBlank line.
+    pub fn frame_progress(&self) -> f32 {
+        let frame_cycles = if self.lcd_enabled() {
+            let line_cycles: usize = self.cycles.into();
+            self.line * LINE_LENGTH + line_cycles
+        } else {
+            self.disabled_cycles.into()
+        };
+        frame_cycles as f32 / FRAME_LENGTH as f32
+    }
+
     #[cfg_attr(feature = "profile", inline(never))]
     fn update_line_and_cycles(&mut self, cycles: Cycles) {
         self.cycles = self.cycles + cycles;
@@ -180,22 +696,95 @@ impl Ppu {
         dma_engine: &mut DmaEngine,
         render: bool,
     ) -> (Interrupts, PpuResult) {
-        self.update_line_and_cycles(cycles);
-
-        let new_mode = mode_for_current_cycle_count(self.cycles, self.line);
-
         if self.regs.dma_config.triggered {
             dma_engine.trigger(self.regs.dma_config.address);
             self.regs.dma_config.triggered = false;
         }
+
+        if !self.lcd_enabled() {
+            return (Interrupts::new(), self.step_disabled(cycles));
+        }
+
+        #[cfg(debug_assertions)]
+        let next_line_cycles = self.record_line_timing(cycles.into());
+        self.update_line_and_cycles(cycles);
+
+        let mut new_mode = self.mode_for_current_cycle_count();
+        if self.blank_frame && self.line == 0 && new_mode == Mode::OamScan {
+            // The first line after turning on the LCD does not perform an OAM scan. The PPU
+            // reports mode 0 instead, and goes straight into mode 3 afterwards.
+            new_mode = Mode::Hblank;
+        }
+
         let (interrupts, result) = self.step_inner(new_mode, render);
+        #[cfg(debug_assertions)]
+        {
+            // The rest of the step is spent in the mode the next line starts with
+            let timing = &mut self.line_timing;
+            timing.mode_cycles[self.mode as usize] += next_line_cycles;
+            timing.largest_step = timing.largest_step.max(next_line_cycles);
+        }
         (interrupts | self.update_lcd_irq(), result)
     }
 
-    pub fn frame(&self) -> &Frame {
+    /// Keeps track of the frame timing while the LCD is off, without raising any interrupts.
+    fn step_disabled(&mut self, cycles: Cycles) -> PpuResult {
+        self.disabled_cycles = self.disabled_cycles + cycles;
+        if self.disabled_cycles >= Cycles::new(FRAME_LENGTH) {
+            self.disabled_cycles = self.disabled_cycles - Cycles::new(FRAME_LENGTH);
+            PpuResult::FrameComplete
+        } else {
+            PpuResult::InProgress(self.mode)
+        }
+    }
+
+    /// Returns the range of lines of the frame that changed since the last call, if any, and
+    /// clears it. Frontends can use it to only update the part of the screen that changed.
+    pub fn take_dirty_lines(&mut self) -> Option<RangeInclusive<usize>> {
+        self.dirty_lines.take()
+    }
+
+    pub fn frame(&self) -> &FrameBuf {
         &self.framebuffer
     }
 
+    /// Writes the frame into `out` with 4 bytes per pixel: red, green, blue and alpha, in that
+    /// order. Alpha is always 0xFF. Pixels are stored row by row, starting at the top left
+    /// corner. Panics if `out` is not `DISPLAY_WIDTH * DISPLAY_HEIGHT * 4` bytes long.
+    pub fn frame_rgba8888(&self, out: &mut [u8]) {
+        assert_eq!(out.len(), DISPLAY_WIDTH * DISPLAY_HEIGHT * 4);
+        let pixels = self.framebuffer.iter().flat_map(|line| line.iter());
+        for (dest, color) in out.chunks_exact_mut(4).zip(pixels) {
+            dest.copy_from_slice(&color.to_rgba8888());
+        }
+    }
+
+    /// Writes the frame into `out` with 1 byte per pixel, from 0 (black) to 255 (white). Pixels
+    /// are stored row by row, starting at the top left corner. Panics if `out` is not
+    /// `DISPLAY_WIDTH * DISPLAY_HEIGHT` bytes long.
+    pub fn frame_grayscale8(&self, out: &mut [u8]) {
+        assert_eq!(out.len(), DISPLAY_WIDTH * DISPLAY_HEIGHT);
+        let pixels = self.framebuffer.iter().flat_map(|line| line.iter());
+        for (dest, color) in out.iter_mut().zip(pixels) {
+            *dest = color.to_grayscale8();
+        }
+    }
+
+    /// Returns the color of the pixel at the given coordinates of the frame, or `None` if they
+    /// are outside of the display.
+    pub fn pixel(&self, x: usize, y: usize) -> Option<Color> {
+        self.framebuffer.get(y)?.get(x).copied()
+    }
+
+    /// Iterates over all pixels of the frame in row-major order, yielding `(x, y, color)`.
+    pub fn pixels(&self) -> impl Iterator<Item = (usize, usize, Color)> + '_ {
+        self.framebuffer.iter().enumerate().flat_map(|(y, line)| {
+            line.iter()
+                .enumerate()
+                .map(move |(x, color)| (x, y, *color))
+        })
+    }
+
     #[cfg_attr(feature = "profile", inline(never))]
     fn update_lcd_irq(&mut self) -> Interrupts {
         let lyc_eq_ly = self.regs.status.read(STAT::LYC_INT_SELECT) != 0
@@ -221,26 +810,46 @@ impl Ppu {
         }
     }
 
+    fn obj_height(&self) -> usize {
+        (self.regs.lcdc.read(regs::LCDC::OBJ_SIZE) as usize + 1) * TILE_HEIGHT
+    }
+
     #[cfg_attr(feature = "profile", inline(never))]
     fn oam_scan(&mut self) {
-        self.selected_oam_entries = heapless::Vec::new();
+        self.selected_oam_entries = self.selected_sprites_for_line(self.line);
+    }
+
+    /// Returns all objects in OAM, e.g. to build a sprite viewer. See [`Ppu::decoded_object`] to
+    /// get the attributes of an object.
+    pub fn oam_entries(&self) -> &[oam::Object] {
+        self.oam.objects()
+    }
 
-        let obj_height = (self.regs.lcdc.read(regs::LCDC::OBJ_SIZE) + 1) * 8;
+    /// Decodes the object at the given index of the OAM.
+    pub fn decoded_object(&self, index: usize) -> oam::DecodedObject {
+        self.oam.decoded(index)
+    }
 
-        let cur_obj_line = (self.line + OBJ_OFFSET_Y) as u8;
-        let is_object_relevant = |(_, object): &(usize, &oam::Object)| -> bool {
-            cur_obj_line >= object.y && cur_obj_line < object.y + obj_height
+    /// Returns the OAM indexes of the objects that the OAM scan selects for the given line, with
+    /// the current contents of OAM and object size. At most 10 objects are selected per line, in
+    /// OAM order.
+    pub fn selected_sprites_for_line(
+        &self,
+        line: usize,
+    ) -> heapless::Vec<usize, MAX_SELECTED_OBJECTS> {
+        let obj_height = self.obj_height() as i16;
+        let line = line as i16;
+        let is_object_relevant = |object: &oam::DecodedObject| -> bool {
+            line >= object.y && line < object.y + obj_height
         };
 
         // Walk all entries from 0 to NUM_OBJS
-        self.selected_oam_entries = self
-            .oam
-            .iter()
-            .enumerate()
+        self.oam
+            .iter_decoded()
             .filter(is_object_relevant)
-            .map(|(i, _)| i)
+            .map(|object| object.index)
             .take(MAX_SELECTED_OBJECTS)
-            .collect();
+            .collect()
     }
 
     #[cfg_attr(feature = "profile", inline(never))]
@@ -254,13 +863,20 @@ impl Ppu {
         self.mode = new_mode;
 
         match self.mode {
-            Mode::OamScan if render => {
+            Mode::OamScan => {
+                // Objects are needed for the timing of mode 3, even if the line is not drawn.
                 self.oam_scan();
             }
-            Mode::DrawingPixels if render => {
-                self.draw_line();
+            Mode::DrawingPixels => {
+                self.drawing_pixels_len = self.compute_drawing_pixels_len();
+                if render {
+                    self.draw_line();
+                }
+                self.advance_window_line();
             }
             Mode::Vblank => {
+                self.blank_frame = false;
+                self.window_line = 0;
                 self.update_registers();
                 return (Interrupt::Vblank.into(), PpuResult::FrameComplete);
             }
@@ -273,8 +889,7 @@ impl Ppu {
 
     #[cfg_attr(feature = "profile", inline(never))]
     fn draw_line_background(&self, line: &mut [PaletteIndex; DISPLAY_WIDTH]) -> Palette {
-        let bg_win_enable = self.regs.lcdc.read(regs::LCDC::BG_AND_WINDOW_ENABLE) != 0;
-        if !bg_win_enable {
+        if !self.bg_and_window_drawn() {
             // BG is disabled
             line.iter_mut().for_each(|p| *p = PaletteIndex::Id0);
             return Palette(0);
@@ -308,10 +923,8 @@ impl Ppu {
             .cycle()
             .skip(x_tile_offset)
             .map(|tile_index| {
-                self.vram
-                    .get_tile(*tile_index, bg_tile_data_area)
-                    .get_line(tile_line_idx)
-                    .iter()
+                let tile = Vram::tile_position(*tile_index, bg_tile_data_area);
+                self.tile_cache.line(tile, tile_line_idx).iter().copied()
             })
             .flatten()
             .skip(x_inner_offset)
@@ -328,17 +941,14 @@ impl Ppu {
 
     #[cfg_attr(feature = "profile", inline(never))]
     fn draw_line_window(&self, line: &mut [PaletteIndex; DISPLAY_WIDTH]) {
-        let bg_win_enable = self.regs.lcdc.read(regs::LCDC::BG_AND_WINDOW_ENABLE) != 0;
-        if !bg_win_enable {
+        if !self.bg_and_window_drawn() {
             return;
         }
 
-        let wy = self.regs.wy as usize;
-        if self.line < wy {
-            // Nothing to draw, return
+        if !self.window_on_line() {
             return;
         }
-        let win_line = self.line - wy;
+        let win_line = self.window_line;
 
         let win_tile_map: crate::regs::LCDC::WINDOW_TILE_MAP::Value = self
             .regs
@@ -352,12 +962,8 @@ impl Ppu {
             .read_as_enum(crate::regs::LCDC::BG_AND_WINDOW_TILE_DATA)
             .expect("Invalid LCDC bit 4");
 
-        const WX_OFFSET: usize = 7;
         let wx = self.regs.wx as usize;
         let disp_x_offset = if wx >= WX_OFFSET { wx - WX_OFFSET } else { 0 };
-        if disp_x_offset >= DISPLAY_WIDTH {
-            return;
-        }
 
         let disp_x_initial_skip = if wx >= WX_OFFSET { 0 } else { WX_OFFSET - wx };
         let tile_line_idx = win_line % vram::TILE_HEIGHT;
@@ -368,10 +974,8 @@ impl Ppu {
             .line(win_line)
             .iter()
             .map(|tile_idx| {
-                self.vram
-                    .get_tile(*tile_idx, win_tile_data_area)
-                    .get_line(tile_line_idx)
-                    .iter()
+                let tile = Vram::tile_position(*tile_idx, win_tile_data_area);
+                self.tile_cache.line(tile, tile_line_idx).iter().copied()
             })
             .flatten()
             .skip(disp_x_initial_skip);
@@ -382,65 +986,95 @@ impl Ppu {
             .for_each(|(dest, palette_index)| *dest = palette_index);
     }
 
+    /// Returns the position in the first VRAM bank of the tile of the object that is drawn on the
+    /// current line, along with the line within the tile. Objects are selected during the OAM
+    /// scan, but the game (or an OAM DMA transfer) may move or shrink them before they are drawn,
+    /// in which case they no longer cover the line and `None` is returned.
+    fn object_tile(
+        &self,
+        object: &oam::DecodedObject,
+        obj_height: usize,
+    ) -> Option<(usize, usize)> {
+        let object_line = self.line as i16 - object.y;
+        if !(0..obj_height as i16).contains(&object_line) {
+            return None;
+        }
+
+        // The flip applies to the whole object, which may be made of 2 tiles.
+        let object_line = object_line as usize;
+        let object_line = if object.y_flip {
+            obj_height - 1 - object_line
+        } else {
+            object_line
+        };
+
+        // Tall objects ignore bit 0 of the tile index. The top tile is always even and the
+        // bottom tile is always odd.
+        let tile_idx = object.tile_idx;
+        let (tile_idx, tile_line) = if obj_height > TILE_HEIGHT {
+            if object_line >= TILE_HEIGHT {
+                (tile_idx | 1, object_line - TILE_HEIGHT)
+            } else {
+                (tile_idx & !1, object_line)
+            }
+        } else {
+            (tile_idx, object_line)
+        };
+
+        debug_assert!(tile_line < TILE_HEIGHT);
+
+        let tile = Vram::tile_position(
+            vram::TileIndex::from(tile_idx),
+            regs::LCDC::BG_AND_WINDOW_TILE_DATA::Value::Blocks0And1,
+        );
+        Some((tile, tile_line))
+    }
+
     #[cfg_attr(feature = "profile", inline(never))]
     fn draw_line_objects(
         &self,
         bg_line: &[PaletteIndex; DISPLAY_WIDTH],
         line: &mut [Option<(usize, Color)>; DISPLAY_WIDTH],
     ) {
-        for (obj_prio, object) in self
+        let obj_height = self.obj_height();
+        let bg_and_window_priority = self.bg_and_window_priority();
+
+        // On the DMG, objects with a smaller X coordinate are drawn over the rest. Objects with
+        // the same X coordinate are drawn in OAM order.
+        let mut objects: heapless::Vec<oam::DecodedObject, MAX_SELECTED_OBJECTS> = self
             .selected_oam_entries
             .iter()
-            .map(|i| &self.oam.objects()[*i])
-            .enumerate()
-        {
-            let y_flip = object.attrs.read(oam::OBJ_ATTRS::Y_FLIP) != 0;
-            let x_flip = object.attrs.read(oam::OBJ_ATTRS::X_FLIP) != 0;
-
-            let tile_line = OBJ_OFFSET_Y + self.line - object.y as usize;
-            let (tile_idx, tile_line) = if tile_line >= TILE_HEIGHT {
-                (object.tile_idx.next(), tile_line - TILE_HEIGHT)
-            } else {
-                (object.tile_idx, tile_line)
-            };
-
-            debug_assert!(tile_line < TILE_HEIGHT);
-            let tile_line = if y_flip {
-                TILE_HEIGHT - 1 - tile_line
-            } else {
-                tile_line
-            };
+            .map(|i| self.oam.decoded(*i))
+            .collect();
+        objects.sort_by_key(|object| (object.x, object.index));
 
-            let palette = match object
-                .attrs
-                .read_as_enum(oam::OBJ_ATTRS::PALETTE_SELECTOR)
-                .unwrap()
-            {
+        for (obj_prio, object) in objects.iter().enumerate() {
+            let palette = match object.palette {
                 oam::OBJ_ATTRS::PALETTE_SELECTOR::Value::Palette0 => self.regs.obj_palette0,
                 oam::OBJ_ATTRS::PALETTE_SELECTOR::Value::Palette1 => self.regs.obj_palette1,
             };
 
-            let tile = self.vram.get_tile(
-                tile_idx,
-                regs::LCDC::BG_AND_WINDOW_TILE_DATA::Value::Blocks0And1,
-            );
-
-            let bg_prio = object.attrs.read(oam::OBJ_ATTRS::PRIO) != 0;
-            let tile_line = tile.get_line(tile_line);
+            let Some((tile, tile_line)) = self.object_tile(object, obj_height) else {
+                continue;
+            };
+            let tile_line = self.tile_cache.line(tile, tile_line);
 
-            for (i, pixel) in tile_line.iter().enumerate() {
+            for (i, &pixel) in tile_line.iter().enumerate() {
                 if pixel == PaletteIndex::Id0 {
                     // Transparent
                     continue;
                 }
 
-                let x = if x_flip { OBJ_OFFSET_X - 1 - i } else { i } + object.x as usize;
-                if x < OBJ_OFFSET_X || x >= DISPLAY_WIDTH + OBJ_OFFSET_X {
+                let x = if object.x_flip { TILE_WIDTH - 1 - i } else { i } as i16 + object.x;
+                if x < 0 || x >= DISPLAY_WIDTH as i16 {
                     continue;
                 }
-                let x = x - OBJ_OFFSET_X;
+                let x = x as usize;
 
-                if bg_line[x] != PaletteIndex::Id0 && bg_prio {
+                if bg_line[x] != PaletteIndex::Id0
+                    && object.below_bg_and_window
+                    && bg_and_window_priority
+                {
                     continue;
                 }
 
@@ -460,18 +1094,48 @@ impl Ppu {
             return;
         }
 
+        let previous = self.framebuffer[self.line];
+        if self.blank_frame {
+            self.framebuffer[self.line].fill(Color::White);
+        } else if self.color_mode {
+            self.compose_line_cgb();
+        } else {
+            self.compose_line();
+        }
+
+        if self.framebuffer[self.line] != previous {
+            self.dirty_lines = Some(match self.dirty_lines.take() {
+                Some(lines) => *lines.start().min(&self.line)..=*lines.end().max(&self.line),
+                None => self.line..=self.line,
+            });
+        }
+    }
+
+    #[cfg_attr(feature = "profile", inline(never))]
+    fn compose_line(&mut self) {
+        // Only tiles written since the last line was drawn need to be decoded again
+        self.tile_cache.update(&mut self.vram);
+
         let mut line: [PaletteIndex; DISPLAY_WIDTH] = [PaletteIndex::Id0; DISPLAY_WIDTH];
 
-        let bg_palette = self.draw_line_background(&mut line);
+        let bg_palette = if self.layers.background {
+            self.draw_line_background(&mut line)
+        } else if self.bg_and_window_drawn() {
+            // The hidden background is drawn with color index 0, but the window still needs the
+            // palette.
+            self.regs.bg_palette
+        } else {
+            Palette(0)
+        };
 
-        if self.regs.lcdc.read(regs::LCDC::WINDOW_ENABLE) == 1 {
+        if self.layers.window && self.regs.lcdc.read(regs::LCDC::WINDOW_ENABLE) == 1 {
             self.draw_line_window(&mut line);
         }
 
         // Either a selected (color, and x coordinate) or nothing
         let mut line_objs: [Option<(usize, Color)>; DISPLAY_WIDTH] = [None; DISPLAY_WIDTH];
 
-        if self.regs.lcdc.read(regs::LCDC::OBJ_ENABLE) == 1 {
+        if self.layers.objects && self.regs.lcdc.read(regs::LCDC::OBJ_ENABLE) == 1 {
             self.draw_line_objects(&line, &mut line_objs);
         }
 
@@ -488,6 +1152,210 @@ impl Ppu {
         }
     }
 
+    /// Returns the pixel of the background map at the given coordinates of the map, resolving the
+    /// tile attributes of the Game Boy Color. The coordinates wrap around the map.
+    fn cgb_map_pixel(
+        &self,
+        map: &TileMap,
+        attr_map: &TileMap,
+        tile_data_area: regs::LCDC::BG_AND_WINDOW_TILE_DATA::Value,
+        x: usize,
+        y: usize,
+    ) -> CgbBgPixel {
+        let column = (x / TILE_WIDTH) % vram::TILE_MAP_WIDTH;
+        let attrs =
+            LocalRegisterCopy::<u8, BG_MAP_ATTRS::Register>::new(attr_map.line(y)[column].into());
+
+        let bank = attrs.read(BG_MAP_ATTRS::BANK) as usize;
+        let tile = Vram::tile_position(map.line(y)[column], tile_data_area) + bank * NUM_TILES;
+
+        let tile_line = y % TILE_HEIGHT;
+        let tile_line = if attrs.is_set(BG_MAP_ATTRS::Y_FLIP) {
+            TILE_HEIGHT - 1 - tile_line
+        } else {
+            tile_line
+        };
+        let tile_column = x % TILE_WIDTH;
+        let tile_column = if attrs.is_set(BG_MAP_ATTRS::X_FLIP) {
+            TILE_WIDTH - 1 - tile_column
+        } else {
+            tile_column
+        };
+
+        CgbBgPixel {
+            index: self.tile_cache.line(tile, tile_line)[tile_column],
+            palette: attrs.read(BG_MAP_ATTRS::COLOR_PALETTE) as usize,
+            priority: attrs.is_set(BG_MAP_ATTRS::PRIO),
+        }
+    }
+
+    #[cfg_attr(feature = "profile", inline(never))]
+    fn draw_line_background_cgb(&self, line: &mut [CgbBgPixel; DISPLAY_WIDTH]) {
+        let bg_tile_map: regs::LCDC::BG_TILE_MAP::Value = self
+            .regs
+            .lcdc
+            .read_as_enum(regs::LCDC::BG_TILE_MAP)
+            .expect("Invalid LCDC bit 3");
+
+        let bg_tile_data_area: regs::LCDC::BG_AND_WINDOW_TILE_DATA::Value = self
+            .regs
+            .lcdc
+            .read_as_enum(regs::LCDC::BG_AND_WINDOW_TILE_DATA)
+            .expect("Invalid LCDC bit 4");
+
+        let map = self.vram.get_bg_tile_map(bg_tile_map);
+        let attr_map = self.vram.get_bg_attr_map(bg_tile_map);
+        let y = self.regs.scy as usize + self.line;
+        for (x, pixel) in line.iter_mut().enumerate() {
+            let x = self.regs.scx as usize + x;
+            *pixel = self.cgb_map_pixel(map, attr_map, bg_tile_data_area, x, y);
+        }
+    }
+
+    #[cfg_attr(feature = "profile", inline(never))]
+    fn draw_line_window_cgb(&self, line: &mut [CgbBgPixel; DISPLAY_WIDTH]) {
+        if !self.window_on_line() {
+            return;
+        }
+        let win_line = self.window_line;
+
+        let win_tile_map: regs::LCDC::WINDOW_TILE_MAP::Value = self
+            .regs
+            .lcdc
+            .read_as_enum(regs::LCDC::WINDOW_TILE_MAP)
+            .expect("Invalid LCDC bit 6");
+
+        let win_tile_data_area: regs::LCDC::BG_AND_WINDOW_TILE_DATA::Value = self
+            .regs
+            .lcdc
+            .read_as_enum(regs::LCDC::BG_AND_WINDOW_TILE_DATA)
+            .expect("Invalid LCDC bit 4");
+
+        let map = self.vram.get_win_tile_map(win_tile_map);
+        let attr_map = self.vram.get_win_attr_map(win_tile_map);
+        let wx = self.regs.wx as usize;
+        let first_x = wx.saturating_sub(WX_OFFSET);
+        for (x, pixel) in line.iter_mut().enumerate().skip(first_x) {
+            let win_x = x + WX_OFFSET - wx;
+            *pixel = self.cgb_map_pixel(map, attr_map, win_tile_data_area, win_x, win_line);
+        }
+    }
+
+    /// Draws the objects of the current line with the color palettes. Objects that come first in
+    /// OAM are drawn over the rest, and each pixel stores whether it is drawn behind background
+    /// and window color indexes 1-3.
+    #[cfg_attr(feature = "profile", inline(never))]
+    fn draw_line_objects_cgb(&self, line: &mut [Option<(Rgb555, bool)>; DISPLAY_WIDTH]) {
+        let obj_height = self.obj_height();
+
+        for object in self
+            .selected_oam_entries
+            .iter()
+            .map(|i| self.oam.decoded(*i))
+        {
+            let Some((tile, tile_line)) = self.object_tile(&object, obj_height) else {
+                continue;
+            };
+            let tile = tile + object.bank * NUM_TILES;
+            let tile_line = self.tile_cache.line(tile, tile_line);
+
+            for (i, &pixel) in tile_line.iter().enumerate() {
+                if pixel == PaletteIndex::Id0 {
+                    // Transparent
+                    continue;
+                }
+
+                let x = if object.x_flip { TILE_WIDTH - 1 - i } else { i } as i16 + object.x;
+                if x < 0 || x >= DISPLAY_WIDTH as i16 {
+                    continue;
+                }
+                let x = x as usize;
+
+                if line[x].is_none() {
+                    let color = self.obj_color_palettes.color(object.color_palette, pixel);
+                    line[x] = Some((color, object.below_bg_and_window));
+                }
+            }
+        }
+    }
+
+    #[cfg_attr(feature = "profile", inline(never))]
+    fn compose_line_cgb(&mut self) {
+        self.tile_cache.update(&mut self.vram);
+
+        // The hidden background is drawn with color index 0 of palette 0
+        let mut line = [CgbBgPixel::BLANK; DISPLAY_WIDTH];
+
+        if self.layers.background {
+            self.draw_line_background_cgb(&mut line);
+        }
+
+        if self.layers.window && self.regs.lcdc.read(regs::LCDC::WINDOW_ENABLE) == 1 {
+            self.draw_line_window_cgb(&mut line);
+        }
+
+        let mut line_objs: [Option<(Rgb555, bool)>; DISPLAY_WIDTH] = [None; DISPLAY_WIDTH];
+
+        if self.layers.objects && self.regs.lcdc.read(regs::LCDC::OBJ_ENABLE) == 1 {
+            self.draw_line_objects_cgb(&mut line_objs);
+        }
+
+        // When LCDC bit 0 is clear, objects are always drawn over the background and the window
+        let bg_and_window_priority = self.bg_and_window_priority();
+        for ((pixel, bg_pixel), object) in self.framebuffer[self.line]
+            .iter_mut()
+            .zip(line.iter())
+            .zip(line_objs.iter())
+        {
+            let color = match object {
+                Some((color, below_bg_and_window))
+                    if !bg_and_window_priority
+                        || bg_pixel.index == PaletteIndex::Id0
+                        || !(bg_pixel.priority || *below_bg_and_window) =>
+                {
+                    *color
+                }
+                _ => self
+                    .bg_color_palettes
+                    .color(bg_pixel.palette, bg_pixel.index),
+            };
+            *pixel = Color::Rgb(color);
+        }
+    }
+
+    /// Restarts the frame when the LCD is turned on. The PPU starts at the beginning of line 0,
+    /// and the frame being drawn is discarded.
+    fn lcd_turned_on(&mut self) {
+        self.line = 0;
+        self.cycles = Cycles::new(0);
+        self.mode = Mode::Hblank;
+        self.blank_frame = true;
+        #[cfg(debug_assertions)]
+        {
+            self.line_timing = LineTiming::new();
+        }
+        self.window_line = 0;
+        self.selected_oam_entries.clear();
+        self.update_registers();
+    }
+
+    /// Parks the PPU at the start of line 0 in mode 0 when the LCD is turned off, and blanks the
+    /// screen. LY reads 0 until the LCD is turned on again.
+    fn lcd_turned_off(&mut self) {
+        self.line = 0;
+        self.cycles = Cycles::new(0);
+        self.disabled_cycles = Cycles::new(0);
+        self.mode = Mode::Hblank;
+        self.stat_irq = false;
+        self.window_line = 0;
+        self.selected_oam_entries.clear();
+        for line in self.framebuffer.iter_mut() {
+            line.fill(Color::White);
+        }
+        self.dirty_lines = Some(0..=DISPLAY_HEIGHT - 1);
+        self.update_registers();
+    }
+
     #[cfg_attr(feature = "profile", inline(never))]
     fn update_registers(&mut self) {
         let line = self.line as u8;
@@ -503,6 +1371,11 @@ impl Ppu {
             0x8000..=0x9FFF => self.vram.read(address),
             0xFE00..=0xFE9F => self.oam.read(address),
             0xFF40..=0xFF4B => self.regs.read(address),
+            0xFF4F => 0xFE | self.vram.bank() as u8,
+            0xFF68 => self.bg_color_palettes.read_index(),
+            0xFF69 => self.bg_color_palettes.read_data(),
+            0xFF6A => self.obj_color_palettes.read_index(),
+            0xFF6B => self.obj_color_palettes.read_data(),
             _ => {
                 panic!("Unmapped address in PPU: {address}")
             }
@@ -513,10 +1386,1097 @@ impl Ppu {
         match address {
             0x8000..=0x9FFF => self.vram.write(address, value),
             0xFE00..=0xFE9F => self.oam.write(address, value),
-            0xFF40..=0xFF4B => self.regs.write(address, value),
+            0xFF40 => {
+                let was_enabled = self.regs.lcdc.read(regs::LCDC::ENABLE) != 0;
+                self.regs.write(address, value);
+                let enabled = self.regs.lcdc.read(regs::LCDC::ENABLE) != 0;
+                if !was_enabled && enabled {
+                    self.lcd_turned_on();
+                } else if was_enabled && !enabled {
+                    self.lcd_turned_off();
+                }
+            }
+            0xFF41..=0xFF4B => self.regs.write(address, value),
+            0xFF4F => self.vram.select_bank(value as usize),
+            0xFF68 => self.bg_color_palettes.write_index(value),
+            0xFF69 => self.bg_color_palettes.write_data(value),
+            0xFF6A => self.obj_color_palettes.write_index(value),
+            0xFF6B => self.obj_color_palettes.write_data(value),
             _ => {
                 panic!("Unmapped address in PPU: {address}")
             }
         }
     }
 }
+
+impl SaveState for Ppu {
+    fn save_state(&self, state: &mut StateWriter) {
+        for bank in 0..vram::NUM_BANKS {
+            for address in 0x8000..=0x9FFF {
+                state.write_u8(self.vram.read_bank(bank, address));
+            }
+        }
+        state.write_u8(self.vram.bank() as u8);
+        self.bg_color_palettes.save_state(state);
+        self.obj_color_palettes.save_state(state);
+        for address in 0xFE00..=0xFE9F {
+            state.write_u8(self.oam.read(address));
+        }
+        self.regs.save_state(state);
+
+        state.write_u8(self.mode as u8);
+        state.write_u32(usize::from(self.cycles) as u32);
+        state.write_u8(self.line as u8);
+        state.write_u16(self.drawing_pixels_len as u16);
+        state.write_u32(usize::from(self.disabled_cycles) as u32);
+        state.write_bool(self.stat_irq);
+        state.write_u8(self.window_line as u8);
+        state.write_bool(self.blank_frame);
+        state.write_u8(self.selected_oam_entries.len() as u8);
+        for entry in self.selected_oam_entries.iter() {
+            state.write_u8(*entry as u8);
+        }
+
+        // The framebuffer is kept so that the frame can be presented right after loading. Frames
+        // with DMG shades only pack 4 pixels in each byte, other frames take 2 bytes per pixel.
+        let rgb = self
+            .framebuffer
+            .iter()
+            .flatten()
+            .any(|color| matches!(color, Color::Rgb(_)));
+        state.write_bool(rgb);
+        if rgb {
+            for color in self.framebuffer.iter().flatten() {
+                state.write_u16(color.bits());
+            }
+            return;
+        }
+        for pixels in self.framebuffer.iter().flat_map(|line| line.chunks(4)) {
+            let packed = pixels.iter().enumerate().fold(0, |packed, (index, color)| {
+                packed | color.shade() << (2 * index)
+            });
+            state.write_u8(packed);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader<'_>) -> Result<(), StateError> {
+        for bank in 0..vram::NUM_BANKS {
+            for address in 0x8000..=0x9FFF {
+                self.vram.write_bank(bank, address, state.read_u8()?);
+            }
+        }
+        let bank = state.read_u8()? as usize;
+        if bank >= vram::NUM_BANKS {
+            return Err(StateError::Invalid);
+        }
+        self.vram.select_bank(bank);
+        self.bg_color_palettes.load_state(state)?;
+        self.obj_color_palettes.load_state(state)?;
+        for address in 0xFE00..=0xFE9F {
+            self.oam.write(address, state.read_u8()?);
+        }
+        self.regs.load_state(state)?;
+
+        self.mode = match state.read_u8()? {
+            0 => Mode::Hblank,
+            1 => Mode::Vblank,
+            2 => Mode::OamScan,
+            3 => Mode::DrawingPixels,
+            _ => return Err(StateError::Invalid),
+        };
+        let cycles = state.read_u32()? as usize;
+        let line = state.read_u8()? as usize;
+        let drawing_pixels_len = state.read_u16()? as usize;
+        let disabled_cycles = state.read_u32()? as usize;
+        if cycles >= LINE_LENGTH
+            || disabled_cycles >= FRAME_LENGTH
+            || line >= NUM_LINES
+            || !(DRAWING_PIXELS_LEN..=MAX_DRAWING_PIXELS_LEN).contains(&drawing_pixels_len)
+        {
+            return Err(StateError::Invalid);
+        }
+        self.cycles = Cycles::new(cycles);
+        self.line = line;
+        self.drawing_pixels_len = drawing_pixels_len;
+        self.disabled_cycles = Cycles::new(disabled_cycles);
+        self.stat_irq = state.read_bool()?;
+        let window_line = state.read_u8()? as usize;
+        if window_line > DISPLAY_HEIGHT {
+            return Err(StateError::Invalid);
+        }
+        self.window_line = window_line;
+        self.blank_frame = state.read_bool()?;
+        #[cfg(debug_assertions)]
+        {
+            self.line_timing = LineTiming::new();
+        }
+        self.selected_oam_entries.clear();
+        for _ in 0..state.read_u8()? {
+            let entry = state.read_u8()? as usize;
+            if entry >= oam::NUM_OBJECTS {
+                return Err(StateError::Invalid);
+            }
+            self.selected_oam_entries
+                .push(entry)
+                .map_err(|_| StateError::Invalid)?;
+        }
+
+        const INDEXES: [PaletteIndex; 4] = [
+            PaletteIndex::Id0,
+            PaletteIndex::Id1,
+            PaletteIndex::Id2,
+            PaletteIndex::Id3,
+        ];
+        if state.read_bool()? {
+            for pixel in self.framebuffer.iter_mut().flatten() {
+                *pixel = Color::from_bits(state.read_u16()?).ok_or(StateError::Invalid)?;
+            }
+            self.dirty_lines = Some(0..=DISPLAY_HEIGHT - 1);
+            return Ok(());
+        }
+        for pixels in self
+            .framebuffer
+            .iter_mut()
+            .flat_map(|line| line.chunks_mut(4))
+        {
+            // The packed pixels have the same layout as a palette register
+            let packed = Palette(state.read_u8()?);
+            for (pixel, index) in pixels.iter_mut().zip(INDEXES) {
+                *pixel = packed.color(index);
+            }
+        }
+        self.dirty_lines = Some(0..=DISPLAY_HEIGHT - 1);
+        Ok(())
+    }
+}
+
+/// Direct access to the PPU state, which allows constructing a scene without running any CPU
+/// code.
+#[cfg(any(test, feature = "debug"))]
+impl Ppu {
+    /// Copies the given data into VRAM, starting at 0x8000.
+    pub fn load_vram(&mut self, data: &[u8]) {
+        for (address, value) in (0x8000..=0x9FFF).zip(data.iter()) {
+            self.vram.write(address, *value);
+        }
+    }
+
+    /// Returns the contents of VRAM.
+    pub fn vram(&self) -> &Vram {
+        &self.vram
+    }
+
+    /// Returns the contents of VRAM for modification, e.g. from a tile editor.
+    pub fn vram_mut(&mut self) -> &mut Vram {
+        &mut self.vram
+    }
+
+    /// Copies the given data into OAM, starting at 0xFE00.
+    pub fn load_oam(&mut self, data: &[u8]) {
+        for (address, value) in (0xFE00..=0xFE9F).zip(data.iter()) {
+            self.oam.write(address, *value);
+        }
+    }
+
+    /// Suppresses drawing of individual layers, even when they are enabled in LCDC. Hidden layers
+    /// are transparent. Useful to diagnose layering bugs.
+    pub fn set_layer_visibility(&mut self, background: bool, window: bool, objects: bool) {
+        self.layers = LayerVisibility {
+            background,
+            window,
+            objects,
+        };
+    }
+
+    /// Sets the LCDC register without triggering any of the side effects of a CPU write.
+    pub fn set_lcdc(&mut self, value: u8) {
+        self.regs.lcdc.set(value);
+    }
+
+    /// Sets the background viewport coordinates (SCX and SCY).
+    pub fn set_scroll(&mut self, scx: u8, scy: u8) {
+        self.regs.scx = scx;
+        self.regs.scy = scy;
+    }
+
+    /// Sets the window coordinates (WX and WY).
+    pub fn set_window(&mut self, wx: u8, wy: u8) {
+        self.regs.wx = wx;
+        self.regs.wy = wy;
+    }
+
+    /// Sets the background palette and both object palettes.
+    pub fn set_palettes(&mut self, bg: u8, obj0: u8, obj1: u8) {
+        self.regs.bg_palette = bg.into();
+        self.regs.obj_palette0 = obj0.into();
+        self.regs.obj_palette1 = obj1.into();
+    }
+
+    /// Renders a whole frame with the current state, regardless of the timing of the PPU, and
+    /// returns it.
+    pub fn render_frame(&mut self) -> &FrameBuf {
+        let line = self.line;
+        let window_line = self.window_line;
+        let blank_frame = self.blank_frame;
+        self.window_line = 0;
+        self.blank_frame = false;
+
+        for current_line in 0..DISPLAY_HEIGHT {
+            self.line = current_line;
+            self.oam_scan();
+            self.draw_line();
+            self.advance_window_line();
+        }
+
+        self.line = line;
+        self.window_line = window_line;
+        self.blank_frame = blank_frame;
+        &self.framebuffer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oam::{OBJ_OFFSET_X, OBJ_OFFSET_Y};
+
+    #[test]
+    fn pixel_accessors() {
+        let mut ppu = Ppu::new();
+        ppu.framebuffer[0][DISPLAY_WIDTH - 1] = Color::Black;
+        ppu.framebuffer[DISPLAY_HEIGHT - 1][3] = Color::DarkGrey;
+
+        assert_eq!(ppu.pixel(DISPLAY_WIDTH - 1, 0), Some(Color::Black));
+        assert_eq!(ppu.pixel(3, DISPLAY_HEIGHT - 1), Some(Color::DarkGrey));
+        assert_eq!(ppu.pixel(DISPLAY_WIDTH, 0), None);
+        assert_eq!(ppu.pixel(0, DISPLAY_HEIGHT), None);
+        assert_eq!(ppu.pixel(usize::MAX, usize::MAX), None);
+
+        assert_eq!(ppu.pixels().count(), DISPLAY_WIDTH * DISPLAY_HEIGHT);
+        for (x, y, color) in ppu.pixels() {
+            assert_eq!(ppu.frame()[y][x], color);
+        }
+    }
+
+    fn run_frame(ppu: &mut Ppu) {
+        let mut dma_engine = DmaEngine::new();
+        while ppu.step(Cycles::new(4), &mut dma_engine, true).1 != PpuResult::FrameComplete {}
+    }
+
+    fn write_tile(ppu: &mut Ppu, base: u16, low: u8, high: u8) {
+        for line in 0..TILE_HEIGHT as u16 {
+            ppu.write(base + line * 2, low);
+            ppu.write(base + line * 2 + 1, high);
+        }
+    }
+
+    #[test]
+    fn window_pixel_hides_object_with_priority_bit() {
+        let mut ppu = Ppu::new();
+
+        // Tile 0 is blank (all zeroes). Tile 1 uses color index 3 and tile 2 uses color index 1.
+        write_tile(&mut ppu, 0x8010, 0xFF, 0xFF);
+        write_tile(&mut ppu, 0x8020, 0xFF, 0x00);
+
+        // The background map at 0x9800 is left blank, the window map at 0x9C00 uses tile 1.
+        for address in 0x9C00..0xA000 {
+            ppu.write(address, 1);
+        }
+
+        // The window starts at x = 80 on the first line.
+        ppu.write(0xFF4A, 0);
+        ppu.write(0xFF4B, 80 + 7);
+
+        // Single 8x8 object straddling the left edge of the window, behind BG and window.
+        ppu.write(0xFE00, OBJ_OFFSET_Y as u8);
+        ppu.write(0xFE01, (76 + OBJ_OFFSET_X) as u8);
+        ppu.write(0xFE02, 2);
+        ppu.write(0xFE03, 0x80);
+
+        ppu.write(0xFF47, 0xE4);
+        ppu.write(0xFF48, 0xE4);
+
+        // LCD, window (map at 0x9C00), tile data at 0x8000, objects and BG enabled.
+        ppu.write(0xFF40, 0xF3);
+
+        // The first frame after turning on the LCD is blank
+        run_frame(&mut ppu);
+        run_frame(&mut ppu);
+
+        let line = &ppu.frame()[4];
+        // The object is visible over the blank background
+        assert!(line[76..80].iter().all(|c| *c == Color::LightGrey));
+        // And hidden behind the non-zero window pixels
+        assert!(line[80..84].iter().all(|c| *c == Color::Black));
+        assert!(line[84..].iter().all(|c| *c == Color::Black));
+        assert!(line[..76].iter().all(|c| *c == Color::White));
+    }
+
+    #[test]
+    fn first_frame_after_lcd_on_is_blank() {
+        let mut ppu = Ppu::new();
+
+        // Color index 0 of the background is black, so the blank background shows up as black.
+        ppu.write(0xFF47, 0xFF);
+        ppu.write(0xFF40, 0x91);
+        assert_eq!(ppu.regs.ly, 0);
+
+        // The first line does not go through OAM scan
+        let mut dma_engine = DmaEngine::new();
+        ppu.step(Cycles::new(4), &mut dma_engine, true);
+        assert_eq!(ppu.mode(), Mode::Hblank);
+
+        run_frame(&mut ppu);
+        assert!(ppu.frame().iter().flatten().all(|c| *c == Color::White));
+
+        run_frame(&mut ppu);
+        assert!(ppu.frame().iter().flatten().all(|c| *c == Color::Black));
+
+        // Turning the LCD off and on again discards the next frame as well
+        ppu.write(0xFF40, 0x11);
+        ppu.write(0xFF40, 0x91);
+        run_frame(&mut ppu);
+        assert!(ppu.frame().iter().flatten().all(|c| *c == Color::White));
+    }
+
+    struct Fnv1a(u64);
+
+    impl core::hash::Hasher for Fnv1a {
+        fn finish(&self) -> u64 {
+            self.0
+        }
+
+        fn write(&mut self, bytes: &[u8]) {
+            for byte in bytes {
+                self.0 = (self.0 ^ *byte as u64).wrapping_mul(0x100_0000_01b3);
+            }
+        }
+    }
+
+    fn hash_of(frame: &FrameBuf) -> u64 {
+        use core::hash::{Hash, Hasher};
+        let mut hasher = Fnv1a(0xcbf2_9ce4_8422_2325);
+        frame.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn frame_equality_and_hash() {
+        let frame_a = FrameBuf([[Color::White; DISPLAY_WIDTH]; DISPLAY_HEIGHT]);
+        let mut frame_b = frame_a.clone();
+        assert_eq!(frame_a, frame_b);
+        assert_eq!(hash_of(&frame_a), hash_of(&frame_b));
+
+        frame_b[DISPLAY_HEIGHT - 1][DISPLAY_WIDTH - 1] = Color::DarkGrey;
+        assert_ne!(frame_a, frame_b);
+        assert_ne!(hash_of(&frame_a), hash_of(&frame_b));
+    }
+
+    #[test]
+    fn mode_remaining_reaches_boundaries() {
+        let mut ppu = Ppu::new();
+        ppu.set_lcdc(0x80);
+        let mut dma_engine = DmaEngine::new();
+        assert_eq!(ppu.mode_remaining(), Cycles::new(OAM_SCAN_LEN));
+
+        ppu.step(Cycles::new(OAM_SCAN_LEN - 4), &mut dma_engine, false);
+        assert_eq!(ppu.mode_remaining(), Cycles::new(4));
+
+        ppu.step(Cycles::new(4), &mut dma_engine, false);
+        assert_eq!(ppu.mode(), Mode::DrawingPixels);
+        assert_eq!(ppu.mode_remaining(), Cycles::new(DRAWING_PIXELS_LEN));
+
+        ppu.step(Cycles::new(DRAWING_PIXELS_LEN), &mut dma_engine, false);
+        assert_eq!(ppu.mode(), Mode::Hblank);
+        assert_eq!(ppu.mode_remaining(), Cycles::new(HBLANK_LEN));
+    }
+
+    #[test]
+    fn vram_and_oam_access_flip_on_mode_boundaries() {
+        let mut ppu = Ppu::new();
+        ppu.set_lcdc(0x80);
+        // A fine scroll makes mode 3 longer than its minimum length
+        ppu.write(0xFF43, 5);
+        let mut dma_engine = DmaEngine::new();
+        ppu.step(Cycles::new(OAM_SCAN_LEN), &mut dma_engine, false);
+        ppu.step(
+            Cycles::new(LINE_LENGTH - OAM_SCAN_LEN),
+            &mut dma_engine,
+            false,
+        );
+        assert_eq!((ppu.line(), ppu.mode()), (1, Mode::OamScan));
+        assert!(ppu.vram_accessible());
+        assert!(!ppu.oam_accessible());
+
+        ppu.step(Cycles::new(OAM_SCAN_LEN), &mut dma_engine, false);
+        assert_eq!(ppu.mode(), Mode::DrawingPixels);
+        assert!(!ppu.vram_accessible());
+        assert!(!ppu.oam_accessible());
+        let drawing_len: usize = ppu.mode_remaining().into();
+        assert_eq!(drawing_len, DRAWING_PIXELS_LEN + 5);
+
+        // Still blocked one cycle before the end of mode 3
+        ppu.step(Cycles::new(drawing_len - 1), &mut dma_engine, false);
+        assert_eq!(ppu.mode(), Mode::DrawingPixels);
+        assert!(!ppu.vram_accessible());
+        assert!(!ppu.oam_accessible());
+
+        ppu.step(Cycles::new(1), &mut dma_engine, false);
+        assert_eq!(ppu.mode(), Mode::Hblank);
+        assert_eq!(ppu.read(0xFF41) & 0x3, 0);
+        assert!(ppu.vram_accessible());
+        assert!(ppu.oam_accessible());
+    }
+
+    /// Runs the OAM scan of the second line and returns the length of mode 3, checking that
+    /// HBlank takes the rest of the line.
+    fn second_line_drawing_len(ppu: &mut Ppu) -> usize {
+        let mut dma_engine = DmaEngine::new();
+        ppu.step(Cycles::new(OAM_SCAN_LEN), &mut dma_engine, false);
+        ppu.step(
+            Cycles::new(LINE_LENGTH - OAM_SCAN_LEN),
+            &mut dma_engine,
+            false,
+        );
+        assert_eq!((ppu.line(), ppu.mode()), (1, Mode::OamScan));
+        ppu.step(Cycles::new(OAM_SCAN_LEN), &mut dma_engine, false);
+        assert_eq!(ppu.mode(), Mode::DrawingPixels);
+        let drawing_len = ppu.mode_remaining();
+
+        ppu.step(drawing_len, &mut dma_engine, false);
+        assert_eq!(ppu.mode(), Mode::Hblank);
+        let drawing_len: usize = drawing_len.into();
+        assert_eq!(
+            ppu.mode_remaining(),
+            Cycles::new(LINE_LENGTH - OAM_SCAN_LEN - drawing_len)
+        );
+        drawing_len
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn modes_add_up_to_line_length_with_many_objects() {
+        // Ten objects on the second line, each one misaligned with the background tiles, and
+        // more objects that are not selected.
+        let mut oam = [0; 0xA0];
+        for (index, object) in oam.chunks_mut(4).enumerate() {
+            object.copy_from_slice(&[16, 8 + 9 * (index % 16) as u8, 0, 0]);
+        }
+
+        // LCD, objects, BG and window on, with the background scrolled by a fraction of a tile
+        let mut ppu = Ppu::new();
+        ppu.load_oam(&oam);
+        ppu.set_lcdc(0xA3);
+        ppu.set_scroll(7, 0);
+        ppu.set_window(7, 0);
+
+        let mut dma_engine = DmaEngine::new();
+        ppu.step(Cycles::new(OAM_SCAN_LEN), &mut dma_engine, false);
+        ppu.step(
+            Cycles::new(LINE_LENGTH - OAM_SCAN_LEN),
+            &mut dma_engine,
+            false,
+        );
+        assert_eq!((ppu.line(), ppu.mode()), (1, Mode::OamScan));
+
+        // Step one cycle at a time, so that each mode takes exactly as long as expected. The
+        // objects are in 10 different tiles, and their offsets within the tiles add 20 cycles.
+        let drawing_pixels = DRAWING_PIXELS_LEN + 7 + WINDOW_PENALTY + 10 * OBJECT_PENALTY + 20;
+        while ppu.line() == 1 {
+            ppu.step(Cycles::new(1), &mut dma_engine, false);
+        }
+        let mut expected = [0; 4];
+        expected[Mode::OamScan as usize] = OAM_SCAN_LEN;
+        expected[Mode::DrawingPixels as usize] = drawing_pixels;
+        expected[Mode::Hblank as usize] = LINE_LENGTH - OAM_SCAN_LEN - drawing_pixels;
+        assert_eq!(ppu.line_timing.last_line, Some(expected));
+
+        // With larger steps, modes end on the first step after their expected length
+        while ppu.line() == 2 {
+            ppu.step(Cycles::new(4), &mut dma_engine, false);
+        }
+        let mode_cycles = ppu.line_timing.last_line.unwrap();
+        assert_eq!(mode_cycles.iter().sum::<usize>(), LINE_LENGTH);
+        assert_eq!(
+            mode_cycles[Mode::DrawingPixels as usize],
+            drawing_pixels.next_multiple_of(4) - OAM_SCAN_LEN % 4
+        );
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Modes of line 1 take")]
+    fn wrong_mode_length_is_detected() {
+        let mut ppu = Ppu::new();
+        ppu.set_lcdc(0x80);
+        let mut dma_engine = DmaEngine::new();
+        ppu.step(Cycles::new(OAM_SCAN_LEN), &mut dma_engine, false);
+        ppu.step(
+            Cycles::new(LINE_LENGTH - OAM_SCAN_LEN),
+            &mut dma_engine,
+            false,
+        );
+        for _ in 0..OAM_SCAN_LEN {
+            ppu.step(Cycles::new(1), &mut dma_engine, false);
+        }
+        assert_eq!(ppu.mode(), Mode::DrawingPixels);
+
+        // Leave mode 3 for a cycle, as a bug in the mode transitions would
+        ppu.mode = Mode::Hblank;
+        while ppu.line() == 1 {
+            ppu.step(Cycles::new(1), &mut dma_engine, false);
+        }
+    }
+
+    #[test]
+    fn mode_3_length_depends_on_scx() {
+        for scx in 0..16 {
+            let mut ppu = Ppu::new();
+            ppu.set_lcdc(0x80);
+            ppu.set_scroll(scx, 0);
+            assert_eq!(
+                second_line_drawing_len(&mut ppu),
+                DRAWING_PIXELS_LEN + (scx as usize % 8),
+                "SCX = {scx}"
+            );
+        }
+    }
+
+    #[test]
+    fn mode_3_length_with_objects_and_window() {
+        // Two objects within the same background tile, one of them aligned with it, and another
+        // one in the next tile, all of them on the second line.
+        let mut oam = [0; 0xA0];
+        oam[..12].copy_from_slice(&[16, 8, 0, 0, 16, 13, 0, 0, 16, 20, 0, 0]);
+
+        // LCD, objects and BG on
+        let mut ppu = Ppu::new();
+        ppu.load_oam(&oam);
+        ppu.set_lcdc(0x83);
+        assert_eq!(
+            second_line_drawing_len(&mut ppu),
+            DRAWING_PIXELS_LEN + 3 * OBJECT_PENALTY + 5 + 1
+        );
+
+        // With the window shown on the line
+        let mut ppu = Ppu::new();
+        ppu.load_oam(&oam);
+        ppu.set_lcdc(0xA3);
+        ppu.set_window(7, 0);
+        assert_eq!(
+            second_line_drawing_len(&mut ppu),
+            DRAWING_PIXELS_LEN + 3 * OBJECT_PENALTY + 5 + 1 + WINDOW_PENALTY
+        );
+
+        // Objects disabled, and the window starting below the line
+        let mut ppu = Ppu::new();
+        ppu.load_oam(&oam);
+        ppu.set_lcdc(0xA1);
+        ppu.set_window(7, 2);
+        assert_eq!(second_line_drawing_len(&mut ppu), DRAWING_PIXELS_LEN);
+    }
+
+    #[test]
+    fn mode_3_length_is_bounded() {
+        // Ten objects aligned with the tiles of the scrolled background
+        let mut oam = [0; 0xA0];
+        for (index, object) in oam.chunks_mut(4).take(10).enumerate() {
+            object[0] = 16;
+            object[1] = 8 * index as u8 + 1;
+        }
+
+        let mut ppu = Ppu::new();
+        ppu.load_oam(&oam);
+        ppu.set_lcdc(0xA3);
+        ppu.set_scroll(7, 0);
+        ppu.set_window(7, 0);
+        assert_eq!(second_line_drawing_len(&mut ppu), MAX_DRAWING_PIXELS_LEN);
+    }
+
+    #[test]
+    fn lcd_off_parks_the_ppu() {
+        let mut ppu = Ppu::new();
+        let mut dma_engine = DmaEngine::new();
+        ppu.write(0xFF40, 0x91);
+
+        // Turn the LCD off in the middle of a frame
+        for _ in 0..50 {
+            ppu.step(Cycles::new(LINE_LENGTH), &mut dma_engine, true);
+        }
+        ppu.step(Cycles::new(100), &mut dma_engine, true);
+        assert_eq!(ppu.read(0xFF44), 50);
+        ppu.write(0xFF40, 0x11);
+        assert_eq!(ppu.read(0xFF44), 0);
+        assert_eq!(ppu.read(0xFF41) & 0x03, Mode::Hblank as u8);
+        assert!(ppu.pixels().all(|(_, _, color)| color == Color::White));
+
+        // Frames keep completing, but LY doesn't move and there are no VBlank interrupts
+        let mut elapsed = 0;
+        loop {
+            let (interrupts, result) = ppu.step(Cycles::new(4), &mut dma_engine, true);
+            elapsed += 4;
+            assert!(!interrupts.has_any());
+            assert_eq!(ppu.read(0xFF44), 0);
+            if result == PpuResult::FrameComplete {
+                break;
+            }
+        }
+        assert_eq!(elapsed, FRAME_LENGTH);
+
+        // Turning it on again restarts the frame from the top
+        ppu.write(0xFF40, 0x91);
+        let mut elapsed = 0;
+        loop {
+            let (interrupts, result) = ppu.step(Cycles::new(4), &mut dma_engine, true);
+            elapsed += 4;
+            if interrupts.has_any() {
+                assert_eq!(interrupts, Interrupt::Vblank.into());
+                assert_eq!(result, PpuResult::FrameComplete);
+                break;
+            }
+        }
+        assert_eq!(elapsed, 144 * LINE_LENGTH);
+        assert_eq!(ppu.read(0xFF44), 144);
+    }
+
+    #[test]
+    fn frame_progress_covers_frame() {
+        let mut ppu = Ppu::new();
+        ppu.set_lcdc(0x80);
+        let mut dma_engine = DmaEngine::new();
+        assert_eq!(ppu.frame_progress(), 0.0);
+
+        for _ in 0..NUM_LINES - 1 {
+            ppu.step(Cycles::new(LINE_LENGTH), &mut dma_engine, false);
+        }
+        ppu.step(Cycles::new(LINE_LENGTH - 4), &mut dma_engine, false);
+        assert_eq!(ppu.line(), NUM_LINES - 1);
+        assert!(ppu.frame_progress() > 0.99);
+        assert!(ppu.frame_progress() < 1.0);
+
+        ppu.step(Cycles::new(4), &mut dma_engine, false);
+        assert_eq!(ppu.frame_progress(), 0.0);
+    }
+
+    #[test]
+    fn dirty_lines_track_changes() {
+        let mut ppu = Ppu::new();
+        ppu.load_oam(&[0; 0xA0]);
+        ppu.set_palettes(0xE4, 0, 0);
+        // LCD and BG on, tile data at 0x8000, map at 0x9800.
+        ppu.set_lcdc(0x91);
+
+        // Only the top-left tile of the background map uses tile 1
+        let mut vram = [0u8; 0x2000];
+        vram[0x1800] = 1;
+        ppu.load_vram(&vram);
+
+        // The initial black screen turns white
+        ppu.render_frame();
+        assert_eq!(ppu.take_dirty_lines(), Some(0..=DISPLAY_HEIGHT - 1));
+        ppu.render_frame();
+        assert_eq!(ppu.take_dirty_lines(), None);
+
+        // Fill the 4th line of tile 1 with color index 3
+        ppu.load_vram(&[&[0u8; 0x16][..], &[0xFF, 0xFF]].concat());
+        ppu.render_frame();
+        assert_eq!(ppu.take_dirty_lines(), Some(3..=3));
+        assert_eq!(ppu.take_dirty_lines(), None);
+    }
+
+    #[test]
+    fn render_injected_checkerboard() {
+        let mut ppu = Ppu::new();
+
+        // Tile 0 uses color index 0 and tile 1 uses color index 3
+        let mut vram = [0u8; 0x2000];
+        vram[0x10..0x20].fill(0xFF);
+        // The background map alternates both tiles
+        for y in 0..32 {
+            for x in 0..32 {
+                vram[0x1800 + y * 32 + x] = ((x + y) % 2) as u8;
+            }
+        }
+        ppu.load_vram(&vram);
+        // No objects on screen
+        ppu.load_oam(&[0; 0xA0]);
+
+        ppu.set_palettes(0xE4, 0, 0);
+        ppu.set_scroll(0, 0);
+        // LCD and BG on, tile data at 0x8000, map at 0x9800.
+        ppu.set_lcdc(0x91);
+
+        let frame = ppu.render_frame();
+        for (y, line) in frame.iter().enumerate() {
+            for (x, pixel) in line.iter().enumerate() {
+                let expected = if (x / TILE_WIDTH + y / TILE_HEIGHT) & 1 == 0 {
+                    Color::White
+                } else {
+                    Color::Black
+                };
+                assert_eq!(*pixel, expected, "pixel at ({x}, {y})");
+            }
+        }
+    }
+
+    #[test]
+    fn tiles_are_only_decoded_after_writes() {
+        let mut ppu = Ppu::new();
+        ppu.load_oam(&[0; 0xA0]);
+        ppu.set_palettes(0xE4, 0, 0);
+        // LCD and BG on, tile data at 0x8000, map at 0x9800.
+        ppu.set_lcdc(0x91);
+
+        ppu.render_frame();
+        let decoded = ppu.tile_cache.decoded_tiles();
+        assert_eq!(decoded, vram::NUM_TILES);
+
+        // Nothing changed, no tile needs to be decoded again
+        ppu.render_frame();
+        assert_eq!(ppu.tile_cache.decoded_tiles(), decoded);
+
+        // Tile 0 is shown across the whole screen, and is decoded again exactly once
+        ppu.load_vram(&[0xFF; 0x10]);
+        assert_eq!(ppu.vram.dirty_tiles().count(), 1);
+        let all_black = ppu
+            .render_frame()
+            .iter()
+            .flatten()
+            .all(|pixel| *pixel == Color::Black);
+        assert!(all_black);
+        assert_eq!(ppu.tile_cache.decoded_tiles(), decoded + 1);
+    }
+
+    #[test]
+    fn hidden_object_layer() {
+        let mut ppu = Ppu::new();
+
+        // Tile 1 uses color index 3, and the background only shows tile 0
+        let mut vram = [0u8; 0x2000];
+        vram[0x10..0x20].fill(0xFF);
+        ppu.load_vram(&vram);
+
+        // A single object with tile 1 at the top-left corner of the screen
+        let mut oam = [0u8; 0xA0];
+        oam[..4].copy_from_slice(&[OBJ_OFFSET_Y as u8, OBJ_OFFSET_X as u8, 1, 0]);
+        ppu.load_oam(&oam);
+
+        ppu.set_palettes(0xE4, 0xE4, 0xE4);
+        // LCD, BG and objects on, tile data at 0x8000, map at 0x9800.
+        ppu.set_lcdc(0x93);
+
+        let black_pixels = |ppu: &mut Ppu| {
+            ppu.render_frame()
+                .iter()
+                .flatten()
+                .filter(|pixel| **pixel == Color::Black)
+                .count()
+        };
+        assert_eq!(black_pixels(&mut ppu), TILE_WIDTH * TILE_HEIGHT);
+
+        ppu.set_layer_visibility(true, true, false);
+        assert_eq!(black_pixels(&mut ppu), 0);
+        assert!(ppu.frame().iter().flatten().all(|p| *p == Color::White));
+
+        ppu.set_layer_visibility(true, true, true);
+        assert_eq!(black_pixels(&mut ppu), TILE_WIDTH * TILE_HEIGHT);
+    }
+
+    #[test]
+    fn bg_and_window_enable_bit_per_model() {
+        let mut ppu = Ppu::new();
+
+        // Tile 0 uses color index 3 and fills the background. Tile 1 uses color index 1.
+        let mut vram = [0u8; 0x2000];
+        vram[..0x10].fill(0xFF);
+        vram[0x10..0x20].copy_from_slice(&[0xFF, 0x00].repeat(8));
+        ppu.load_vram(&vram);
+
+        // An object behind the background at the top-left corner of the screen
+        let mut oam = [0u8; 0xA0];
+        oam[..4].copy_from_slice(&[OBJ_OFFSET_Y as u8, OBJ_OFFSET_X as u8, 1, 0x80]);
+        ppu.load_oam(&oam);
+
+        ppu.set_palettes(0xE4, 0xE4, 0xE4);
+        // LCD and objects on, BG and window off, tile data at 0x8000, map at 0x9800.
+        ppu.set_lcdc(0x92);
+
+        // DMG blanks the background, and the object is drawn
+        let frame = ppu.render_frame();
+        assert_eq!(frame[0][0], Color::LightGrey);
+        assert_eq!(frame[0][TILE_WIDTH], Color::White);
+        assert_eq!(frame[DISPLAY_HEIGHT - 1][DISPLAY_WIDTH - 1], Color::White);
+
+        // CGB still draws the background, and the object is drawn on top of it regardless of
+        // its priority
+        ppu.set_cgb_mode(true);
+        let frame = ppu.render_frame();
+        assert_eq!(frame[0][0], Color::LightGrey);
+        assert_eq!(frame[0][TILE_WIDTH], Color::Black);
+        assert_eq!(frame[DISPLAY_HEIGHT - 1][DISPLAY_WIDTH - 1], Color::Black);
+
+        // With bit 0 set, the background has priority over the object again
+        ppu.set_lcdc(0x93);
+        let frame = ppu.render_frame();
+        assert_eq!(frame[0][0], Color::Black);
+    }
+
+    #[test]
+    fn color_mode_registers() {
+        let mut ppu = Ppu::new();
+        ppu.set_color_mode(true);
+        assert!(ppu.color_mode());
+
+        assert_eq!(ppu.read(0xFF4F), 0xFE);
+        ppu.write(0xFF4F, 0x01);
+        assert_eq!(ppu.read(0xFF4F), 0xFF);
+        ppu.write(0x8000, 0x42);
+        ppu.write(0xFF4F, 0x00);
+        assert_eq!(ppu.read(0x8000), 0x00);
+
+        ppu.write(0xFF68, 0x82);
+        ppu.write(0xFF69, 0x1F);
+        ppu.write(0xFF69, 0x00);
+        assert_eq!(ppu.read(0xFF68), 0xC4);
+        assert_eq!(
+            ppu.bg_color_palettes.color(0, PaletteIndex::Id1),
+            Rgb555(0x001F)
+        );
+
+        ppu.write(0xFF6A, 0x3F);
+        ppu.write(0xFF6B, 0x12);
+        assert_eq!(ppu.read(0xFF6A), 0x7F);
+        assert_eq!(ppu.read(0xFF6B), 0x12);
+    }
+
+    #[test]
+    fn render_with_color_palettes_and_attributes() {
+        const RED: Rgb555 = Rgb555(0x001F);
+        const GREEN: Rgb555 = Rgb555(0x03E0);
+        const WHITE: Rgb555 = Rgb555(0x7FFF);
+
+        let mut ppu = Ppu::new();
+        ppu.set_color_mode(true);
+
+        // Tile 1 of the second bank has color index 3 in its left column. The same tile of the
+        // first bank is blank. Tile 2 of the first bank uses color index 1.
+        ppu.write(0xFF4F, 1);
+        write_tile(&mut ppu, 0x8010, 0x80, 0x80);
+        // The first two tiles of the map use palette 2 and the second bank. The first one is
+        // flipped horizontally and the second one is drawn over objects.
+        ppu.write(0x9800, 0x2A);
+        ppu.write(0x9801, 0x8A);
+        ppu.write(0xFF4F, 0);
+        write_tile(&mut ppu, 0x8020, 0xFF, 0x00);
+        ppu.write(0x9800, 1);
+        ppu.write(0x9801, 1);
+
+        // Color 3 of background palette 2 is red, and color 1 of object palette 1 is green
+        ppu.write(0xFF68, 0x80 | 22);
+        ppu.write(0xFF69, 0x1F);
+        ppu.write(0xFF69, 0x00);
+        ppu.write(0xFF6A, 0x80 | 10);
+        ppu.write(0xFF6B, 0xE0);
+        ppu.write(0xFF6B, 0x03);
+
+        // An object with tile 2 and palette 1 over the second tile of the map
+        let mut oam = [0u8; 0xA0];
+        oam[..4].copy_from_slice(&[OBJ_OFFSET_Y as u8, OBJ_OFFSET_X as u8 + 8, 2, 0x01]);
+        ppu.load_oam(&oam);
+
+        // LCD, BG and objects on, tile data at 0x8000, map at 0x9800.
+        ppu.set_lcdc(0x93);
+        let frame = ppu.render_frame();
+        assert_eq!(frame[0][0], Color::Rgb(WHITE));
+        assert_eq!(frame[0][7], Color::Rgb(RED));
+        assert_eq!(frame[0][8], Color::Rgb(RED));
+        assert_eq!(frame[0][9], Color::Rgb(GREEN));
+        assert_eq!(frame[0][16], Color::Rgb(WHITE));
+
+        // With LCDC bit 0 clear, objects are drawn over the background regardless of priorities
+        ppu.set_lcdc(0x92);
+        let frame = ppu.render_frame();
+        assert_eq!(frame[0][7], Color::Rgb(RED));
+        assert_eq!(frame[0][8], Color::Rgb(GREEN));
+
+        // DMG rendering ignores the attributes and the palettes
+        ppu.set_color_mode(false);
+        ppu.set_palettes(0xE4, 0xE4, 0xE4);
+        ppu.set_lcdc(0x93);
+        let frame = ppu.render_frame();
+        assert_eq!(frame[0][7], Color::White);
+        assert_eq!(frame[0][8], Color::LightGrey);
+    }
+
+    #[test]
+    fn window_line_only_advances_when_shown() {
+        let mut ppu = Ppu::new();
+
+        // The first row of the window map uses tile 1 (color index 3) and the second row uses
+        // tile 2 (color index 1). The background is blank.
+        write_tile(&mut ppu, 0x8010, 0xFF, 0xFF);
+        write_tile(&mut ppu, 0x8020, 0xFF, 0x00);
+        for column in 0..32 {
+            ppu.write(0x9C00 + column, 1);
+            ppu.write(0x9C20 + column, 2);
+        }
+        ppu.write(0xFF47, 0xE4);
+        ppu.write(0xFF4A, 0);
+
+        // LCD, window (map at 0x9C00), tile data at 0x8000 and BG enabled.
+        ppu.write(0xFF40, 0xF1);
+        run_frame(&mut ppu);
+
+        // The window is shown on even lines, and moved past the right edge of the screen on odd
+        // lines.
+        let mut dma_engine = DmaEngine::new();
+        loop {
+            let wx = if ppu.line() & 1 == 0 { 7 } else { 167 };
+            ppu.write(0xFF4B, wx);
+            if ppu.step(Cycles::new(4), &mut dma_engine, true).1 == PpuResult::FrameComplete {
+                break;
+            }
+        }
+
+        let frame = ppu.frame();
+        for line in (0..16).step_by(2) {
+            assert!(
+                frame[line].iter().all(|c| *c == Color::Black),
+                "line {line}"
+            );
+            assert!(frame[line + 1].iter().all(|c| *c == Color::White));
+        }
+        // The second row of the map is only reached after 8 lines with the window shown
+        assert!(frame[16].iter().all(|c| *c == Color::LightGrey));
+        assert!(frame[30].iter().all(|c| *c == Color::LightGrey));
+        assert!(frame[32].iter().all(|c| *c == Color::White));
+    }
+
+    #[test]
+    fn object_priority_by_x_and_oam_index() {
+        let mut ppu = Ppu::new();
+
+        // Tiles 1, 2 and 3 use color indexes 1, 2 and 3. The background is blank.
+        let mut vram = [0u8; 0x2000];
+        vram[0x10..0x20].copy_from_slice(&[0xFF, 0x00].repeat(8));
+        vram[0x20..0x30].copy_from_slice(&[0x00, 0xFF].repeat(8));
+        vram[0x30..0x40].fill(0xFF);
+        ppu.load_vram(&vram);
+
+        let mut oam = [0u8; 0xA0];
+        let y = OBJ_OFFSET_Y as u8;
+        let x = OBJ_OFFSET_X as u8;
+        // Three objects at the same position on the first line, with different tiles
+        oam[..12].copy_from_slice(&[y, x, 2, 0, y, x, 1, 0, y, x, 3, 0]);
+        // On line 16, an object further right comes first in OAM
+        oam[12..20].copy_from_slice(&[y + 16, x + 4, 1, 0, y + 16, x, 3, 0]);
+        ppu.load_oam(&oam);
+
+        ppu.set_palettes(0xE4, 0xE4, 0xE4);
+        // LCD, BG and objects on, tile data at 0x8000, map at 0x9800.
+        ppu.set_lcdc(0x93);
+        let frame = ppu.render_frame();
+
+        // The first object in OAM wins among objects at the same X coordinate
+        assert!(frame[0][..8].iter().all(|c| *c == Color::DarkGrey));
+        assert_eq!(frame[0][8], Color::White);
+
+        // The leftmost object wins regardless of the OAM order
+        assert!(frame[16][..8].iter().all(|c| *c == Color::Black));
+        assert!(frame[16][8..12].iter().all(|c| *c == Color::LightGrey));
+    }
+
+    #[test]
+    fn objects_shrunk_after_the_oam_scan_are_skipped() {
+        for attributes in [0x00, 0x40] {
+            // A tall black object over lines 0 to 7, optionally flipped vertically
+            let mut vram = [0u8; 0x2000];
+            vram[..0x20].fill(0xFF);
+            let mut oam = [0u8; 0xA0];
+            oam[..4].copy_from_slice(&[OBJ_OFFSET_Y as u8 - 8, OBJ_OFFSET_X as u8, 0, attributes]);
+
+            let mut ppu = Ppu::new();
+            ppu.load_vram(&vram);
+            ppu.load_oam(&oam);
+            ppu.set_palettes(0xE4, 0xE4, 0xE4);
+            // LCD and 8x16 objects on, BG off
+            ppu.set_lcdc(0x86);
+
+            let mut dma_engine = DmaEngine::new();
+            while ppu.step(Cycles::new(4), &mut dma_engine, true).1 != PpuResult::FrameComplete {}
+            while (ppu.line(), ppu.mode()) != (1, Mode::OamScan) {
+                ppu.step(Cycles::new(4), &mut dma_engine, true);
+            }
+
+            // The object was selected for line 1, which it no longer covers with 8x8 objects
+            ppu.set_lcdc(0x82);
+            while ppu.line() != 2 {
+                ppu.step(Cycles::new(4), &mut dma_engine, true);
+            }
+            assert!(ppu.frame()[0][..8].iter().all(|c| *c == Color::Black));
+            assert!(ppu.frame()[1].iter().all(|c| *c == Color::White));
+        }
+    }
+
+    #[test]
+    fn selected_sprites_for_arbitrary_lines() {
+        let mut ppu = Ppu::new();
+
+        // Twelve 8x8 objects on lines 0-7, and one more on lines 8-15
+        let mut oam = [0u8; 0xA0];
+        for object in oam.chunks_mut(4).take(12) {
+            object.copy_from_slice(&[OBJ_OFFSET_Y as u8, 0x20, 0, 0]);
+        }
+        oam[12 * 4..13 * 4].copy_from_slice(&[OBJ_OFFSET_Y as u8 + 8, 0x20, 1, 0x20]);
+        ppu.load_oam(&oam);
+        ppu.set_lcdc(0x93);
+
+        assert_eq!(ppu.oam_entries().len(), oam::NUM_OBJECTS);
+        assert_eq!(ppu.oam_entries()[12].read(3), 0x20);
+        assert!(ppu.decoded_object(12).x_flip);
+
+        let selected = ppu.selected_sprites_for_line(7);
+        assert_eq!(
+            selected[..],
+            (0..MAX_SELECTED_OBJECTS).collect::<alloc::vec::Vec<_>>()[..]
+        );
+        assert_eq!(ppu.selected_sprites_for_line(8)[..], [12]);
+        assert!(ppu.selected_sprites_for_line(16).is_empty());
+
+        // With 8x16 objects, the first ten objects also cover line 8
+        ppu.set_lcdc(0x97);
+        assert_eq!(ppu.selected_sprites_for_line(8).len(), MAX_SELECTED_OBJECTS);
+        assert_eq!(ppu.line(), 0);
+    }
+
+    #[test]
+    fn frame_pixel_formats() {
+        let mut ppu = Ppu::new();
+        ppu.set_lcdc(0x91);
+        ppu.set_palettes(0xE4, 0xE4, 0xE4);
+        ppu.render_frame();
+
+        // A blank frame is solid white
+        let mut rgba = alloc::vec![0; DISPLAY_WIDTH * DISPLAY_HEIGHT * 4];
+        ppu.frame_rgba8888(&mut rgba);
+        assert!(rgba.iter().all(|byte| *byte == 0xFF));
+
+        let mut grayscale = alloc::vec![0; DISPLAY_WIDTH * DISPLAY_HEIGHT];
+        ppu.frame_grayscale8(&mut grayscale);
+        assert!(grayscale.iter().all(|byte| *byte == 0xFF));
+
+        let shades = [
+            Color::White,
+            Color::LightGrey,
+            Color::DarkGrey,
+            Color::Black,
+        ];
+        let gray: alloc::vec::Vec<_> = shades.iter().map(Color::to_grayscale8).collect();
+        assert_eq!(gray, [255, 170, 85, 0]);
+        assert_eq!(
+            Color::Rgb(Rgb555(0x001F)).to_rgba8888(),
+            [0xFF, 0x00, 0x00, 0xFF]
+        );
+        assert_eq!(Color::Rgb(Rgb555(0x001F)).to_grayscale8(), 76);
+    }
+}
@@ -42,6 +42,15 @@ register_bitfields! [
             Palette0 = 0,
             Palette1 = 1,
         ],
+
+        /// Determines which VRAM bank holds the tile of the object. Game Boy Color only.
+        BANK OFFSET(3) NUMBITS(1) [
+            Bank0 = 0,
+            Bank1 = 1,
+        ],
+
+        /// Determines which color palette must be used to draw the object. Game Boy Color only.
+        COLOR_PALETTE OFFSET(0) NUMBITS(3) [],
     ],
 ];
 
@@ -91,6 +100,58 @@ impl Object {
     }
 }
 
+/// Offset between the Y coordinate of an object in OAM and the screen
+pub(crate) const OBJ_OFFSET_Y: i16 = 16;
+/// Offset between the X coordinate of an object in OAM and the screen
+pub(crate) const OBJ_OFFSET_X: i16 = 8;
+
+/// An OAM object with its attributes decoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodedObject {
+    /// Index of the object in OAM
+    pub index: usize,
+    /// Screen X coordinate of the left column of the object. Negative for objects that are
+    /// partially or totally offscreen.
+    pub x: i16,
+    /// Screen Y coordinate of the top line of the object. Negative for objects that are
+    /// partially or totally offscreen.
+    pub y: i16,
+    /// Tile index, which may be the top tile of a 8x16 object
+    pub tile_idx: u8,
+    /// Background and window color indexes 1-3 are drawn over the object
+    pub below_bg_and_window: bool,
+    /// The object is flipped vertically
+    pub y_flip: bool,
+    /// The object is flipped horizontally
+    pub x_flip: bool,
+    /// The palette used to draw the object
+    pub palette: OBJ_ATTRS::PALETTE_SELECTOR::Value,
+    /// The VRAM bank of the tile, only used by the Game Boy Color
+    pub bank: usize,
+    /// The color palette used to draw the object, only used by the Game Boy Color
+    pub color_palette: usize,
+}
+
+impl Object {
+    fn decode(&self, index: usize) -> DecodedObject {
+        DecodedObject {
+            index,
+            x: self.x as i16 - OBJ_OFFSET_X,
+            y: self.y as i16 - OBJ_OFFSET_Y,
+            tile_idx: self.tile_idx.into(),
+            below_bg_and_window: self.attrs.read(OBJ_ATTRS::PRIO) != 0,
+            y_flip: self.attrs.read(OBJ_ATTRS::Y_FLIP) != 0,
+            x_flip: self.attrs.read(OBJ_ATTRS::X_FLIP) != 0,
+            palette: self
+                .attrs
+                .read_as_enum(OBJ_ATTRS::PALETTE_SELECTOR)
+                .expect("The palette selector is a single bit"),
+            bank: self.attrs.read(OBJ_ATTRS::BANK) as usize,
+            color_palette: self.attrs.read(OBJ_ATTRS::COLOR_PALETTE) as usize,
+        }
+    }
+}
+
 /// The number of objects that fit in the OAM
 pub const NUM_OBJECTS: usize = 40;
 
@@ -131,6 +192,19 @@ impl Oam {
         &*self.objects
     }
 
+    /// Decodes the object at the given index of the OAM.
+    pub fn decoded(&self, index: usize) -> DecodedObject {
+        self.objects[index].decode(index)
+    }
+
+    /// Returns an iterator over all objects in OAM with their attributes decoded.
+    pub fn iter_decoded(&self) -> impl Iterator<Item = DecodedObject> + '_ {
+        self.objects
+            .iter()
+            .enumerate()
+            .map(|(index, object)| object.decode(index))
+    }
+
     #[cfg_attr(feature = "profile", inline(never))]
     pub fn read(&self, address: sm83::memory::Address) -> u8 {
         let (object_idx, object_member_offset) = Self::cpu_addr_to_object_addr(address);
@@ -143,3 +217,51 @@ impl Oam {
         self.objects[object_idx].write(object_member_offset, value);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decoded_objects_match_raw_attributes() {
+        let mut oam = Oam::new();
+        let sample: [[u8; OBJECT_SIZE]; 4] = [
+            // Offscreen at the top left, no attributes
+            [0x00, 0x00, 0x01, 0x00],
+            // Fully visible, flipped in X, palette 1
+            [0x20, 0x30, 0x42, 0x30],
+            // Below the background, flipped in Y
+            [0x98, 0xA7, 0xFF, 0xC0],
+            // Tile in the second VRAM bank, color palette 5
+            [0x40, 0x40, 0x10, 0x0D],
+        ];
+        for (index, object) in sample.iter().enumerate() {
+            for (offset, value) in object.iter().enumerate() {
+                oam.write(
+                    (Oam::OAM_BASE + index * OBJECT_SIZE + offset) as u16,
+                    *value,
+                );
+            }
+        }
+
+        let decoded: alloc::vec::Vec<_> = oam.iter_decoded().take(sample.len()).collect();
+        for (index, (object, raw)) in decoded.iter().zip(sample.iter()).enumerate() {
+            let [y, x, tile_idx, attrs] = *raw;
+            assert_eq!(object.index, index);
+            assert_eq!(object.y, y as i16 - 16);
+            assert_eq!(object.x, x as i16 - 8);
+            assert_eq!(object.tile_idx, tile_idx);
+            assert_eq!(object.below_bg_and_window, attrs & 0x80 != 0);
+            assert_eq!(object.y_flip, attrs & 0x40 != 0);
+            assert_eq!(object.x_flip, attrs & 0x20 != 0);
+            let palette1 = object.palette == OBJ_ATTRS::PALETTE_SELECTOR::Value::Palette1;
+            assert_eq!(palette1, attrs & 0x10 != 0);
+            assert_eq!(object.bank, (attrs as usize >> 3) & 1);
+            assert_eq!(object.color_palette, attrs as usize & 7);
+            assert_eq!(*object, oam.decoded(index));
+        }
+
+        assert_eq!(decoded[0].x, -8);
+        assert_eq!(decoded[0].y, -16);
+    }
+}
@@ -1,6 +1,7 @@
 //! Implements the memory mapped interface to VRAM, etc
 
 use super::Palette;
+use sm83::state::{SaveState, StateError, StateReader, StateWriter};
 use tock_registers::interfaces::{Readable, Writeable};
 use tock_registers::{register_bitfields, registers::InMemoryRegister};
 
@@ -190,7 +191,7 @@ impl Registers {
             0xFF44 => {}
             0xFF45 => self.lyc = value,
             0xFF46 => {
-                debug_assert!(!self.dma_config.triggered);
+                // Writing again before the PPU picks up the request restarts it from the new address
                 self.dma_config.triggered = true;
                 self.dma_config.address = value;
             }
@@ -205,3 +206,38 @@ impl Registers {
         };
     }
 }
+
+impl SaveState for Registers {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.lcdc.get());
+        state.write_u8(self.status.get());
+        state.write_u8(self.scy);
+        state.write_u8(self.scx);
+        state.write_u8(self.ly);
+        state.write_u8(self.lyc);
+        state.write_bool(self.dma_config.triggered);
+        state.write_u8(self.dma_config.address);
+        state.write_u8(self.bg_palette.into());
+        state.write_u8(self.obj_palette0.into());
+        state.write_u8(self.obj_palette1.into());
+        state.write_u8(self.wy);
+        state.write_u8(self.wx);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.lcdc.set(state.read_u8()?);
+        self.status.set(state.read_u8()?);
+        self.scy = state.read_u8()?;
+        self.scx = state.read_u8()?;
+        self.ly = state.read_u8()?;
+        self.lyc = state.read_u8()?;
+        self.dma_config.triggered = state.read_bool()?;
+        self.dma_config.address = state.read_u8()?;
+        self.bg_palette = state.read_u8()?.into();
+        self.obj_palette0 = state.read_u8()?.into();
+        self.obj_palette1 = state.read_u8()?.into();
+        self.wy = state.read_u8()?;
+        self.wx = state.read_u8()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,57 @@
+//! Cache of decoded tile data, so that tiles are only decoded again after they are written.
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+
+use crate::vram::{Vram, TILE_HEIGHT, TILE_WIDTH, TOTAL_TILES};
+use crate::PaletteIndex;
+
+/// The palette indexes of a line of a tile, from left to right
+pub(crate) type DecodedLine = [PaletteIndex; TILE_WIDTH];
+
+type DecodedTile = [DecodedLine; TILE_HEIGHT];
+
+pub(crate) struct TileCache {
+    tiles: Box<[DecodedTile; TOTAL_TILES]>,
+    #[cfg(test)]
+    decoded_tiles: usize,
+}
+
+impl TileCache {
+    pub(crate) fn new() -> Self {
+        Self {
+            tiles: Box::new([[[PaletteIndex::Id0; TILE_WIDTH]; TILE_HEIGHT]; TOTAL_TILES]),
+            #[cfg(test)]
+            decoded_tiles: 0,
+        }
+    }
+
+    /// Decodes the tiles that have been written since the last update.
+    #[cfg_attr(feature = "profile", inline(never))]
+    pub(crate) fn update(&mut self, vram: &mut Vram) {
+        for tile_position in vram.take_dirty_tiles().iter() {
+            let tile = vram.get_tile_at(tile_position);
+            for (line_idx, decoded) in self.tiles[tile_position].iter_mut().enumerate() {
+                for (dest, pixel) in decoded.iter_mut().zip(tile.get_line(line_idx).iter()) {
+                    *dest = pixel;
+                }
+            }
+            #[cfg(test)]
+            {
+                self.decoded_tiles += 1;
+            }
+        }
+    }
+
+    /// Returns the given line of the tile at the given position in VRAM.
+    pub(crate) fn line(&self, tile_position: usize, line: usize) -> &DecodedLine {
+        &self.tiles[tile_position][line]
+    }
+
+    /// Number of tiles decoded since the cache was created.
+    #[cfg(test)]
+    pub(crate) fn decoded_tiles(&self) -> usize {
+        self.decoded_tiles
+    }
+}
@@ -8,6 +8,43 @@ use alloc::boxed::Box;
 
 use super::PaletteIndex;
 
+use tock_registers::register_bitfields;
+
+register_bitfields! [
+    u8,
+
+    /// Attributes of a tile in the background or window map. They are stored in the second VRAM
+    /// bank, at the same address as the tile index, and are only used by the Game Boy Color.
+    pub BG_MAP_ATTRS [
+        /// Determines whether color indexes 1-3 of the tile are drawn over objects
+        PRIO OFFSET(7) NUMBITS(1) [
+            No = 0,
+            AboveObjects = 1,
+        ],
+
+        /// Determines whether the tile is drawn normally or flipped around the Y axis
+        Y_FLIP OFFSET(6) NUMBITS(1) [
+            No = 0,
+            Yes = 1,
+        ],
+
+        /// Determines whether the tile is drawn normally or flipped around the X axis
+        X_FLIP OFFSET(5) NUMBITS(1) [
+            No = 0,
+            Yes = 1,
+        ],
+
+        /// Determines which VRAM bank holds the tile
+        BANK OFFSET(3) NUMBITS(1) [
+            Bank0 = 0,
+            Bank1 = 1,
+        ],
+
+        /// Determines which color palette must be used to draw the tile
+        COLOR_PALETTE OFFSET(0) NUMBITS(3) [],
+    ],
+];
+
 /// The width of the tile
 pub const TILE_WIDTH: usize = 8;
 /// The number of lines in each tile (height)
@@ -28,6 +65,16 @@ pub const NUM_TILE_BLOCKS: usize = 3;
 /// The number of tile maps in VRAM
 pub const NUM_TILE_MAPS: usize = 2;
 
+/// The number of tiles in each VRAM bank
+pub const NUM_TILES: usize = NUM_TILE_BLOCKS * TILES_PER_BLOCK;
+
+/// The number of VRAM banks. The second bank is only accessible on the Game Boy Color.
+pub const NUM_BANKS: usize = 2;
+
+/// The total number of tiles in all VRAM banks. Tiles of the second bank are placed after the
+/// ones of the first bank.
+pub const TOTAL_TILES: usize = NUM_BANKS * NUM_TILES;
+
 /// Represents a single line of a tile. Each byte in the u16 indicates
 #[repr(C)]
 #[derive(Clone, Copy, Eq, PartialEq, Debug)]
@@ -171,9 +218,6 @@ impl TileIndex {
     pub const fn new(index: u8) -> Self {
         Self(index)
     }
-    pub fn next(self) -> Self {
-        Self(self.0 + 1)
-    }
 }
 
 impl From<u8> for TileIndex {
@@ -240,15 +284,78 @@ struct VramImpl {
 // The VRAM occupies 0x2000 bytes.
 static_assertions::assert_eq_size!([u8; 0x2000], VramImpl);
 
-#[repr(C)]
+/// A set of tiles, indexed by their position in VRAM (from 0 to `TOTAL_TILES`), whose data has
+/// been written since they were last cleared.
 #[derive(Clone, Eq, PartialEq, Debug)]
-pub struct Vram(Box<VramImpl>);
+pub struct DirtyTiles([u64; TOTAL_TILES / 64]);
+
+impl DirtyTiles {
+    /// All tiles of the first bank
+    const fn first_bank() -> Self {
+        let mut words = [0; TOTAL_TILES / 64];
+        let mut word = 0;
+        while word < NUM_TILES / 64 {
+            words[word] = u64::MAX;
+            word += 1;
+        }
+        Self(words)
+    }
 
-impl Vram {
-    const VRAM_BASE: u16 = 0x8000;
-    const TILE_MAP_BASE: u16 = 0x9800;
+    const fn none() -> Self {
+        Self([0; TOTAL_TILES / 64])
+    }
 
-    pub fn new() -> Self {
+    fn mark(&mut self, tile: usize) {
+        self.0[tile / 64] |= 1 << (tile % 64);
+    }
+
+    /// Returns true if the given tile has been written since it was last cleared.
+    pub fn is_dirty(&self, tile: usize) -> bool {
+        self.0[tile / 64] & (1 << (tile % 64)) != 0
+    }
+
+    /// Returns the number of dirty tiles.
+    pub fn count(&self) -> usize {
+        self.0.iter().map(|word| word.count_ones() as usize).sum()
+    }
+
+    /// Returns an iterator over the dirty tiles, in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.0.iter().enumerate().flat_map(|(word_idx, word)| {
+            let mut bits = *word;
+            core::iter::from_fn(move || {
+                if bits == 0 {
+                    return None;
+                }
+                let bit = bits.trailing_zeros() as usize;
+                bits &= bits - 1;
+                Some(word_idx * 64 + bit)
+            })
+        })
+    }
+}
+
+/// Error returned when accessing a tile line outside of VRAM
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidTileLine {
+    /// Position of the tile in VRAM, which must be below `NUM_TILES`
+    pub tile: usize,
+    /// Line within the tile, which must be below `TILE_HEIGHT`
+    pub line: usize,
+}
+
+impl core::fmt::Display for InvalidTileLine {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "line {} of tile {} is out of bounds",
+            self.line, self.tile
+        )
+    }
+}
+
+impl VramImpl {
+    fn new() -> Self {
         const UNINIT_TILE_BLOCK: MaybeUninit<TileBlock> = MaybeUninit::uninit();
         let mut tile_blocks = [UNINIT_TILE_BLOCK; NUM_TILE_BLOCKS];
         for tile in tile_blocks.iter_mut() {
@@ -261,12 +368,58 @@ impl Vram {
             tile.write(TileMap::new());
         }
 
-        Self(Box::new(VramImpl {
+        Self {
             tile_blocks: unsafe {
                 core::mem::transmute::<_, [TileBlock; NUM_TILE_BLOCKS]>(tile_blocks)
             },
             tile_maps: unsafe { core::mem::transmute::<_, [TileMap; NUM_TILE_MAPS]>(tile_maps) },
-        }))
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Vram {
+    banks: Box<[VramImpl; NUM_BANKS]>,
+    /// Bank mapped at 0x8000 to 0x9FFF, selected by VBK
+    bank: usize,
+    dirty_tiles: DirtyTiles,
+}
+
+impl Vram {
+    const VRAM_BASE: u16 = 0x8000;
+    const TILE_MAP_BASE: u16 = 0x9800;
+
+    pub fn new() -> Self {
+        Self {
+            banks: Box::new([VramImpl::new(), VramImpl::new()]),
+            bank: 0,
+            // Nothing has been decoded from a new VRAM yet. The second bank is only used by the
+            // Game Boy Color, and decodes to the same blank tiles as a new tile cache, so it is
+            // left clean to avoid decoding it on the DMG.
+            dirty_tiles: DirtyTiles::first_bank(),
+        }
+    }
+
+    /// Returns the bank mapped at 0x8000 to 0x9FFF.
+    pub fn bank(&self) -> usize {
+        self.bank
+    }
+
+    /// Maps the given bank at 0x8000 to 0x9FFF. Only bit 0 is taken into account, like VBK.
+    pub fn select_bank(&mut self, bank: usize) {
+        self.bank = bank % NUM_BANKS;
+    }
+
+    /// Returns the set of tiles that have been written since they were last cleared.
+    pub fn dirty_tiles(&self) -> &DirtyTiles {
+        &self.dirty_tiles
+    }
+
+    /// Returns the set of tiles that have been written since they were last cleared, and clears
+    /// it.
+    pub fn take_dirty_tiles(&mut self) -> DirtyTiles {
+        core::mem::replace(&mut self.dirty_tiles, DirtyTiles::none())
     }
 
     const fn vram_address_to_block_address(
@@ -292,8 +445,18 @@ impl Vram {
     #[cfg_attr(feature = "profile", inline(never))]
     pub(crate) fn get_bg_tile_map(&self, map: crate::regs::LCDC::BG_TILE_MAP::Value) -> &TileMap {
         match map {
-            crate::regs::LCDC::BG_TILE_MAP::Value::HighMap => &self.0.tile_maps[1],
-            crate::regs::LCDC::BG_TILE_MAP::Value::LowMap => &self.0.tile_maps[0],
+            crate::regs::LCDC::BG_TILE_MAP::Value::HighMap => &self.banks[0].tile_maps[1],
+            crate::regs::LCDC::BG_TILE_MAP::Value::LowMap => &self.banks[0].tile_maps[0],
+        }
+    }
+
+    /// Returns the attributes of the tiles of the background map, which are stored in the second
+    /// bank at the same addresses as the tile indexes.
+    #[cfg_attr(feature = "profile", inline(never))]
+    pub(crate) fn get_bg_attr_map(&self, map: crate::regs::LCDC::BG_TILE_MAP::Value) -> &TileMap {
+        match map {
+            crate::regs::LCDC::BG_TILE_MAP::Value::HighMap => &self.banks[1].tile_maps[1],
+            crate::regs::LCDC::BG_TILE_MAP::Value::LowMap => &self.banks[1].tile_maps[0],
         }
     }
 
@@ -303,45 +466,219 @@ impl Vram {
         map: crate::regs::LCDC::WINDOW_TILE_MAP::Value,
     ) -> &TileMap {
         match map {
-            crate::regs::LCDC::WINDOW_TILE_MAP::Value::HighMap => &self.0.tile_maps[1],
-            crate::regs::LCDC::WINDOW_TILE_MAP::Value::LowMap => &self.0.tile_maps[0],
+            crate::regs::LCDC::WINDOW_TILE_MAP::Value::HighMap => &self.banks[0].tile_maps[1],
+            crate::regs::LCDC::WINDOW_TILE_MAP::Value::LowMap => &self.banks[0].tile_maps[0],
         }
     }
 
+    /// Returns the attributes of the tiles of the window map, which are stored in the second bank
+    /// at the same addresses as the tile indexes.
     #[cfg_attr(feature = "profile", inline(never))]
-    pub(crate) fn get_tile(
+    pub(crate) fn get_win_attr_map(
         &self,
+        map: crate::regs::LCDC::WINDOW_TILE_MAP::Value,
+    ) -> &TileMap {
+        match map {
+            crate::regs::LCDC::WINDOW_TILE_MAP::Value::HighMap => &self.banks[1].tile_maps[1],
+            crate::regs::LCDC::WINDOW_TILE_MAP::Value::LowMap => &self.banks[1].tile_maps[0],
+        }
+    }
+
+    /// Returns the position of the tile in the first bank of VRAM (from 0 to `NUM_TILES`) for the
+    /// given index and addressing mode. Tiles of the second bank are `NUM_TILES` positions after.
+    pub(crate) fn tile_position(
         index: TileIndex,
         index_mode: crate::regs::LCDC::BG_AND_WINDOW_TILE_DATA::Value,
-    ) -> &Tile {
+    ) -> usize {
         let block = (index.0 / 128) as usize;
         let index = (index.0 % 128) as usize;
         let block = match index_mode {
             crate::regs::LCDC::BG_AND_WINDOW_TILE_DATA::Value::Blocks0And1 => block,
             crate::regs::LCDC::BG_AND_WINDOW_TILE_DATA::Value::Blocks2And1 => 2 - block,
         };
-        self.0.tile_blocks[block].get_tile(index)
+        block * TILES_PER_BLOCK + index
+    }
+
+    #[cfg_attr(feature = "profile", inline(never))]
+    pub(crate) fn get_tile_at(&self, tile: usize) -> &Tile {
+        let (bank, tile) = (tile / NUM_TILES, tile % NUM_TILES);
+        self.banks[bank].tile_blocks[tile / TILES_PER_BLOCK].get_tile(tile % TILES_PER_BLOCK)
+    }
+
+    fn tile_line_mut(
+        &mut self,
+        tile: usize,
+        line: usize,
+    ) -> Result<&mut TileLine, InvalidTileLine> {
+        if tile >= NUM_TILES || line >= TILE_HEIGHT {
+            return Err(InvalidTileLine { tile, line });
+        }
+        Ok(
+            &mut self.banks[0].tile_blocks[tile / TILES_PER_BLOCK].0[tile % TILES_PER_BLOCK].0
+                [line],
+        )
+    }
+
+    /// Returns the two bitplane bytes of a line of the tile at the given position in the first
+    /// bank of VRAM (from 0 to `NUM_TILES`). The first byte holds the low bit of each pixel and the second one the high
+    /// bit, with the leftmost pixel in the most significant bit.
+    pub fn tile_plane_bytes(
+        &self,
+        tile: usize,
+        line: usize,
+    ) -> Result<[u8; TILE_LINE_SIZE], InvalidTileLine> {
+        if tile >= NUM_TILES || line >= TILE_HEIGHT {
+            return Err(InvalidTileLine { tile, line });
+        }
+        Ok(self.get_tile_at(tile).get_line(line).0)
+    }
+
+    /// Overwrites the two bitplane bytes of a line of the tile at the given position in VRAM. See
+    /// `tile_plane_bytes` for the layout.
+    pub fn set_tile_plane_bytes(
+        &mut self,
+        tile: usize,
+        line: usize,
+        planes: [u8; TILE_LINE_SIZE],
+    ) -> Result<(), InvalidTileLine> {
+        self.tile_line_mut(tile, line)?.0 = planes;
+        self.dirty_tiles.mark(tile);
+        Ok(())
+    }
+
+    /// Returns the palette indexes of the pixels in a line of the tile at the given position in
+    /// VRAM, from left to right.
+    pub fn tile_line_pixels(
+        &self,
+        tile: usize,
+        line: usize,
+    ) -> Result<[PaletteIndex; TILE_WIDTH], InvalidTileLine> {
+        let planes = self.tile_plane_bytes(tile, line)?;
+        let mut pixels = [PaletteIndex::Id0; TILE_WIDTH];
+        for (pixel, value) in pixels.iter_mut().zip(TileLine(planes).iter()) {
+            *pixel = value;
+        }
+        Ok(pixels)
     }
 
     #[cfg_attr(feature = "profile", inline(never))]
     pub fn read(&self, address: sm83::memory::Address) -> u8 {
+        self.read_bank(self.bank, address)
+    }
+
+    #[cfg_attr(feature = "profile", inline(never))]
+    pub fn write(&mut self, address: sm83::memory::Address, value: u8) {
+        self.write_bank(self.bank, address, value)
+    }
+
+    /// Reads the given address of a bank, regardless of the selected one.
+    pub fn read_bank(&self, bank: usize, address: sm83::memory::Address) -> u8 {
+        let data = &self.banks[bank];
         if address < 0x9800 {
             let (blk_idx, blk_address) = Self::vram_address_to_block_address(address);
-            self.0.tile_blocks[blk_idx].read(blk_address)
+            data.tile_blocks[blk_idx].read(blk_address)
         } else {
             let (tile_map_idx, tile_map_address) = Self::vram_address_to_tile_map_address(address);
-            self.0.tile_maps[tile_map_idx].read(tile_map_address)
+            data.tile_maps[tile_map_idx].read(tile_map_address)
         }
     }
 
-    #[cfg_attr(feature = "profile", inline(never))]
-    pub fn write(&mut self, address: sm83::memory::Address, value: u8) {
+    /// Writes the given address of a bank, regardless of the selected one.
+    pub fn write_bank(&mut self, bank: usize, address: sm83::memory::Address, value: u8) {
+        let data = &mut self.banks[bank];
         if address < 0x9800 {
             let (blk_idx, blk_address) = Self::vram_address_to_block_address(address);
-            self.0.tile_blocks[blk_idx].write(blk_address, value)
+            let tile = (address - Self::VRAM_BASE) as usize / core::mem::size_of::<Tile>();
+            self.dirty_tiles.mark(bank * NUM_TILES + tile);
+            data.tile_blocks[blk_idx].write(blk_address, value)
         } else {
             let (tile_map_idx, tile_map_address) = Self::vram_address_to_tile_map_address(address);
-            self.0.tile_maps[tile_map_idx].write(tile_map_address, value)
+            data.tile_maps[tile_map_idx].write(tile_map_address, value)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_marks_single_tile_dirty() {
+        let mut vram = Vram::new();
+        assert_eq!(vram.take_dirty_tiles().count(), NUM_TILES);
+        assert_eq!(vram.dirty_tiles().count(), 0);
+
+        // Second byte of the 2nd line of tile 0x81
+        vram.write(0x8000 + 0x81 * 16 + 3, 0xAA);
+        let dirty = vram.take_dirty_tiles();
+        assert_eq!(dirty.iter().collect::<alloc::vec::Vec<_>>(), [0x81]);
+        assert!(dirty.is_dirty(0x81));
+
+        // Tile map writes don't dirty any tile
+        vram.write(0x9800, 0x81);
+        assert_eq!(vram.dirty_tiles().count(), 0);
+    }
+
+    #[test]
+    fn tile_plane_bytes() {
+        let mut vram = Vram::new();
+        vram.take_dirty_tiles();
+
+        vram.set_tile_plane_bytes(0x101, 7, [0b1010_0101, 0b1100_0011])
+            .unwrap();
+        assert_eq!(
+            vram.tile_plane_bytes(0x101, 7),
+            Ok([0b1010_0101, 0b1100_0011])
+        );
+        assert_eq!(vram.read(0x8000 + 0x101 * 16 + 14), 0b1010_0101);
+        assert_eq!(vram.read(0x8000 + 0x101 * 16 + 15), 0b1100_0011);
+        assert!(vram.dirty_tiles().is_dirty(0x101));
+
+        use PaletteIndex::*;
+        assert_eq!(
+            vram.tile_line_pixels(0x101, 7),
+            Ok([Id3, Id2, Id1, Id0, Id0, Id1, Id2, Id3])
+        );
+
+        assert_eq!(
+            vram.set_tile_plane_bytes(NUM_TILES, 0, [0; 2]),
+            Err(InvalidTileLine {
+                tile: NUM_TILES,
+                line: 0
+            })
+        );
+        assert_eq!(
+            vram.tile_plane_bytes(0, TILE_HEIGHT),
+            Err(InvalidTileLine {
+                tile: 0,
+                line: TILE_HEIGHT
+            })
+        );
+    }
+
+    #[test]
+    fn second_bank() {
+        let mut vram = Vram::new();
+        // Only the tiles of the first bank need to be decoded
+        assert_eq!(vram.take_dirty_tiles().iter().max(), Some(NUM_TILES - 1));
+
+        vram.write(0x8010, 0x11);
+        vram.select_bank(3);
+        assert_eq!(vram.bank(), 1);
+        assert_eq!(vram.read(0x8010), 0);
+        vram.write(0x8010, 0x22);
+        vram.write(0x9800, 0x80);
+
+        assert_eq!(vram.read_bank(0, 0x8010), 0x11);
+        assert_eq!(vram.read_bank(0, 0x9800), 0);
+        assert_eq!(vram.read_bank(1, 0x9800), 0x80);
+
+        // Tiles of the second bank come after the ones of the first bank
+        let dirty = vram.take_dirty_tiles();
+        assert_eq!(
+            dirty.iter().collect::<alloc::vec::Vec<_>>(),
+            [1, NUM_TILES + 1]
+        );
+        assert_eq!(vram.get_tile_at(NUM_TILES + 1).get_line(0).0, [0x22, 0]);
+    }
+}
@@ -28,6 +28,8 @@ fn main() -> anyhow::Result<()> {
     println!("\tROM size: {} KiB", header.rom_size / 1024);
     println!("\tRAM size: {}", header.ram_size);
     println!("\tType: {}", header.cartridge_type);
+    println!("\tDestination: {}", header.destination);
+    println!("\tVersion: {}", header.rom_version);
     println!("\tEntrypoint:");
     for (addr, insn) in disassembler
         .entrypoint()
@@ -1,21 +1,29 @@
+use std::collections::HashMap;
 use std::io::BufWriter;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 
-use anyhow::bail;
+use anyhow::{bail, Context};
 use cartridge::Cartridge;
 use clap::Parser;
 use ppu::{Color, Frame, DISPLAY_HEIGHT, DISPLAY_WIDTH};
 
-use rusty_boy::RustyBoy;
+use rusty_boy::{joypad, AccuracyProfile, RustyBoy};
+use sdl2::keyboard::Keycode;
 
 /// Runs the given Game Boy emulator ROM
 #[derive(Parser, Debug)]
 #[command(version, about)]
 struct Args {
     /// The ROM to run
-    rom_path: PathBuf,
+    #[arg(required_unless_present = "rom_dir", conflicts_with = "rom_dir")]
+    rom_path: Option<PathBuf>,
+
+    /// Runs the ROMs found in the given directory instead of a single ROM. Page Up and Page Down
+    /// switch to the previous and next ROM.
+    #[arg(long)]
+    rom_dir: Option<PathBuf>,
 
     /// Saves PNG files with each frame to the current directory
     #[arg(short)]
@@ -24,29 +32,142 @@ struct Args {
     /// Enable debugging
     #[arg(short)]
     debug: bool,
+
+    /// Disables audio output. Emulation is paced with the wall clock instead.
+    #[arg(long)]
+    mute: bool,
+
+    /// Trade emulation accuracy for speed
+    #[arg(long, value_enum, default_value_t = Accuracy::Accurate)]
+    accuracy: Accuracy,
+
+    /// Maps keys to Game Boy buttons, with a `key = "button"` line for each key. Defaults to WASD
+    /// for the D-pad, J and K for A and B, Semicolon for Start and Space for Select.
+    #[arg(long)]
+    keymap: Option<PathBuf>,
+
+    /// Frames skipped between rendered frames while fast forwarding, by holding Tab
+    #[arg(long, default_value_t = 4)]
+    fast_forward_skip: usize,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum Accuracy {
+    Accurate,
+    Balanced,
+    Fast,
+}
+
+impl From<Accuracy> for AccuracyProfile {
+    fn from(value: Accuracy) -> Self {
+        match value {
+            Accuracy::Accurate => AccuracyProfile::Accurate,
+            Accuracy::Balanced => AccuracyProfile::Balanced,
+            Accuracy::Fast => AccuracyProfile::Fast,
+        }
+    }
+}
+
+/// A Game Boy button that can be mapped to a key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Button {
+    Up,
+    Down,
+    Left,
+    Right,
+    A,
+    B,
+    Start,
+    Select,
+}
+
+impl Button {
+    fn set(self, joypad: &mut joypad::State, pressed: bool) {
+        let button = match self {
+            Button::Up => &mut joypad.up,
+            Button::Down => &mut joypad.down,
+            Button::Left => &mut joypad.left,
+            Button::Right => &mut joypad.right,
+            Button::A => &mut joypad.a,
+            Button::B => &mut joypad.b,
+            Button::Start => &mut joypad.start,
+            Button::Select => &mut joypad.select,
+        };
+        *button = pressed;
+    }
+}
+
+impl FromStr for Button {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Ok(match name.to_ascii_lowercase().as_str() {
+            "up" => Button::Up,
+            "down" => Button::Down,
+            "left" => Button::Left,
+            "right" => Button::Right,
+            "a" => Button::A,
+            "b" => Button::B,
+            "start" => Button::Start,
+            "select" => Button::Select,
+            _ => bail!(
+                "Unknown button `{name}`, expected one of up, down, left, right, a, b, start or select"
+            ),
+        })
+    }
+}
+
+type Keymap = HashMap<Keycode, Button>;
+
+fn default_keymap() -> Keymap {
+    HashMap::from([
+        (Keycode::W, Button::Up),
+        (Keycode::S, Button::Down),
+        (Keycode::A, Button::Left),
+        (Keycode::D, Button::Right),
+        (Keycode::J, Button::A),
+        (Keycode::K, Button::B),
+        (Keycode::Semicolon, Button::Start),
+        (Keycode::Space, Button::Select),
+    ])
+}
+
+/// Parses a TOML keymap with a `key = "button"` entry for each key, using the SDL key names. Keys
+/// with characters other than letters, digits, `_` and `-` must be quoted, e.g. `";" = "start"`.
+fn parse_keymap(config: &str) -> anyhow::Result<Keymap> {
+    let entries: HashMap<String, String> = toml::from_str(config)?;
+    entries
+        .into_iter()
+        .map(|(key, button)| {
+            let context = || format!("Invalid keymap entry for key `{key}`");
+            let keycode = Keycode::from_name(&key)
+                .with_context(|| format!("Unknown key `{key}`"))
+                .with_context(context)?;
+            let button = button.parse().with_context(context)?;
+            Ok((keycode, button))
+        })
+        .collect()
+}
+
+fn load_keymap(path: &Path) -> anyhow::Result<Keymap> {
+    let config = std::fs::read_to_string(path)
+        .with_context(|| format!("Unable to read {}", path.display()))?;
+    parse_keymap(&config).with_context(|| format!("Unable to parse {}", path.display()))
 }
 
 fn save_png(idx: usize, frame: &[[Color; DISPLAY_WIDTH]; DISPLAY_HEIGHT]) -> anyhow::Result<()> {
     let path = PathBuf::from_str(&format!("frame_{idx}.png"))?;
 
-    const MAX: u8 = 255;
     let frame: Vec<u8> = frame
-        .into_iter()
-        .flat_map(|l| {
-            l.into_iter().map(|c| match c {
-                Color::White => MAX,
-                Color::LightGrey => MAX / 3 * 2,
-                Color::DarkGrey => MAX / 3,
-                Color::Black => 0,
-            })
-        })
+        .iter()
+        .flat_map(|l| l.iter().flat_map(|c| c.to_rgb888()))
         .collect();
 
     let file = std::fs::File::create(&path)?;
     let w = BufWriter::new(file);
     let mut png_encoder = png::Encoder::new(w, DISPLAY_WIDTH as u32, DISPLAY_HEIGHT as u32);
 
-    png_encoder.set_color(png::ColorType::Grayscale);
+    png_encoder.set_color(png::ColorType::Rgb);
     png_encoder.set_depth(png::BitDepth::Eight);
     let mut writer = png_encoder.write_header()?;
 
@@ -67,16 +188,10 @@ fn draw_surface_argb8888(surface: &mut [u8], frame: &Frame) -> anyhow::Result<()
     // The size of each ARGB8888 pixel is 4 bytes
     const PIXEL_SIZE: usize = 4;
     for (dest, src) in surface.chunks_mut(PIXEL_SIZE).zip(pixel_iter) {
-        const MAX: u8 = 255;
-        let color = match src {
-            Color::White => MAX,
-            Color::LightGrey => MAX / 3 * 2,
-            Color::DarkGrey => MAX / 3,
-            Color::Black => 0,
-        };
-        dest[0] = color; // B
-        dest[1] = color; // G
-        dest[2] = color; // R
+        let [r, g, b] = src.to_rgb888();
+        dest[0] = b;
+        dest[1] = g;
+        dest[2] = r;
         dest[3] = 0xFF; // A
     }
 
@@ -89,42 +204,189 @@ fn draw_surface_rgb888(surface: &mut [u8], frame: &Frame) -> anyhow::Result<()>
     // The size of each RGB888 pixel is 4 bytes, last one is unused...
     const PIXEL_SIZE: usize = 4;
     for (dest, src) in surface.chunks_mut(PIXEL_SIZE).zip(pixel_iter) {
-        const MAX: u8 = 255;
-        let color = match src {
-            Color::White => MAX,
-            Color::LightGrey => MAX / 3 * 2,
-            Color::DarkGrey => MAX / 3,
-            Color::Black => 0,
-        };
-        dest[0] = color; // B
-        dest[1] = color; // G
-        dest[2] = color; // R
+        let [r, g, b] = src.to_rgb888();
+        dest[0] = b;
+        dest[1] = g;
+        dest[2] = r;
     }
 
     Ok(())
 }
 
-fn attempt_restore_save_file(rusty_boy: &mut RustyBoy, rom_path: &Path) -> anyhow::Result<()> {
-    let save_file_path = rom_path.with_extension("save");
+fn save_file_path(rom_path: &Path, save_key: &str) -> PathBuf {
+    rom_path.with_file_name(format!("{save_key}.save"))
+}
 
-    let data = match std::fs::read(&save_file_path) {
-        Ok(data) => data,
-        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
-        Err(v) => {
-            anyhow::bail!("Unable to read save file: {v}")
+/// Returns true for the file extensions of Game Boy and Game Boy Color ROMs, regardless of case.
+fn is_rom_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            extension.eq_ignore_ascii_case("gb") || extension.eq_ignore_ascii_case("gbc")
+        })
+}
+
+/// Keeps the ROMs among the given paths, sorted by name.
+fn filter_roms(paths: impl IntoIterator<Item = PathBuf>) -> Vec<PathBuf> {
+    let mut roms: Vec<PathBuf> = paths.into_iter().filter(|path| is_rom_file(path)).collect();
+    roms.sort();
+    roms
+}
+
+/// Lists the ROMs in the given directory, sorted by name. Subdirectories are not scanned.
+fn list_roms(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let entries =
+        std::fs::read_dir(dir).with_context(|| format!("Unable to read {}", dir.display()))?;
+    let mut paths = vec![];
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            paths.push(entry.path());
         }
+    }
+    Ok(filter_roms(paths))
+}
+
+/// The ROMs that can be run, and the one that is currently running
+struct Playlist {
+    roms: Vec<PathBuf>,
+    current: usize,
+}
+
+impl Playlist {
+    fn from_args(args: &Args) -> anyhow::Result<Self> {
+        let roms = match (&args.rom_path, &args.rom_dir) {
+            (Some(rom_path), _) => vec![rom_path.clone()],
+            (None, Some(rom_dir)) => list_roms(rom_dir)?,
+            (None, None) => unreachable!("clap requires either a ROM or a ROM directory"),
+        };
+        if roms.is_empty() {
+            bail!("No ROMs found");
+        }
+        Ok(Self { roms, current: 0 })
+    }
+
+    fn current(&self) -> &Path {
+        &self.roms[self.current]
+    }
+
+    /// Returns the index of the ROM that is `offset` positions away from the current one,
+    /// wrapping around at both ends of the list.
+    fn index_at(&self, offset: isize) -> usize {
+        (self.current as isize + offset).rem_euclid(self.roms.len() as isize) as usize
+    }
+
+    /// Title of the window while the current ROM runs
+    fn title(&self) -> String {
+        let name = self.current().file_stem().unwrap_or_default();
+        if self.roms.len() == 1 {
+            format!("rusty-boy - {}", name.to_string_lossy())
+        } else {
+            format!(
+                "rusty-boy - {} ({}/{})",
+                name.to_string_lossy(),
+                self.current + 1,
+                self.roms.len()
+            )
+        }
+    }
+}
+
+fn load_cartridge(rom_path: &Path) -> anyhow::Result<Cartridge> {
+    let rom_data = std::fs::read(rom_path)
+        .with_context(|| format!("Unable to read {}", rom_path.display()))?;
+    Cartridge::try_new(rom_data).map_err(|e| match e {
+        cartridge::Error::UnsupportedMapper(ty) => {
+            anyhow::format_err!("Cartridges of type {ty:?} are not supported yet")
+        }
+        e => anyhow::Error::new(e).context("Invalid cartridge"),
+    })
+}
+
+/// Swaps the running cartridge for the one in `rom_path`, saving the RAM of the current one
+/// and restoring the save of the new one. Returns the save key of the new cartridge. If the new
+/// ROM can't be loaded, the current cartridge keeps running.
+fn switch_cartridge(
+    rusty_boy: &mut RustyBoy,
+    current: (&Path, &str),
+    rom_path: &Path,
+) -> anyhow::Result<String> {
+    let cartridge = load_cartridge(rom_path)?;
+    let save_key = cartridge.save_key();
+    let save = if cartridge.has_battery() {
+        read_save_file(rom_path, &save_key)?
+    } else {
+        None
+    };
+
+    let (current_path, current_key) = current;
+    store_save_file(rusty_boy, current_path, current_key)?;
+
+    rusty_boy
+        .load_cartridge(cartridge, save.as_deref(), unix_time())
+        .context("Unable to load cartridge ram")?;
+    Ok(save_key)
+}
+
+/// Returns the current UNIX time, which is stored in saves of cartridges with a real-time clock.
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+fn attempt_restore_save_file(
+    rusty_boy: &mut RustyBoy,
+    rom_path: &Path,
+    save_key: &str,
+) -> anyhow::Result<()> {
+    let Some(data) = read_save_file(rom_path, save_key)? else {
+        return Ok(());
     };
 
     rusty_boy
-        .restore_cartridge_ram(&data)
-        .map_err(|e| anyhow::format_err!("Unable to load cartridge ram: {}", e))?;
+        .restore_cartridge_ram(&data, unix_time())
+        .context("Unable to load cartridge ram")?;
 
     Ok(())
 }
 
-fn save_file(rom_path: &Path, data: &[u8]) -> anyhow::Result<()> {
-    let save_file_path = rom_path.with_extension("save");
-    std::fs::write(&save_file_path, data)?;
+fn read_save_file(rom_path: &Path, save_key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+    // Saves used to be named after the ROM file, keep loading them if there is no save file
+    // using the cartridge save key yet.
+    let candidates = [
+        save_file_path(rom_path, save_key),
+        rom_path.with_extension("save"),
+    ];
+
+    for path in &candidates {
+        match std::fs::read(path) {
+            Ok(data) => return Ok(Some(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(v) => {
+                anyhow::bail!("Unable to read save file: {v}")
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Writes the battery-backed RAM of the running cartridge to its save file, if it has any.
+fn store_save_file(
+    rusty_boy: &mut RustyBoy,
+    rom_path: &Path,
+    save_key: &str,
+) -> anyhow::Result<()> {
+    if rusty_boy.supports_battery_backed_ram() {
+        if let Some(ram) = rusty_boy.get_cartridge_ram(unix_time()) {
+            save_file(rom_path, save_key, ram)?;
+        }
+    }
+    Ok(())
+}
+
+fn save_file(rom_path: &Path, save_key: &str, data: &[u8]) -> anyhow::Result<()> {
+    std::fs::write(save_file_path(rom_path, save_key), data)?;
     Ok(())
 }
 
@@ -137,6 +399,103 @@ fn configure_sched_affinity() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Profiling clock with nanosecond resolution, counting from the moment it is created
+#[cfg(feature = "profile")]
+struct InstantClock(Instant);
+
+#[cfg(feature = "profile")]
+impl rusty_boy::profiling::ProfilingClock for InstantClock {
+    fn now(&self) -> u64 {
+        self.0.elapsed().as_nanos() as u64
+    }
+}
+
+const AUDIO_SAMPLE_RATE: i32 = 48000;
+
+// Enough for a few frames worth of interleaved stereo samples, even with frame skip
+const AUDIO_BUFFER_LEN: usize = 1 << 14;
+
+// Emulation runs ahead of the audio device by this much, which absorbs the jitter of the host
+const TARGET_AUDIO_LATENCY: Duration = Duration::from_millis(50);
+
+// If the backlog grows beyond this, the queue is flushed to recover the target latency
+const MAX_AUDIO_LATENCY: Duration = Duration::from_millis(250);
+
+/// Plays the audio generated by the emulator, and paces the emulation with the audio device
+/// clock by keeping the backlog of queued audio close to `TARGET_AUDIO_LATENCY`.
+struct AudioOutput {
+    queue: sdl2::audio::AudioQueue<i16>,
+    buffer: Vec<i16>,
+    bytes_per_second: u32,
+}
+
+impl AudioOutput {
+    fn new(sdl_context: &sdl2::Sdl, rusty_boy: &mut RustyBoy) -> anyhow::Result<Self> {
+        let audio_subsys = sdl_context
+            .audio()
+            .map_err(|e| anyhow::format_err!("Unable to initialize audio: {e}"))?;
+        let queue: sdl2::audio::AudioQueue<i16> = audio_subsys
+            .open_queue(
+                None,
+                &sdl2::audio::AudioSpecDesired {
+                    freq: Some(AUDIO_SAMPLE_RATE),
+                    channels: Some(2),
+                    samples: None,
+                },
+            )
+            .map_err(|e| anyhow::format_err!("Unable to open the audio device: {e}"))?;
+
+        let spec = queue.spec();
+        rusty_boy.set_audio_sample_rate(spec.freq as usize);
+        let bytes_per_second =
+            spec.freq as u32 * spec.channels as u32 * std::mem::size_of::<i16>() as u32;
+
+        queue.resume();
+        Ok(Self {
+            queue,
+            buffer: vec![0; AUDIO_BUFFER_LEN],
+            bytes_per_second,
+        })
+    }
+
+    fn backlog(&self) -> Duration {
+        Duration::from_secs_f64(self.queue.size() as f64 / self.bytes_per_second as f64)
+    }
+
+    /// Queues the audio generated since the last call.
+    fn queue_samples(&mut self, rusty_boy: &mut RustyBoy) -> anyhow::Result<()> {
+        if self.backlog() > MAX_AUDIO_LATENCY {
+            // The emulator ran well ahead of the audio device. Dropping the backlog introduces a
+            // single glitch instead of playing with an ever growing delay.
+            log::debug!("Audio overrun, flushing queued samples");
+            self.queue.clear();
+        } else if self.queue.size() == 0 {
+            log::debug!("Audio underrun");
+        }
+
+        let num_samples = rusty_boy.audio_samples(&mut self.buffer);
+        self.queue
+            .queue_audio(&self.buffer[..num_samples * 2])
+            .map_err(|e| anyhow::format_err!("Unable to queue audio: {e}"))
+    }
+
+    /// Sleeps until the backlog drains to the target latency. Returns right away after an
+    /// underrun, so that emulation catches up without waiting for a frame deadline.
+    fn wait_for_backlog(&self) {
+        if let Some(excess) = self.backlog().checked_sub(TARGET_AUDIO_LATENCY) {
+            std::thread::sleep(excess);
+        }
+    }
+}
+
+/// Drops the audio generated since the last call, so that it doesn't pile up in the emulator.
+fn discard_samples(rusty_boy: &mut RustyBoy) {
+    let mut buffer = [0; 1024];
+    while rusty_boy.audio_samples(&mut buffer) != 0 {}
+}
+
+const FRAME_TIME: Duration = Duration::from_nanos(16_666_667); // 60 fps
+
 fn main() -> anyhow::Result<()> {
     #[cfg(feature = "profile")]
     configure_sched_affinity()?;
@@ -145,16 +504,21 @@ fn main() -> anyhow::Result<()> {
 
     let args = Args::parse();
 
-    let rom_data = std::fs::read(&args.rom_path)?;
-    let cartridge = Cartridge::try_new(rom_data)
-        .map_err(|e| anyhow::format_err!("Invalid cartridge: {}", e))?;
+    let keymap = match &args.keymap {
+        Some(path) => load_keymap(path)?,
+        None => default_keymap(),
+    };
+    let mut playlist = Playlist::from_args(&args)?;
+    let cartridge = load_cartridge(playlist.current())?;
+    let mut save_key = cartridge.save_key();
     let mut rusty_boy = RustyBoy::new_with_cartridge(cartridge);
 
-    #[cfg(feature = "approximate")]
-    rusty_boy.configure_cpu_step(sm83::core::Cycles::new(60));
+    rusty_boy.set_accuracy(args.accuracy.into());
+    #[cfg(feature = "profile")]
+    rusty_boy.configure_profiling(Some(Box::new(InstantClock(Instant::now()))));
 
     if rusty_boy.supports_battery_backed_ram() {
-        attempt_restore_save_file(&mut rusty_boy, &args.rom_path)?;
+        attempt_restore_save_file(&mut rusty_boy, playlist.current(), &save_key)?;
     }
 
     if args.debug {
@@ -164,11 +528,24 @@ fn main() -> anyhow::Result<()> {
     let sdl_context = sdl2::init().unwrap();
     let video_subsys = sdl_context.video().unwrap();
 
-    let window = video_subsys
-        .window("rusty-boy", DISPLAY_WIDTH as u32, DISPLAY_HEIGHT as u32)
+    let mut window = video_subsys
+        .window(
+            &playlist.title(),
+            DISPLAY_WIDTH as u32,
+            DISPLAY_HEIGHT as u32,
+        )
         .position_centered()
         .build()?;
 
+    let mut audio = if args.mute {
+        None
+    } else {
+        // Machines without an audio device can still run games, paced with the wall clock
+        AudioOutput::new(&sdl_context, &mut rusty_boy)
+            .inspect_err(|e| log::warn!("Running without audio: {e:#}"))
+            .ok()
+    };
+
     let mut event_pump = sdl_context.event_pump().unwrap();
     let surface = window.surface(&event_pump).unwrap();
     let update_surface_func = match surface.pixel_format_enum() {
@@ -182,61 +559,88 @@ fn main() -> anyhow::Result<()> {
 
     let mut frame_id = 0;
 
-    let mut joypad = rusty_boy::joypad::State::new();
+    let mut joypad = joypad::State::new();
+    let mut fast_forward = false;
 
     let mut next_deadline = Instant::now();
     let mut start = Instant::now();
     let mut load = Duration::from_millis(0);
     'running: loop {
+        let mut rom_offset = 0;
         for event in event_pump.poll_iter() {
             match event {
                 sdl2::event::Event::Quit { .. }
                 | sdl2::event::Event::KeyDown {
-                    keycode: Some(sdl2::keyboard::Keycode::Escape),
+                    keycode: Some(Keycode::Escape),
                     ..
                 } => break 'running,
 
                 sdl2::event::Event::KeyDown {
                     keycode: Some(key), ..
-                } => match key {
-                    sdl2::keyboard::Keycode::W => joypad.up = true,
-                    sdl2::keyboard::Keycode::S => joypad.down = true,
-                    sdl2::keyboard::Keycode::D => joypad.right = true,
-                    sdl2::keyboard::Keycode::A => joypad.left = true,
-                    sdl2::keyboard::Keycode::J => joypad.a = true,
-                    sdl2::keyboard::Keycode::K => joypad.b = true,
-                    sdl2::keyboard::Keycode::Semicolon => joypad.start = true,
-                    sdl2::keyboard::Keycode::Space => joypad.select = true,
-                    _ => {}
-                },
+                } => {
+                    if let Some(button) = keymap.get(&key) {
+                        button.set(&mut joypad, true);
+                    }
+                    match key {
+                        Keycode::PageUp => rom_offset -= 1,
+                        Keycode::PageDown => rom_offset += 1,
+                        Keycode::Tab => fast_forward = true,
+                        _ => {}
+                    }
+                }
 
                 sdl2::event::Event::KeyUp {
                     keycode: Some(key), ..
-                } => match key {
-                    sdl2::keyboard::Keycode::W => joypad.up = false,
-                    sdl2::keyboard::Keycode::S => joypad.down = false,
-                    sdl2::keyboard::Keycode::D => joypad.right = false,
-                    sdl2::keyboard::Keycode::A => joypad.left = false,
-                    sdl2::keyboard::Keycode::J => joypad.a = false,
-                    sdl2::keyboard::Keycode::K => joypad.b = false,
-                    sdl2::keyboard::Keycode::Semicolon => joypad.start = false,
-                    sdl2::keyboard::Keycode::Space => joypad.select = false,
-                    _ => {}
-                },
+                } => {
+                    if let Some(button) = keymap.get(&key) {
+                        button.set(&mut joypad, false);
+                    }
+                    if key == Keycode::Tab {
+                        fast_forward = false;
+                        // Pace the next frames from now on, instead of catching up with the
+                        // deadlines missed while fast forwarding
+                        next_deadline = Instant::now();
+                    }
+                }
 
                 _ => {}
             }
         }
 
+        let next_rom = playlist.index_at(rom_offset);
+        if next_rom != playlist.current {
+            let rom_path = &playlist.roms[next_rom];
+            match switch_cartridge(&mut rusty_boy, (playlist.current(), &save_key), rom_path) {
+                Ok(key) => {
+                    log::info!("Switched to {}", rom_path.display());
+                    save_key = key;
+                    playlist.current = next_rom;
+                    window.set_title(&playlist.title())?;
+                }
+                Err(e) => log::error!("Unable to switch to {}: {e:#}", rom_path.display()),
+            }
+        }
+
         rusty_boy.update_keys(&joypad);
 
         let frame = {
             let frame_start = Instant::now();
 
-            #[cfg(feature = "approximate")]
-            rusty_boy.run_until_next_frame(false);
-
-            let frame = rusty_boy.run_until_next_frame(true);
+            let skipped_frames = if fast_forward {
+                args.fast_forward_skip
+            } else {
+                0
+            };
+            let result = (0..skipped_frames)
+                .try_for_each(|_| rusty_boy.run_until_next_frame(false).map(|_| ()))
+                .and_then(|()| rusty_boy.run_frame());
+            let frame = match result {
+                Ok(frame) => frame,
+                Err(e) => {
+                    log::error!("Emulation stopped: {e}");
+                    break 'running;
+                }
+            };
             let frame_end = Instant::now();
             load += frame_end - frame_start;
             frame
@@ -252,34 +656,107 @@ fn main() -> anyhow::Result<()> {
         });
         surface.finish().unwrap();
 
+        match &mut audio {
+            Some(audio) if !fast_forward => audio.queue_samples(&mut rusty_boy)?,
+            // Nothing is played while fast forwarding or without an audio device
+            _ => discard_samples(&mut rusty_boy),
+        }
+
         {
             let now = Instant::now();
             let duration = now - start;
             if duration > Duration::from_secs(1) {
                 let load_pct = load.as_nanos() as f64 / duration.as_nanos() as f64 * 100.0;
                 log::info!("CPU usage is {} %", load_pct);
+                #[cfg(feature = "profile")]
+                {
+                    let timing = rusty_boy.last_frame_timing();
+                    log::info!(
+                        "Last frame took {} us: CPU {} us, PPU {} us, timer and DMA {} us",
+                        timing.total / 1000,
+                        timing.cpu / 1000,
+                        timing.ppu / 1000,
+                        timing.timer_and_dma / 1000
+                    );
+                }
                 start = now;
                 load = Duration::from_secs(0);
             }
         }
 
-        #[cfg(feature = "approximate")]
-        const FRAME_TIME: Duration = Duration::from_nanos(33_333_333); // 30 fps
-        #[cfg(not(feature = "approximate"))]
-        const FRAME_TIME: Duration = Duration::from_nanos(16_666_667); // 60 fps
+        match &audio {
+            _ if fast_forward => {}
+            Some(audio) => audio.wait_for_backlog(),
+            None => {
+                next_deadline += FRAME_TIME * (rusty_boy.frame_skip() as u32 + 1);
+                sleep_until(next_deadline);
+            }
+        }
 
-        next_deadline += FRAME_TIME;
+        frame_id += 1;
+    }
 
-        sleep_until(next_deadline);
+    store_save_file(&mut rusty_boy, playlist.current(), &save_key)?;
 
-        frame_id += 1;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rom_listing_keeps_game_boy_roms() {
+        let paths = [
+            "b.gbc",
+            "a.GB",
+            "notes.txt",
+            "c.gb.save",
+            "save.sav",
+            "gb",
+            "d.gb",
+        ]
+        .map(|name| Path::new("roms").join(name));
+        assert_eq!(
+            filter_roms(paths),
+            ["a.GB", "b.gbc", "d.gb"].map(|name| Path::new("roms").join(name))
+        );
     }
 
-    if rusty_boy.supports_battery_backed_ram() {
-        if let Some(ram) = rusty_boy.get_cartridge_ram() {
-            save_file(&args.rom_path, ram)?;
-        }
+    #[test]
+    fn keymap_parsing() {
+        let keymap = parse_keymap(
+            "# Arrows for the D-pad\nUp = \"up\" # Up arrow\n'Down' = 'down'\n\n\";\" = \"Start\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            keymap,
+            HashMap::from([
+                (Keycode::Up, Button::Up),
+                (Keycode::Down, Button::Down),
+                (Keycode::Semicolon, Button::Start),
+            ])
+        );
+
+        let error = parse_keymap("Up = \"up\"\nX = \"turbo\"").unwrap_err();
+        assert_eq!(error.to_string(), "Invalid keymap entry for key `X`");
+        assert!(error
+            .root_cause()
+            .to_string()
+            .contains("Unknown button `turbo`"));
+        assert!(parse_keymap("Up").is_err());
+        assert!(parse_keymap("NotAKey = \"a\"").is_err());
     }
 
-    Ok(())
+    #[test]
+    fn playlist_wraps_around() {
+        let playlist = Playlist {
+            roms: filter_roms(["a.gb", "b.gb", "c.gb"].map(PathBuf::from)),
+            current: 0,
+        };
+        assert_eq!(playlist.index_at(1), 1);
+        assert_eq!(playlist.index_at(-1), 2);
+        assert_eq!(playlist.index_at(3), 0);
+        assert_eq!(playlist.title(), "rusty-boy - a (1/3)");
+    }
 }
@@ -1,8 +1,14 @@
 use core::iter::Cloned;
 use core::slice::Iter;
 
+extern crate alloc;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
+use alloc::vec::Vec;
+
 use cartridge::header::CartridgeHeader;
 
+use sm83::core::{Flag, Registers};
 use sm83::decoder::{Bit, Condition, Register, RegisterPair, ResetTarget};
 
 use sm83::memory::Memory;
@@ -28,6 +34,11 @@ impl<'a> Disassembler<'a> {
         Self { data }
     }
 
+    /// Disassembles the full ROM image of the given cartridge.
+    pub fn from_cartridge(cartridge: &'a cartridge::Cartridge) -> Self {
+        Self::new(cartridge.rom())
+    }
+
     pub fn header(&'a self) -> Result<CartridgeHeader<'a>, Error> {
         Ok(CartridgeHeader::try_new(self.data)?)
     }
@@ -56,10 +67,141 @@ impl<'a> Disassembler<'a> {
             entrypoint as usize,
         ))
     }
+
+    /// Builds the control flow graph of the code that is statically reachable from the
+    /// entrypoint at 0x100. Jumps, relative jumps, calls and resets with known targets are
+    /// followed, while `ret`, `reti` and `jp HL` end the flow. The switchable ROM bank is assumed
+    /// to be bank 1, which is the one mapped at boot.
+    pub fn control_flow_graph(&self) -> ControlFlowGraph {
+        ControlFlowGraph::build(self.data, 0x100)
+    }
+
+    fn decode_at(data: &[u8], address: u16) -> Option<(Instruction, u16)> {
+        // Only code in ROM can be disassembled statically
+        if address >= 0x8000 {
+            return None;
+        }
+
+        let mut iter = InstructionIter::new(data.get(address as usize..)?, address as usize);
+        let (_, insn) = iter.next()?;
+        Some((insn, iter.address() as u16))
+    }
+}
+
+/// Where control may go after executing an instruction
+struct Flow {
+    /// Absolute address targeted by the instruction, if any
+    target: Option<u16>,
+    /// Whether execution may continue with the next instruction
+    falls_through: bool,
 }
 
-pub fn disassemble_single_inst(
-    memory: &mut crate::memory::GbAddressSpace,
+impl Flow {
+    fn of(insn: &Instruction, next: u16) -> Self {
+        let (target, falls_through) = match insn {
+            Instruction::JpImm(cond, _) => (insn.target_address(), cond.is_some()),
+            Instruction::JrImm(cond, offset) => (
+                Some(next.wrapping_add_signed(*offset as i16)),
+                cond.is_some(),
+            ),
+            Instruction::CallImm(..) | Instruction::Reset(_) => (insn.target_address(), true),
+            Instruction::Ret(cond) => (None, cond.is_some()),
+            Instruction::Reti | Instruction::JpHl | Instruction::Illegal => (None, false),
+            _ => (None, true),
+        };
+        Self {
+            target,
+            falls_through,
+        }
+    }
+
+    fn is_branch(&self) -> bool {
+        self.target.is_some() || !self.falls_through
+    }
+}
+
+/// A sequence of instructions that is only entered through the first one and only left through
+/// the last one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BasicBlock {
+    /// Address of the first instruction of the block
+    pub start: u16,
+    /// Address right after the last instruction of the block
+    pub end: u16,
+    /// Start addresses of the blocks that may execute after this one
+    pub successors: Vec<u16>,
+}
+
+/// The statically reachable basic blocks of a program, indexed by their start address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControlFlowGraph {
+    pub blocks: BTreeMap<u16, BasicBlock>,
+}
+
+impl ControlFlowGraph {
+    fn build(data: &[u8], entrypoint: u16) -> Self {
+        // First, find all reachable instructions and the addresses where blocks start.
+        let mut leaders = BTreeSet::from([entrypoint]);
+        let mut visited = BTreeSet::new();
+        let mut pending = Vec::from([entrypoint]);
+        while let Some(mut address) = pending.pop() {
+            while visited.insert(address) {
+                let Some((insn, next)) = Disassembler::decode_at(data, address) else {
+                    visited.remove(&address);
+                    break;
+                };
+
+                let flow = Flow::of(&insn, next);
+                if let Some(target) = flow.target {
+                    leaders.insert(target);
+                    pending.push(target);
+                }
+                if !flow.falls_through {
+                    break;
+                }
+                if flow.is_branch() {
+                    leaders.insert(next);
+                }
+                address = next;
+            }
+        }
+
+        // Then, split the reachable instructions into blocks at each leader.
+        let mut blocks = BTreeMap::new();
+        for &start in leaders.iter().filter(|leader| visited.contains(leader)) {
+            let mut address = start;
+            let successors = loop {
+                let (insn, next) = Disassembler::decode_at(data, address)
+                    .expect("Visited instructions can be decoded");
+                let flow = Flow::of(&insn, next);
+                address = next;
+
+                let falls_through = flow.falls_through && visited.contains(&next);
+                if flow.is_branch() || !falls_through || leaders.contains(&next) {
+                    let mut successors: Vec<u16> = flow.target.into_iter().collect();
+                    if falls_through {
+                        successors.push(next);
+                    }
+                    break successors;
+                }
+            };
+
+            blocks.insert(
+                start,
+                BasicBlock {
+                    start,
+                    end: address,
+                    successors,
+                },
+            );
+        }
+
+        Self { blocks }
+    }
+}
+
+pub fn disassemble_single_inst<T: Memory + ?Sized>(
+    memory: &T,
     addr: sm83::memory::Address,
 ) -> Instruction {
     // An instruction is at most 3 bytes
@@ -175,6 +317,56 @@ pub enum Instruction {
 }
 
 impl Instruction {
+    /// Returns the condition of a conditional branch instruction. Unconditional branches and
+    /// other instructions return `None`.
+    pub fn branch_condition(&self) -> Option<Condition> {
+        match self {
+            Instruction::JrImm(cond, _)
+            | Instruction::Ret(cond)
+            | Instruction::JpImm(cond, _)
+            | Instruction::CallImm(cond, _) => *cond,
+            _ => None,
+        }
+    }
+
+    /// Returns whether a conditional branch would be taken with the given CPU state, or `None`
+    /// if the instruction is not a conditional branch.
+    pub fn branch_taken(&self, regs: &Registers) -> Option<bool> {
+        self.branch_condition().map(|cond| match cond {
+            Condition::Z => regs.flags.is_flag_set(Flag::Z),
+            Condition::NZ => !regs.flags.is_flag_set(Flag::Z),
+            Condition::C => regs.flags.is_flag_set(Flag::C),
+            Condition::NC => !regs.flags.is_flag_set(Flag::C),
+        })
+    }
+
+    /// Returns the absolute address targeted by jumps, calls and resets.
+    pub fn target_address(&self) -> Option<u16> {
+        match self {
+            Instruction::JpImm(_, address) | Instruction::CallImm(_, address) => Some(*address),
+            Instruction::Reset(target) => Some(Self::reset_target_address(*target)),
+            _ => None,
+        }
+    }
+
+    /// Returns a displayable version of the instruction that annotates the target of jumps, calls
+    /// and resets with its symbol, if any.
+    pub fn symbolize<'a>(&'a self, symbols: &'a SymbolTable) -> SymbolizedInstruction<'a> {
+        SymbolizedInstruction {
+            instruction: self,
+            symbols,
+        }
+    }
+
+    /// Returns a displayable version of the instruction that annotates conditional branches
+    /// with whether they would be taken with the given CPU state.
+    pub fn annotate<'a>(&'a self, regs: &'a Registers) -> AnnotatedInstruction<'a> {
+        AnnotatedInstruction {
+            instruction: self,
+            regs,
+        }
+    }
+
     fn reg_to_repr(reg: Register) -> &'static str {
         match reg {
             Register::A => "A",
@@ -208,16 +400,16 @@ impl Instruction {
         }
     }
 
-    fn reset_target_to_repr(target: ResetTarget) -> &'static str {
+    fn reset_target_address(target: ResetTarget) -> u16 {
         match target {
-            ResetTarget::Addr0x00 => "0x00",
-            ResetTarget::Addr0x08 => "0x08",
-            ResetTarget::Addr0x10 => "0x10",
-            ResetTarget::Addr0x18 => "0x18",
-            ResetTarget::Addr0x20 => "0x20",
-            ResetTarget::Addr0x28 => "0x28",
-            ResetTarget::Addr0x30 => "0x30",
-            ResetTarget::Addr0x38 => "0x38",
+            ResetTarget::Addr0x00 => 0x00,
+            ResetTarget::Addr0x08 => 0x08,
+            ResetTarget::Addr0x10 => 0x10,
+            ResetTarget::Addr0x18 => 0x18,
+            ResetTarget::Addr0x20 => 0x20,
+            ResetTarget::Addr0x28 => 0x28,
+            ResetTarget::Addr0x30 => 0x30,
+            ResetTarget::Addr0x38 => 0x38,
         }
     }
 
@@ -255,6 +447,65 @@ impl Instruction {
     }
 }
 
+/// Maps addresses to symbol names
+#[derive(Default)]
+pub struct SymbolTable {
+    symbols: BTreeMap<u16, String>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a symbol for the given address, replacing any previous symbol.
+    pub fn insert(&mut self, address: u16, name: impl Into<String>) {
+        self.symbols.insert(address, name.into());
+    }
+
+    /// Returns the symbol registered for the given address, if any.
+    pub fn lookup(&self, address: u16) -> Option<&str> {
+        self.symbols.get(&address).map(|name| name.as_str())
+    }
+}
+
+/// An instruction annotated with symbols. See [`Instruction::symbolize`].
+pub struct SymbolizedInstruction<'a> {
+    instruction: &'a Instruction,
+    symbols: &'a SymbolTable,
+}
+
+impl core::fmt::Display for SymbolizedInstruction<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.instruction)?;
+        match self
+            .instruction
+            .target_address()
+            .and_then(|address| self.symbols.lookup(address))
+        {
+            Some(symbol) => write!(f, " <{symbol}>"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// An instruction annotated with live CPU state. See [`Instruction::annotate`].
+pub struct AnnotatedInstruction<'a> {
+    instruction: &'a Instruction,
+    regs: &'a Registers,
+}
+
+impl core::fmt::Display for AnnotatedInstruction<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.instruction)?;
+        match self.instruction.branch_taken(self.regs) {
+            Some(true) => write!(f, " (taken)"),
+            Some(false) => write!(f, " (not taken)"),
+            None => Ok(()),
+        }
+    }
+}
+
 impl core::fmt::Display for Instruction {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
@@ -379,7 +630,7 @@ impl core::fmt::Display for Instruction {
                 }
             }
             Instruction::Reset(target) => {
-                write!(f, "rst {}", Self::reset_target_to_repr(*target))
+                write!(f, "rst {:#04x}", Self::reset_target_address(*target))
             }
             Instruction::Pop(reg) => {
                 write!(f, "pop {}", Self::reg_pair_to_repr(*reg))
@@ -459,20 +710,40 @@ impl core::fmt::Display for Instruction {
     }
 }
 
+/// Counts the bytes consumed from the inner iterator, so that the address of each instruction is
+/// known regardless of how its operands are decoded.
+struct CountingIter<T> {
+    iter: T,
+    count: usize,
+}
+
+impl<T> Iterator for CountingIter<T>
+where
+    T: Iterator<Item = u8>,
+{
+    type Item = u8;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let next = self.iter.next()?;
+        self.count += 1;
+        Some(next)
+    }
+}
+
 pub struct InstructionIter<T>
 where
     T: Iterator<Item = u8>,
 {
-    address: usize,
-    iter: T,
+    base: usize,
+    iter: CountingIter<T>,
 }
 
 impl<'a> InstructionIter<Cloned<Iter<'a, u8>>> {
     pub fn new(data: &'a [u8], base: usize) -> Self {
         let iter = data.iter().cloned();
         Self {
-            iter,
-            address: base,
+            iter: CountingIter { iter, count: 0 },
+            base,
         }
     }
 }
@@ -481,23 +752,24 @@ impl<T> InstructionIter<T>
 where
     T: Iterator<Item = u8>,
 {
+    /// Returns the address of the next instruction
+    pub fn address(&self) -> usize {
+        self.base + self.iter.count
+    }
+
     fn next_with_address(&mut self) -> Option<(usize, u8)> {
+        let addr = self.address();
         let next = self.iter.next()?;
-        let addr = self.address;
-        self.address += 1;
         Some((addr, next))
     }
 
     fn read_8_bit_imm(&mut self) -> Option<u8> {
-        let next = self.iter.next()?;
-        self.address += 1;
-        Some(next)
+        self.iter.next()
     }
 
     fn read_16_bit_imm(&mut self) -> Option<u16> {
         let lo = self.iter.next()?;
         let hi = self.iter.next()?;
-        self.address += 2;
         Some(lo as u16 | ((hi as u16) << 8))
     }
 }
@@ -643,3 +915,142 @@ where
         Some((addr, insn))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern crate alloc;
+    use alloc::string::ToString;
+
+    fn regs_with_zero_flag(z: bool) -> Registers {
+        let mut cpu = sm83::core::Cpu::new();
+        cpu.get_mut_regs().flags = sm83::core::Flags::new().with(Flag::Z, z);
+        cpu.get_regs().clone()
+    }
+
+    #[test]
+    fn annotate_conditional_branch() {
+        // jr z, PC + 4
+        let (_, insn) = InstructionIter::new(&[0x28, 0x04], 0).next().unwrap();
+
+        let regs = regs_with_zero_flag(true);
+        assert_eq!(insn.branch_taken(&regs), Some(true));
+        assert_eq!(insn.annotate(&regs).to_string(), "jr Z, PC + 4 (taken)");
+
+        let regs = regs_with_zero_flag(false);
+        assert_eq!(insn.branch_taken(&regs), Some(false));
+        assert_eq!(insn.annotate(&regs).to_string(), "jr Z, PC + 4 (not taken)");
+    }
+
+    #[test]
+    fn assembled_program_round_trip() {
+        use sm83::asm;
+        use sm83::decoder::Register;
+
+        let program = [
+            &asm::ld_a_imm(0x80)[..],
+            &asm::ldh_a8_a(0x40),
+            &asm::ld_hl_imm(0xC000),
+            &asm::inc_ind_hl(),
+            &asm::cp_a_imm(0x10),
+            &asm::jr_cond(Condition::NZ, -5),
+            &asm::ld_r_r(Register::B, Register::A),
+            &asm::call(0x0150),
+            &asm::rst(ResetTarget::Addr0x38),
+            &asm::jp(0x0100),
+        ]
+        .concat();
+
+        let disassembly: alloc::vec::Vec<_> = InstructionIter::new(&program, 0)
+            .map(|(_, insn)| insn.to_string())
+            .collect();
+        assert_eq!(
+            disassembly,
+            [
+                "ld A 0x80",
+                "ld [0xFF00 + 0x40] A",
+                "ld HL 0xc000",
+                "inc [HL]",
+                "cp A 0x10",
+                "jr NZ, PC + -5",
+                "ld B A",
+                "call 0x150",
+                "rst 0x38",
+                "jp 0x100",
+            ]
+        );
+    }
+
+    #[test]
+    fn control_flow_graph_with_loop_and_call() {
+        use sm83::asm;
+
+        let mut rom = alloc::vec![0; 0x8000];
+        let mut place = |address: usize, code: &[&[u8]]| {
+            let code = code.concat();
+            rom[address..address + code.len()].copy_from_slice(&code);
+        };
+        place(0x100, &[&asm::nop(), &asm::jp(0x150)]);
+        place(
+            0x150,
+            &[
+                &asm::call(0x160),
+                &asm::dec_r(Register::A),
+                &asm::jr_cond(Condition::NZ, -3),
+                &asm::halt(),
+                &asm::jr(-2),
+            ],
+        );
+        place(0x160, &[&asm::ld_a_imm(4), &asm::ret()]);
+
+        let cfg = Disassembler::new(&rom).control_flow_graph();
+        let blocks: alloc::vec::Vec<_> = cfg
+            .blocks
+            .values()
+            .map(|block| (block.start, block.end, block.successors.as_slice()))
+            .collect();
+        assert_eq!(
+            blocks,
+            [
+                (0x100, 0x104, &[0x150][..]),
+                (0x150, 0x153, &[0x160, 0x153]),
+                (0x153, 0x156, &[0x153, 0x156]),
+                (0x156, 0x157, &[0x157]),
+                (0x157, 0x159, &[0x157]),
+                (0x160, 0x163, &[]),
+            ]
+        );
+    }
+
+    #[test]
+    fn reset_target_symbol() {
+        // rst 0x28
+        let (_, insn) = InstructionIter::new(&[0xEF], 0).next().unwrap();
+        assert_eq!(insn.to_string(), "rst 0x28");
+        assert_eq!(insn.target_address(), Some(0x28));
+
+        let mut symbols = SymbolTable::new();
+        assert_eq!(insn.symbolize(&symbols).to_string(), "rst 0x28");
+
+        symbols.insert(0x28, "jump_table");
+        assert_eq!(
+            insn.symbolize(&symbols).to_string(),
+            "rst 0x28 <jump_table>"
+        );
+
+        // rst 0x00 keeps both digits
+        let (_, insn) = InstructionIter::new(&[0xC7], 0).next().unwrap();
+        assert_eq!(insn.to_string(), "rst 0x00");
+    }
+
+    #[test]
+    fn annotate_unconditional_instruction() {
+        // jr PC + 4
+        let (_, insn) = InstructionIter::new(&[0x18, 0x04], 0).next().unwrap();
+
+        let regs = regs_with_zero_flag(true);
+        assert_eq!(insn.branch_taken(&regs), None);
+        assert_eq!(insn.annotate(&regs).to_string(), insn.to_string());
+    }
+}
@@ -1,8 +1,102 @@
+use sm83::state::{SaveState, StateError, StateReader, StateWriter};
+
+extern crate alloc;
+use alloc::boxed::Box;
+
+/// Number of bytes in a Super Game Boy command packet
+pub const SGB_PACKET_LEN: usize = 16;
+
+const SGB_PACKET_BITS: usize = SGB_PACKET_LEN * 8;
+
+/// P1 select lines, in the bit positions of the register
+const P14: u8 = 0x10;
+const P15: u8 = 0x20;
+
+/// A command packet sent by the game to the Super Game Boy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SgbPacket(pub [u8; SGB_PACKET_LEN]);
+
+impl SgbPacket {
+    /// Returns the command code. Only meaningful in the first packet of a command.
+    pub fn command(&self) -> u8 {
+        self.0[0] >> 3
+    }
+
+    /// Returns the number of packets that make up the command. Only meaningful in the first
+    /// packet of a command.
+    pub fn packet_count(&self) -> usize {
+        (self.0[0] & 0x07) as usize
+    }
+}
+
+/// Callback invoked with every packet the game sends to the Super Game Boy
+pub type SgbListener = Box<dyn FnMut(&SgbPacket)>;
+
+/// Reassembles Super Game Boy packets from the pulses the game writes to P1.
+///
+/// A packet starts with a reset pulse, where both select lines are driven low. Then each bit is
+/// sent LSB first by driving P14 low for a 0 or P15 low for a 1, releasing both lines after
+/// every bit. The packet finishes with a 0 stop bit.
+struct SgbPacketDetector {
+    /// Number of bits received in the current packet, or `None` if there isn't any in progress
+    received_bits: Option<usize>,
+    data: [u8; SGB_PACKET_LEN],
+    /// Whether the select lines were released after the last bit
+    released: bool,
+}
+
+impl SgbPacketDetector {
+    const fn new() -> Self {
+        Self {
+            received_bits: None,
+            data: [0; SGB_PACKET_LEN],
+            released: false,
+        }
+    }
+
+    /// Processes a write to P1, returning the packet it completes, if any.
+    fn write(&mut self, value: u8) -> Option<SgbPacket> {
+        let bit = match value & (P14 | P15) {
+            0 => {
+                self.received_bits = Some(0);
+                self.data = [0; SGB_PACKET_LEN];
+                self.released = false;
+                return None;
+            }
+            lines if lines == P14 | P15 => {
+                self.released = true;
+                return None;
+            }
+            // P15 low
+            P14 => 1,
+            // P14 low
+            _ => 0,
+        };
+
+        // Holding a line for several writes still sends a single bit
+        if !core::mem::replace(&mut self.released, false) {
+            return None;
+        }
+
+        let received_bits = self.received_bits?;
+        if received_bits == SGB_PACKET_BITS {
+            self.received_bits = None;
+            return (bit == 0).then_some(SgbPacket(self.data));
+        }
+
+        self.data[received_bits / 8] |= bit << (received_bits % 8);
+        self.received_bits = Some(received_bits + 1);
+        None
+    }
+}
+
 pub struct Joypad {
     buttons: u8,
     dpad: u8,
     sel_buttons: bool,
     sel_dpad: bool,
+    sgb_detector: SgbPacketDetector,
+    sgb_listener: Option<SgbListener>,
 }
 
 impl Joypad {
@@ -13,8 +107,25 @@ impl Joypad {
             dpad: 0xf,
             sel_dpad: false,
             sel_buttons: false,
+            sgb_detector: SgbPacketDetector::new(),
+            sgb_listener: None,
         }
     }
+
+    /// Releases all buttons and deselects both lines, keeping the SGB packet listener.
+    pub fn reset(&mut self) {
+        *self = Self {
+            sgb_listener: self.sgb_listener.take(),
+            ..Self::new()
+        };
+    }
+
+    /// Registers a callback that receives the Super Game Boy packets sent by the game, replacing
+    /// the previous one, if any. Packets are only detected while there is a listener.
+    pub fn on_sgb_packet(&mut self, listener: Option<SgbListener>) {
+        self.sgb_listener = listener;
+        self.sgb_detector = SgbPacketDetector::new();
+    }
     pub fn update_buttons(&mut self, state: &State) {
         let to_bit = |val: bool, bit: usize| -> u8 {
             if val {
@@ -35,6 +146,21 @@ impl Joypad {
             & to_bit(state.down, 3);
     }
 
+    /// Returns the state of the buttons, as seen by the game when reading the joypad register.
+    pub fn buttons_state(&self) -> State {
+        let pressed = |lines: u8, bit: usize| lines & (1 << bit) == 0;
+        State {
+            a: pressed(self.buttons, 0),
+            b: pressed(self.buttons, 1),
+            select: pressed(self.buttons, 2),
+            start: pressed(self.buttons, 3),
+            right: pressed(self.dpad, 0),
+            left: pressed(self.dpad, 1),
+            up: pressed(self.dpad, 2),
+            down: pressed(self.dpad, 3),
+        }
+    }
+
     pub fn read(&self, _: sm83::memory::Address) -> u8 {
         if self.sel_dpad && self.sel_buttons {
             self.dpad & self.buttons
@@ -47,12 +173,42 @@ impl Joypad {
         }
     }
 
+    /// Returns true if a button is pressed in any of the lines selected in P1. This is what wakes
+    /// the CPU up from STOP.
+    pub fn selected_line_low(&self) -> bool {
+        let buttons = if self.sel_buttons { self.buttons } else { 0xf };
+        let dpad = if self.sel_dpad { self.dpad } else { 0xf };
+        buttons & dpad != 0xf
+    }
+
     pub fn write(&mut self, _: sm83::memory::Address, value: u8) {
         self.sel_dpad = value & 0x10 == 0;
         self.sel_buttons = value & 0x20 == 0;
+
+        if let Some(listener) = &mut self.sgb_listener {
+            if let Some(packet) = self.sgb_detector.write(value) {
+                log::debug!("SGB packet: command {:#04x}", packet.command());
+                listener(&packet);
+            }
+        }
+    }
+}
+
+/// Only the line selection is saved. The state of the buttons follows the input of the frontend.
+impl SaveState for Joypad {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.sel_buttons);
+        state.write_bool(self.sel_dpad);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.sel_buttons = state.read_bool()?;
+        self.sel_dpad = state.read_bool()?;
+        Ok(())
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct State {
     pub left: bool,
     pub right: bool,
@@ -78,3 +234,120 @@ impl State {
         }
     }
 }
+
+/// A button press that is held for a fixed number of emulated frames and then released
+/// automatically. Useful for frontends that trigger buttons from events without a release, like
+/// menu items or on-screen buttons.
+pub struct PulsedInput {
+    hold_frames: usize,
+    remaining_frames: usize,
+}
+
+impl PulsedInput {
+    /// Creates a released input that holds the button for `hold_frames` frames when pressed.
+    pub const fn new(hold_frames: usize) -> Self {
+        Self {
+            hold_frames,
+            remaining_frames: 0,
+        }
+    }
+
+    /// Presses the button. If the button was already pressed, the hold period starts over.
+    pub fn press(&mut self) {
+        self.remaining_frames = self.hold_frames;
+    }
+
+    /// Returns true while the button is held.
+    pub fn is_pressed(&self) -> bool {
+        self.remaining_frames > 0
+    }
+
+    /// Accounts for the given number of emulated frames, releasing the button once the hold
+    /// period elapses.
+    pub fn advance(&mut self, frames: usize) {
+        self.remaining_frames = self.remaining_frames.saturating_sub(frames);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use alloc::rc::Rc;
+    use alloc::vec;
+    use core::cell::RefCell;
+
+    fn send_sgb_packet(joypad: &mut Joypad, data: &[u8; SGB_PACKET_LEN]) {
+        joypad.write(0xFF00, 0x00);
+        joypad.write(0xFF00, 0x30);
+        for byte in data {
+            for bit in 0..8 {
+                let value = if byte & (1 << bit) != 0 { 0x10 } else { 0x20 };
+                joypad.write(0xFF00, value);
+                joypad.write(0xFF00, 0x30);
+            }
+        }
+        // Stop bit
+        joypad.write(0xFF00, 0x20);
+        joypad.write(0xFF00, 0x30);
+    }
+
+    #[test]
+    fn sgb_packet_detection() {
+        // MLT_REQ, requesting 2 players
+        let mut mlt_req = [0; SGB_PACKET_LEN];
+        mlt_req[0] = 0x89;
+        mlt_req[1] = 0x01;
+        // Second packet of a multi-packet command, with every bit pattern
+        let data: [u8; SGB_PACKET_LEN] = core::array::from_fn(|index| (index as u8) * 0x11);
+
+        let mut joypad = Joypad::new();
+        let packets = Rc::new(RefCell::new(vec![]));
+        let listener_packets = packets.clone();
+        joypad.on_sgb_packet(Some(Box::new(move |packet| {
+            listener_packets.borrow_mut().push(*packet)
+        })));
+
+        // Regular joypad polling doesn't produce packets
+        for value in [0x20, 0x30, 0x10, 0x30] {
+            joypad.write(0xFF00, value);
+        }
+        assert!(packets.borrow().is_empty());
+
+        send_sgb_packet(&mut joypad, &mlt_req);
+        send_sgb_packet(&mut joypad, &data);
+        assert_eq!(*packets.borrow(), [SgbPacket(mlt_req), SgbPacket(data)]);
+        assert_eq!(packets.borrow()[0].command(), 0x11);
+        assert_eq!(packets.borrow()[0].packet_count(), 1);
+
+        // Packets with an invalid stop bit are dropped
+        joypad.write(0xFF00, 0x00);
+        joypad.write(0xFF00, 0x30);
+        for _ in 0..=SGB_PACKET_BITS {
+            joypad.write(0xFF00, 0x10);
+            joypad.write(0xFF00, 0x30);
+        }
+        assert_eq!(packets.borrow().len(), 2);
+    }
+
+    #[test]
+    fn pulsed_input_auto_releases() {
+        const HOLD_FRAMES: usize = 5;
+        let mut input = PulsedInput::new(HOLD_FRAMES);
+        assert!(!input.is_pressed());
+
+        input.press();
+        for _ in 0..HOLD_FRAMES {
+            assert!(input.is_pressed());
+            input.advance(1);
+        }
+        assert!(!input.is_pressed());
+
+        // Advancing more frames than remaining releases the button as well
+        input.press();
+        input.advance(2);
+        assert!(input.is_pressed());
+        input.advance(HOLD_FRAMES);
+        assert!(!input.is_pressed());
+    }
+}
@@ -1,43 +1,485 @@
 #![no_std]
 
+#[cfg(any(test, feature = "std"))]
+extern crate std;
+
+extern crate alloc;
+
 pub mod disassembler;
 pub mod joypad;
 pub mod memory;
+pub mod profiling;
+pub mod save_state;
+pub mod serial;
+pub mod trace;
+pub mod video;
 
-use crate::memory::GbAddressSpace;
+use crate::memory::{
+    Bus, GbAddressSpace, LcdListener, OpenBus, RamInit, RomWriteTrace, WatchedBus, BOOT_ROM_SIZE,
+};
+use crate::profiling::{FrameTiming, Profiler, ProfilingClock};
+use crate::save_state::SaveStateError;
+use crate::serial::{LinkClock, SerialLink};
+use crate::trace::{TraceMismatch, TraceRecord};
+use crate::video::layout::{FrameLayout, LayoutError, PixelFormat};
 
+use alloc::boxed::Box;
+use alloc::collections::BTreeSet;
+use alloc::vec::Vec;
 use cartridge::Cartridge;
-use ppu::{dma::DmaEngine, Color, PpuResult, DISPLAY_HEIGHT, DISPLAY_WIDTH};
-use sm83::core::{Cpu, Cycles};
+use ppu::{FrameBuf, PpuResult};
+use sm83::{
+    core::{Cpu, Cycles, ExitReason},
+    interrupts::{Interrupt, Interrupts},
+    memory::Memory,
+    state::{SaveState, StateError, StateReader, StateWriter},
+};
+
+/// Errors that stop the emulated system from making progress
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmulationError {
+    /// The CPU attempted to execute an illegal opcode. Just like real hardware, the CPU locks up
+    /// and stops executing instructions, while the rest of the system keeps running.
+    IllegalOpcode {
+        /// Address of the illegal opcode
+        address: u16,
+        /// The offending opcode
+        opcode: u8,
+    },
+}
+
+impl core::fmt::Display for EmulationError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            EmulationError::IllegalOpcode { address, opcode } => {
+                write!(f, "illegal opcode {opcode:#04x} at address {address:#06x}")
+            }
+        }
+    }
+}
+
+#[cfg(any(test, feature = "std"))]
+impl std::error::Error for EmulationError {}
+
+/// Presets that trade emulation accuracy for speed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AccuracyProfile {
+    /// Peripherals are updated after every CPU machine cycle, and every frame is rendered.
+    #[default]
+    Accurate,
+    /// Peripherals are updated after running the CPU for a long time, but never past a PPU mode
+    /// change, so that no LCD interrupts are missed. Every frame is rendered.
+    Balanced,
+    /// Peripherals are updated after running the CPU for a long time, and only every other frame
+    /// is rendered.
+    Fast,
+}
+
+impl AccuracyProfile {
+    fn cycle_step(&self) -> Cycles {
+        match self {
+            AccuracyProfile::Accurate => Cycles::new(4),
+            AccuracyProfile::Balanced | AccuracyProfile::Fast => Cycles::new(60),
+        }
+    }
+
+    fn cap_cpu_step(&self) -> bool {
+        matches!(self, AccuracyProfile::Balanced)
+    }
+
+    fn frame_skip(&self) -> usize {
+        match self {
+            AccuracyProfile::Accurate | AccuracyProfile::Balanced => 0,
+            AccuracyProfile::Fast => 1,
+        }
+    }
+}
+
+/// Interrupt state of the system right after a reset, which is normally left behind by the boot
+/// ROM. Test ROMs sometimes expect specific entry conditions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BootInterrupts {
+    /// Whether the CPU has interrupts globally enabled (IME)
+    pub ime: bool,
+    /// Value of the interrupt enable register (IE)
+    pub enable: u8,
+    /// Value of the interrupt flags register (IF)
+    pub flags: u8,
+}
+
+/// Top-level error type of the emulator, which wraps errors from the different components.
+///
+/// Accesses to unmapped addresses are not errors, as on real hardware: reads return the open bus
+/// value (see [`OpenBus`]) and writes are ignored.
+#[derive(Debug)]
+pub enum RustyBoyError {
+    /// The emulated system stopped making progress
+    Emulation(EmulationError),
+    /// The cartridge reported an error
+    Cartridge(cartridge::Error),
+    /// The boot ROM doesn't have the size of a DMG boot ROM
+    InvalidBootRom {
+        /// Size of the given boot ROM, in bytes
+        len: usize,
+    },
+}
+
+impl From<EmulationError> for RustyBoyError {
+    fn from(value: EmulationError) -> Self {
+        Self::Emulation(value)
+    }
+}
+
+impl From<cartridge::Error> for RustyBoyError {
+    fn from(value: cartridge::Error) -> Self {
+        Self::Cartridge(value)
+    }
+}
+
+impl core::fmt::Display for RustyBoyError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            RustyBoyError::Emulation(_) => write!(f, "emulation error"),
+            RustyBoyError::Cartridge(_) => write!(f, "cartridge error"),
+            RustyBoyError::InvalidBootRom { len } => {
+                write!(f, "boot ROM is {len} bytes long instead of {BOOT_ROM_SIZE}")
+            }
+        }
+    }
+}
+
+#[cfg(any(test, feature = "std"))]
+impl std::error::Error for RustyBoyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RustyBoyError::Emulation(error) => Some(error),
+            RustyBoyError::Cartridge(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// Address where execution of the cartridge starts after the boot ROM
+const ENTRYPOINT: u16 = 0x100;
 
-pub struct RustyBoy {
+/// LCDC as left behind by the boot ROM: LCD and background on, with tile data at 0x8000. Games
+/// expect the PPU to be running when they start, e.g. to wait for VBlank before turning the LCD
+/// off.
+const BOOT_LCDC: u8 = 0x91;
+
+/// Leaves the PPU as the boot ROM does. Games that support the Game Boy Color run with its color
+/// palettes and VRAM banks, like they would on a CGB.
+fn apply_boot_ppu_state(address_space: &mut GbAddressSpace) {
+    let color_mode = address_space
+        .cartridge
+        .try_header()
+        .is_ok_and(|header| header.supports_cgb());
+    address_space.ppu.set_color_mode(color_mode);
+    address_space.ppu.write(0xFF40, BOOT_LCDC);
+}
+
+/// The emulated system. It drives the CPU against a `Bus`, which is the Game Boy address space
+/// by default.
+pub struct RustyBoy<B = GbAddressSpace> {
     cpu: Cpu,
-    dma_engine: DmaEngine,
-    address_space: GbAddressSpace,
+    address_space: B,
     debug: bool,
     cycle_step: Cycles,
+    cap_cpu_step: bool,
+    frame_skip: usize,
+    boot_interrupts: BootInterrupts,
+    vblank_count: usize,
+    cpu_fault: Option<EmulationError>,
+    profiler: Option<Profiler>,
+    breakpoints: BTreeSet<u16>,
+    watchpoints: BTreeSet<u16>,
+    watchpoint_hit: Option<u16>,
 }
 
-impl RustyBoy {
-    pub fn new_with_cartridge(cartridge: Cartridge) -> Self {
-        const ENTRYPOINT: u16 = 0x100;
+impl<B: Bus> RustyBoy<B> {
+    /// Creates an emulator that runs on a custom bus. The CPU starts at the cartridge entrypoint,
+    /// as it does with the Game Boy address space.
+    pub fn new_with_bus(bus: B) -> Self {
+        Self {
+            debug: false,
+            cpu: Self::boot_cpu(),
+            address_space: bus,
+            cycle_step: AccuracyProfile::Accurate.cycle_step(),
+            cap_cpu_step: AccuracyProfile::Accurate.cap_cpu_step(),
+            frame_skip: AccuracyProfile::Accurate.frame_skip(),
+            boot_interrupts: BootInterrupts::default(),
+            vblank_count: 0,
+            cpu_fault: None,
+            profiler: None,
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeSet::new(),
+            watchpoint_hit: None,
+        }
+    }
 
+    fn boot_cpu() -> Cpu {
         let mut cpu = Cpu::new();
         cpu.get_mut_regs().pc_reg = ENTRYPOINT;
+        cpu
+    }
 
-        Self {
-            debug: false,
-            cpu,
-            dma_engine: DmaEngine::new(),
-            address_space: GbAddressSpace::new(cartridge),
-            cycle_step: Cycles::new(4), // Default cycle step for maximum accuracy
-        }
+    /// Returns the bus the CPU runs against.
+    pub fn bus(&self) -> &B {
+        &self.address_space
+    }
+
+    /// Returns the bus the CPU runs against, for modification.
+    pub fn bus_mut(&mut self) -> &mut B {
+        &mut self.address_space
+    }
+
+    fn apply_boot_interrupts(&mut self) {
+        let BootInterrupts { ime, enable, flags } = self.boot_interrupts;
+        self.cpu.get_mut_regs().irq_en = ime;
+        self.address_space.write(0xFFFF, enable);
+        self.address_space.write(0xFF0F, flags);
     }
 
     pub fn enable_debug(&mut self) {
         self.debug = true;
     }
 
+    /// Sets a breakpoint at the given address. Debuggers check `at_breakpoint` between steps to
+    /// stop the execution. Returns false if there was already a breakpoint at the address.
+    pub fn add_breakpoint(&mut self, address: u16) -> bool {
+        self.breakpoints.insert(address)
+    }
+
+    /// Removes the breakpoint at the given address. Returns false if there was none.
+    pub fn remove_breakpoint(&mut self, address: u16) -> bool {
+        self.breakpoints.remove(&address)
+    }
+
+    /// Returns the addresses of all breakpoints, in ascending order.
+    pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    /// Returns true if the next instruction to execute is at a breakpoint.
+    pub fn at_breakpoint(&self) -> bool {
+        self.breakpoints.contains(&self.cpu.get_regs().pc_reg)
+    }
+
+    /// Sets a watchpoint on the given address. Any read or write of the address by the CPU,
+    /// including instruction fetches, stops `step_bounded` and is reported by
+    /// `take_watchpoint_hit`. Returns false if the address was already watched.
+    pub fn add_watchpoint(&mut self, address: u16) -> bool {
+        self.watchpoints.insert(address)
+    }
+
+    /// Removes the watchpoint on the given address. Returns false if there was none.
+    pub fn remove_watchpoint(&mut self, address: u16) -> bool {
+        self.watchpoints.remove(&address)
+    }
+
+    /// Returns the addresses of all watchpoints, in ascending order.
+    pub fn watchpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.watchpoints.iter().copied()
+    }
+
+    /// Returns the watched address accessed by the CPU since the last call, if any, and clears it.
+    /// When several watched addresses are accessed, only the first one is reported.
+    pub fn take_watchpoint_hit(&mut self) -> Option<u16> {
+        self.watchpoint_hit.take()
+    }
+
+    /// Removes all breakpoints and watchpoints.
+    pub fn clear_all_breakpoints(&mut self) {
+        self.breakpoints.clear();
+        self.watchpoints.clear();
+    }
+
+    /// Configures the interrupt state the system starts with after a reset, and applies it right
+    /// away. Useful to reproduce the entry conditions of test ROMs without running a boot ROM.
+    pub fn configure_boot_interrupts(&mut self, interrupts: BootInterrupts) {
+        self.boot_interrupts = interrupts;
+        self.apply_boot_interrupts();
+    }
+
+    /// Steps a single CPU instruction. A halted CPU idles for up to `budget` cycles at once.
+    fn step_cpu(&mut self, budget: Cycles) -> Cycles {
+        if self.cpu_fault.is_some() {
+            // The CPU is locked up, but the rest of the system keeps running.
+            return Cycles::new(4);
+        }
+
+        if self.cpu.is_stopped() && self.address_space.stop_wakeup() {
+            self.cpu.resume_from_stop();
+        }
+
+        if self.debug {
+            let pc = self.cpu.get_regs().pc_reg;
+            let inst = disassembler::disassemble_single_inst(&self.address_space, pc);
+            let regs = self.cpu.get_regs();
+            log::trace!("{pc:#04x} {} -- {regs:x?}", inst.annotate(regs));
+        }
+
+        let interrupts = self.address_space.active_interrupts();
+        let exit_reason = if self.watchpoints.is_empty() {
+            self.cpu
+                .step_with_budget(&mut self.address_space, interrupts, budget)
+        } else {
+            let mut bus = WatchedBus::new(&mut self.address_space, &self.watchpoints);
+            let exit_reason = self.cpu.step_with_budget(&mut bus, interrupts, budget);
+            self.watchpoint_hit = self.watchpoint_hit.or(bus.hit());
+            exit_reason
+        };
+        match exit_reason {
+            ExitReason::Step(cycles) | ExitReason::Stop(cycles) | ExitReason::Halt(cycles) => {
+                cycles
+            }
+            ExitReason::InterruptTaken(cycles, interrupt) => {
+                self.address_space.acknowledge_interrupt(interrupt);
+                cycles
+            }
+            ExitReason::IllegalOpcode => {
+                // Illegal opcodes are never prefixed, so the opcode is right before the PC.
+                let address = self.cpu.get_regs().pc_reg.wrapping_sub(1);
+                let opcode = self.address_space.read(address);
+                log::error!("Illegal CPU opcode {opcode:#04x} at address {address:#06x}");
+                self.cpu_fault = Some(EmulationError::IllegalOpcode { address, opcode });
+                Cycles::new(4)
+            }
+        }
+    }
+
+    fn count_vblanks(&mut self, interrupts: Interrupts) {
+        if (interrupts & Interrupt::Vblank.into()).has_any() {
+            self.vblank_count += 1;
+        }
+    }
+
+    /// Captures the state of the CPU before the next instruction, in the format of reference
+    /// traces.
+    pub fn trace_record(&self) -> TraceRecord {
+        TraceRecord::capture(self.cpu.get_regs(), &self.address_space)
+    }
+
+    /// Steps the emulator one instruction at a time, comparing its state before each instruction
+    /// with the records of a reference trace. Returns the first record that doesn't match, which
+    /// is useful to bring up test ROMs against logs of other emulators.
+    pub fn run_against_trace(
+        &mut self,
+        reference: impl Iterator<Item = TraceRecord>,
+    ) -> Result<(), TraceMismatch> {
+        for (index, expected) in reference.enumerate() {
+            let actual = self.trace_record();
+            if actual != expected {
+                return Err(TraceMismatch {
+                    index,
+                    expected,
+                    actual,
+                });
+            }
+
+            let cycles = self.step_cpu(Cycles::new(0));
+            let interrupts = self.address_space.step(cycles);
+            self.count_vblanks(interrupts);
+        }
+        Ok(())
+    }
+
+    /// Runs at most `max_instructions` CPU steps, updating all peripherals after each of them.
+    /// Stops right after an instruction that accesses a watchpoint, see `take_watchpoint_hit`.
+    /// Returns an error if the CPU locks up. Regardless of the contents of the ROM or the inputs,
+    /// this never panics nor loops forever, which makes it suitable as a fuzzing target.
+    pub fn step_bounded(&mut self, max_instructions: usize) -> Result<(), EmulationError> {
+        for _ in 0..max_instructions {
+            if let Some(fault) = self.cpu_fault {
+                return Err(fault);
+            }
+
+            let cycles = self.step_cpu(Cycles::new(0));
+            let interrupts = self.address_space.step(cycles);
+            self.count_vblanks(interrupts);
+            if self.watchpoint_hit.is_some() {
+                break;
+            }
+        }
+
+        match self.cpu_fault {
+            Some(fault) => Err(fault),
+            None => Ok(()),
+        }
+    }
+}
+
+impl RustyBoy {
+    pub fn new_with_cartridge(cartridge: Cartridge) -> Self {
+        let mut address_space = GbAddressSpace::new(cartridge);
+        apply_boot_ppu_state(&mut address_space);
+        Self::new_with_bus(address_space)
+    }
+
+    /// Creates an emulator that runs the given DMG boot ROM before the cartridge, instead of
+    /// starting right at the cartridge entrypoint. The boot ROM is mapped over 0x0000 to 0x00FF
+    /// until the game writes to 0xFF50. Fails if the boot ROM is not 256 bytes long.
+    pub fn with_boot_rom(cartridge: Cartridge, boot: Vec<u8>) -> Result<Self, RustyBoyError> {
+        let len = boot.len();
+        let boot: Box<[u8; BOOT_ROM_SIZE]> = boot
+            .into_boxed_slice()
+            .try_into()
+            .map_err(|_| RustyBoyError::InvalidBootRom { len })?;
+
+        let mut address_space = GbAddressSpace::new(cartridge);
+        address_space.set_boot_rom(Some(boot));
+        let mut rusty_boy = Self::new_with_bus(address_space);
+        rusty_boy.cpu.get_mut_regs().pc_reg = 0;
+        Ok(rusty_boy)
+    }
+
+    /// Resets the system to its state right after the boot ROM runs. The CPU, PPU, timer, DMA
+    /// engine and interrupt registers are restored, as well as the mapper banking registers. The
+    /// loaded cartridge is kept, and its ROM and RAM (including battery-backed RAM) are untouched.
+    /// Emulator configuration (accuracy, debugging, link clock) is preserved.
+    ///
+    /// When the system has a boot ROM, it runs again from the start instead.
+    pub fn reset(&mut self) {
+        self.cpu.reset();
+        self.address_space.reset();
+        self.vblank_count = 0;
+        self.cpu_fault = None;
+        self.watchpoint_hit = None;
+        if self.address_space.boot_rom_mapped() {
+            self.cpu.get_mut_regs().pc_reg = 0;
+        } else {
+            self.cpu.get_mut_regs().pc_reg = ENTRYPOINT;
+            apply_boot_ppu_state(&mut self.address_space);
+            self.apply_boot_interrupts();
+        }
+    }
+
+    /// Performs a soft reset, as if the Game Boy was power cycled with the same cartridge. This
+    /// is the same as [`RustyBoy::reset`].
+    pub fn reset_keep_ram(&mut self) {
+        self.reset();
+    }
+
+    /// Replaces the cartridge with a new one, optionally restoring its battery-backed RAM from
+    /// `save`, and resets the system. The real-time clock of the save catches up with `now`, as in
+    /// `restore_cartridge_ram`. On error, the current cartridge is kept and the system is not
+    /// reset.
+    pub fn load_cartridge(
+        &mut self,
+        mut cartridge: Cartridge,
+        save: Option<&[u8]>,
+        now: u64,
+    ) -> Result<(), cartridge::Error> {
+        if let Some(save) = save {
+            cartridge.restore_battery_backed_ram(save)?;
+            cartridge.catch_up_rtc(now);
+        }
+
+        self.address_space.cartridge = cartridge;
+        self.reset_keep_ram();
+        Ok(())
+    }
+
     /// Configures the number of cycles that the CPU runs before updating other peripherals.
     /// This makes the emulation less accurate, so be careful when using it, as it introduces
     /// jitter in operations around the CPU and reduces cycle accuracy.
@@ -45,76 +487,1267 @@ impl RustyBoy {
         self.cycle_step = cycles;
     }
 
+    /// When enabled, the number of cycles that the CPU runs before updating other peripherals
+    /// (see `configure_cpu_step`) is capped so that it never crosses a PPU mode boundary. This
+    /// keeps most of the speedup of a large step without missing LCD interrupts.
+    pub fn configure_cpu_step_capping(&mut self, enabled: bool) {
+        self.cap_cpu_step = enabled;
+    }
+
+    /// Configures the cycle step and frame skipping according to the given accuracy profile.
+    pub fn set_accuracy(&mut self, profile: AccuracyProfile) {
+        self.cycle_step = profile.cycle_step();
+        self.cap_cpu_step = profile.cap_cpu_step();
+        self.frame_skip = profile.frame_skip();
+    }
+
+    /// Returns the number of frames that are emulated without rendering by `run_frame` before
+    /// rendering one.
+    pub fn frame_skip(&self) -> usize {
+        self.frame_skip
+    }
+
+    /// Configures the clock used by the serial port for transfers driven by the internal clock.
+    pub fn configure_link_clock(&mut self, clock: LinkClock) {
+        self.address_space.serial.configure_clock(clock);
+    }
+
+    /// Connects a peripheral to the serial port through the link cable, replacing the previous
+    /// one, if any.
+    pub fn connect_serial_link(&mut self, link: Box<dyn SerialLink>) {
+        self.address_space.serial.connect(link);
+    }
+
+    /// Configures how writes to the mapper control region of the cartridge are traced. This is a
+    /// debugging aid for banking issues.
+    pub fn configure_rom_write_trace(&mut self, trace: RomWriteTrace) {
+        self.address_space.rom_write_trace = trace;
+    }
+
+    /// Selects the value of reads from I/O addresses that no device drives, according to the
+    /// emulated hardware model.
+    pub fn configure_open_bus(&mut self, open_bus: OpenBus) {
+        self.address_space.open_bus = open_bus;
+    }
+
+    /// Configures the contents of WRAM, HRAM and OAM at power on, and applies it right away. Games
+    /// that read these memories before writing them see the same values on every run, and after
+    /// every reset.
+    pub fn configure_ram_init(&mut self, ram_init: RamInit) {
+        self.address_space.ram_init = ram_init;
+        self.address_space.apply_ram_init();
+    }
+
+    /// Returns the decoded header of the loaded cartridge.
+    pub fn header(
+        &self,
+    ) -> Result<cartridge::header::CartridgeHeader<'_>, cartridge::header::Error> {
+        self.address_space.cartridge.try_header()
+    }
+
     pub fn supports_battery_backed_ram(&mut self) -> bool {
         self.address_space.cartridge.has_battery()
     }
 
-    pub fn restore_cartridge_ram(&mut self, data: &[u8]) -> Result<(), cartridge::Error> {
+    /// Restores the battery-backed RAM of the cartridge from a save. Cartridges with a real-time
+    /// clock advance it by the time elapsed between the save and `now`, the current UNIX time.
+    pub fn restore_cartridge_ram(&mut self, data: &[u8], now: u64) -> Result<(), cartridge::Error> {
         self.address_space
             .cartridge
-            .restore_battery_backed_ram(data)
+            .restore_battery_backed_ram(data)?;
+        self.address_space.cartridge.catch_up_rtc(now);
+        Ok(())
     }
 
-    pub fn get_cartridge_ram(&mut self) -> Option<&[u8]> {
+    /// Advances the real-time clock of the cartridge by the given number of seconds. Frontends
+    /// use it to keep the clock of MBC3 cartridges in sync with the wall-clock time.
+    pub fn tick_rtc(&mut self, seconds: u64) {
+        self.address_space.cartridge.tick_rtc(seconds)
+    }
+
+    /// Returns the battery-backed RAM of the cartridge to be saved. Cartridges with a real-time
+    /// clock store `now`, the current UNIX time, along with it.
+    pub fn get_cartridge_ram(&mut self, now: u64) -> Option<&[u8]> {
+        self.address_space.cartridge.stamp_rtc(now);
         self.address_space.cartridge.battery_backed_ram()
     }
 
+    /// Dumps the whole 64 KiB address space as seen by the CPU, with the currently selected banks.
+    /// Reads don't have side effects, so this can be used at any time to export memory to
+    /// external debuggers. Only available on native targets, as the dump lives on the stack.
+    #[cfg(any(test, feature = "std"))]
+    pub fn dump_memory(&self) -> [u8; 0x10000] {
+        let mut dump = [0; 0x10000];
+        for (address, byte) in dump.iter_mut().enumerate() {
+            *byte = self.address_space.peek(address as u16);
+        }
+        dump
+    }
+
+    /// Serializes the whole state of the machine: CPU, devices, and the banking registers and RAM
+    /// of the cartridge. The state can only be loaded with the same cartridge.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        save_state::write_header(&mut state, self.address_space.cartridge.title_hash());
+        self.cpu.save_state(&mut state);
+        self.address_space.save_state(&mut state);
+        state.into_bytes()
+    }
+
+    /// Loads a state serialized with `save_state`, migrating states from older versions of the
+    /// emulator. States from unsupported versions or other cartridges are rejected, and the
+    /// emulator is left untouched on error.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), SaveStateError> {
+        let machine_state = save_state::decode(data, self.address_space.cartridge.title_hash())?;
+        let mut state = StateReader::new(&machine_state);
+
+        // Keep the current state around to roll back if the data turns out to be corrupted
+        let backup = self.save_state();
+        if let Err(error) = self.load_machine_state(&mut state) {
+            let mut backup = StateReader::new(&backup[save_state::HEADER_LEN..]);
+            self.load_machine_state(&mut backup)
+                .expect("The backup state is always valid");
+            return Err(error.into());
+        }
+        Ok(())
+    }
+
+    fn load_machine_state(&mut self, state: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.cpu.load_state(state)?;
+        self.address_space.load_state(state)?;
+        if !state.is_empty() {
+            return Err(StateError::Invalid);
+        }
+        Ok(())
+    }
+
     fn step(&mut self, render: bool) -> PpuResult {
         // Run a bunch of CPU cycles at once. This is technically potentially incorrect, but saves a lot of
         // emulation time
+        let cycle_step = if self.cap_cpu_step {
+            self.cycle_step.min(self.address_space.ppu.mode_remaining())
+        } else {
+            self.cycle_step
+        };
+
+        let start = self.timestamp();
         let mut cycles = Cycles::new(0);
-        while cycles < self.cycle_step {
-            if self.debug {
-                let pc = self.cpu.get_regs().pc_reg;
-                let inst = disassembler::disassemble_single_inst(&mut self.address_space, pc);
-                let regs = self.cpu.get_regs();
-                log::trace!("{pc:#04x} {inst} -- {regs:x?}");
-            }
-
-            let interrupts = self.address_space.interrupt_regs.active_interrupts();
-            let result = self.cpu.step(&mut self.address_space, interrupts);
-
-            cycles = cycles
-                + match result {
-                    sm83::core::ExitReason::Step(cycles)
-                    | sm83::core::ExitReason::Stop(cycles)
-                    | sm83::core::ExitReason::Halt(cycles) => cycles,
-                    sm83::core::ExitReason::InterruptTaken(cycles, interrupt) => {
-                        self.address_space.interrupt_regs.acknowledge(interrupt);
-                        cycles
-                    }
-                    sm83::core::ExitReason::IllegalOpcode => {
-                        panic!(
-                            "Illegal CPU opcode at address: {}",
-                            self.cpu.get_regs().pc_reg
-                        )
-                    }
-                };
-        }
-
-        let (ppu_interrupts, ppu_result) =
-            self.address_space
-                .ppu
-                .step(cycles, &mut self.dma_engine, render);
-        let timer_interrupts = self.address_space.timer.step(cycles);
-        self.dma_engine.run(cycles, &mut self.address_space);
+        while cycles < cycle_step {
+            cycles = cycles + self.step_cpu(cycle_step - cycles);
+        }
+        let cpu_end = self.timestamp();
 
-        self.address_space
-            .interrupt_regs
-            .trigger(ppu_interrupts | timer_interrupts);
+        let (ppu_interrupts, ppu_result) = self.address_space.step_ppu(cycles, render);
+        let ppu_end = self.timestamp();
+        let interrupts = ppu_interrupts | self.address_space.step_timers_and_dma(cycles);
+        let end = self.timestamp();
+
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record_step(start, cpu_end, ppu_end, end);
+            if ppu_result == PpuResult::FrameComplete {
+                profiler.finish_frame();
+            }
+        }
 
+        self.count_vblanks(interrupts);
         ppu_result
     }
 
-    pub fn run_until_next_frame(
-        &mut self,
-        render: bool,
-    ) -> &[[Color; DISPLAY_WIDTH]; DISPLAY_HEIGHT] {
-        while PpuResult::FrameComplete != self.step(render) {}
+    /// Returns the current timestamp of the profiling clock, or 0 if profiling is disabled.
+    fn timestamp(&self) -> u64 {
+        self.profiler.as_ref().map_or(0, |profiler| profiler.now())
+    }
+
+    /// Enables the per-frame timing breakdown, taking timestamps from the given clock, or disables
+    /// it if `clock` is `None`. Taking timestamps has a cost, so it is disabled by default.
+    pub fn configure_profiling(&mut self, clock: Option<Box<dyn ProfilingClock>>) {
+        self.profiler = clock.map(Profiler::new);
+    }
+
+    /// Returns the time spent in each component during the last complete frame. All values are 0
+    /// while profiling is disabled or before the first frame completes.
+    pub fn last_frame_timing(&self) -> FrameTiming {
+        self.profiler
+            .as_ref()
+            .map_or(FrameTiming::default(), |profiler| profiler.last_frame())
+    }
+
+    /// Runs the emulator until the PPU completes a frame, and returns it. Returns an error as soon
+    /// as the CPU locks up instead, e.g. when a corrupt or unsupported ROM executes an illegal
+    /// opcode.
+    pub fn run_until_next_frame(&mut self, render: bool) -> Result<&FrameBuf, EmulationError> {
+        loop {
+            let result = self.step(render);
+            if let Some(fault) = self.cpu_fault {
+                return Err(fault);
+            }
+            if result == PpuResult::FrameComplete {
+                return Ok(self.address_space.ppu.frame());
+            }
+        }
+    }
+
+    /// Runs the emulator for as many frames as dictated by the frame skip configured via the
+    /// accuracy profile, rendering only the last one, which is returned. Returns an error as soon
+    /// as the CPU locks up.
+    pub fn run_frame(&mut self) -> Result<&FrameBuf, EmulationError> {
+        for _ in 0..self.frame_skip {
+            self.run_until_next_frame(false)?;
+        }
+        self.run_until_next_frame(true)
+    }
+
+    /// Runs the emulator until the PPU moves on to the next line, which may be a visible line or a
+    /// VBlank line. The CPU and the rest of the peripherals run along the way. LY stays at 0 while
+    /// the LCD is off, so it runs until the end of the frame instead.
+    pub fn step_scanline(&mut self) {
+        let line = self.address_space.ppu.line();
+        while self.step(true) != PpuResult::FrameComplete {
+            if self.address_space.ppu.line() != line {
+                break;
+            }
+        }
+    }
+
+    /// Runs the emulator until the PPU has completed the given number of frames, and returns the
+    /// last frame. Frames end with a VBlank interrupt, except while the LCD is off, where they
+    /// still take the same time but raise no interrupts.
+    pub fn run_to_vblank_count(&mut self, count: usize, render: bool) -> &FrameBuf {
+        let mut frames = 0;
+        while frames < count {
+            if self.step(render) == PpuResult::FrameComplete {
+                frames += 1;
+            }
+        }
         self.address_space.ppu.frame()
     }
 
+    /// Returns the last frame produced by the PPU.
+    pub fn frame(&self) -> &FrameBuf {
+        self.address_space.ppu.frame()
+    }
+
+    /// Returns the layout of the buffers written by `render_into` for the given pixel format, with
+    /// no padding between rows. Embedders may use a layout with a larger stride instead. See
+    /// [`video::layout`] for the contract.
+    pub const fn framebuffer_ptr_layout(format: PixelFormat) -> FrameLayout {
+        FrameLayout::packed(format)
+    }
+
+    /// Writes the current frame into a caller-provided buffer with the given layout. Callers that
+    /// only have a raw pointer can build the slice with `core::slice::from_raw_parts_mut` and the
+    /// length given by `FrameLayout::buffer_len`.
+    pub fn render_into(&self, layout: &FrameLayout, buffer: &mut [u8]) -> Result<(), LayoutError> {
+        video::layout::write_frame(self.frame(), layout, buffer)
+    }
+
+    /// Returns the range of lines of the frame that changed since the last call, if any, and
+    /// clears it.
+    pub fn take_dirty_lines(&mut self) -> Option<core::ops::RangeInclusive<usize>> {
+        self.address_space.ppu.take_dirty_lines()
+    }
+
+    /// Returns whether the game has the LCD turned on (bit 7 of LCDC).
+    pub fn lcd_enabled(&self) -> bool {
+        self.address_space.ppu.lcd_enabled()
+    }
+
+    /// Turns the LCD on or off, just like a write to bit 7 of LCDC from the game would.
+    pub fn set_lcd_enabled(&mut self, enabled: bool) {
+        let lcdc = self.address_space.read(0xFF40);
+        let lcdc = if enabled { lcdc | 0x80 } else { lcdc & !0x80 };
+        self.address_space.write(0xFF40, lcdc);
+    }
+
+    /// Registers a callback that is invoked with the new state of the LCD whenever it is turned
+    /// on or off, e.g. to dim a backlight. Passing `None` removes the callback.
+    pub fn on_lcd_toggle(&mut self, listener: Option<LcdListener>) {
+        self.address_space.on_lcd_toggle(listener);
+    }
+
+    /// Registers a callback that is invoked whenever the game selects a different ROM or RAM bank
+    /// of the cartridge, to debug banking issues. Passing `None` removes the callback.
+    pub fn on_bank_switch(&mut self, listener: Option<cartridge::BankListener>) {
+        self.address_space.cartridge.on_bank_switch(listener);
+    }
+
+    /// Returns the raw value of the background palette register (BGP).
+    pub fn bg_palette(&self) -> u8 {
+        self.address_space.ppu.bg_palette()
+    }
+
+    /// Returns the raw value of the first object palette register (OBP0).
+    pub fn obj_palette0(&self) -> u8 {
+        self.address_space.ppu.obj_palette0()
+    }
+
+    /// Returns the raw value of the second object palette register (OBP1).
+    pub fn obj_palette1(&self) -> u8 {
+        self.address_space.ppu.obj_palette1()
+    }
+
+    /// Returns the line the PPU is currently drawing (LY), including VBlank lines.
+    pub fn current_line(&self) -> usize {
+        self.address_space.ppu.line()
+    }
+
+    /// Returns how far the PPU is into the current frame, from 0.0 to 1.0. Useful to display the
+    /// position of the beam.
+    pub fn frame_progress(&self) -> f32 {
+        self.address_space.ppu.frame_progress()
+    }
+
+    /// Registers a callback that receives the Super Game Boy command packets sent by the game,
+    /// replacing the previous one, if any. Packets are only detected while there is a listener.
+    pub fn on_sgb_packet(&mut self, listener: Option<joypad::SgbListener>) {
+        self.address_space.joypad.on_sgb_packet(listener);
+    }
+
+    /// Drains the audio generated so far into `out` as interleaved 16-bit stereo samples. Returns
+    /// the number of stereo samples written.
+    pub fn audio_samples(&mut self, out: &mut [i16]) -> usize {
+        self.address_space.apu.samples(out)
+    }
+
+    /// Selects the rate of the samples returned by `audio_samples`, in Hz.
+    pub fn set_audio_sample_rate(&mut self, sample_rate: usize) {
+        self.address_space.apu.set_sample_rate(sample_rate);
+    }
+
     pub fn update_keys(&mut self, state: &joypad::State) {
         self.address_space.joypad.update_buttons(state);
     }
+
+    /// Returns the joypad state that the emulator uses for the current frame, e.g. to verify that
+    /// the inputs of a replay match the recorded ones.
+    pub fn inputs_snapshot(&self) -> joypad::State {
+        self.address_space.joypad.buttons_state()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern crate alloc;
+    use alloc::vec;
+    use sm83::interrupts::InterruptRegs;
+
+    const PROGRAM_BASE: usize = 0x150;
+
+    /// Builds a 32 KiB ROM-only cartridge that jumps to the given program, placing the given
+    /// interrupt handlers at their vectors.
+    pub(crate) fn test_cartridge(program: &[u8], handlers: &[(usize, &[u8])]) -> Cartridge {
+        let mut rom = vec![0; 0x8000];
+
+        // jp PROGRAM_BASE
+        rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, PROGRAM_BASE as u8, 0x01]);
+        rom[0x134..0x138].copy_from_slice(b"TEST");
+        rom[PROGRAM_BASE..PROGRAM_BASE + program.len()].copy_from_slice(program);
+        for (vector, handler) in handlers {
+            rom[*vector..*vector + handler.len()].copy_from_slice(handler);
+        }
+
+        Cartridge::try_new(rom).unwrap()
+    }
+
+    #[test]
+    fn run_to_vblank_count_advances_exact_vblanks() {
+        let program = [
+            0x3E, 0x01, // ld a, 0x01
+            0xE0, 0xFF, // ldh [IE], a
+            0x21, 0x00, 0xC0, // ld hl, 0xC000
+            0x3E, 0x80, // ld a, 0x80
+            0xE0, 0x40, // ldh [LCDC], a
+            0xFB, // ei
+            0x18, 0xFE, // jr -2
+        ];
+        // inc [hl]; reti
+        let vblank_handler: &[u8] = &[0x34, 0xD9];
+        let mut rusty_boy =
+            RustyBoy::new_with_cartridge(test_cartridge(&program, &[(0x40, vblank_handler)]));
+
+        rusty_boy.run_to_vblank_count(5, false);
+        // The handler for the last VBlank has not had a chance to run yet.
+        assert_eq!(rusty_boy.address_space.wram[0], 4);
+
+        rusty_boy.run_to_vblank_count(3, false);
+        assert_eq!(rusty_boy.address_space.wram[0], 7);
+
+        rusty_boy.run_to_vblank_count(0, false);
+        assert_eq!(rusty_boy.address_space.wram[0], 7);
+    }
+
+    /// Turns off the LCD and keeps it off
+    const LCD_OFF_PROGRAM: [u8; 6] = [
+        0x3E, 0x00, // ld a, 0x00
+        0xE0, 0x40, // ldh [LCDC], a
+        0x18, 0xFE, // jr -2
+    ];
+
+    #[test]
+    fn run_to_vblank_count_with_lcd_off() {
+        let mut rusty_boy = RustyBoy::new_with_cartridge(test_cartridge(&LCD_OFF_PROGRAM, &[]));
+        rusty_boy.step_bounded(4).unwrap();
+        assert!(!rusty_boy.lcd_enabled());
+
+        // Frames still end, but raise no VBlank interrupts
+        rusty_boy.run_to_vblank_count(3, false);
+        assert_eq!(rusty_boy.vblank_count, 0);
+        assert_eq!(rusty_boy.current_line(), 0);
+    }
+
+    #[test]
+    fn step_scanline_with_lcd_off() {
+        let mut rusty_boy = RustyBoy::new_with_cartridge(test_cartridge(&LCD_OFF_PROGRAM, &[]));
+        rusty_boy.step_bounded(4).unwrap();
+        assert!(!rusty_boy.lcd_enabled());
+
+        // LY stays at 0, so each call runs until the end of the frame
+        rusty_boy.step_scanline();
+        rusty_boy.step_scanline();
+        assert_eq!(rusty_boy.current_line(), 0);
+        assert!(!rusty_boy.lcd_enabled());
+    }
+
+    #[test]
+    fn step_scanline_advances_one_line() {
+        let program = [
+            0x3E, 0x80, // ld a, 0x80
+            0xE0, 0x40, // ldh [LCDC], a
+            0x18, 0xFE, // jr -2
+        ];
+        let mut rusty_boy = RustyBoy::new_with_cartridge(test_cartridge(&program, &[]));
+
+        // Let the program turn on the LCD
+        rusty_boy.step_scanline();
+        rusty_boy.step_scanline();
+
+        let start_line = rusty_boy.address_space.ppu.line();
+        let start_vblanks = rusty_boy.vblank_count;
+        let mut line = start_line;
+        for _ in 0..154 {
+            rusty_boy.step_scanline();
+            let next_line = rusty_boy.address_space.ppu.line();
+            assert_eq!(next_line, (line + 1) % 154);
+            assert_eq!(rusty_boy.address_space.read(0xFF44) as usize, next_line);
+            line = next_line;
+        }
+
+        assert_eq!(rusty_boy.address_space.ppu.line(), start_line);
+        assert_eq!(rusty_boy.vblank_count, start_vblanks + 1);
+    }
+
+    #[test]
+    fn illegal_opcode_stops_frame() {
+        // nop; illegal opcode
+        let mut rusty_boy = RustyBoy::new_with_cartridge(test_cartridge(&[0x00, 0xD3], &[]));
+        let error = EmulationError::IllegalOpcode {
+            address: 0x151,
+            opcode: 0xD3,
+        };
+        assert_eq!(rusty_boy.run_until_next_frame(true).err(), Some(error));
+        assert_eq!(rusty_boy.run_frame().err(), Some(error));
+    }
+
+    #[test]
+    fn inputs_snapshot_matches_keys() {
+        let mut rusty_boy = RustyBoy::new_with_cartridge(test_cartridge(&[], &[]));
+        assert_eq!(rusty_boy.inputs_snapshot(), joypad::State::new());
+
+        let state = joypad::State {
+            a: true,
+            start: true,
+            left: true,
+            down: true,
+            ..joypad::State::new()
+        };
+        rusty_boy.update_keys(&state);
+        assert_eq!(rusty_boy.inputs_snapshot(), state);
+    }
+
+    #[test]
+    fn error_chain() {
+        use alloc::string::ToString;
+        use std::error::Error;
+
+        let error: RustyBoyError = EmulationError::IllegalOpcode {
+            address: 0x1234,
+            opcode: 0xD3,
+        }
+        .into();
+        assert_eq!(error.to_string(), "emulation error");
+        let source = error.source().unwrap();
+        assert_eq!(source.to_string(), "illegal opcode 0xd3 at address 0x1234");
+        assert!(source.source().is_none());
+
+        let error: RustyBoyError =
+            cartridge::Error::InvalidHeader(cartridge::header::Error::NoHeader).into();
+        assert_eq!(error.to_string(), "cartridge error");
+        let source = error.source().unwrap();
+        assert_eq!(source.to_string(), "Invalid cartridge header");
+        assert_eq!(
+            source.source().unwrap().to_string(),
+            "ROM is too small to contain a header"
+        );
+        assert!(source.source().unwrap().source().is_none());
+
+        let error = RustyBoyError::InvalidBootRom { len: 0x200 };
+        assert_eq!(
+            error.to_string(),
+            "boot ROM is 512 bytes long instead of 256"
+        );
+        assert!(error.source().is_none());
+    }
+
+    fn count_lcd_interrupts(configure: impl FnOnce(&mut RustyBoy)) -> (u8, u8) {
+        use sm83::asm;
+
+        let program = [
+            &asm::ld_a_imm(0x08)[..], // HBlank STAT interrupt
+            &asm::ldh_a8_a(0x41),
+            &asm::ld_a_imm(0x03), // VBlank and STAT interrupts
+            &asm::ldh_a8_a(0xFF),
+            &asm::ld_a_imm(0x80),
+            &asm::ldh_a8_a(0x40),
+            &asm::ei(),
+            &asm::jr(-2),
+        ]
+        .concat();
+        let vblank_handler = [
+            &asm::ld_hl_imm(0xC000)[..],
+            &asm::inc_ind_hl(),
+            &asm::reti(),
+        ]
+        .concat();
+        let stat_handler = [
+            &asm::ld_hl_imm(0xC001)[..],
+            &asm::inc_ind_hl(),
+            &asm::reti(),
+        ]
+        .concat();
+
+        let mut rusty_boy = RustyBoy::new_with_cartridge(test_cartridge(
+            &program,
+            &[(0x40, &vblank_handler), (0x48, &stat_handler)],
+        ));
+        configure(&mut rusty_boy);
+        rusty_boy.run_to_vblank_count(3, false);
+        (
+            rusty_boy.address_space.wram[0],
+            rusty_boy.address_space.wram[1],
+        )
+    }
+
+    #[test]
+    fn capped_cpu_step_keeps_lcd_interrupts() {
+        let accurate = count_lcd_interrupts(|_| {});
+        let capped = count_lcd_interrupts(|rusty_boy| {
+            rusty_boy.configure_cpu_step(Cycles::new(60));
+            rusty_boy.configure_cpu_step_capping(true);
+        });
+        assert_eq!(accurate.0, 2);
+        assert_eq!(accurate, capped);
+    }
+
+    #[test]
+    fn boot_rom_is_unmapped_by_ff50_writes() {
+        let mut boot = vec![0; BOOT_ROM_SIZE];
+        // ld a, 0x01; ldh [0xFF50], a
+        boot[..4].copy_from_slice(&[0x3E, 0x01, 0xE0, 0x50]);
+        let cartridge = test_cartridge(&[], &[(0x0000, &[0xAB])]);
+        let mut rusty_boy = RustyBoy::with_boot_rom(cartridge, boot.clone()).unwrap();
+
+        assert_eq!(rusty_boy.cpu.get_regs().pc_reg, 0x0000);
+        assert_eq!(rusty_boy.address_space.read(0x0000), 0x3E);
+        assert_eq!(rusty_boy.address_space.read(0x0100), 0x00);
+
+        rusty_boy.step_bounded(1).unwrap();
+        assert!(rusty_boy.address_space.boot_rom_mapped());
+        rusty_boy.step_bounded(1).unwrap();
+        assert!(!rusty_boy.address_space.boot_rom_mapped());
+        assert_eq!(rusty_boy.address_space.read(0x0000), 0xAB);
+        assert_eq!(rusty_boy.cpu.get_regs().pc_reg, 0x0004);
+
+        // Writing 0 doesn't map it back, but resetting the system runs it again
+        rusty_boy.address_space.write(0xFF50, 0);
+        assert_eq!(rusty_boy.address_space.read(0x0000), 0xAB);
+        rusty_boy.reset();
+        assert_eq!(rusty_boy.address_space.read(0x0000), 0x3E);
+        assert_eq!(rusty_boy.cpu.get_regs().pc_reg, 0x0000);
+
+        boot.pop();
+        let cartridge = test_cartridge(&[], &[]);
+        assert!(matches!(
+            RustyBoy::with_boot_rom(cartridge, boot),
+            Err(RustyBoyError::InvalidBootRom { len: 0xFF })
+        ));
+    }
+
+    #[test]
+    fn ram_init_patterns() {
+        use memory::RamPattern;
+
+        // ldh a, [0xFF80]; ld [0xC100], a
+        let program = [0xF0, 0x80, 0xEA, 0x00, 0xC1];
+        let mut rusty_boy = RustyBoy::new_with_cartridge(test_cartridge(&program, &[]));
+        rusty_boy.configure_ram_init(RamInit {
+            wram: RamPattern::Fill(0xFF),
+            hram: RamPattern::Repeat(vec![0x5A, 0xA5]),
+            oam: RamPattern::Repeat(vec![0x12, 0x34, 0x56]),
+        });
+
+        assert_eq!(rusty_boy.address_space.read(0xFF80), 0x5A);
+        assert_eq!(rusty_boy.address_space.read(0xFF81), 0xA5);
+        assert_eq!(rusty_boy.address_space.read(0xFE00), 0x12);
+        assert_eq!(rusty_boy.address_space.read(0xFE9E), 0x56);
+
+        // The game sees the pattern, even after a reset
+        rusty_boy.address_space.write(0xFF80, 0x00);
+        rusty_boy.reset();
+        rusty_boy.step_bounded(4).unwrap();
+        assert_eq!(rusty_boy.address_space.read(0xC100), 0x5A);
+        assert_eq!(rusty_boy.address_space.read(0xC101), 0xFF);
+    }
+
+    #[test]
+    fn load_cartridge_resets_state() {
+        use sm83::asm;
+
+        let program = [
+            &asm::ld_hl_imm(0xC000)[..],
+            &asm::inc_ind_hl(),
+            &asm::jr(-3),
+        ]
+        .concat();
+        let mut rusty_boy = RustyBoy::new_with_cartridge(test_cartridge(&program, &[]));
+        rusty_boy.run_until_next_frame(false).unwrap();
+        assert_ne!(rusty_boy.address_space.wram[0], 0);
+
+        let mut rom = vec![0; 0x8000];
+        rom[0x134..0x139].copy_from_slice(b"OTHER");
+        let cartridge = Cartridge::try_new(rom).unwrap();
+        rusty_boy.load_cartridge(cartridge, None, 0).unwrap();
+
+        assert_eq!(rusty_boy.address_space.cartridge.header().title, "OTHER");
+        assert_eq!(rusty_boy.cpu.get_regs().pc_reg, 0x100);
+        assert_eq!(rusty_boy.address_space.wram[0], 0);
+        assert_eq!(rusty_boy.address_space.ppu.line(), 0);
+        assert_eq!(rusty_boy.vblank_count, 0);
+
+        // A save that doesn't fit the cartridge keeps the current one
+        let cartridge = test_cartridge(&program, &[]);
+        assert!(rusty_boy
+            .load_cartridge(cartridge, Some(&[0; 16]), 0)
+            .is_err());
+        assert_eq!(rusty_boy.address_space.cartridge.header().title, "OTHER");
+    }
+
+    #[test]
+    fn color_mode_follows_cgb_flag() {
+        use sm83::memory::Memory;
+
+        let mut rusty_boy = RustyBoy::new_with_cartridge(test_cartridge(&[], &[]));
+        assert!(!rusty_boy.address_space.ppu.color_mode());
+        assert_eq!(rusty_boy.address_space.read(0xFF4F), 0x00);
+
+        let mut rom = vec![0; 0x8000];
+        rom[0x134..0x139].copy_from_slice(b"COLOR");
+        rom[0x143] = 0x80;
+        let cartridge = Cartridge::try_new(rom).unwrap();
+        rusty_boy.load_cartridge(cartridge, None, 0).unwrap();
+        assert!(rusty_boy.address_space.ppu.color_mode());
+        assert_eq!(rusty_boy.address_space.read(0xFF4F), 0xFE);
+        rusty_boy.address_space.write(0xFF4F, 0x01);
+        assert_eq!(rusty_boy.address_space.peek(0xFF4F), 0xFF);
+    }
+
+    #[test]
+    fn render_into_strided_buffer() {
+        let rusty_boy = RustyBoy::new_with_cartridge(test_cartridge(&[], &[]));
+
+        // Rows aligned to 256 bytes, with a canary in the padding
+        let layout = FrameLayout::with_stride(PixelFormat::Indexed, 256);
+        let mut buffer = vec![0xAA; layout.buffer_len().unwrap()];
+        rusty_boy.render_into(&layout, &mut buffer).unwrap();
+        for (row, line) in buffer.chunks(256).zip(rusty_boy.frame().iter()) {
+            assert!(row[..ppu::DISPLAY_WIDTH]
+                .iter()
+                .zip(line.iter())
+                .all(|(shade, color)| *shade == color.shade()));
+            assert!(row[ppu::DISPLAY_WIDTH..].iter().all(|byte| *byte == 0xAA));
+        }
+
+        let layout = RustyBoy::framebuffer_ptr_layout(PixelFormat::Rgba8888);
+        assert_eq!(layout.stride, 4 * ppu::DISPLAY_WIDTH);
+        let mut buffer = vec![0; layout.buffer_len().unwrap() - 1];
+        assert!(rusty_boy.render_into(&layout, &mut buffer).is_err());
+    }
+
+    #[test]
+    fn list_and_clear_breakpoints() {
+        let mut rusty_boy = RustyBoy::new_with_cartridge(test_cartridge(&[], &[]));
+        for address in [0x0150, 0x0100, 0x4000] {
+            assert!(rusty_boy.add_breakpoint(address));
+        }
+        assert!(!rusty_boy.add_breakpoint(0x0100));
+        assert!(rusty_boy.add_watchpoint(0xC000));
+        assert!(rusty_boy.add_watchpoint(0xFF44));
+
+        assert_eq!(
+            rusty_boy.breakpoints().collect::<Vec<_>>(),
+            [0x0100, 0x0150, 0x4000]
+        );
+        assert_eq!(
+            rusty_boy.watchpoints().collect::<Vec<_>>(),
+            [0xC000, 0xFF44]
+        );
+        assert!(rusty_boy.at_breakpoint());
+
+        assert!(rusty_boy.remove_breakpoint(0x0100));
+        assert!(!rusty_boy.remove_breakpoint(0x0100));
+        assert!(rusty_boy.remove_watchpoint(0xFF44));
+        assert_eq!(
+            rusty_boy.breakpoints().collect::<Vec<_>>(),
+            [0x0150, 0x4000]
+        );
+        assert_eq!(rusty_boy.watchpoints().collect::<Vec<_>>(), [0xC000]);
+        assert!(!rusty_boy.at_breakpoint());
+
+        rusty_boy.clear_all_breakpoints();
+        assert_eq!(rusty_boy.breakpoints().count(), 0);
+        assert_eq!(rusty_boy.watchpoints().count(), 0);
+    }
+
+    #[test]
+    fn watchpoint_stops_on_write() {
+        let program = [
+            0x21, 0x00, 0xC0, // ld hl, 0xC000
+            0x3E, 0x42, // ld a, 0x42
+            0x00, // nop
+            0x77, // ld [hl], a
+            0x18, 0xFE, // jr -2
+        ];
+        let mut rusty_boy = RustyBoy::new_with_cartridge(test_cartridge(&program, &[]));
+        rusty_boy.add_watchpoint(0xC000);
+
+        rusty_boy.step_bounded(100).unwrap();
+        assert_eq!(rusty_boy.take_watchpoint_hit(), Some(0xC000));
+        assert_eq!(rusty_boy.take_watchpoint_hit(), None);
+        // Stopped right after the store
+        assert_eq!(rusty_boy.cpu.get_regs().pc_reg, PROGRAM_BASE as u16 + 7);
+        assert_eq!(rusty_boy.address_space.read(0xC000), 0x42);
+
+        // The loop never touches the address again
+        rusty_boy.step_bounded(100).unwrap();
+        assert_eq!(rusty_boy.take_watchpoint_hit(), None);
+    }
+
+    #[test]
+    fn boot_interrupts_are_serviced_on_first_step() {
+        let mut rusty_boy = RustyBoy::new_with_cartridge(test_cartridge(&[], &[]));
+        rusty_boy.configure_boot_interrupts(BootInterrupts {
+            ime: true,
+            enable: Interrupt::Timer as u8,
+            flags: Interrupt::Timer as u8,
+        });
+
+        rusty_boy.step_bounded(1).unwrap();
+        assert_eq!(rusty_boy.cpu.get_regs().pc_reg, 0x50);
+        assert!(!rusty_boy.cpu.get_regs().irq_en);
+        assert_eq!(rusty_boy.address_space.interrupt_regs.read(0xFF0F), 0);
+
+        // The configuration survives resets
+        rusty_boy.reset_keep_ram();
+        assert!(rusty_boy.cpu.get_regs().irq_en);
+        assert_eq!(
+            rusty_boy.address_space.interrupt_regs.read(0xFFFF),
+            Interrupt::Timer as u8
+        );
+    }
+
+    #[test]
+    fn accuracy_profiles() {
+        let mut rusty_boy = RustyBoy::new_with_cartridge(test_cartridge(&[], &[]));
+        assert_eq!(rusty_boy.cycle_step, Cycles::new(4));
+        assert_eq!(rusty_boy.frame_skip(), 0);
+
+        rusty_boy.set_accuracy(AccuracyProfile::Fast);
+        assert_eq!(rusty_boy.cycle_step, Cycles::new(60));
+        assert_eq!(rusty_boy.frame_skip(), 1);
+
+        rusty_boy.set_accuracy(AccuracyProfile::Balanced);
+        assert_eq!(rusty_boy.cycle_step, Cycles::new(60));
+        assert!(rusty_boy.cap_cpu_step);
+        assert_eq!(rusty_boy.frame_skip(), 0);
+
+        rusty_boy.set_accuracy(AccuracyProfile::Accurate);
+        assert_eq!(rusty_boy.cycle_step, Cycles::new(4));
+        assert!(!rusty_boy.cap_cpu_step);
+        assert_eq!(rusty_boy.frame_skip(), 0);
+    }
+
+    struct XorShift(u32);
+
+    impl XorShift {
+        fn next(&mut self) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0
+        }
+    }
+
+    #[test]
+    fn random_roms_do_not_panic() {
+        const CARTRIDGE_TYPES: [u8; 5] = [0x00, 0x01, 0x03, 0x13, 0x1B];
+        const RAM_SIZES: [u8; 3] = [0x00, 0x02, 0x03];
+
+        for seed in 1..=256u32 {
+            let mut rng = XorShift(seed.wrapping_mul(0x9E37_79B9));
+            let mut rom: alloc::vec::Vec<u8> = (0..0x8000).map(|_| rng.next() as u8).collect();
+
+            // Keep the header parseable, everything else is random
+            rom[0x134..0x144].fill(0);
+            rom[0x147] = CARTRIDGE_TYPES[rng.next() as usize % CARTRIDGE_TYPES.len()];
+            rom[0x148] = 0;
+            rom[0x149] = RAM_SIZES[rng.next() as usize % RAM_SIZES.len()];
+
+            let mut rusty_boy = RustyBoy::new_with_cartridge(Cartridge::try_new(rom).unwrap());
+            for _ in 0..50 {
+                let keys = rng.next();
+                rusty_boy.update_keys(&joypad::State {
+                    left: keys & 0x01 != 0,
+                    right: keys & 0x02 != 0,
+                    up: keys & 0x04 != 0,
+                    down: keys & 0x08 != 0,
+                    a: keys & 0x10 != 0,
+                    b: keys & 0x20 != 0,
+                    start: keys & 0x40 != 0,
+                    select: keys & 0x80 != 0,
+                });
+
+                if rusty_boy.step_bounded(1000).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// A flat 64 KiB bus with a device that raises a timer interrupt every 256 cycles.
+    struct FlatBus {
+        memory: alloc::vec::Vec<u8>,
+        interrupt_regs: InterruptRegs,
+        elapsed: usize,
+        raised: usize,
+    }
+
+    impl Memory for FlatBus {
+        fn read(&self, address: sm83::memory::Address) -> u8 {
+            match address {
+                0xFF0F | 0xFFFF => self.interrupt_regs.read(address),
+                _ => self.memory[address as usize],
+            }
+        }
+
+        fn write(&mut self, address: sm83::memory::Address, value: u8) {
+            match address {
+                0xFF0F | 0xFFFF => self.interrupt_regs.write(address, value),
+                _ => self.memory[address as usize] = value,
+            }
+        }
+    }
+
+    impl Bus for FlatBus {
+        fn active_interrupts(&self) -> Interrupts {
+            self.interrupt_regs.active_interrupts()
+        }
+
+        fn acknowledge_interrupt(&mut self, interrupt: Interrupt) {
+            self.interrupt_regs.acknowledge(interrupt);
+        }
+
+        fn step(&mut self, cycles: Cycles) -> Interrupts {
+            self.elapsed += usize::from(cycles);
+            if self.elapsed < 256 {
+                return Interrupts::new();
+            }
+            self.elapsed -= 256;
+            self.raised += 1;
+            self.interrupt_regs.trigger(Interrupt::Timer.into());
+            Interrupt::Timer.into()
+        }
+    }
+
+    #[test]
+    fn custom_bus() {
+        let mut memory = vec![0; 0x10000];
+        let program = [
+            0x3E, 0x04, // ld a, 0x04
+            0xE0, 0xFF, // ldh [IE], a
+            0x21, 0x00, 0x80, // ld hl, 0x8000
+            0xFB, // ei
+            0x18, 0xFE, // jr -2
+        ];
+        memory[0x100..0x100 + program.len()].copy_from_slice(&program);
+        memory[0x50..0x52].copy_from_slice(&[
+            0x34, // inc [hl]
+            0xD9, // reti
+        ]);
+
+        let mut rusty_boy = RustyBoy::new_with_bus(FlatBus {
+            memory,
+            interrupt_regs: InterruptRegs::new(),
+            elapsed: 0,
+            raised: 0,
+        });
+        rusty_boy.step_bounded(200).unwrap();
+
+        // The program writes to what would be VRAM on the Game Boy from the interrupt handler.
+        let bus = rusty_boy.bus();
+        assert!(bus.raised > 1);
+        assert_eq!(bus.memory[0x8000] as usize, bus.raised);
+    }
+
+    #[test]
+    fn stop_wakes_up_on_selected_joypad_line() {
+        let program = [
+            0x3E, 0x20, // ld a, 0x20
+            0xE0, 0x00, // ldh [P1], a  (select the d-pad)
+            0x10, // stop
+            0x3E, 0x42, // ld a, 0x42
+            0x18, 0xFE, // jr -2
+        ];
+        let mut rusty_boy = RustyBoy::new_with_cartridge(test_cartridge(&program, &[]));
+        rusty_boy.step_bounded(10).unwrap();
+        assert!(rusty_boy.cpu.is_stopped());
+
+        // Buttons are not selected, so pressing A doesn't wake the CPU up
+        let mut state = joypad::State::new();
+        state.a = true;
+        rusty_boy.update_keys(&state);
+        rusty_boy.step_bounded(10).unwrap();
+        assert!(rusty_boy.cpu.is_stopped());
+        assert_eq!(rusty_boy.cpu.get_regs().a_reg, 0x20);
+
+        state.right = true;
+        rusty_boy.update_keys(&state);
+        rusty_boy.step_bounded(10).unwrap();
+        assert!(!rusty_boy.cpu.is_stopped());
+        assert_eq!(rusty_boy.cpu.get_regs().a_reg, 0x42);
+    }
+
+    #[test]
+    fn dump_memory() {
+        let mut rusty_boy = RustyBoy::new_with_cartridge(test_cartridge(&[0x18, 0xFE], &[]));
+        for offset in 0..0x100 {
+            rusty_boy
+                .address_space
+                .write(0x8000 + offset, (offset as u8).wrapping_mul(3));
+        }
+        rusty_boy.address_space.write(0xC123, 0x42);
+
+        let dump = rusty_boy.dump_memory();
+        for offset in 0..0x100 {
+            assert_eq!(dump[0x8000 + offset], (offset as u8).wrapping_mul(3));
+        }
+        assert_eq!(dump[0xC123], 0x42);
+        assert_eq!(&dump[0x134..0x138], b"TEST");
+    }
+
+    #[test]
+    fn run_against_trace() {
+        let program = [
+            0x3E, 0x42, // ld a, 0x42
+            0x3C, // inc a
+            0x06, 0x10, // ld b, 0x10
+            0x18, 0xFB, // jr -5
+        ];
+        let cartridge = || test_cartridge(&program, &[]);
+
+        let mut reference_boy = RustyBoy::new_with_cartridge(cartridge());
+        let mut reference = vec![];
+        for _ in 0..10 {
+            reference.push(reference_boy.trace_record());
+            reference_boy.step_bounded(1).unwrap();
+        }
+
+        let mut rusty_boy = RustyBoy::new_with_cartridge(cartridge());
+        assert_eq!(
+            rusty_boy.run_against_trace(reference.iter().copied()),
+            Ok(())
+        );
+
+        // The second `inc a` is expected to produce a different value
+        reference[7].a = 0x00;
+        let mut rusty_boy = RustyBoy::new_with_cartridge(cartridge());
+        let mismatch = rusty_boy
+            .run_against_trace(reference.iter().copied())
+            .unwrap_err();
+        assert_eq!(mismatch.index, 7);
+        assert_eq!(mismatch.expected.a, 0x00);
+        assert_eq!(mismatch.actual.a, 0x44);
+        assert_eq!(mismatch.actual.pc, 0x153);
+    }
+
+    #[test]
+    fn lcd_toggle() {
+        use alloc::rc::Rc;
+        use core::cell::RefCell;
+
+        let mut rusty_boy = RustyBoy::new_with_cartridge(test_cartridge(&[0x18, 0xFE], &[]));
+        let toggles = Rc::new(RefCell::new(vec![]));
+        let listener_toggles = toggles.clone();
+        rusty_boy.on_lcd_toggle(Some(Box::new(move |enabled| {
+            listener_toggles.borrow_mut().push(enabled)
+        })));
+
+        // The LCD is on after boot
+        assert!(rusty_boy.lcd_enabled());
+        rusty_boy.address_space.write(0xFF40, 0x00);
+        assert!(!rusty_boy.lcd_enabled());
+
+        rusty_boy.address_space.write(0xFF40, 0x91);
+        assert!(rusty_boy.lcd_enabled());
+
+        // Writes that keep the LCD on are not reported
+        rusty_boy.address_space.write(0xFF40, 0x81);
+        assert!(rusty_boy.lcd_enabled());
+
+        rusty_boy.set_lcd_enabled(false);
+        assert!(!rusty_boy.lcd_enabled());
+        assert_eq!(rusty_boy.address_space.read(0xFF40), 0x01);
+
+        assert_eq!(*toggles.borrow(), [false, true, false]);
+    }
+
+    #[test]
+    fn header() {
+        let rusty_boy = RustyBoy::new_with_cartridge(test_cartridge(&[], &[]));
+        assert_eq!(rusty_boy.header().unwrap().title, "TEST");
+    }
+
+    #[test]
+    fn palette_registers() {
+        let mut rusty_boy = RustyBoy::new_with_cartridge(test_cartridge(&[], &[]));
+        rusty_boy.address_space.write(0xFF47, 0xE4);
+        rusty_boy.address_space.write(0xFF48, 0x1B);
+        rusty_boy.address_space.write(0xFF49, 0xD2);
+        assert_eq!(rusty_boy.bg_palette(), 0xE4);
+        assert_eq!(rusty_boy.obj_palette0(), 0x1B);
+        assert_eq!(rusty_boy.obj_palette1(), 0xD2);
+    }
+
+    #[test]
+    fn serial_transfer_without_peer() {
+        let program = [
+            0x3E, 0x08, // ld a, 0x08
+            0xE0, 0xFF, // ldh [IE], a
+            0x3E, 0x42, // ld a, 0x42
+            0xE0, 0x01, // ldh [SB], a
+            0x3E, 0x81, // ld a, 0x81
+            0xE0, 0x02, // ldh [SC], a
+            0xFB, // ei
+            0x18, 0xFE, // jr -2
+        ];
+        let serial_handler: &[u8] = &[
+            0xF0, 0x01, // ldh a, [SB]
+            0xEA, 0x00, 0xC0, // ld [0xC000], a
+            0xD9, // reti
+        ];
+        let mut rusty_boy =
+            RustyBoy::new_with_cartridge(test_cartridge(&program, &[(0x58, serial_handler)]));
+
+        // A transfer takes 4096 cycles, and each loop iteration takes 12 cycles
+        rusty_boy.step_bounded(300).unwrap();
+        assert_eq!(rusty_boy.address_space.wram[0], 0x00);
+        assert_ne!(rusty_boy.address_space.read(0xFF02) & 0x80, 0);
+
+        rusty_boy.step_bounded(100).unwrap();
+        // Without a peer, ones are shifted in
+        assert_eq!(rusty_boy.address_space.wram[0], 0xFF);
+        assert_eq!(rusty_boy.address_space.read(0xFF02) & 0x80, 0);
+        assert_eq!(rusty_boy.address_space.read(0xFF0F) & 0x08, 0);
+    }
+
+    #[test]
+    fn apu_is_stepped_with_the_system() {
+        let program = [
+            0x3E, 0x80, // ld a, 0x80
+            0xE0, 0x26, // ldh [NR52], a
+            0x3E, 0x3F, // ld a, 0x3F
+            0xE0, 0x16, // ldh [NR21], a
+            0x3E, 0xF0, // ld a, 0xF0
+            0xE0, 0x17, // ldh [NR22], a
+            0x3E, 0xC0, // ld a, 0xC0
+            0xE0, 0x19, // ldh [NR24], a
+            0x18, 0xFE, // jr -2
+        ];
+        let mut rusty_boy = RustyBoy::new_with_cartridge(test_cartridge(&program, &[]));
+
+        // Runs the entry point and the register writes
+        rusty_boy.step_bounded(10).unwrap();
+        assert_eq!(rusty_boy.address_space.read(0xFF26), 0xF2);
+
+        // The length counter of channel 2 expires on the first frame sequencer step, after 8192
+        // cycles, and each loop iteration takes 12 cycles
+        rusty_boy.step_bounded(700).unwrap();
+        assert_eq!(rusty_boy.address_space.read(0xFF26), 0xF0);
+
+        let mut samples = [0; 1024];
+        assert!(rusty_boy.audio_samples(&mut samples) > 0);
+    }
+
+    #[test]
+    fn frame_timing_breakdown() {
+        use core::cell::Cell;
+
+        /// A clock that advances a bit more every time it is read, so that every component
+        /// accumulates a different amount of time.
+        struct MockClock {
+            now: Cell<u64>,
+            reads: Cell<u64>,
+        }
+
+        impl profiling::ProfilingClock for MockClock {
+            fn now(&self) -> u64 {
+                self.reads.set(self.reads.get() + 1);
+                self.now.set(self.now.get() + self.reads.get());
+                self.now.get()
+            }
+        }
+
+        let mut rusty_boy = RustyBoy::new_with_cartridge(vblank_counter_cartridge(b"TEST"));
+        rusty_boy.run_until_next_frame(false).unwrap();
+        assert_eq!(rusty_boy.last_frame_timing(), FrameTiming::default());
+
+        rusty_boy.configure_profiling(Some(Box::new(MockClock {
+            now: Cell::new(0),
+            reads: Cell::new(0),
+        })));
+        rusty_boy.run_until_next_frame(false).unwrap();
+        let timing = rusty_boy.last_frame_timing();
+        assert!(timing.cpu > 0);
+        assert!(timing.ppu > timing.cpu);
+        assert!(timing.timer_and_dma > timing.ppu);
+        assert_eq!(timing.cpu + timing.ppu + timing.timer_and_dma, timing.total);
+
+        rusty_boy.configure_profiling(None);
+        assert_eq!(rusty_boy.last_frame_timing(), FrameTiming::default());
+    }
+
+    fn vblank_counter_cartridge(title: &[u8]) -> Cartridge {
+        let program = [
+            0x3E, 0x01, // ld a, 0x01
+            0xE0, 0xFF, // ldh [IE], a
+            0x21, 0x00, 0xC0, // ld hl, 0xC000
+            0x3E, 0x80, // ld a, 0x80
+            0xE0, 0x40, // ldh [LCDC], a
+            0xFB, // ei
+            0x18, 0xFE, // jr -2
+        ];
+        // inc [hl]; reti
+        let vblank_handler: &[u8] = &[0x34, 0xD9];
+        let cartridge = test_cartridge(&program, &[(0x40, vblank_handler)]);
+
+        let mut rom = cartridge.rom().to_vec();
+        rom[0x134..0x138].copy_from_slice(title);
+        Cartridge::try_new(rom).unwrap()
+    }
+
+    #[test]
+    fn save_and_load_state() {
+        let mut rusty_boy = RustyBoy::new_with_cartridge(vblank_counter_cartridge(b"TEST"));
+        rusty_boy.run_to_vblank_count(2, false);
+        let state = rusty_boy.save_state();
+
+        rusty_boy.run_to_vblank_count(3, false);
+        let expected_record = rusty_boy.trace_record();
+        let expected_memory = rusty_boy.dump_memory();
+
+        // A freshly booted emulator resumes from the saved point
+        let mut restored = RustyBoy::new_with_cartridge(vblank_counter_cartridge(b"TEST"));
+        restored.load_state(&state).unwrap();
+        // The handler for the last VBlank has not had a chance to run yet.
+        assert_eq!(restored.address_space.read(0xC000), 1);
+        assert!(restored.address_space.ppu.lcd_enabled());
+
+        restored.run_to_vblank_count(3, false);
+        assert_eq!(restored.trace_record(), expected_record);
+        assert_eq!(restored.dump_memory(), expected_memory);
+    }
+
+    #[test]
+    fn save_state_restores_apu() {
+        let mut rusty_boy = RustyBoy::new_with_cartridge(vblank_counter_cartridge(b"TEST"));
+        rusty_boy.address_space.write(0xFF26, 0x80);
+        rusty_boy.address_space.write(0xFF24, 0x77);
+        rusty_boy.address_space.write(0xFF12, 0xF3);
+        rusty_boy.address_space.write(0xFF14, 0x80);
+        rusty_boy.address_space.write(0xFF30, 0x12);
+        let state = rusty_boy.save_state();
+
+        rusty_boy.address_space.write(0xFF24, 0x11);
+        rusty_boy.address_space.write(0xFF12, 0x00);
+        rusty_boy.address_space.write(0xFF30, 0x99);
+        assert_eq!(rusty_boy.address_space.read(0xFF26), 0xF0);
+
+        rusty_boy.load_state(&state).unwrap();
+        assert_eq!(rusty_boy.address_space.read(0xFF24), 0x77);
+        assert_eq!(rusty_boy.address_space.read(0xFF12), 0xF3);
+        assert_eq!(rusty_boy.address_space.read(0xFF30), 0x12);
+        // Channel 1 is playing again
+        assert_eq!(rusty_boy.address_space.read(0xFF26), 0xF1);
+    }
+
+    #[test]
+    fn save_state_restores_bus_latch() {
+        let mut rusty_boy = RustyBoy::new_with_cartridge(vblank_counter_cartridge(b"TEST"));
+        rusty_boy.configure_open_bus(OpenBus::Cgb);
+        rusty_boy.address_space.write(0xC000, 0x42);
+        let state = rusty_boy.save_state();
+
+        rusty_boy.address_space.write(0xC000, 0x24);
+        rusty_boy.load_state(&state).unwrap();
+        assert_eq!(rusty_boy.address_space.read(0xFF7F), 0x42);
+
+        // States from before the latch was saved leave it as after a reset
+        let mut old_state = state[..state.len() - 1].to_vec();
+        old_state[save_state::MAGIC.len()..save_state::MAGIC.len() + 2]
+            .copy_from_slice(&9u16.to_le_bytes());
+        rusty_boy.load_state(&old_state).unwrap();
+        assert_eq!(rusty_boy.address_space.read(0xFF7F), 0x00);
+    }
+
+    #[test]
+    fn load_state_rejects_invalid_data() {
+        let mut rusty_boy = RustyBoy::new_with_cartridge(vblank_counter_cartridge(b"TEST"));
+        rusty_boy.run_to_vblank_count(2, false);
+        let mut state = rusty_boy.save_state();
+
+        let mut other_game = RustyBoy::new_with_cartridge(vblank_counter_cartridge(b"GAME"));
+        assert_eq!(
+            other_game.load_state(&state),
+            Err(SaveStateError::CartridgeMismatch)
+        );
+
+        // A corrupted state leaves the emulator untouched
+        let mut restored = RustyBoy::new_with_cartridge(vblank_counter_cartridge(b"TEST"));
+        restored.step_bounded(10).unwrap();
+        let record = restored.trace_record();
+        assert_eq!(
+            restored.load_state(&state[..state.len() - 1]),
+            Err(SaveStateError::Corrupted(StateError::Truncated))
+        );
+        assert_eq!(restored.trace_record(), record);
+        assert_eq!(restored.address_space.read(0xC000), 0);
+
+        state[save_state::MAGIC.len()] += 1;
+        assert_eq!(
+            restored.load_state(&state),
+            Err(SaveStateError::UnsupportedVersion(save_state::VERSION + 1))
+        );
+        state[0] = b'X';
+        assert_eq!(restored.load_state(&state), Err(SaveStateError::BadMagic));
+    }
 }
@@ -1,17 +1,166 @@
 use crate::joypad::Joypad;
-use cartridge::Cartridge;
-use ppu::Ppu;
-use sm83::interrupts::InterruptRegs;
+use crate::serial::Serial;
+use apu::Apu;
+use cartridge::{mappers::ControlWrite, Cartridge};
+use ppu::{dma::DmaEngine, oam::OAM_SIZE, Ppu, PpuResult};
+use sm83::{
+    core::Cycles,
+    interrupts::{Interrupt, InterruptRegs, Interrupts},
+    memory::Memory,
+    state::{SaveState, StateError, StateReader, StateWriter},
+};
 use timer::Timer;
 
 extern crate alloc;
 use alloc::boxed::Box;
+use alloc::collections::BTreeSet;
+use alloc::vec::Vec;
 
+use core::cell::Cell;
 use core::mem::MaybeUninit;
 
 pub type Wram = Box<[u8; 0x2000]>;
 pub type Hram = Box<[u8; 0x7f]>;
 
+/// Callback invoked with the new state of the LCD whenever it is turned on or off
+pub type LcdListener = Box<dyn FnMut(bool)>;
+
+/// Size of the DMG boot ROM, which is mapped over the start of the cartridge ROM
+pub const BOOT_ROM_SIZE: usize = 0x100;
+
+/// A system bus the emulator drives the CPU against. `GbAddressSpace` implements the bus of the
+/// Game Boy, while custom implementations allow modeling other memory layouts and peripherals.
+pub trait Bus: Memory {
+    /// Returns the interrupts that are both requested and enabled.
+    fn active_interrupts(&self) -> Interrupts;
+
+    /// Clears the request of an interrupt once the CPU services it.
+    fn acknowledge_interrupt(&mut self, interrupt: Interrupt);
+
+    /// Advances the devices attached to the bus by the given number of cycles, requesting the
+    /// interrupts they raise. Returns the raised interrupts.
+    fn step(&mut self, _cycles: Cycles) -> Interrupts {
+        Interrupts::new()
+    }
+
+    /// Returns true when the CPU must resume from STOP. Buses without a joypad never wake it up.
+    fn stop_wakeup(&self) -> bool {
+        false
+    }
+}
+
+/// Regions of the Game Boy address space
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    /// 0x0000 to 0x3FFF: Fixed ROM bank of the cartridge
+    RomBank0,
+    /// 0x4000 to 0x7FFF: Switchable ROM bank of the cartridge
+    RomBankN,
+    /// 0x8000 to 0x9FFF: Video RAM
+    Vram,
+    /// 0xA000 to 0xBFFF: External RAM in the cartridge
+    CartRam,
+    /// 0xC000 to 0xCFFF: Fixed work RAM bank
+    Wram0,
+    /// 0xD000 to 0xDFFF: Second work RAM bank (switchable on CGB)
+    WramN,
+    /// 0xE000 to 0xFDFF: Mirror of 0xC000 to 0xDDFF
+    EchoRam,
+    /// 0xFE00 to 0xFE9F: Object attribute memory
+    Oam,
+    /// 0xFEA0 to 0xFEFF: Not usable
+    Unusable,
+    /// 0xFF00 to 0xFF7F: I/O registers
+    Io,
+    /// 0xFF80 to 0xFFFE: High RAM
+    Hram,
+    /// 0xFFFF: Interrupt enable register
+    IeReg,
+}
+
+/// Returns the region of the address space the given address belongs to.
+pub const fn region_for(address: sm83::memory::Address) -> Region {
+    match address {
+        0x0000..=0x3FFF => Region::RomBank0,
+        0x4000..=0x7FFF => Region::RomBankN,
+        0x8000..=0x9FFF => Region::Vram,
+        0xA000..=0xBFFF => Region::CartRam,
+        0xC000..=0xCFFF => Region::Wram0,
+        0xD000..=0xDFFF => Region::WramN,
+        0xE000..=0xFDFF => Region::EchoRam,
+        0xFE00..=0xFE9F => Region::Oam,
+        0xFEA0..=0xFEFF => Region::Unusable,
+        0xFF00..=0xFF7F => Region::Io,
+        0xFF80..=0xFFFE => Region::Hram,
+        0xFFFF => Region::IeReg,
+    }
+}
+
+/// Controls how writes to the mapper control region of the cartridge (0x0000 to 0x7FFF) are
+/// traced. Useful to diagnose banking bugs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RomWriteTrace {
+    /// Writes are not traced
+    #[default]
+    Off,
+    /// Writes are logged along with their decoded effect
+    Log,
+    /// Writes are logged, and writes that have no effect on the mapper cause a panic
+    PanicOnIgnored,
+}
+
+/// Selects the value returned by reads from I/O addresses that no device drives, which depends on
+/// the hardware model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OpenBus {
+    /// Undefined reads return 0
+    #[default]
+    Zero,
+    /// Original Game Boy. Undefined reads return 0xFF, as the data lines are pulled up.
+    Dmg,
+    /// Game Boy Color. Undefined reads return the last byte driven on the bus.
+    Cgb,
+}
+
+/// A pattern that fills a memory at power on
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum RamPattern {
+    /// All bytes are 0
+    #[default]
+    Zero,
+    /// All bytes have the given value
+    Fill(u8),
+    /// The given bytes are repeated over the whole memory
+    Repeat(Vec<u8>),
+}
+
+impl RamPattern {
+    fn apply(&self, memory: &mut [u8]) {
+        match self {
+            RamPattern::Zero => memory.fill(0),
+            RamPattern::Fill(value) => memory.fill(*value),
+            RamPattern::Repeat(pattern) if pattern.is_empty() => memory.fill(0),
+            RamPattern::Repeat(pattern) => memory
+                .iter_mut()
+                .zip(pattern.iter().cycle())
+                .for_each(|(byte, value)| *byte = *value),
+        }
+    }
+}
+
+/// Contents of the memories that are not initialized at power on. Real hardware leaves them with
+/// values that depend on the unit, and some games read them before writing, so reproducing the
+/// values of a specific unit is useful for TAS and bug reports.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct RamInit {
+    /// Work RAM, from 0xC000 to 0xDFFF
+    pub wram: RamPattern,
+    /// High RAM, from 0xFF80 to 0xFFFE
+    pub hram: RamPattern,
+    /// Object attribute memory, from 0xFE00 to 0xFE9F
+    pub oam: RamPattern,
+}
+
 pub struct GbAddressSpace {
     pub cartridge: Cartridge,
     pub ppu: Ppu,
@@ -20,9 +169,21 @@ pub struct GbAddressSpace {
     pub interrupt_regs: InterruptRegs,
     pub joypad: Joypad,
     pub timer: Timer,
-
-    pub sb: u8,
-    pub sc: u8,
+    pub serial: Serial,
+    pub apu: Apu,
+    pub rom_write_trace: RomWriteTrace,
+    pub open_bus: OpenBus,
+    /// Contents of WRAM, HRAM and OAM after a reset
+    pub ram_init: RamInit,
+    /// Last traced write to the mapper control region, as (address, value, decoded effect)
+    pub last_control_write: Option<(sm83::memory::Address, u8, ControlWrite)>,
+    dma_engine: DmaEngine,
+    lcd_listener: Option<LcdListener>,
+    /// Last byte driven on the bus by a read or a write
+    bus_latch: Cell<u8>,
+    boot_rom: Option<Box<[u8; BOOT_ROM_SIZE]>>,
+    /// The boot ROM is mapped over the cartridge until the game writes to 0xFF50
+    boot_rom_mapped: bool,
 }
 
 impl GbAddressSpace {
@@ -46,61 +207,463 @@ impl GbAddressSpace {
             interrupt_regs: InterruptRegs::new(),
             joypad: Joypad::new(),
             timer: Timer::new(),
-            sb: 0,
-            sc: 0,
+            serial: Serial::new(),
+            apu: Apu::new(),
+            rom_write_trace: RomWriteTrace::Off,
+            open_bus: OpenBus::Zero,
+            ram_init: RamInit::default(),
+            last_control_write: None,
+            dma_engine: DmaEngine::new(),
+            lcd_listener: None,
+            bus_latch: Cell::new(0),
+            boot_rom: None,
+            boot_rom_mapped: false,
         }
     }
-}
 
-impl sm83::memory::Memory for GbAddressSpace {
-    fn read(&self, address: sm83::memory::Address) -> u8 {
+    /// Fills WRAM, HRAM and OAM according to `ram_init`.
+    pub fn apply_ram_init(&mut self) {
+        self.ram_init.wram.apply(&mut self.wram[..]);
+        self.ram_init.hram.apply(&mut self.hram[..]);
+
+        let mut oam = [0; OAM_SIZE];
+        self.ram_init.oam.apply(&mut oam);
+        for (address, value) in (0xFE00..).zip(oam) {
+            self.ppu.write(address, value);
+        }
+    }
+
+    /// Installs a boot ROM and maps it over the start of the cartridge ROM, or removes it.
+    pub fn set_boot_rom(&mut self, boot_rom: Option<Box<[u8; BOOT_ROM_SIZE]>>) {
+        self.boot_rom_mapped = boot_rom.is_some();
+        self.boot_rom = boot_rom;
+    }
+
+    /// Returns true while the boot ROM hides the start of the cartridge ROM.
+    pub fn boot_rom_mapped(&self) -> bool {
+        self.boot_rom_mapped
+    }
+
+    /// Reads the given address without any side effects, not even trace logs for unimplemented
+    /// registers. Meant for debuggers and tools that inspect memory while the emulation is paused.
+    pub fn peek(&self, address: sm83::memory::Address) -> u8 {
+        match address {
+            0xFF00..=0xFF3F | 0xFF4C..=0xFF7F if !self.is_mapped_io(address) => 0,
+            _ => self.read_bus(address),
+        }
+    }
+
+    /// Returns true if the I/O register at the given address is emulated.
+    fn is_mapped_io(&self, address: sm83::memory::Address) -> bool {
+        matches!(
+            address,
+            0xFF00..=0xFF02 | 0xFF04..=0xFF07 | 0xFF0F | 0xFF10..=0xFF26 | 0xFF30..=0xFF3F
+        ) || self.is_mapped_cgb_io(address)
+    }
+
+    /// Returns true if the address is a Game Boy Color register of the PPU, which are only mapped
+    /// when the PPU runs in color mode.
+    fn is_mapped_cgb_io(&self, address: sm83::memory::Address) -> bool {
+        matches!(address, 0xFF4F | 0xFF68..=0xFF6B) && self.ppu.color_mode()
+    }
+
+    /// Restores all devices to their power-on state, keeping the contents of the cartridge RAM and
+    /// the debugging configuration.
+    pub fn reset(&mut self) {
+        self.cartridge.reset();
+        self.ppu.reset();
+        self.apply_ram_init();
+        self.interrupt_regs = InterruptRegs::new();
+        self.joypad.reset();
+        self.timer.reset();
+        self.serial.reset();
+        self.apu.reset();
+        self.last_control_write = None;
+        self.dma_engine = DmaEngine::new();
+        self.bus_latch.set(0);
+        self.boot_rom_mapped = self.boot_rom.is_some();
+    }
+
+    /// Registers a callback that observes the LCD being turned on or off by writes to LCDC,
+    /// replacing the previous one, if any.
+    pub fn on_lcd_toggle(&mut self, listener: Option<LcdListener>) {
+        self.lcd_listener = listener;
+    }
+
+    fn write_lcdc(&mut self, value: u8) {
+        let was_enabled = self.ppu.lcd_enabled();
+        self.ppu.write(0xFF40, value);
+        let enabled = self.ppu.lcd_enabled();
+        if enabled != was_enabled {
+            if let Some(listener) = &mut self.lcd_listener {
+                listener(enabled);
+            }
+        }
+    }
+
+    /// Advances all devices by the given number of cycles and requests the interrupts they raise.
+    /// Returns the raised interrupts along with the result of the PPU step.
+    pub fn step_devices(&mut self, cycles: Cycles, render: bool) -> (Interrupts, PpuResult) {
+        let (ppu_interrupts, ppu_result) = self.step_ppu(cycles, render);
+        let interrupts = ppu_interrupts | self.step_timers_and_dma(cycles);
+        (interrupts, ppu_result)
+    }
+
+    /// Advances the PPU by the given number of cycles and requests the interrupts it raises.
+    pub(crate) fn step_ppu(&mut self, cycles: Cycles, render: bool) -> (Interrupts, PpuResult) {
+        let (interrupts, ppu_result) = self.ppu.step(cycles, &mut self.dma_engine, render);
+        self.interrupt_regs.trigger(interrupts);
+        (interrupts, ppu_result)
+    }
+
+    /// Advances the timer, the serial port, the APU and the DMA engine by the given number of
+    /// cycles and requests the interrupts they raise.
+    pub(crate) fn step_timers_and_dma(&mut self, cycles: Cycles) -> Interrupts {
+        let timer_interrupts = self.timer.step(cycles);
+        let serial_interrupts = self.serial.step(cycles);
+        self.apu.step(cycles);
+
+        // The DMA engine copies data through the bus itself.
+        let mut dma_engine = core::mem::replace(&mut self.dma_engine, DmaEngine::new());
+        dma_engine.run(cycles, &mut DmaBus(self));
+        self.dma_engine = dma_engine;
+
+        let interrupts = timer_interrupts | serial_interrupts;
+        self.interrupt_regs.trigger(interrupts);
+        interrupts
+    }
+
+    /// Returns the value of a read from an address that no device drives.
+    fn open_bus_value(&self) -> u8 {
+        match self.open_bus {
+            OpenBus::Zero => 0,
+            OpenBus::Dmg => 0xFF,
+            OpenBus::Cgb => self.bus_latch.get(),
+        }
+    }
+
+    fn read_bus(&self, address: sm83::memory::Address) -> u8 {
         match address {
+            0x0000..=0x00FF if self.boot_rom_mapped => match &self.boot_rom {
+                Some(boot_rom) => boot_rom[address as usize],
+                None => unreachable!("The boot ROM is mapped but missing"),
+            },
             0x0000..=0x7FFF | 0xA000..=0xBFFF => self.cartridge.read(address),
             0xC000..=0xDFFF => self.wram[address as usize - 0xC000],
+            // Echo RAM mirrors the first 0x1E00 bytes of WRAM
+            0xE000..=0xFDFF => self.wram[address as usize - 0xE000],
             0xFF80..=0xFFFE => self.hram[address as usize - 0xFF80],
             0x8000..=0x9FFF | 0xFE00..=0xFE9F | 0xFF40..=0xFF4B => self.ppu.read(address),
             0xFF00 => self.joypad.read(address),
-            0xFF01 => self.sb,
-            0xFF02 => self.sc,
+            0xFF01..=0xFF02 => self.serial.read(address),
             0xFF04..=0xFF07 => self.timer.read(address),
+            0xFF10..=0xFF26 | 0xFF30..=0xFF3F => self.apu.read_register(address),
             0xFF0F | 0xFFFF => self.interrupt_regs.read(address),
+            _ if self.is_mapped_cgb_io(address) => self.ppu.read(address),
             0xFF00..=0xFF3F | 0xFF4C..=0xFF7F => {
                 log::trace!("Unimplemented read from I/O regs: {address:#x}");
-                0
+                self.open_bus_value()
             }
             0xFEA0..=0xFEFF => {
                 // This region must not be used, but unfortunately some games seem to rely on it.
                 0
             }
-            _ => panic!("Invalid read address: {}", address),
         }
     }
 
+    fn trace_control_write(&mut self, address: sm83::memory::Address, value: u8) {
+        let decoded = self.cartridge.decode_control_write(address, value);
+        log::debug!("Mapper control write {address:#06x} = {value:#04x}: {decoded}");
+        if self.rom_write_trace == RomWriteTrace::PanicOnIgnored && decoded == ControlWrite::Ignored
+        {
+            panic!("Write to ROM with no effect: {address:#06x} = {value:#04x}");
+        }
+        self.last_control_write = Some((address, value, decoded));
+    }
+}
+
+/// Saves the state of all devices. The debugging configuration is not part of the state.
+impl SaveState for GbAddressSpace {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.cartridge.save_state(state);
+        self.ppu.save_state(state);
+        state.write_bytes(&self.wram[..]);
+        state.write_bytes(&self.hram[..]);
+        self.interrupt_regs.save_state(state);
+        self.joypad.save_state(state);
+        self.timer.save_state(state);
+        self.serial.save_state(state);
+        self.apu.save_state(state);
+        self.dma_engine.save_state(state);
+        state.write_bool(self.boot_rom_mapped);
+        state.write_u8(self.bus_latch.get());
+    }
+
+    fn load_state(&mut self, state: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.cartridge.load_state(state)?;
+        self.ppu.load_state(state)?;
+        state.read_bytes(&mut self.wram[..])?;
+        state.read_bytes(&mut self.hram[..])?;
+        self.interrupt_regs.load_state(state)?;
+        self.joypad.load_state(state)?;
+        self.timer.load_state(state)?;
+        self.serial.load_state(state)?;
+        self.apu.load_state(state)?;
+        self.dma_engine.load_state(state)?;
+        let boot_rom_mapped = state.read_bool()?;
+        if boot_rom_mapped && self.boot_rom.is_none() {
+            return Err(StateError::Invalid);
+        }
+        self.boot_rom_mapped = boot_rom_mapped;
+        self.bus_latch.set(state.read_u8()?);
+        self.last_control_write = None;
+        Ok(())
+    }
+}
+
+impl Bus for GbAddressSpace {
+    fn active_interrupts(&self) -> Interrupts {
+        self.interrupt_regs.active_interrupts()
+    }
+
+    fn acknowledge_interrupt(&mut self, interrupt: Interrupt) {
+        self.interrupt_regs.acknowledge(interrupt);
+    }
+
+    fn step(&mut self, cycles: Cycles) -> Interrupts {
+        self.step_devices(cycles, true).0
+    }
+
+    fn stop_wakeup(&self) -> bool {
+        self.joypad.selected_line_low()
+    }
+}
+
+/// The CPU executes whole instructions before the PPU catches up, so VRAM and OAM accesses are
+/// resolved against the PPU mode at the start of the instruction, not at the machine cycle of the
+/// access. E.g. the write of `ld [hl], a` is still blocked if mode 0 begins during the instruction.
+impl sm83::memory::Memory for GbAddressSpace {
+    fn read(&self, address: sm83::memory::Address) -> u8 {
+        let value = match address {
+            0x8000..=0x9FFF if !self.ppu.vram_accessible() => 0xFF,
+            0xFE00..=0xFE9F if !self.ppu.oam_accessible() => 0xFF,
+            _ => self.read_bus(address),
+        };
+        self.bus_latch.set(value);
+        value
+    }
+
     fn write(&mut self, address: sm83::memory::Address, value: u8) {
+        self.bus_latch.set(value);
         match address {
-            0x0000..=0x7FFF | 0xA000..=0xBFFF => self.cartridge.write(address, value),
+            // Writes are ignored while the PPU uses the memory
+            0x8000..=0x9FFF if !self.ppu.vram_accessible() => {}
+            0xFE00..=0xFE9F if !self.ppu.oam_accessible() => {}
+            0x0000..=0x7FFF => {
+                if self.rom_write_trace != RomWriteTrace::Off {
+                    self.trace_control_write(address, value);
+                }
+                self.cartridge.write(address, value)
+            }
+            0xA000..=0xBFFF => self.cartridge.write(address, value),
             0xC000..=0xDFFF => {
                 self.wram[address as usize - 0xC000] = value;
             }
+            0xE000..=0xFDFF => {
+                self.wram[address as usize - 0xE000] = value;
+            }
             0xFF80..=0xFFFE => {
                 self.hram[address as usize - 0xFF80] = value;
             }
-            0x8000..=0x9FFF | 0xFE00..=0xFE9F | 0xFF40..=0xFF4B => self.ppu.write(address, value),
+            0xFF40 => self.write_lcdc(value),
+            0x8000..=0x9FFF | 0xFE00..=0xFE9F | 0xFF41..=0xFF4B => self.ppu.write(address, value),
             0xFF00 => self.joypad.write(address, value),
-            0xFF01 => self.sb = value,
-            0xFF02 => {
-                self.sc = value;
-                self.sc &= 0x7f;
-            }
+            0xFF01..=0xFF02 => self.serial.write(address, value),
             0xFF04..=0xFF07 => self.timer.write(address, value),
+            0xFF10..=0xFF26 | 0xFF30..=0xFF3F => self.apu.write_register(address, value),
             0xFF0F | 0xFFFF => self.interrupt_regs.write(address, value),
+            // Any non-zero value unmaps the boot ROM until the next reset
+            0xFF50 if value != 0 => self.boot_rom_mapped = false,
+            _ if self.is_mapped_cgb_io(address) => self.ppu.write(address, value),
             0xFF00..=0xFF3F | 0xFF4C..=0xFF7F => {
                 log::trace!("Unimplemented write to I/O regs: {address:#x} = {value:#x}")
             }
             0xFEA0..=0xFEFF => {
                 // This region must not be used, but unfortunately some games seem to rely on it.
             }
-            _ => panic!("Invalid write address: {address:#x}, value {value:#x}"),
         }
     }
 }
+
+/// The bus as seen by the OAM DMA engine, which can access VRAM and OAM while the PPU blocks them
+/// for the CPU. DMA transfers don't drive the bus seen by the CPU, so they leave the latch alone.
+struct DmaBus<'a>(&'a mut GbAddressSpace);
+
+impl sm83::memory::Memory for DmaBus<'_> {
+    fn read(&self, address: sm83::memory::Address) -> u8 {
+        self.0.read_bus(address)
+    }
+
+    fn write(&mut self, address: sm83::memory::Address, value: u8) {
+        match address {
+            0xFE00..=0xFE9F => self.0.ppu.write(address, value),
+            _ => self.0.write(address, value),
+        }
+    }
+}
+
+/// Wraps the bus of the CPU to record the first access to any of the watched addresses, either a
+/// read (including instruction fetches) or a write.
+pub(crate) struct WatchedBus<'a, M> {
+    bus: &'a mut M,
+    watchpoints: &'a BTreeSet<sm83::memory::Address>,
+    hit: Cell<Option<sm83::memory::Address>>,
+}
+
+impl<'a, M: sm83::memory::Memory> WatchedBus<'a, M> {
+    pub(crate) fn new(bus: &'a mut M, watchpoints: &'a BTreeSet<sm83::memory::Address>) -> Self {
+        Self {
+            bus,
+            watchpoints,
+            hit: Cell::new(None),
+        }
+    }
+
+    /// Returns the first watched address that was accessed, if any.
+    pub(crate) fn hit(&self) -> Option<sm83::memory::Address> {
+        self.hit.get()
+    }
+
+    fn check(&self, address: sm83::memory::Address) {
+        if self.hit.get().is_none() && self.watchpoints.contains(&address) {
+            self.hit.set(Some(address));
+        }
+    }
+}
+
+impl<M: sm83::memory::Memory> sm83::memory::Memory for WatchedBus<'_, M> {
+    fn read(&self, address: sm83::memory::Address) -> u8 {
+        self.check(address);
+        self.bus.read(address)
+    }
+
+    fn write(&mut self, address: sm83::memory::Address, value: u8) {
+        self.check(address);
+        self.bus.write(address, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sm83::memory::Memory;
+
+    use alloc::string::ToString;
+    use alloc::vec;
+
+    #[test]
+    fn address_regions() {
+        assert_eq!(region_for(0x0000), Region::RomBank0);
+        assert_eq!(region_for(0x0150), Region::RomBank0);
+        assert_eq!(region_for(0x4000), Region::RomBankN);
+        assert_eq!(region_for(0x7FFF), Region::RomBankN);
+        assert_eq!(region_for(0x9800), Region::Vram);
+        assert_eq!(region_for(0xA000), Region::CartRam);
+        assert_eq!(region_for(0xC000), Region::Wram0);
+        assert_eq!(region_for(0xD000), Region::WramN);
+        assert_eq!(region_for(0xE000), Region::EchoRam);
+        assert_eq!(region_for(0xFDFF), Region::EchoRam);
+        assert_eq!(region_for(0xFE00), Region::Oam);
+        assert_eq!(region_for(0xFEA0), Region::Unusable);
+        assert_eq!(region_for(0xFF40), Region::Io);
+        assert_eq!(region_for(0xFF80), Region::Hram);
+        assert_eq!(region_for(0xFFFE), Region::Hram);
+        assert_eq!(region_for(0xFFFF), Region::IeReg);
+    }
+
+    #[test]
+    fn open_bus_reads() {
+        let mut address_space = GbAddressSpace::new(Cartridge::try_new(vec![0; 0x8000]).unwrap());
+
+        // 0xFF7F is not connected to any device
+        address_space.write(0xC000, 0x42);
+        assert_eq!(address_space.read(0xFF7F), 0x00);
+
+        address_space.open_bus = OpenBus::Dmg;
+        assert_eq!(address_space.read(0xFF7F), 0xFF);
+
+        address_space.open_bus = OpenBus::Cgb;
+        address_space.write(0xC000, 0x42);
+        assert_eq!(address_space.read(0xFF7F), 0x42);
+
+        // Reads drive the bus too, but peeking doesn't
+        address_space.write(0xC001, 0x24);
+        address_space.write(0xC000, 0x42);
+        assert_eq!(address_space.read(0xC001), 0x24);
+        assert_eq!(address_space.peek(0xC000), 0x42);
+        assert_eq!(address_space.read(0xFF7F), 0x24);
+
+        // Mapped registers are not affected
+        address_space.write(0xFF80, 0x99);
+        assert_eq!(address_space.read(0xFF05), 0x00);
+    }
+
+    #[test]
+    fn ppu_blocks_cpu_accesses() {
+        let mut address_space = GbAddressSpace::new(Cartridge::try_new(vec![0; 0x8000]).unwrap());
+        let next_mode = |address_space: &mut GbAddressSpace| {
+            let remaining = address_space.ppu.mode_remaining();
+            address_space.step_ppu(remaining, false);
+        };
+
+        address_space.write(0xFF40, 0x00);
+        address_space.write(0x8000, 0x12);
+        address_space.write(0xFE00, 0x34);
+        address_space.write(0xC000, 0x56);
+        address_space.write(0xFF40, 0x80);
+        while address_space.ppu.mode() != ppu::modes::Mode::OamScan {
+            next_mode(&mut address_space);
+        }
+        assert_eq!(address_space.read(0x8000), 0x12);
+        assert_eq!(address_space.read(0xFE00), 0xFF);
+        // Debuggers still see the contents
+        assert_eq!(address_space.peek(0xFE00), 0x34);
+
+        next_mode(&mut address_space);
+        assert_eq!(address_space.ppu.mode(), ppu::modes::Mode::DrawingPixels);
+        assert_eq!(address_space.read(0x8000), 0xFF);
+        assert_eq!(address_space.read(0xFE00), 0xFF);
+        address_space.write(0x8000, 0xAB);
+        // OAM DMA can write OAM while the CPU can't
+        address_space.write(0xFF46, 0xC0);
+
+        next_mode(&mut address_space);
+        address_space.step_timers_and_dma(Cycles::from_machine_cycles(OAM_SIZE));
+        assert_eq!(address_space.ppu.mode(), ppu::modes::Mode::Hblank);
+        assert_eq!(address_space.read(0x8000), 0x12);
+        assert_eq!(address_space.read(0xFE00), 0x56);
+    }
+
+    #[test]
+    fn trace_bank_select_write() {
+        let mut rom = vec![0; 0x20000];
+        rom[0x147] = 0x01; // MBC1
+        rom[0x148] = 0x02; // 128 KiB
+        let mut address_space = GbAddressSpace::new(Cartridge::try_new(rom).unwrap());
+
+        // Writes are not traced by default
+        address_space.write(0x2000, 0x05);
+        assert_eq!(address_space.last_control_write, None);
+
+        address_space.rom_write_trace = RomWriteTrace::Log;
+        address_space.write(0x2100, 0x03);
+        let (address, value, decoded) = address_space.last_control_write.unwrap();
+        assert_eq!((address, value), (0x2100, 0x03));
+        assert_eq!(decoded, ControlWrite::RomBank(3));
+        assert_eq!(decoded.to_string(), "ROM bank 0x3 selected");
+
+        address_space.write(0x0000, 0x0A);
+        let (_, _, decoded) = address_space.last_control_write.unwrap();
+        assert_eq!(decoded.to_string(), "RAM enabled");
+    }
+}
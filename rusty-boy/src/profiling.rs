@@ -0,0 +1,76 @@
+//! Breakdown of the time spent emulating each frame, split by component.
+//!
+//! The emulator has no notion of time on its own, so timestamps come from a clock provided by
+//! the frontend. This keeps the core `no_std` and lets tests drive the clock.
+
+extern crate alloc;
+use alloc::boxed::Box;
+
+/// Source of timestamps for the profiler. Units are up to the implementation, but must be
+/// monotonic. Nanoseconds are a good choice on hosts with a high resolution clock.
+pub trait ProfilingClock {
+    /// Returns the current timestamp
+    fn now(&self) -> u64;
+}
+
+/// Time spent in each component while emulating a frame, in the units of the `ProfilingClock`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FrameTiming {
+    /// Time spent executing CPU instructions
+    pub cpu: u64,
+    /// Time spent in the PPU, which includes drawing the frame
+    pub ppu: u64,
+    /// Time spent in the timer, the serial port, the APU and DMA transfers
+    pub timer_and_dma: u64,
+    /// Total time spent emulating the frame
+    pub total: u64,
+}
+
+impl core::ops::AddAssign for FrameTiming {
+    fn add_assign(&mut self, rhs: Self) {
+        self.cpu += rhs.cpu;
+        self.ppu += rhs.ppu;
+        self.timer_and_dma += rhs.timer_and_dma;
+        self.total += rhs.total;
+    }
+}
+
+/// Accumulates the timing of every step into the timing of the current frame.
+pub(crate) struct Profiler {
+    clock: Box<dyn ProfilingClock>,
+    current_frame: FrameTiming,
+    last_frame: FrameTiming,
+}
+
+impl Profiler {
+    pub(crate) fn new(clock: Box<dyn ProfilingClock>) -> Self {
+        Self {
+            clock,
+            current_frame: FrameTiming::default(),
+            last_frame: FrameTiming::default(),
+        }
+    }
+
+    pub(crate) fn now(&self) -> u64 {
+        self.clock.now()
+    }
+
+    /// Records the timestamps taken at the start of a step and after each of its components ran.
+    pub(crate) fn record_step(&mut self, start: u64, cpu_end: u64, ppu_end: u64, end: u64) {
+        self.current_frame += FrameTiming {
+            cpu: cpu_end - start,
+            ppu: ppu_end - cpu_end,
+            timer_and_dma: end - ppu_end,
+            total: end - start,
+        };
+    }
+
+    /// Publishes the timing of the current frame and starts accounting for the next one.
+    pub(crate) fn finish_frame(&mut self) {
+        self.last_frame = core::mem::take(&mut self.current_frame);
+    }
+
+    pub(crate) fn last_frame(&self) -> FrameTiming {
+        self.last_frame
+    }
+}
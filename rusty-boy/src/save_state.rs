@@ -0,0 +1,168 @@
+//! Save states capture the whole state of the emulated machine, so that emulation can be resumed
+//! later from the exact same point.
+//!
+//! A save state starts with a magic header, a version number and a hash of the title of the
+//! cartridge, followed by the state of the CPU and all the devices in the address space. States
+//! from older versions are forward-migrated before decoding, while states from newer or unknown
+//! versions are rejected.
+
+use alloc::vec::Vec;
+use sm83::state::{StateError, StateReader, StateWriter};
+
+/// Magic header at the start of every save state
+pub const MAGIC: [u8; 4] = *b"RBST";
+
+/// Current version of the save state format. Bump it whenever the layout of the state of any
+/// component changes, and add the corresponding entry to `MIGRATIONS`.
+pub const VERSION: u16 = 10;
+
+/// Oldest version that can be loaded. Older versions predate migrations.
+pub const MIN_VERSION: u16 = 9;
+
+/// Length of the magic header, the version and the hash of the cartridge title
+pub(crate) const HEADER_LEN: usize =
+    MAGIC.len() + core::mem::size_of::<u16>() + core::mem::size_of::<u32>();
+
+/// Converts the machine state of a save state from one version to the next one.
+type Migration = fn(&[u8]) -> Result<Vec<u8>, SaveStateError>;
+
+/// Forward migrations of older save state versions. Entry `i` converts a state of version
+/// `MIN_VERSION + i` into a state of version `MIN_VERSION + i + 1`.
+const MIGRATIONS: [Migration; (VERSION - MIN_VERSION) as usize] = [migrate_v9];
+
+/// Version 10 appends the last value driven on the bus to the state of the address space, which
+/// comes last. Older states get the value of the bus after a reset.
+fn migrate_v9(state: &[u8]) -> Result<Vec<u8>, SaveStateError> {
+    Ok([state, &[0]].concat())
+}
+
+/// Writes the header of a save state for the cartridge with the given title hash.
+pub(crate) fn write_header(state: &mut StateWriter, title_hash: u32) {
+    state.write_bytes(&MAGIC);
+    state.write_u16(VERSION);
+    state.write_u32(title_hash);
+}
+
+/// Checks the header of a save state and returns the state of the machine that follows it,
+/// migrated to the current version.
+pub(crate) fn decode(data: &[u8], title_hash: u32) -> Result<Vec<u8>, SaveStateError> {
+    decode_with(data, title_hash, MIN_VERSION, &MIGRATIONS)
+}
+
+fn decode_with(
+    data: &[u8],
+    title_hash: u32,
+    min_version: u16,
+    migrations: &[Migration],
+) -> Result<Vec<u8>, SaveStateError> {
+    let mut state = StateReader::new(data);
+    if state.take(MAGIC.len()) != Ok(&MAGIC[..]) {
+        return Err(SaveStateError::BadMagic);
+    }
+    let version = state.read_u16()?;
+    let current_version = min_version + migrations.len() as u16;
+    if version < min_version || version > current_version {
+        return Err(SaveStateError::UnsupportedVersion(version));
+    }
+    if state.read_u32()? != title_hash {
+        return Err(SaveStateError::CartridgeMismatch);
+    }
+
+    let mut machine_state = data[HEADER_LEN..].to_vec();
+    for migration in &migrations[(version - min_version) as usize..] {
+        machine_state = migration(&machine_state)?;
+    }
+    Ok(machine_state)
+}
+
+/// Errors that prevent a save state from being loaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SaveStateError {
+    /// The data does not start with the save state magic header
+    BadMagic,
+    /// The save state was created by a different version of the emulator
+    UnsupportedVersion(u16),
+    /// The save state was created with a different cartridge
+    CartridgeMismatch,
+    /// The state of the machine could not be decoded
+    Corrupted(StateError),
+}
+
+impl From<StateError> for SaveStateError {
+    fn from(value: StateError) -> Self {
+        Self::Corrupted(value)
+    }
+}
+
+impl core::fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            SaveStateError::BadMagic => write!(f, "data is not a save state"),
+            SaveStateError::UnsupportedVersion(version) => write!(
+                f,
+                "save state version {version} is not supported (expected {MIN_VERSION} to {VERSION})"
+            ),
+            SaveStateError::CartridgeMismatch => {
+                write!(f, "save state belongs to a different cartridge")
+            }
+            SaveStateError::Corrupted(error) => write!(f, "save state is corrupted: {error}"),
+        }
+    }
+}
+
+#[cfg(any(test, feature = "std"))]
+impl std::error::Error for SaveStateError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    const TITLE_HASH: u32 = 0x1234_5678;
+
+    fn state_with_version(version: u16, machine_state: &[u8]) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        write_header(&mut state, TITLE_HASH);
+        state.write_bytes(machine_state);
+        let mut state = state.into_bytes();
+        state[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&version.to_le_bytes());
+        state
+    }
+
+    #[test]
+    fn older_versions_are_migrated() {
+        // Version 2 appended a byte to the state, and version 3 doubled every byte
+        let migrations: [Migration; 2] = [
+            |state| Ok([state, &[0xFF]].concat()),
+            |state| Ok(state.iter().map(|byte| byte.wrapping_mul(2)).collect()),
+        ];
+
+        let decode = |version| {
+            decode_with(
+                &state_with_version(version, &[1, 2]),
+                TITLE_HASH,
+                1,
+                &migrations,
+            )
+        };
+        assert_eq!(decode(1), Ok(vec![2, 4, 0xFE]));
+        assert_eq!(decode(2), Ok(vec![2, 4]));
+        assert_eq!(decode(3), Ok(vec![1, 2]));
+        assert_eq!(decode(0), Err(SaveStateError::UnsupportedVersion(0)));
+        assert_eq!(decode(4), Err(SaveStateError::UnsupportedVersion(4)));
+    }
+
+    #[test]
+    fn current_version_is_decoded() {
+        let state = state_with_version(VERSION, &[1, 2, 3]);
+        assert_eq!(decode(&state, TITLE_HASH), Ok(vec![1, 2, 3]));
+        assert_eq!(
+            decode(&state, TITLE_HASH + 1),
+            Err(SaveStateError::CartridgeMismatch)
+        );
+        assert_eq!(
+            decode(&state[..HEADER_LEN - 1], TITLE_HASH),
+            Err(SaveStateError::Corrupted(StateError::Truncated))
+        );
+    }
+}
@@ -0,0 +1,345 @@
+use sm83::{
+    core::Cycles,
+    interrupts::{Interrupt, Interrupts},
+    state::{SaveState, StateError, StateReader, StateWriter},
+};
+
+extern crate alloc;
+use alloc::boxed::Box;
+
+const SC_TRANSFER_START: u8 = 1 << 7;
+const SC_FAST_CLOCK: u8 = 1 << 1;
+const SC_INTERNAL_CLOCK: u8 = 1 << 0;
+
+// Bits 1-6 of SC are unused on the DMG and always read back as 1.
+const SC_UNUSED_BITS: u8 = 0x7E;
+
+const BITS_PER_TRANSFER: usize = 8;
+
+// The internal serial clock runs at 8192 Hz, which at 4194304 Hz are 512 clock cycles per bit.
+const NORMAL_BIT_PERIOD: usize = 4194304 / 8192;
+static_assertions::const_assert_eq!(NORMAL_BIT_PERIOD, 512);
+
+// The CGB fast clock runs at 262144 Hz, which are 16 clock cycles per bit.
+const FAST_BIT_PERIOD: usize = 4194304 / 262144;
+static_assertions::const_assert_eq!(FAST_BIT_PERIOD, 16);
+
+/// Selects how the serial clock is derived when the Game Boy drives the link cable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkClock {
+    /// Original Game Boy. The clock always runs at 8192 Hz and the fast clock bit is ignored.
+    Dmg,
+    /// Game Boy Color in normal speed mode. Honors the fast clock bit of SC.
+    CgbNormalSpeed,
+    /// Game Boy Color in double speed mode. The serial clock is twice as fast as in normal speed.
+    CgbDoubleSpeed,
+}
+
+/// A peripheral connected to the other end of the link cable.
+pub trait SerialLink {
+    /// Exchanges a full byte with the peripheral. `out` is the byte sent by the Game Boy, and
+    /// the returned byte is received by the Game Boy in SB.
+    fn transfer(&mut self, out: u8) -> u8;
+
+    /// Advances the peripheral by the given number of cycles. Peripherals that drive the clock
+    /// of the link cable return true when they have clocked a full byte, which completes a
+    /// pending transfer using the external clock through `transfer`.
+    fn tick(&mut self, _cycles: Cycles) -> bool {
+        false
+    }
+}
+
+pub struct Serial {
+    sb: u8,
+    sc: u8,
+    clock: LinkClock,
+    bit_cycles: Cycles,
+    remaining_bits: usize,
+    /// The byte being sent in the current transfer
+    transfer_out: u8,
+    link: Option<Box<dyn SerialLink>>,
+}
+
+impl Serial {
+    pub const fn new() -> Self {
+        Self {
+            sb: 0,
+            sc: 0,
+            clock: LinkClock::Dmg,
+            bit_cycles: Cycles::new(0),
+            remaining_bits: 0,
+            transfer_out: 0,
+            link: None,
+        }
+    }
+
+    /// Aborts any ongoing transfer and clears the registers, keeping the configured clock and
+    /// the connected peripheral.
+    pub fn reset(&mut self) {
+        *self = Self {
+            clock: self.clock,
+            link: self.link.take(),
+            ..Self::new()
+        };
+    }
+
+    /// Connects a peripheral to the link cable, returning the previously connected one, if any.
+    pub fn connect(&mut self, link: Box<dyn SerialLink>) -> Option<Box<dyn SerialLink>> {
+        self.link.replace(link)
+    }
+
+    /// Disconnects the peripheral from the link cable, if any.
+    pub fn disconnect(&mut self) -> Option<Box<dyn SerialLink>> {
+        self.link.take()
+    }
+
+    /// Selects the source of the serial clock used for transfers driven by the internal clock.
+    pub fn configure_clock(&mut self, clock: LinkClock) {
+        self.clock = clock;
+    }
+
+    /// Returns the number of clock cycles it takes to shift a single bit with the current
+    /// configuration.
+    pub fn bit_period(&self) -> Cycles {
+        let fast = self.clock != LinkClock::Dmg && (self.sc & SC_FAST_CLOCK) != 0;
+        let period = if fast {
+            FAST_BIT_PERIOD
+        } else {
+            NORMAL_BIT_PERIOD
+        };
+
+        match self.clock {
+            LinkClock::Dmg | LinkClock::CgbNormalSpeed => Cycles::new(period),
+            LinkClock::CgbDoubleSpeed => Cycles::new(period / 2),
+        }
+    }
+
+    /// Returns the number of clock cycles it takes to transfer a full byte with the current
+    /// configuration.
+    pub fn transfer_duration(&self) -> Cycles {
+        Cycles::new(usize::from(self.bit_period()) * BITS_PER_TRANSFER)
+    }
+
+    fn transfer_in_progress(&self) -> bool {
+        (self.sc & SC_TRANSFER_START) != 0
+    }
+
+    fn complete_transfer(&mut self) -> Interrupts {
+        if let Some(link) = &mut self.link {
+            self.sb = link.transfer(self.transfer_out);
+        }
+        self.sc &= !SC_TRANSFER_START;
+        self.bit_cycles = Cycles::new(0);
+        Interrupt::Serial.into()
+    }
+
+    pub fn step(&mut self, cycles: Cycles) -> Interrupts {
+        let peer_clocked = match &mut self.link {
+            Some(link) => link.tick(cycles),
+            None => false,
+        };
+
+        if !self.transfer_in_progress() {
+            return Interrupts::new();
+        }
+
+        // Transfers clocked externally only complete when a peer on the other side of the link
+        // cable provides the clock.
+        if (self.sc & SC_INTERNAL_CLOCK) == 0 {
+            if peer_clocked {
+                self.remaining_bits = 0;
+                return self.complete_transfer();
+            }
+            return Interrupts::new();
+        }
+
+        let bit_period = self.bit_period();
+        self.bit_cycles = self.bit_cycles + cycles;
+        while self.remaining_bits != 0 && self.bit_cycles >= bit_period {
+            self.bit_cycles = self.bit_cycles - bit_period;
+            self.remaining_bits -= 1;
+
+            // Without a peer, the input line is pulled high and ones are shifted in.
+            self.sb = (self.sb << 1) | 1;
+        }
+
+        if self.remaining_bits == 0 {
+            self.complete_transfer()
+        } else {
+            Interrupts::new()
+        }
+    }
+
+    pub fn read(&self, address: sm83::memory::Address) -> u8 {
+        match address {
+            0xFF01 => self.sb,
+            0xFF02 => self.sc | SC_UNUSED_BITS,
+            _ => unreachable!("Unexpected serial read from {:#x}", address),
+        }
+    }
+
+    pub fn write(&mut self, address: sm83::memory::Address, value: u8) {
+        match address {
+            0xFF01 => self.sb = value,
+            0xFF02 => {
+                self.sc = value & (SC_TRANSFER_START | SC_FAST_CLOCK | SC_INTERNAL_CLOCK);
+                if self.transfer_in_progress() {
+                    self.remaining_bits = BITS_PER_TRANSFER;
+                    self.bit_cycles = Cycles::new(0);
+                    self.transfer_out = self.sb;
+                }
+            }
+            _ => unreachable!(
+                "Unexpected serial write to {:#x}, value {:#x}",
+                address, value
+            ),
+        }
+    }
+}
+
+/// The configured clock and the connected peripheral are not part of the state.
+impl SaveState for Serial {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.sb);
+        state.write_u8(self.sc);
+        state.write_u32(usize::from(self.bit_cycles) as u32);
+        state.write_u8(self.remaining_bits as u8);
+        state.write_u8(self.transfer_out);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.sb = state.read_u8()?;
+        self.sc = state.read_u8()? & (SC_TRANSFER_START | SC_FAST_CLOCK | SC_INTERNAL_CLOCK);
+        self.bit_cycles = Cycles::new(state.read_u32()? as usize);
+        let remaining_bits = state.read_u8()? as usize;
+        if remaining_bits > BITS_PER_TRANSFER {
+            return Err(StateError::Invalid);
+        }
+        self.remaining_bits = remaining_bits;
+        self.transfer_out = state.read_u8()?;
+        Ok(())
+    }
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normal_speed_transfer_timing() {
+        let mut serial = Serial::new();
+        serial.write(0xFF01, 0x55);
+        serial.write(0xFF02, SC_TRANSFER_START | SC_INTERNAL_CLOCK);
+
+        assert_eq!(serial.transfer_duration(), Cycles::new(8 * 512));
+
+        // Step in M-cycle increments, as the CPU would
+        let mut elapsed = Cycles::new(0);
+        let irq = loop {
+            let irq = serial.step(Cycles::new(4));
+            elapsed = elapsed + Cycles::new(4);
+            if irq.has_any() {
+                break irq;
+            }
+            assert!(elapsed < Cycles::new(8 * 512));
+        };
+
+        assert_eq!(elapsed, Cycles::new(8 * 512));
+        assert_eq!(irq, Interrupt::Serial.into());
+        assert_eq!(serial.read(0xFF01), 0xFF);
+        assert_eq!(serial.read(0xFF02) & SC_TRANSFER_START, 0);
+
+        // No more interrupts once the transfer is done
+        assert!(!serial.step(Cycles::new(8 * 512)).has_any());
+    }
+
+    #[test]
+    fn cgb_clock_rates() {
+        let mut serial = Serial::new();
+        serial.write(0xFF02, SC_FAST_CLOCK | SC_INTERNAL_CLOCK);
+        assert_eq!(serial.transfer_duration(), Cycles::new(8 * 512));
+
+        serial.configure_clock(LinkClock::CgbNormalSpeed);
+        assert_eq!(serial.transfer_duration(), Cycles::new(8 * 16));
+
+        serial.configure_clock(LinkClock::CgbDoubleSpeed);
+        assert_eq!(serial.transfer_duration(), Cycles::new(8 * 8));
+
+        serial.write(0xFF02, SC_INTERNAL_CLOCK);
+        assert_eq!(serial.transfer_duration(), Cycles::new(8 * 256));
+    }
+
+    struct XorPeripheral {
+        key: u8,
+        clock_period: Option<usize>,
+        elapsed: usize,
+    }
+
+    impl SerialLink for XorPeripheral {
+        fn transfer(&mut self, out: u8) -> u8 {
+            out ^ self.key
+        }
+
+        fn tick(&mut self, cycles: Cycles) -> bool {
+            let Some(period) = self.clock_period else {
+                return false;
+            };
+            self.elapsed += usize::from(cycles);
+            if self.elapsed >= period {
+                self.elapsed -= period;
+                true
+            } else {
+                false
+            }
+        }
+    }
+
+    #[test]
+    fn internal_clock_transfer_with_peripheral() {
+        let mut serial = Serial::new();
+        serial.connect(Box::new(XorPeripheral {
+            key: 0x5A,
+            clock_period: None,
+            elapsed: 0,
+        }));
+        serial.write(0xFF01, 0x12);
+        serial.write(0xFF02, SC_TRANSFER_START | SC_INTERNAL_CLOCK);
+
+        assert!(!serial
+            .step(serial.transfer_duration() - Cycles::new(4))
+            .has_any());
+        assert_eq!(serial.step(Cycles::new(4)), Interrupt::Serial.into());
+        assert_eq!(serial.read(0xFF01), 0x12 ^ 0x5A);
+    }
+
+    #[test]
+    fn external_clock_driven_by_peripheral() {
+        let mut serial = Serial::new();
+        serial.connect(Box::new(XorPeripheral {
+            key: 0xFF,
+            clock_period: Some(1024),
+            elapsed: 0,
+        }));
+        serial.write(0xFF01, 0x0F);
+        serial.write(0xFF02, SC_TRANSFER_START);
+
+        assert!(!serial.step(Cycles::new(1020)).has_any());
+        assert_eq!(serial.step(Cycles::new(4)), Interrupt::Serial.into());
+        assert_eq!(serial.read(0xFF01), 0xF0);
+        assert_eq!(serial.read(0xFF02) & SC_TRANSFER_START, 0);
+    }
+
+    #[test]
+    fn external_clock_never_completes() {
+        let mut serial = Serial::new();
+        serial.write(0xFF02, SC_TRANSFER_START);
+        assert!(!serial.step(Cycles::new(0x10000)).has_any());
+        assert_ne!(serial.read(0xFF02) & SC_TRANSFER_START, 0);
+    }
+}
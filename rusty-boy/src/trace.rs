@@ -0,0 +1,196 @@
+//! Execution traces of the CPU, used to compare the emulator against reference logs of other
+//! emulators or real hardware.
+//!
+//! Records are formatted in the format popularized by
+//! [Gameboy Doctor](https://github.com/robert/gameboy-doctor), with one line per instruction:
+//!
+//! ```text
+//! A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02
+//! ```
+
+use sm83::{core::Registers, memory::Memory};
+
+/// Number of bytes of memory at the program counter included in every record
+pub const PCMEM_LEN: usize = 4;
+
+/// The state of the CPU right before executing an instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub a: u8,
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+    /// Memory contents at the program counter
+    pub pcmem: [u8; PCMEM_LEN],
+}
+
+impl TraceRecord {
+    /// Captures a record from the given CPU registers and the memory they run against.
+    pub fn capture<T: Memory + ?Sized>(regs: &Registers, memory: &T) -> Self {
+        let pc = regs.pc_reg;
+        Self {
+            a: regs.a_reg,
+            f: regs.af() as u8,
+            b: regs.b_reg,
+            c: regs.c_reg,
+            d: regs.d_reg,
+            e: regs.e_reg,
+            h: regs.h_reg,
+            l: regs.l_reg,
+            sp: regs.sp_reg,
+            pc,
+            pcmem: core::array::from_fn(|offset| memory.read(pc.wrapping_add(offset as u16))),
+        }
+    }
+}
+
+impl core::fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let [m0, m1, m2, m3] = self.pcmem;
+        write!(
+            f,
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} \
+             PC:{:04X} PCMEM:{m0:02X},{m1:02X},{m2:02X},{m3:02X}",
+            self.a, self.f, self.b, self.c, self.d, self.e, self.h, self.l, self.sp, self.pc
+        )
+    }
+}
+
+/// Errors found while parsing a line of a reference trace
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseTraceError {
+    /// The line doesn't contain the given field
+    MissingField(&'static str),
+    /// A field is unknown or its value is not a valid hexadecimal number
+    InvalidField,
+}
+
+impl core::fmt::Display for ParseTraceError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ParseTraceError::MissingField(field) => write!(f, "missing field {field}"),
+            ParseTraceError::InvalidField => write!(f, "invalid field"),
+        }
+    }
+}
+
+#[cfg(any(test, feature = "std"))]
+impl std::error::Error for ParseTraceError {}
+
+impl core::str::FromStr for TraceRecord {
+    type Err = ParseTraceError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        const FIELDS: [&str; 11] = ["A", "F", "B", "C", "D", "E", "H", "L", "SP", "PC", "PCMEM"];
+
+        let mut values: [Option<&str>; FIELDS.len()] = [None; FIELDS.len()];
+        for field in line.split_whitespace() {
+            let (name, value) = field.split_once(':').ok_or(ParseTraceError::InvalidField)?;
+            let index = FIELDS
+                .iter()
+                .position(|f| *f == name)
+                .ok_or(ParseTraceError::InvalidField)?;
+            values[index] = Some(value);
+        }
+
+        let field =
+            |index: usize| values[index].ok_or(ParseTraceError::MissingField(FIELDS[index]));
+        let u8_field = |index: usize| {
+            u8::from_str_radix(field(index)?, 16).map_err(|_| ParseTraceError::InvalidField)
+        };
+        let u16_field = |index: usize| {
+            u16::from_str_radix(field(index)?, 16).map_err(|_| ParseTraceError::InvalidField)
+        };
+
+        Ok(Self {
+            a: u8_field(0)?,
+            f: u8_field(1)?,
+            b: u8_field(2)?,
+            c: u8_field(3)?,
+            d: u8_field(4)?,
+            e: u8_field(5)?,
+            h: u8_field(6)?,
+            l: u8_field(7)?,
+            sp: u16_field(8)?,
+            pc: u16_field(9)?,
+            pcmem: parse_pcmem(field(10)?)?,
+        })
+    }
+}
+
+fn parse_pcmem(value: &str) -> Result<[u8; PCMEM_LEN], ParseTraceError> {
+    let mut pcmem = [0; PCMEM_LEN];
+    let mut bytes = value.split(',');
+    for byte in pcmem.iter_mut() {
+        let value = bytes.next().ok_or(ParseTraceError::InvalidField)?;
+        *byte = u8::from_str_radix(value, 16).map_err(|_| ParseTraceError::InvalidField)?;
+    }
+    if bytes.next().is_some() {
+        return Err(ParseTraceError::InvalidField);
+    }
+    Ok(pcmem)
+}
+
+/// The first difference found between the emulator and a reference trace
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceMismatch {
+    /// Index of the record in the reference trace, which is also the number of instructions that
+    /// executed before the mismatch
+    pub index: usize,
+    /// The record in the reference trace
+    pub expected: TraceRecord,
+    /// The state of the emulator
+    pub actual: TraceRecord,
+}
+
+impl core::fmt::Display for TraceMismatch {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "trace mismatch at record {}\n  expected: {}\n  actual:   {}",
+            self.index, self.expected, self.actual
+        )
+    }
+}
+
+#[cfg(any(test, feature = "std"))]
+impl std::error::Error for TraceMismatch {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINE: &str = "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02";
+
+    #[test]
+    fn parse_and_format_records() {
+        let record: TraceRecord = LINE.parse().unwrap();
+        assert_eq!(record.a, 0x01);
+        assert_eq!(record.f, 0xB0);
+        assert_eq!(record.l, 0x4D);
+        assert_eq!(record.sp, 0xFFFE);
+        assert_eq!(record.pc, 0x0100);
+        assert_eq!(record.pcmem, [0x00, 0xC3, 0x13, 0x02]);
+        assert_eq!(std::format!("{record}"), LINE);
+
+        assert_eq!(
+            "A:01 F:B0".parse::<TraceRecord>(),
+            Err(ParseTraceError::MissingField("B"))
+        );
+        assert_eq!(
+            LINE.replace("H:01", "H:XY").parse::<TraceRecord>(),
+            Err(ParseTraceError::InvalidField)
+        );
+        assert_eq!(
+            LINE.replace("PCMEM:00,C3,13,02", "PCMEM:00,C3")
+                .parse::<TraceRecord>(),
+            Err(ParseTraceError::InvalidField)
+        );
+    }
+}
@@ -0,0 +1,4 @@
+//! Helpers to present the frames produced by the PPU on different displays.
+
+pub mod layout;
+pub mod scaling;
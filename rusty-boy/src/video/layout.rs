@@ -0,0 +1,282 @@
+//! Flat framebuffer layouts with a stable contract, for embedders that share the frame with code
+//! written in other languages, e.g. through a C FFI wrapper.
+//!
+//! A buffer with a [`FrameLayout`] holds `height` rows of `width` pixels. Each row starts
+//! `stride` bytes after the previous one, and the first row starts at the beginning of the
+//! buffer. Bytes between the end of a row and the start of the next one are never written, so
+//! the stride can be used to match the pitch of a texture or a shared memory region.
+
+use ppu::{Frame, DISPLAY_HEIGHT, DISPLAY_WIDTH};
+
+/// Format of each pixel of a flat framebuffer
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// 4 bytes per pixel with the red, green, blue and alpha components, in that order. Alpha is
+    /// always 0xFF.
+    Rgba8888 = 0,
+    /// 1 byte per pixel with the DMG shade, from 0 (white) to 3 (black). Colors of the Game Boy
+    /// Color are reduced to the closest shade.
+    Indexed = 1,
+}
+
+impl PixelFormat {
+    /// Returns the number of bytes taken by each pixel.
+    pub const fn bytes_per_pixel(&self) -> usize {
+        match self {
+            PixelFormat::Rgba8888 => 4,
+            PixelFormat::Indexed => 1,
+        }
+    }
+}
+
+/// Describes how a frame is laid out in a flat buffer. See the [module docs](self) for the
+/// contract.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameLayout {
+    /// Number of pixels in each row, which is always `DISPLAY_WIDTH`
+    pub width: usize,
+    /// Number of rows, which is always `DISPLAY_HEIGHT`
+    pub height: usize,
+    /// Distance in bytes between the start of two consecutive rows
+    pub stride: usize,
+    /// Format of each pixel
+    pub format: PixelFormat,
+}
+
+impl FrameLayout {
+    /// Returns the layout of a frame with the given format and no padding between rows.
+    pub const fn packed(format: PixelFormat) -> Self {
+        Self::with_stride(format, DISPLAY_WIDTH * format.bytes_per_pixel())
+    }
+
+    /// Returns the layout of a frame with the given format, where rows start `stride` bytes
+    /// apart.
+    pub const fn with_stride(format: PixelFormat, stride: usize) -> Self {
+        Self {
+            width: DISPLAY_WIDTH,
+            height: DISPLAY_HEIGHT,
+            stride,
+            format,
+        }
+    }
+
+    /// Returns the number of bytes of pixel data in each row.
+    pub const fn row_len(&self) -> usize {
+        self.width * self.format.bytes_per_pixel()
+    }
+
+    /// Returns the minimum length of a buffer with this layout. The last row doesn't need any
+    /// padding after it. Returns `None` for layouts without rows, or too large to fit in memory.
+    pub const fn buffer_len(&self) -> Option<usize> {
+        let Some(rows) = self.height.checked_sub(1) else {
+            return None;
+        };
+        let Some(padded_rows) = self.stride.checked_mul(rows) else {
+            return None;
+        };
+        let Some(row_len) = self.width.checked_mul(self.format.bytes_per_pixel()) else {
+            return None;
+        };
+        padded_rows.checked_add(row_len)
+    }
+}
+
+/// Errors that prevent writing a frame into a buffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayoutError {
+    /// The layout doesn't describe a Game Boy frame
+    InvalidSize,
+    /// The stride is smaller than the pixel data of a row, so rows would overlap
+    StrideTooSmall {
+        /// The stride of the layout
+        stride: usize,
+        /// The length of the pixel data of a row
+        row_len: usize,
+    },
+    /// The buffer is too small for the layout
+    BufferTooSmall {
+        /// The minimum length of the buffer
+        expected: usize,
+        /// The length of the buffer
+        actual: usize,
+    },
+}
+
+impl core::fmt::Display for LayoutError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            LayoutError::InvalidSize => {
+                write!(f, "layout is not {DISPLAY_WIDTH}x{DISPLAY_HEIGHT} pixels")
+            }
+            LayoutError::StrideTooSmall { stride, row_len } => {
+                write!(
+                    f,
+                    "stride of {stride} bytes is smaller than a row ({row_len} bytes)"
+                )
+            }
+            LayoutError::BufferTooSmall { expected, actual } => write!(
+                f,
+                "buffer of {actual} bytes is too small for the layout ({expected} bytes)"
+            ),
+        }
+    }
+}
+
+#[cfg(any(test, feature = "std"))]
+impl std::error::Error for LayoutError {}
+
+/// Writes the frame into the buffer with the given layout. Padding bytes between rows are left
+/// untouched.
+pub fn write_frame(
+    frame: &Frame,
+    layout: &FrameLayout,
+    buffer: &mut [u8],
+) -> Result<(), LayoutError> {
+    if layout.width != DISPLAY_WIDTH || layout.height != DISPLAY_HEIGHT {
+        return Err(LayoutError::InvalidSize);
+    }
+    if layout.stride < layout.row_len() {
+        return Err(LayoutError::StrideTooSmall {
+            stride: layout.stride,
+            row_len: layout.row_len(),
+        });
+    }
+    // The size is fixed, so only a huge stride can make the length overflow
+    let expected = layout.buffer_len().unwrap_or(usize::MAX);
+    if buffer.len() < expected {
+        return Err(LayoutError::BufferTooSmall {
+            expected,
+            actual: buffer.len(),
+        });
+    }
+
+    let bytes_per_pixel = layout.format.bytes_per_pixel();
+    for (line, row) in frame.iter().zip(buffer.chunks_mut(layout.stride)) {
+        for (color, dest) in line.iter().zip(row.chunks_exact_mut(bytes_per_pixel)) {
+            match layout.format {
+                PixelFormat::Rgba8888 => dest.copy_from_slice(&color.to_rgba8888()),
+                PixelFormat::Indexed => dest[0] = color.shade(),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use ppu::Color;
+
+    #[test]
+    fn strided_rows() {
+        let mut frame = [[Color::White; DISPLAY_WIDTH]; DISPLAY_HEIGHT];
+        frame[1][0] = Color::Black;
+        frame[DISPLAY_HEIGHT - 1][DISPLAY_WIDTH - 1] = Color::LightGrey;
+
+        // Rows padded to 1 KiB, with a canary in the padding
+        let layout = FrameLayout::with_stride(PixelFormat::Rgba8888, 1024);
+        let mut buffer = vec![0xAA; layout.buffer_len().unwrap()];
+        write_frame(&frame, &layout, &mut buffer).unwrap();
+
+        assert_eq!(
+            buffer.len(),
+            1024 * (DISPLAY_HEIGHT - 1) + DISPLAY_WIDTH * 4
+        );
+        assert_eq!(buffer[..4], [0xFF, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(buffer[1024..1028], [0x00, 0x00, 0x00, 0xFF]);
+        assert_eq!(buffer[buffer.len() - 4..], [170, 170, 170, 0xFF]);
+        for row in buffer.chunks(1024) {
+            assert!(row[layout.row_len()..].iter().all(|byte| *byte == 0xAA));
+        }
+
+        let layout = FrameLayout::packed(PixelFormat::Indexed);
+        let mut buffer = vec![0xAA; DISPLAY_WIDTH * DISPLAY_HEIGHT];
+        write_frame(&frame, &layout, &mut buffer).unwrap();
+        assert_eq!(buffer[DISPLAY_WIDTH], 3);
+        assert_eq!(buffer[buffer.len() - 1], 1);
+        assert!(buffer.iter().all(|shade| *shade <= 3));
+    }
+
+    #[test]
+    fn invalid_layouts() {
+        let frame = [[Color::White; DISPLAY_WIDTH]; DISPLAY_HEIGHT];
+        let mut buffer = vec![0; 4 * DISPLAY_WIDTH * DISPLAY_HEIGHT];
+
+        let layout = FrameLayout::with_stride(PixelFormat::Rgba8888, DISPLAY_WIDTH);
+        assert_eq!(
+            write_frame(&frame, &layout, &mut buffer),
+            Err(LayoutError::StrideTooSmall {
+                stride: DISPLAY_WIDTH,
+                row_len: 4 * DISPLAY_WIDTH
+            })
+        );
+
+        let layout = FrameLayout::packed(PixelFormat::Rgba8888);
+        assert_eq!(
+            write_frame(&frame, &layout, &mut buffer[1..]),
+            Err(LayoutError::BufferTooSmall {
+                expected: buffer.len(),
+                actual: buffer.len() - 1
+            })
+        );
+
+        let layout = FrameLayout::with_stride(PixelFormat::Indexed, usize::MAX);
+        assert_eq!(
+            write_frame(&frame, &layout, &mut buffer),
+            Err(LayoutError::BufferTooSmall {
+                expected: usize::MAX,
+                actual: buffer.len()
+            })
+        );
+
+        let layout = FrameLayout {
+            width: 2 * DISPLAY_WIDTH,
+            ..layout
+        };
+        assert_eq!(
+            write_frame(&frame, &layout, &mut buffer),
+            Err(LayoutError::InvalidSize)
+        );
+    }
+
+    #[test]
+    fn buffer_len_of_degenerate_layouts() {
+        let layout = FrameLayout::packed(PixelFormat::Rgba8888);
+        assert_eq!(
+            layout.buffer_len(),
+            Some(4 * DISPLAY_WIDTH * DISPLAY_HEIGHT)
+        );
+
+        assert_eq!(
+            FrameLayout {
+                height: 0,
+                ..layout
+            }
+            .buffer_len(),
+            None
+        );
+        assert_eq!(
+            FrameLayout {
+                height: 1,
+                ..layout
+            }
+            .buffer_len(),
+            Some(layout.row_len())
+        );
+        assert_eq!(
+            FrameLayout::with_stride(PixelFormat::Rgba8888, usize::MAX / 2).buffer_len(),
+            None
+        );
+        assert_eq!(
+            FrameLayout {
+                width: usize::MAX,
+                ..layout
+            }
+            .buffer_len(),
+            None
+        );
+    }
+}
@@ -0,0 +1,132 @@
+//! Nearest-neighbor scaling of the Game Boy screen to displays of other sizes.
+//!
+//! The tables are meant to be built at compile time, so that invalid target sizes are rejected
+//! during const evaluation:
+//!
+//! ```rust
+//! use rusty_boy::video::scaling::{width_for_height, ScalingTable};
+//!
+//! const HEIGHT: usize = 240;
+//! const WIDTH: usize = width_for_height(HEIGHT);
+//! const TABLE: ScalingTable<WIDTH, HEIGHT> = ScalingTable::new();
+//!
+//! assert_eq!(TABLE.x_offsets[WIDTH - 1], ppu::DISPLAY_WIDTH - 1);
+//! ```
+
+use core::ops::RangeInclusive;
+
+use ppu::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
+
+/// Returns the width of a target that keeps the aspect ratio of the Game Boy screen for the given
+/// height, rounded down.
+pub const fn width_for_height(height: usize) -> usize {
+    height * DISPLAY_WIDTH / DISPLAY_HEIGHT
+}
+
+/// Returns the source coordinate sampled by the given target coordinate, using the center of
+/// the target pixel.
+const fn nearest(target: usize, target_len: usize, source_len: usize) -> usize {
+    (2 * target + 1) * source_len / (2 * target_len)
+}
+
+/// Maps every column and row of a `WIDTH`x`HEIGHT` target to the column and row of the Game Boy
+/// screen shown on it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScalingTable<const WIDTH: usize, const HEIGHT: usize> {
+    /// Column of the Game Boy screen shown on each column of the target
+    pub x_offsets: [usize; WIDTH],
+    /// Line of the Game Boy screen shown on each row of the target
+    pub y_offsets: [usize; HEIGHT],
+}
+
+impl<const WIDTH: usize, const HEIGHT: usize> ScalingTable<WIDTH, HEIGHT> {
+    /// Builds the scaling table. Panics if the target is empty, which fails the build when
+    /// evaluated in a const context.
+    pub const fn new() -> Self {
+        assert!(WIDTH > 0 && HEIGHT > 0, "The target display can't be empty");
+
+        let mut x_offsets = [0; WIDTH];
+        let mut x = 0;
+        while x < WIDTH {
+            x_offsets[x] = nearest(x, WIDTH, DISPLAY_WIDTH);
+            assert!(x_offsets[x] < DISPLAY_WIDTH);
+            x += 1;
+        }
+
+        let mut y_offsets = [0; HEIGHT];
+        let mut y = 0;
+        while y < HEIGHT {
+            y_offsets[y] = nearest(y, HEIGHT, DISPLAY_HEIGHT);
+            assert!(y_offsets[y] < DISPLAY_HEIGHT);
+            y += 1;
+        }
+
+        Self {
+            x_offsets,
+            y_offsets,
+        }
+    }
+
+    /// Returns the rows of the target that show any of the given lines of the Game Boy screen,
+    /// if any. Useful to only update the rows of the display that changed.
+    pub fn rows_for_lines(&self, lines: RangeInclusive<usize>) -> Option<RangeInclusive<usize>> {
+        let first = self
+            .y_offsets
+            .iter()
+            .position(|line| lines.contains(line))?;
+        let last = self
+            .y_offsets
+            .iter()
+            .rposition(|line| lines.contains(line))?;
+        Some(first..=last)
+    }
+}
+
+impl<const WIDTH: usize, const HEIGHT: usize> Default for ScalingTable<WIDTH, HEIGHT> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The Playdate screen is 400x240
+    const PLAYDATE_WIDTH: usize = width_for_height(240);
+    const PLAYDATE: ScalingTable<PLAYDATE_WIDTH, 240> = ScalingTable::new();
+
+    const DOUBLE: ScalingTable<{ 2 * DISPLAY_WIDTH }, { 2 * DISPLAY_HEIGHT }> = ScalingTable::new();
+
+    #[test]
+    fn const_tables() {
+        assert_eq!(PLAYDATE_WIDTH, 266);
+        assert_eq!(PLAYDATE.x_offsets[0], 0);
+        assert_eq!(PLAYDATE.x_offsets[PLAYDATE_WIDTH - 1], DISPLAY_WIDTH - 1);
+        assert_eq!(PLAYDATE.y_offsets[0], 0);
+        assert_eq!(PLAYDATE.y_offsets[239], DISPLAY_HEIGHT - 1);
+
+        for (x, offset) in DOUBLE.x_offsets.iter().enumerate() {
+            assert_eq!(*offset, x / 2);
+        }
+        for (y, offset) in DOUBLE.y_offsets.iter().enumerate() {
+            assert_eq!(*offset, y / 2);
+        }
+    }
+
+    #[test]
+    fn rows_for_lines() {
+        assert_eq!(DOUBLE.rows_for_lines(3..=4), Some(6..=9));
+        assert_eq!(
+            DOUBLE.rows_for_lines(0..=DISPLAY_HEIGHT - 1),
+            Some(0..=2 * DISPLAY_HEIGHT - 1)
+        );
+        assert_eq!(DOUBLE.rows_for_lines(DISPLAY_HEIGHT..=DISPLAY_HEIGHT), None);
+
+        // Downscaling skips some lines entirely
+        const HALF: ScalingTable<{ DISPLAY_WIDTH / 2 }, { DISPLAY_HEIGHT / 2 }> =
+            ScalingTable::new();
+        assert_eq!(HALF.rows_for_lines(1..=1), Some(0..=0));
+        assert_eq!(HALF.rows_for_lines(0..=0), None);
+    }
+}
@@ -1,5 +1,6 @@
 extern crate alloc;
 
+use core::ops::RangeInclusive;
 use core::sync::atomic::{AtomicBool, Ordering};
 
 use alloc::{boxed::Box, format, string::String, vec, vec::Vec};
@@ -19,9 +20,14 @@ use {
 };
 
 use cartridge::Cartridge;
-use rusty_boy::RustyBoy;
+use rusty_boy::{
+    joypad::PulsedInput,
+    video::scaling::{width_for_height, ScalingTable},
+    AccuracyProfile, RustyBoy,
+};
 
-const DUMMY_BUTTON_CYCLES: usize = 30;
+/// Number of emulated frames that select and start are held for when triggered from the menu
+const MENU_BUTTON_FRAMES: usize = 60;
 
 static TERMINATE: AtomicBool = AtomicBool::new(false);
 static SELECT_BUTTON: AtomicBool = AtomicBool::new(false);
@@ -50,6 +56,15 @@ fn setup_menu_items(system: &System) -> Result<MenuItems, anyhow::Error> {
     })
 }
 
+/// Seconds from the UNIX epoch to 2000-01-01, the epoch of the Playdate clock
+const PLAYDATE_EPOCH: u64 = 946_684_800;
+
+/// Returns the current UNIX time, which is stored in saves of cartridges with a real-time clock.
+fn unix_time(system: &System) -> Result<u64, anyhow::Error> {
+    let (seconds, _milliseconds) = system.get_seconds_since_epoch()?;
+    Ok(PLAYDATE_EPOCH + seconds as u64)
+}
+
 fn find_saved_game(fs: &FileSystem, name: &str) -> Result<Vec<u8>, anyhow::Error> {
     fs.mkdir("savegames")?;
 
@@ -70,12 +85,16 @@ fn save_game(fs: &FileSystem, name: &str, data: &[u8]) -> Result<(), anyhow::Err
     Ok(())
 }
 
-fn render_frame(graphics: &Graphics, frame: &Frame) -> Result<(), anyhow::Error> {
+fn render_frame(
+    graphics: &Graphics,
+    frame: &Frame,
+    dirty_lines: RangeInclusive<usize>,
+) -> Result<(), anyhow::Error> {
     let target = graphics.get_frame()?;
 
-    const TARGET_WIDTH: usize =
-        ((LCD_ROWS as f64) * (ppu::DISPLAY_WIDTH as f64) / (ppu::DISPLAY_HEIGHT as f64)) as usize;
     const TARGET_HEIGHT: usize = LCD_ROWS as usize;
+    const TARGET_WIDTH: usize = width_for_height(TARGET_HEIGHT);
+    const SCALING: ScalingTable<TARGET_WIDTH, TARGET_HEIGHT> = ScalingTable::new();
 
     let x_offset = (LCD_COLUMNS as usize - TARGET_WIDTH) / 2;
     let y_offset = (LCD_ROWS as usize - TARGET_HEIGHT) / 2;
@@ -89,19 +108,18 @@ fn render_frame(graphics: &Graphics, frame: &Frame) -> Result<(), anyhow::Error>
     )?;
 
     for y in y_offset..(y_offset + TARGET_HEIGHT) {
-        let ppu_y = ((y - y_offset) * ppu::DISPLAY_HEIGHT + (TARGET_HEIGHT / 2)) / TARGET_HEIGHT;
-        let ppu_line = &frame[ppu_y];
+        let ppu_line = &frame[SCALING.y_offsets[y - y_offset]];
         for x in x_offset..(x_offset + TARGET_WIDTH) {
-            let ppu_x = ((x - x_offset) * ppu::DISPLAY_WIDTH + (TARGET_WIDTH / 2)) / TARGET_WIDTH;
-            let pixel = ppu_line[ppu_x];
-
-            let on = match pixel {
-                ppu::Color::White => 1,
-                ppu::Color::LightGrey if ((y & 1) != 0) || ((x & 1) != 0) => 1,
-                ppu::Color::LightGrey => 0,
-                ppu::Color::DarkGrey if ((y & 1) != 0) || ((x & 1) != 0) => 0,
-                ppu::Color::DarkGrey => 1,
-                ppu::Color::Black => 0,
+            let pixel = ppu_line[SCALING.x_offsets[x - x_offset]];
+
+            // Colors of the Game Boy Color are dithered like the closest shade
+            let on = match pixel.shade() {
+                0 => 1,
+                1 if ((y & 1) != 0) || ((x & 1) != 0) => 1,
+                1 => 0,
+                2 if ((y & 1) != 0) || ((x & 1) != 0) => 0,
+                2 => 1,
+                _ => 0,
             };
 
             let target_offset = (y * LCD_ROWSIZE as usize) + x / 8;
@@ -111,15 +129,20 @@ fn render_frame(graphics: &Graphics, frame: &Frame) -> Result<(), anyhow::Error>
         }
     }
 
-    graphics.mark_updated_rows((y_offset as i32)..=(y_offset + TARGET_HEIGHT) as i32)?;
+    // Only the rows showing lines that changed need to be updated on the display.
+    if let Some(rows) = SCALING.rows_for_lines(dirty_lines) {
+        let first_row = (y_offset + rows.start()) as i32;
+        let last_row = (y_offset + rows.end()) as i32;
+        graphics.mark_updated_rows(first_row..=last_row)?;
+    }
     Ok(())
 }
 
 pub struct GameRunner {
     game: String,
     rusty_boy: RustyBoy,
-    select_cycles: usize,
-    start_cycles: usize,
+    select: PulsedInput,
+    start: PulsedInput,
     _menu_items: MenuItems,
 }
 
@@ -131,12 +154,16 @@ impl GameRunner {
         rom: crate::game_selector::Rom,
     ) -> Result<Self, anyhow::Error> {
         let cartridge = Cartridge::try_new(rom.data).map_err(|e| anyhow::format_err!("{e:?}"))?;
+        let save_key = cartridge.save_key();
         let mut rusty_boy = RustyBoy::new_with_cartridge(cartridge);
-        rusty_boy.configure_cpu_step(sm83::core::Cycles::new(60));
+        rusty_boy.set_accuracy(AccuracyProfile::Fast);
 
-        if let Ok(saved_game) = find_saved_game(fs, &rom.file_name) {
+        // Saves used to be named after the ROM file, keep loading them as a fallback.
+        if let Ok(saved_game) =
+            find_saved_game(fs, &save_key).or_else(|_| find_saved_game(fs, &rom.file_name))
+        {
             rusty_boy
-                .restore_cartridge_ram(&saved_game)
+                .restore_cartridge_ram(&saved_game, unix_time(system)?)
                 .map_err(|e| anyhow::format_err!("{e:?}"))?;
         };
 
@@ -150,17 +177,17 @@ impl GameRunner {
         let menu_items = setup_menu_items(system)?;
 
         Ok(Self {
-            game: rom.file_name,
+            game: save_key,
             rusty_boy,
-            select_cycles: 0,
-            start_cycles: 0,
+            select: PulsedInput::new(MENU_BUTTON_FRAMES),
+            start: PulsedInput::new(MENU_BUTTON_FRAMES),
             _menu_items: menu_items,
         })
     }
 
     pub fn save_game(&mut self) -> Result<(), anyhow::Error> {
         if self.rusty_boy.supports_battery_backed_ram() {
-            if let Some(ram) = self.rusty_boy.get_cartridge_ram() {
+            if let Some(ram) = self.rusty_boy.get_cartridge_ram(unix_time(&System::get())?) {
                 System::log_to_console("Game was saved");
                 save_game(&FileSystem::get(), &self.game, ram)?;
             }
@@ -175,10 +202,10 @@ impl GameRunner {
         }
 
         if SELECT_BUTTON.swap(false, Ordering::Relaxed) {
-            self.select_cycles = DUMMY_BUTTON_CYCLES;
+            self.select.press();
         }
         if START_BUTTON.swap(false, Ordering::Relaxed) {
-            self.start_cycles = DUMMY_BUTTON_CYCLES;
+            self.start.press();
         }
 
         let mut joypad_state = rusty_boy::joypad::State::new();
@@ -201,24 +228,28 @@ impl GameRunner {
         if (current & PDButtons::kButtonDown).0 != 0 {
             joypad_state.down = true;
         }
-        if self.select_cycles > 0 {
-            self.select_cycles -= 1;
-            joypad_state.select = true;
-        }
-        if self.start_cycles > 0 {
-            self.start_cycles -= 1;
-            joypad_state.start = true;
-        }
+        joypad_state.select = self.select.is_pressed();
+        joypad_state.start = self.start.is_pressed();
 
         self.rusty_boy.update_keys(&joypad_state);
 
-        // We run two frames back to back, only rendering one of them. This trick allows us to get
-        // lower framerate, but better overall speed.
-        self.rusty_boy.run_until_next_frame(false);
-        let frame = self.rusty_boy.run_until_next_frame(true);
+        // The fast profile runs two frames back to back, only rendering one of them. This trick
+        // allows us to get lower framerate, but better overall speed.
+        let frames = self.rusty_boy.frame_skip() + 1;
+        self.select.advance(frames);
+        self.start.advance(frames);
+        if let Err(error) = self.rusty_boy.run_frame() {
+            // Go back to the game selector, there is no way to recover from a CPU lock up
+            System::log_to_console(&format!("Emulation stopped: {error}"));
+            self.save_game()?;
+            return Ok(true);
+        }
 
-        let graphics = Graphics::get();
-        render_frame(&graphics, frame)?;
+        // Nothing to draw if the screen did not change
+        if let Some(dirty_lines) = self.rusty_boy.take_dirty_lines() {
+            let graphics = Graphics::get();
+            render_frame(&graphics, self.rusty_boy.frame(), dirty_lines)?;
+        }
 
         Ok(false)
     }
@@ -0,0 +1,229 @@
+//! A tiny assembler to write test programs with mnemonics instead of raw opcode bytes.
+//!
+//! Each function returns the encoded bytes of a single instruction. Programs can then be built by
+//! concatenating them:
+//!
+//! ```rust
+//! use sm83::asm;
+//! use sm83::decoder::Register;
+//!
+//! let program = [
+//!     &asm::ld_r_imm(Register::A, 0x42)[..],
+//!     &asm::ldh_a8_a(0x80),
+//!     &asm::jr(-2),
+//! ]
+//! .concat();
+//! assert_eq!(program, [0x3E, 0x42, 0xE0, 0x80, 0x18, 0xFE]);
+//! ```
+
+use crate::decoder::{Condition, Register, ResetTarget};
+
+const fn imm16(opcode: u8, value: u16) -> [u8; 3] {
+    let [low, high] = value.to_le_bytes();
+    [opcode, low, high]
+}
+
+/// nop
+pub const fn nop() -> [u8; 1] {
+    [0x00]
+}
+
+/// halt
+pub const fn halt() -> [u8; 1] {
+    [0x76]
+}
+
+/// di
+pub const fn di() -> [u8; 1] {
+    [0xF3]
+}
+
+/// ei
+pub const fn ei() -> [u8; 1] {
+    [0xFB]
+}
+
+/// ld r, n8
+pub const fn ld_r_imm(reg: Register, value: u8) -> [u8; 2] {
+    [0x06 | ((reg as u8) << 3), value]
+}
+
+/// ld a, n8
+pub const fn ld_a_imm(value: u8) -> [u8; 2] {
+    ld_r_imm(Register::A, value)
+}
+
+/// ld r, r'
+pub const fn ld_r_r(dest: Register, src: Register) -> [u8; 1] {
+    [0x40 | ((dest as u8) << 3) | src as u8]
+}
+
+/// ld hl, n16
+pub const fn ld_hl_imm(value: u16) -> [u8; 3] {
+    imm16(0x21, value)
+}
+
+/// ld sp, n16
+pub const fn ld_sp_imm(value: u16) -> [u8; 3] {
+    imm16(0x31, value)
+}
+
+/// ld [a16], a
+pub const fn ld_ind_a16_a(address: u16) -> [u8; 3] {
+    imm16(0xEA, address)
+}
+
+/// ld a, [a16]
+pub const fn ld_a_ind_a16(address: u16) -> [u8; 3] {
+    imm16(0xFA, address)
+}
+
+/// ldh [a8], a
+pub const fn ldh_a8_a(offset: u8) -> [u8; 2] {
+    [0xE0, offset]
+}
+
+/// ldh a, [a8]
+pub const fn ldh_a_a8(offset: u8) -> [u8; 2] {
+    [0xF0, offset]
+}
+
+/// inc r
+pub const fn inc_r(reg: Register) -> [u8; 1] {
+    [0x04 | ((reg as u8) << 3)]
+}
+
+/// dec r
+pub const fn dec_r(reg: Register) -> [u8; 1] {
+    [0x05 | ((reg as u8) << 3)]
+}
+
+/// inc [hl]
+pub const fn inc_ind_hl() -> [u8; 1] {
+    [0x34]
+}
+
+/// add a, n8
+pub const fn add_a_imm(value: u8) -> [u8; 2] {
+    [0xC6, value]
+}
+
+/// sub a, n8
+pub const fn sub_a_imm(value: u8) -> [u8; 2] {
+    [0xD6, value]
+}
+
+/// and a, n8
+pub const fn and_a_imm(value: u8) -> [u8; 2] {
+    [0xE6, value]
+}
+
+/// xor a, n8
+pub const fn xor_a_imm(value: u8) -> [u8; 2] {
+    [0xEE, value]
+}
+
+/// or a, n8
+pub const fn or_a_imm(value: u8) -> [u8; 2] {
+    [0xF6, value]
+}
+
+/// cp a, n8
+pub const fn cp_a_imm(value: u8) -> [u8; 2] {
+    [0xFE, value]
+}
+
+/// jp a16
+pub const fn jp(address: u16) -> [u8; 3] {
+    imm16(0xC3, address)
+}
+
+/// jp cc, a16
+pub const fn jp_cond(cond: Condition, address: u16) -> [u8; 3] {
+    imm16(0xC2 | ((cond as u8) << 3), address)
+}
+
+/// jr e8
+pub const fn jr(offset: i8) -> [u8; 2] {
+    [0x18, offset as u8]
+}
+
+/// jr cc, e8
+pub const fn jr_cond(cond: Condition, offset: i8) -> [u8; 2] {
+    [0x20 | ((cond as u8) << 3), offset as u8]
+}
+
+/// call a16
+pub const fn call(address: u16) -> [u8; 3] {
+    imm16(0xCD, address)
+}
+
+/// call cc, a16
+pub const fn call_cond(cond: Condition, address: u16) -> [u8; 3] {
+    imm16(0xC4 | ((cond as u8) << 3), address)
+}
+
+/// ret
+pub const fn ret() -> [u8; 1] {
+    [0xC9]
+}
+
+/// reti
+pub const fn reti() -> [u8; 1] {
+    [0xD9]
+}
+
+/// rst target
+pub const fn rst(target: ResetTarget) -> [u8; 1] {
+    [0xC7 | ((target as u8) << 3)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::{decode, AddressingMode, OpCode};
+
+    #[test]
+    fn encodings_decode_to_expected_opcodes() {
+        assert!(matches!(
+            decode(ld_r_imm(Register::B, 0)[0]),
+            OpCode::Ld8(
+                AddressingMode::Register(Register::B),
+                AddressingMode::Immediate
+            )
+        ));
+        assert!(matches!(
+            decode(ld_r_r(Register::H, Register::E)[0]),
+            OpCode::Ld8(
+                AddressingMode::Register(Register::H),
+                AddressingMode::Register(Register::E)
+            )
+        ));
+        assert!(matches!(
+            decode(inc_r(Register::L)[0]),
+            OpCode::Inc8(AddressingMode::Register(Register::L))
+        ));
+        assert!(matches!(
+            decode(dec_r(Register::A)[0]),
+            OpCode::Dec8(AddressingMode::Register(Register::A))
+        ));
+        assert!(matches!(
+            decode(jp_cond(Condition::NC, 0)[0]),
+            OpCode::JpImm(Some(Condition::NC))
+        ));
+        assert!(matches!(
+            decode(jr_cond(Condition::Z, 0)[0]),
+            OpCode::JrImm(Some(Condition::Z))
+        ));
+        assert!(matches!(
+            decode(call_cond(Condition::C, 0)[0]),
+            OpCode::CallImm(Some(Condition::C))
+        ));
+        assert!(matches!(
+            decode(rst(ResetTarget::Addr0x28)[0]),
+            OpCode::Reset(ResetTarget::Addr0x28)
+        ));
+        assert_eq!(jp(0x1234), [0xC3, 0x34, 0x12]);
+        assert_eq!(jr(-2), [0x18, 0xFE]);
+    }
+}
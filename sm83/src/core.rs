@@ -5,6 +5,7 @@ use crate::{
     decoder::{self, AddressingMode, Bit, Condition, OpCode, Register, RegisterPair, ResetTarget},
     interrupts::{Interrupt, Interrupts},
     memory::Memory,
+    state::{SaveState, StateError, StateReader, StateWriter},
 };
 
 /// A single CPU flag
@@ -114,6 +115,48 @@ impl Registers {
             irq_en: false,
         }
     }
+
+    /// Returns the AF register pair
+    pub const fn af(&self) -> u16 {
+        u16::from_be_bytes([self.a_reg, self.flags.0])
+    }
+
+    /// Returns the BC register pair
+    pub const fn bc(&self) -> u16 {
+        u16::from_be_bytes([self.b_reg, self.c_reg])
+    }
+
+    /// Returns the DE register pair
+    pub const fn de(&self) -> u16 {
+        u16::from_be_bytes([self.d_reg, self.e_reg])
+    }
+
+    /// Returns the HL register pair
+    pub const fn hl(&self) -> u16 {
+        u16::from_be_bytes([self.h_reg, self.l_reg])
+    }
+
+    /// Sets the AF register pair. The low nibble of F is hardwired to 0, so it is ignored.
+    pub fn set_af(&mut self, value: u16) {
+        let [a, f] = value.to_be_bytes();
+        self.a_reg = a;
+        self.flags = Flags(f & 0xF0);
+    }
+
+    /// Sets the BC register pair
+    pub fn set_bc(&mut self, value: u16) {
+        [self.b_reg, self.c_reg] = value.to_be_bytes();
+    }
+
+    /// Sets the DE register pair
+    pub fn set_de(&mut self, value: u16) {
+        [self.d_reg, self.e_reg] = value.to_be_bytes();
+    }
+
+    /// Sets the HL register pair
+    pub fn set_hl(&mut self, value: u16) {
+        [self.h_reg, self.l_reg] = value.to_be_bytes();
+    }
 }
 
 const fn carry_bit8(a: u8, b: u8, c: u8, bit: usize) -> bool {
@@ -383,6 +426,9 @@ const fn translate_irq_target(interrupt: Interrupt) -> u16 {
     }
 }
 
+/// The number of clock cycles in a machine cycle
+const CYCLES_PER_MACHINE_CYCLE: usize = 4;
+
 /// Clock cycles, not machine cycles
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 #[repr(transparent)]
@@ -394,6 +440,23 @@ impl Cycles {
         Self(value)
     }
 
+    /// Creates a Cycles instance from a number of machine cycles, each of them lasting 4 clock
+    /// cycles
+    pub const fn from_machine_cycles(machine_cycles: usize) -> Self {
+        Self(machine_cycles * CYCLES_PER_MACHINE_CYCLE)
+    }
+
+    /// Returns the number of machine cycles. The number of clock cycles is expected to be a
+    /// multiple of 4, which is always the case for CPU instructions.
+    pub fn to_machine_cycles(&self) -> usize {
+        debug_assert!(
+            self.0 & (CYCLES_PER_MACHINE_CYCLE - 1) == 0,
+            "{} clock cycles are not a whole number of machine cycles",
+            self.0
+        );
+        self.0 / CYCLES_PER_MACHINE_CYCLE
+    }
+
     /// Wraps around the given maximum value
     pub fn wrap(&self, max: usize) -> Cycles {
         Self(self.0 % max)
@@ -447,6 +510,12 @@ pub enum ExitReason {
 pub struct Cpu {
     regs: Registers,
     halted: bool,
+    stopped: bool,
+    /// Number of instructions left to execute before a pending EI takes effect.
+    ime_pending: Option<u8>,
+    /// Set when HALT is executed with IME=0 and an interrupt pending. The next opcode fetch
+    /// doesn't increment the PC, so the byte after HALT is read twice.
+    halt_bug: bool,
 }
 
 impl Cpu {
@@ -455,6 +524,9 @@ impl Cpu {
         Self {
             regs: Registers::new(),
             halted: false,
+            stopped: false,
+            ime_pending: None,
+            halt_bug: false,
         }
     }
 
@@ -506,55 +578,33 @@ impl Cpu {
     }
 
     fn get_reg_pair(&mut self, reg: RegisterPair) -> u16 {
-        let (hi, lo) = match reg {
-            RegisterPair::BC => (self.regs.b_reg, self.regs.c_reg),
-            RegisterPair::DE => (self.regs.d_reg, self.regs.e_reg),
-            RegisterPair::HL | RegisterPair::HLINC | RegisterPair::HLDEC => {
-                (self.regs.h_reg, self.regs.l_reg)
-            }
-            RegisterPair::SP => {
-                return self.regs.sp_reg;
-            }
-            RegisterPair::AF => (self.regs.a_reg, self.regs.flags.0),
-        };
-        let value = ((hi as u16) << 8) | (lo as u16);
-
         match reg {
+            RegisterPair::BC => self.regs.bc(),
+            RegisterPair::DE => self.regs.de(),
+            RegisterPair::HL => self.regs.hl(),
             RegisterPair::HLINC => {
-                let value = value.wrapping_add(1);
-                self.regs.h_reg = (value >> 8) as u8;
-                self.regs.l_reg = (value & 0xFF) as u8;
+                let value = self.regs.hl();
+                self.regs.set_hl(value.wrapping_add(1));
+                value
             }
             RegisterPair::HLDEC => {
-                let value = value.wrapping_sub(1);
-                self.regs.h_reg = (value >> 8) as u8;
-                self.regs.l_reg = (value & 0xFF) as u8;
+                let value = self.regs.hl();
+                self.regs.set_hl(value.wrapping_sub(1));
+                value
             }
-            _ => {}
+            RegisterPair::SP => self.regs.sp_reg,
+            RegisterPair::AF => self.regs.af(),
         }
-        value
     }
 
     fn set_reg_pair(&mut self, reg: RegisterPair, value: u16) {
-        let (hi, lo) = match reg {
-            RegisterPair::BC => (&mut self.regs.b_reg, &mut self.regs.c_reg),
-            RegisterPair::DE => (&mut self.regs.d_reg, &mut self.regs.e_reg),
-            RegisterPair::HL | RegisterPair::HLINC | RegisterPair::HLDEC => {
-                (&mut self.regs.h_reg, &mut self.regs.l_reg)
-            }
-            RegisterPair::SP => {
-                self.regs.sp_reg = value;
-                return;
-            }
-            RegisterPair::AF => {
-                let (hi, lo) = (&mut self.regs.a_reg, &mut self.regs.flags);
-                *hi = ((value >> 8) & 0xff) as u8;
-                *lo = ((value & 0xf0) as u8).into(); // lo-bits are hardcoded to 0
-                return;
-            }
-        };
-        *hi = ((value >> 8) & 0xff) as u8;
-        *lo = (value & 0xff) as u8;
+        match reg {
+            RegisterPair::BC => self.regs.set_bc(value),
+            RegisterPair::DE => self.regs.set_de(value),
+            RegisterPair::HL | RegisterPair::HLINC | RegisterPair::HLDEC => self.regs.set_hl(value),
+            RegisterPair::SP => self.regs.sp_reg = value,
+            RegisterPair::AF => self.regs.set_af(value),
+        }
     }
 
     #[cfg_attr(feature = "profile", inline(never))]
@@ -612,7 +662,12 @@ impl Cpu {
 
     #[cfg_attr(feature = "profile", inline(never))]
     fn fetch_and_decode<T: Memory>(&mut self, memory: &mut T) -> OpCode {
-        let pc = self.step_pc();
+        let pc = if self.halt_bug {
+            self.halt_bug = false;
+            self.regs.pc_reg
+        } else {
+            self.step_pc()
+        };
         let insn = memory.read(pc);
 
         match decoder::decode(insn) {
@@ -625,11 +680,71 @@ impl Cpu {
         }
     }
 
-    /// Executes a single CPU instruction and returns from the function.
+    /// Restores the registers to their values after the boot ROM runs, and leaves any halted,
+    /// stopped or pending EI state.
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Returns whether the CPU is halted waiting for an interrupt.
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Returns whether the CPU is stopped. Unlike halt, interrupts do not resume execution, only
+    /// `resume_from_stop` does.
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Resumes execution after a STOP instruction. On the Game Boy this happens when a selected
+    /// joypad line goes low.
+    pub fn resume_from_stop(&mut self) {
+        self.stopped = false;
+    }
+
+    /// Executes a single CPU instruction, just like `step`. However, while the CPU is halted and
+    /// no interrupt is pending, it idles for the whole `budget` at once instead of a single
+    /// machine cycle. The budget is rounded up to whole machine cycles, and the idle cycles are
+    /// returned in `ExitReason::Halt`. A stopped CPU idles for the budget as well, returning
+    /// `ExitReason::Stop`.
+    pub fn step_with_budget<T: Memory>(
+        &mut self,
+        memory: &mut T,
+        interrupts: Interrupts,
+        budget: Cycles,
+    ) -> ExitReason {
+        let machine_cycles = usize::from(budget).div_ceil(CYCLES_PER_MACHINE_CYCLE);
+        let idle_cycles = Cycles::from_machine_cycles(machine_cycles.max(1));
+        if self.stopped {
+            return ExitReason::Stop(idle_cycles);
+        }
+        if self.halted && !interrupts.has_any() {
+            return ExitReason::Halt(idle_cycles);
+        }
+        self.step(memory, interrupts)
+    }
+
+    /// Executes a single CPU instruction and returns from the function. `interrupts` are sampled
+    /// only at the start of the instruction, so an interrupt that becomes pending while an
+    /// instruction executes is serviced after it completes.
     #[cfg_attr(feature = "profile", inline(never))]
     pub fn step<T: Memory>(&mut self, memory: &mut T, interrupts: Interrupts) -> ExitReason {
+        // EI enables interrupts only after the instruction that follows it has executed.
+        self.ime_pending = match self.ime_pending {
+            Some(0) => {
+                self.regs.irq_en = true;
+                None
+            }
+            Some(remaining) => Some(remaining - 1),
+            None => None,
+        };
+
+        if self.stopped {
+            return ExitReason::Stop(Cycles::from_machine_cycles(1));
+        }
         if self.halted && !interrupts.has_any() {
-            return ExitReason::Halt(Cycles::new(4));
+            return ExitReason::Halt(Cycles::from_machine_cycles(1));
         }
         self.halted = false;
 
@@ -639,12 +754,24 @@ impl Cpu {
                 .and_then(|irq| if self.regs.irq_en { Some(irq) } else { None })
         {
             self.regs.irq_en = false;
-            let return_addr = self.regs.pc_reg;
+            // After `EI; HALT` with an interrupt already pending, the HALT bug leaves the PC on
+            // the HALT instruction, so the handler returns to it and the CPU halts then.
+            let return_addr = if core::mem::take(&mut self.halt_bug) {
+                self.regs.pc_reg.wrapping_sub(1)
+            } else {
+                self.regs.pc_reg
+            };
             self.stack_push(memory, return_addr);
             self.regs.pc_reg = translate_irq_target(irq);
-            ExitReason::InterruptTaken(Cycles::new(20), irq)
+            ExitReason::InterruptTaken(Cycles::from_machine_cycles(5), irq)
         } else {
             let instruction = self.fetch_and_decode(memory);
+            if matches!(instruction, OpCode::Halt) && interrupts.has_any() {
+                // The HALT bug: the CPU doesn't halt because an interrupt is already pending,
+                // but it fails to increment the PC on the next fetch.
+                self.halt_bug = true;
+                return ExitReason::Step(Cycles::from_machine_cycles(1));
+            }
             self.execute(memory, instruction)
         }
     }
@@ -757,10 +884,11 @@ impl Cpu {
             }
             OpCode::Halt => {
                 self.halted = true;
-                return ExitReason::Halt(Cycles::new(4));
+                return ExitReason::Halt(Cycles::from_machine_cycles(1));
             }
             OpCode::Stop => {
-                return ExitReason::Stop(Cycles::new(4));
+                self.stopped = true;
+                return ExitReason::Stop(Cycles::from_machine_cycles(1));
             }
             OpCode::Illegal => {
                 return ExitReason::IllegalOpcode;
@@ -1088,11 +1216,11 @@ impl Cpu {
             }
             OpCode::Di => {
                 self.regs.irq_en = false;
+                self.ime_pending = None;
                 Cycles::new(4)
             }
             OpCode::Ei => {
-                // TODO: make this delayed by 1 instruction
-                self.regs.irq_en = true;
+                self.ime_pending = Some(1);
                 Cycles::new(4)
             }
             OpCode::Rlca => {
@@ -1209,9 +1337,44 @@ impl Cpu {
     }
 }
 
+impl SaveState for Cpu {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.regs.af());
+        state.write_u16(self.regs.bc());
+        state.write_u16(self.regs.de());
+        state.write_u16(self.regs.hl());
+        state.write_u16(self.regs.sp_reg);
+        state.write_u16(self.regs.pc_reg);
+        state.write_bool(self.regs.irq_en);
+        state.write_bool(self.halted);
+        state.write_bool(self.stopped);
+        state.write_bool(self.ime_pending.is_some());
+        state.write_u8(self.ime_pending.unwrap_or(0));
+        state.write_bool(self.halt_bug);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.regs.set_af(state.read_u16()?);
+        self.regs.set_bc(state.read_u16()?);
+        self.regs.set_de(state.read_u16()?);
+        self.regs.set_hl(state.read_u16()?);
+        self.regs.sp_reg = state.read_u16()?;
+        self.regs.pc_reg = state.read_u16()?;
+        self.regs.irq_en = state.read_bool()?;
+        self.halted = state.read_bool()?;
+        self.stopped = state.read_bool()?;
+        let ime_pending = state.read_bool()?;
+        let countdown = state.read_u8()?;
+        self.ime_pending = ime_pending.then_some(countdown);
+        self.halt_bug = state.read_bool()?;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 pub mod test {
     use super::*;
+    use crate::memory::Address;
 
     #[test]
     pub fn test_sign_extend() {
@@ -1219,6 +1382,26 @@ pub mod test {
         assert_eq!(sign_extend(0x7fu8), 0x007fu16);
     }
 
+    #[test]
+    pub fn test_register_pairs() {
+        let mut regs = Registers::new();
+
+        regs.set_af(0x12FF);
+        assert_eq!(regs.a_reg, 0x12);
+        assert_eq!(u8::from(regs.flags), 0xF0);
+        assert_eq!(regs.af(), 0x12F0);
+
+        regs.set_bc(0x3456);
+        regs.set_de(0x789A);
+        regs.set_hl(0xBCDE);
+        assert_eq!((regs.b_reg, regs.c_reg), (0x34, 0x56));
+        assert_eq!((regs.d_reg, regs.e_reg), (0x78, 0x9A));
+        assert_eq!((regs.h_reg, regs.l_reg), (0xBC, 0xDE));
+        assert_eq!(regs.bc(), 0x3456);
+        assert_eq!(regs.de(), 0x789A);
+        assert_eq!(regs.hl(), 0xBCDE);
+    }
+
     #[test]
     pub fn test_daa() {
         assert_eq!(daa(0x23, Flags::new()), (0x23, Flags::new()));
@@ -1242,4 +1425,181 @@ pub mod test {
             (0x25, Flags::new().with(Flag::C, true))
         );
     }
+
+    #[test]
+    pub fn test_machine_cycles() {
+        assert_eq!(Cycles::from_machine_cycles(0), Cycles::new(0));
+        assert_eq!(Cycles::from_machine_cycles(5), Cycles::new(20));
+        assert_eq!(Cycles::new(456).to_machine_cycles(), 114);
+        assert_eq!(Cycles::from_machine_cycles(3).to_machine_cycles(), 3);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "not a whole number of machine cycles")]
+    pub fn test_partial_machine_cycles() {
+        Cycles::new(6).to_machine_cycles();
+    }
+
+    struct FlatMemory([u8; 0x100]);
+
+    impl Memory for FlatMemory {
+        fn read(&self, address: Address) -> u8 {
+            self.0[address as usize & 0xFF]
+        }
+
+        fn write(&mut self, address: Address, value: u8) {
+            self.0[address as usize & 0xFF] = value;
+        }
+    }
+
+    #[test]
+    pub fn test_halt_with_budget() {
+        // halt; nop
+        let mut memory = FlatMemory([0; 0x100]);
+        memory.0[0] = 0x76;
+
+        let mut cpu = Cpu::new();
+        assert_eq!(
+            cpu.step_with_budget(&mut memory, Interrupts::new(), Cycles::new(0x10000)),
+            ExitReason::Halt(Cycles::new(4))
+        );
+        assert!(cpu.is_halted());
+
+        // The whole budget is consumed at once, rounded up to machine cycles
+        assert_eq!(
+            cpu.step_with_budget(&mut memory, Interrupts::new(), Cycles::new(0x10000)),
+            ExitReason::Halt(Cycles::new(0x10000))
+        );
+        assert_eq!(
+            cpu.step_with_budget(&mut memory, Interrupts::new(), Cycles::new(6)),
+            ExitReason::Halt(Cycles::new(8))
+        );
+        assert_eq!(
+            cpu.step_with_budget(&mut memory, Interrupts::new(), Cycles::new(0)),
+            ExitReason::Halt(Cycles::new(4))
+        );
+        assert_eq!(cpu.get_regs().pc_reg, 1);
+
+        // A pending interrupt wakes up the CPU, even with IME disabled
+        assert_eq!(
+            cpu.step_with_budget(&mut memory, Interrupt::Timer.into(), Cycles::new(0x10000)),
+            ExitReason::Step(Cycles::new(4))
+        );
+        assert!(!cpu.is_halted());
+        assert_eq!(cpu.get_regs().pc_reg, 2);
+    }
+
+    struct FullMemory([u8; 0x10000]);
+
+    impl Memory for FullMemory {
+        fn read(&self, address: Address) -> u8 {
+            self.0[address as usize]
+        }
+
+        fn write(&mut self, address: Address, value: u8) {
+            self.0[address as usize] = value;
+        }
+    }
+
+    #[test]
+    pub fn test_ld_indirect_imm16_sp() {
+        // ld [0xC000], sp; ld [0xFFFF], sp
+        let mut memory = FullMemory([0; 0x10000]);
+        memory.0[..6].copy_from_slice(&[0x08, 0x00, 0xC0, 0x08, 0xFF, 0xFF]);
+
+        let mut cpu = Cpu::new();
+        cpu.get_mut_regs().sp_reg = 0xBEEF;
+
+        assert_eq!(
+            cpu.step(&mut memory, Interrupts::new()),
+            ExitReason::Step(Cycles::new(20))
+        );
+        assert_eq!(memory.0[0xC000], 0xEF);
+        assert_eq!(memory.0[0xC001], 0xBE);
+        assert_eq!(cpu.get_regs().pc_reg, 3);
+
+        // The high byte wraps around to address 0
+        assert_eq!(
+            cpu.step(&mut memory, Interrupts::new()),
+            ExitReason::Step(Cycles::new(20))
+        );
+        assert_eq!(memory.0[0xFFFF], 0xEF);
+        assert_eq!(memory.0[0x0000], 0xBE);
+        assert_eq!(cpu.get_regs().pc_reg, 6);
+        assert_eq!(cpu.get_regs().sp_reg, 0xBEEF);
+    }
+
+    #[test]
+    pub fn test_ei_halt_with_pending_interrupt() {
+        // ei; halt; nop
+        let mut memory = FullMemory([0; 0x10000]);
+        memory.0[..2].copy_from_slice(&[0xFB, 0x76]);
+        // Timer handler: inc a; reti
+        memory.0[0x50..0x52].copy_from_slice(&[0x3C, 0xD9]);
+
+        let mut cpu = Cpu::new();
+        cpu.get_mut_regs().a_reg = 0;
+        cpu.get_mut_regs().sp_reg = 0xFFFE;
+        let pending: Interrupts = Interrupt::Timer.into();
+
+        // The interrupt is already pending, so HALT doesn't halt, but IME is not set yet
+        assert_eq!(
+            cpu.step(&mut memory, pending),
+            ExitReason::Step(Cycles::new(4))
+        );
+        assert_eq!(
+            cpu.step(&mut memory, pending),
+            ExitReason::Step(Cycles::new(4))
+        );
+        assert!(!cpu.is_halted());
+
+        // Once EI takes effect, the interrupt is serviced and returns to the HALT
+        assert_eq!(
+            cpu.step(&mut memory, pending),
+            ExitReason::InterruptTaken(Cycles::new(20), Interrupt::Timer)
+        );
+        assert_eq!(cpu.get_regs().pc_reg, 0x50);
+        assert_eq!(memory.0[0xFFFC], 0x01);
+        assert_eq!(memory.0[0xFFFD], 0x00);
+
+        // The handler runs normally
+        cpu.step(&mut memory, Interrupts::new());
+        cpu.step(&mut memory, Interrupts::new());
+        assert_eq!(cpu.get_regs().a_reg, 1);
+        assert_eq!(cpu.get_regs().pc_reg, 0x01);
+
+        // And the HALT executes again, now without any pending interrupt
+        assert_eq!(
+            cpu.step(&mut memory, Interrupts::new()),
+            ExitReason::Halt(Cycles::new(4))
+        );
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.get_regs().pc_reg, 0x02);
+    }
+
+    #[test]
+    pub fn test_reset() {
+        // ei; halt
+        let mut memory = FlatMemory([0; 0x100]);
+        memory.0[0] = 0xFB;
+        memory.0[1] = 0x76;
+
+        let mut cpu = Cpu::new();
+        cpu.get_mut_regs().a_reg = 0x42;
+        cpu.step(&mut memory, Interrupts::new());
+        cpu.step(&mut memory, Interrupts::new());
+        assert!(cpu.is_halted());
+
+        cpu.reset();
+        assert!(!cpu.is_halted());
+        assert_eq!(*cpu.get_regs(), Registers::new());
+
+        // The EI that was pending before the reset doesn't take effect
+        memory.0[0] = 0x00;
+        memory.0[1] = 0x00;
+        cpu.step(&mut memory, Interrupts::new());
+        cpu.step(&mut memory, Interrupts::new());
+        assert!(!cpu.get_regs().irq_en);
+    }
 }
@@ -41,6 +41,20 @@ pub enum AddressingMode {
     Immediate16,
 }
 
+impl AddressingMode {
+    /// Returns the number of immediate bytes that follow the opcode to encode this operand.
+    pub const fn immediate_bytes(&self) -> usize {
+        match self {
+            AddressingMode::IndirectRegister(_)
+            | AddressingMode::IndirectZeroPageRegister(_)
+            | AddressingMode::Register(_)
+            | AddressingMode::RegisterPair(_) => 0,
+            AddressingMode::Immediate | AddressingMode::IndirectZeroPageImmediate => 1,
+            AddressingMode::Immediate16 | AddressingMode::IndirectImmediate => 2,
+        }
+    }
+}
+
 /// Opcodes of the CPU.
 #[derive(Debug, Clone, Copy)]
 pub enum OpCode {
@@ -335,3 +349,42 @@ pub fn decode(byte: u8) -> OpCode {
 pub fn decode_prefixed(byte: u8) -> OpCode {
     generated::PREFIXED_TABLE[byte as usize]
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn immediate_bytes() {
+        let cases = [
+            (AddressingMode::IndirectRegister(RegisterPair::HL), 0),
+            (AddressingMode::IndirectZeroPageRegister(Register::C), 0),
+            (AddressingMode::Register(Register::A), 0),
+            (AddressingMode::RegisterPair(RegisterPair::SP), 0),
+            (AddressingMode::Immediate, 1),
+            (AddressingMode::IndirectZeroPageImmediate, 1),
+            (AddressingMode::Immediate16, 2),
+            (AddressingMode::IndirectImmediate, 2),
+        ];
+        for (mode, bytes) in cases {
+            assert_eq!(mode.immediate_bytes(), bytes, "{mode:?}");
+        }
+    }
+
+    #[test]
+    fn immediate_bytes_of_decoded_operands() {
+        // ld a, [0x1234]
+        let OpCode::Ld8(dest, src) = decode(0xFA) else {
+            panic!("Unexpected opcode");
+        };
+        assert_eq!(dest.immediate_bytes(), 0);
+        assert_eq!(src.immediate_bytes(), 2);
+
+        // ldh [n8], a
+        let OpCode::Ld8(dest, src) = decode(0xE0) else {
+            panic!("Unexpected opcode");
+        };
+        assert_eq!(dest.immediate_bytes(), 1);
+        assert_eq!(src.immediate_bytes(), 0);
+    }
+}
@@ -1,5 +1,7 @@
 //! Abstractions for CPU interrupts.
 
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+
 /// The set of memory-mapped interrupt registers
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct InterruptRegs {
@@ -57,6 +59,19 @@ impl InterruptRegs {
     }
 }
 
+impl SaveState for InterruptRegs {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.enable_reg.into());
+        state.write_u8(self.flags_reg.into());
+    }
+
+    fn load_state(&mut self, state: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.enable_reg = Interrupts(state.read_u8()?) & ALL_INTERRUPTS;
+        self.flags_reg = Interrupts(state.read_u8()?) & ALL_INTERRUPTS;
+        Ok(())
+    }
+}
+
 /// Represents a single interrupt source
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
@@ -73,6 +88,44 @@ pub enum Interrupt {
     Joypad = 0x10,
 }
 
+impl Interrupt {
+    /// Returns a human readable label for the interrupt source
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Interrupt::Vblank => "VBlank",
+            Interrupt::Lcd => "LCD STAT",
+            Interrupt::Timer => "Timer",
+            Interrupt::Serial => "Serial",
+            Interrupt::Joypad => "Joypad",
+        }
+    }
+}
+
+impl core::fmt::Display for Interrupt {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Error returned when a value does not map to a single interrupt source
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct InvalidInterrupt(pub u8);
+
+impl TryFrom<u8> for Interrupt {
+    type Error = InvalidInterrupt;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x01 => Ok(Interrupt::Vblank),
+            0x02 => Ok(Interrupt::Lcd),
+            0x04 => Ok(Interrupt::Timer),
+            0x08 => Ok(Interrupt::Serial),
+            0x10 => Ok(Interrupt::Joypad),
+            _ => Err(InvalidInterrupt(value)),
+        }
+    }
+}
+
 /// Represents an aggregation of interrupt sources
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub struct Interrupts(u8);
@@ -192,4 +245,30 @@ pub mod tests {
             interrupts.acknowledge(Interrupt::Lcd)
         );
     }
+
+    #[test]
+    fn interrupt_names() {
+        assert_eq!(Interrupt::Vblank.name(), "VBlank");
+        assert_eq!(Interrupt::Lcd.name(), "LCD STAT");
+        assert_eq!(Interrupt::Timer.name(), "Timer");
+        assert_eq!(Interrupt::Serial.name(), "Serial");
+        assert_eq!(Interrupt::Joypad.name(), "Joypad");
+    }
+
+    #[test]
+    fn interrupt_try_from_round_trip() {
+        for interrupt in [
+            Interrupt::Vblank,
+            Interrupt::Lcd,
+            Interrupt::Timer,
+            Interrupt::Serial,
+            Interrupt::Joypad,
+        ] {
+            assert_eq!(Interrupt::try_from(interrupt as u8), Ok(interrupt));
+        }
+
+        assert_eq!(Interrupt::try_from(0), Err(InvalidInterrupt(0)));
+        assert_eq!(Interrupt::try_from(0x03), Err(InvalidInterrupt(0x03)));
+        assert_eq!(Interrupt::try_from(0x20), Err(InvalidInterrupt(0x20)));
+    }
 }
@@ -1,7 +1,10 @@
 //! Implementation of an SM83 CPU emulator, used in the Game Boy.
 #![no_std]
 
+#[cfg(any(test, feature = "asm"))]
+pub mod asm;
 pub mod core;
 pub mod decoder;
 pub mod interrupts;
 pub mod memory;
+pub mod state;
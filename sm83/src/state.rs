@@ -0,0 +1,148 @@
+//! Building blocks to serialize the state of the emulator into a compact byte stream.
+//!
+//! Each component writes its fields in a fixed order with a [`StateWriter`] and reads them back
+//! in the same order with a [`StateReader`]. The format has no framing of its own, so versioning
+//! and identification of the stream are left to the owner of the whole machine state.
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+
+/// Errors that prevent a serialized state from being decoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateError {
+    /// The data ended before all the state could be decoded
+    Truncated,
+    /// The data contains a value that is not valid for the decoded component
+    Invalid,
+}
+
+impl core::fmt::Display for StateError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            StateError::Truncated => write!(f, "state is truncated"),
+            StateError::Invalid => write!(f, "state contains invalid values"),
+        }
+    }
+}
+
+/// Serializes values in little endian order into a growing buffer.
+#[derive(Debug, Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    /// Constructs an empty writer
+    pub fn new() -> Self {
+        Self { data: Vec::new() }
+    }
+
+    /// Appends a single byte
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    /// Appends a 16-bit value
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Appends a 32-bit value
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Appends a 64-bit value
+    pub fn write_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Appends a boolean as a single byte
+    pub fn write_bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    /// Appends the given bytes as they are
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    /// Returns the serialized data
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// Deserializes the values written by a [`StateWriter`].
+#[derive(Debug)]
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    /// Constructs a reader over the given data
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    /// Consumes the next `len` bytes
+    pub fn take(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() < len {
+            return Err(StateError::Truncated);
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    /// Reads a single byte
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    /// Reads a 16-bit value
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    /// Reads a 32-bit value
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    /// Reads a 64-bit value
+    pub fn read_u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// Reads a boolean. Bytes other than 0 and 1 are rejected.
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::Invalid),
+        }
+    }
+
+    /// Fills the given buffer with the next bytes
+    pub fn read_bytes(&mut self, bytes: &mut [u8]) -> Result<(), StateError> {
+        bytes.copy_from_slice(self.take(bytes.len())?);
+        Ok(())
+    }
+
+    /// Returns true if all the data has been consumed
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+/// A component whose state can be saved and restored.
+pub trait SaveState {
+    /// Serializes the state of the component.
+    fn save_state(&self, state: &mut StateWriter);
+
+    /// Restores the state previously serialized with `save_state`. The component may be left
+    /// partially restored on error.
+    fn load_state(&mut self, state: &mut StateReader<'_>) -> Result<(), StateError>;
+}
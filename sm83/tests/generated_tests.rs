@@ -112,6 +112,8 @@ impl From<&TestInterrupt> for Interrupt {
     }
 }
 
+/// Interrupts requested at the given cycle. Triggers must be sorted by cycle, and may point to the
+/// middle of an instruction.
 #[derive(Debug, Deserialize)]
 #[serde(deny_unknown_fields)]
 struct TestInterrupts {
@@ -232,17 +234,16 @@ fn run_test(test_suite_name: &str, tests_str: &str) {
 
         let mut active_interrupts = Interrupts::new();
         let mut executed_cycles = Cycles::new(0);
+        let mut pending_triggers = test.interrupt_triggers.iter().peekable();
         let exit_reason = loop {
-            let interrupts = if let Some(interrupts) = test
-                .interrupt_triggers
-                .iter()
-                .find(|test_interrupts| Cycles::new(test_interrupts.cycle) == executed_cycles)
+            // The CPU only samples interrupts at the start of each instruction, so triggers that
+            // land in the middle of an instruction become pending at the next instruction
+            // boundary. Sub-instruction timing of interrupts is not modeled.
+            while let Some(interrupts) = pending_triggers
+                .next_if(|test_interrupts| Cycles::new(test_interrupts.cycle) <= executed_cycles)
             {
-                translate_interrupts(&interrupts.triggers)
-            } else {
-                Interrupts::new()
-            };
-            active_interrupts = active_interrupts | interrupts;
+                active_interrupts = active_interrupts | translate_interrupts(&interrupts.triggers);
+            }
             println!("Active interrupts at {executed_cycles:?} = {active_interrupts:?}");
             let reason = cpu.step(&mut memory_interface, active_interrupts);
 
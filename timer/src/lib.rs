@@ -4,7 +4,11 @@ use sm83::interrupts::Interrupt;
 use tock_registers::interfaces::{Readable, Writeable};
 use tock_registers::{register_bitfields, registers::InMemoryRegister};
 
-use sm83::{core::Cycles, interrupts::Interrupts};
+use sm83::{
+    core::Cycles,
+    interrupts::Interrupts,
+    state::{SaveState, StateError, StateReader, StateWriter},
+};
 
 pub struct Timer {
     div: u16,
@@ -12,6 +16,9 @@ pub struct Timer {
     tma: u8,
     tac: InMemoryRegister<u8, TAC::Register>,
     request_div_reset: bool,
+    /// Cycles left until TIMA is reloaded from TMA after overflowing, or 0 when there is no
+    /// reload pending. TIMA reads as 0 in the meantime.
+    tima_reload_delay: u16,
 }
 
 register_bitfields! [
@@ -44,6 +51,10 @@ static_assertions::const_assert_eq!(NUM_CYCLES_PER_DIV_TICK, 256);
 const HIDDEN_BITS: u32 = NUM_CYCLES_PER_DIV_TICK.trailing_zeros();
 static_assertions::const_assert_eq!(HIDDEN_BITS, 8);
 
+// TIMA reads as 0 for 4 cycles after overflowing. Then it is reloaded from TMA and the timer
+// interrupt is requested.
+const TIMA_RELOAD_DELAY: u16 = 4;
+
 impl Timer {
     pub const fn new() -> Self {
         Self {
@@ -52,9 +63,15 @@ impl Timer {
             tma: 0,
             tac: InMemoryRegister::new(0),
             request_div_reset: false,
+            tima_reload_delay: 0,
         }
     }
 
+    /// Restores DIV, TIMA, TMA and TAC to their power-on values.
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
     fn clk_select_bit(&self) -> u16 {
         match self.tac.read_as_enum(TAC::CLK_SELECT).unwrap() {
             // 4 m-cycles are 16 clk-cycles. 16 clk-cycles are represented in 4 bits
@@ -75,39 +92,69 @@ impl Timer {
         debug_assert!(cycles <= <u16 as Into<usize>>::into(u16::max_value()));
 
         let prev_div = self.div;
-        let (cur_div, div_overflow) = if self.request_div_reset {
-            self.request_div_reset = false;
-            (0, true)
+        let div_reset = core::mem::take(&mut self.request_div_reset);
+        let (cur_div, div_overflow) = if div_reset {
+            (0, false)
         } else {
             prev_div.overflowing_add(cycles as u16)
         };
         self.div = cur_div;
 
+        let mut interrupts = Interrupts::new();
+        if self.tima_reload_delay != 0 {
+            // TIMA overflowed at the end of a previous step. TMA is read when the reload happens,
+            // so writes to TMA in the meantime change the reloaded value.
+            if cycles >= self.tima_reload_delay as usize {
+                self.tima_reload_delay = 0;
+                self.tima = self.tma;
+                interrupts = Interrupt::Timer.into();
+            } else {
+                self.tima_reload_delay -= cycles as u16;
+            }
+        }
+
         if self
             .tac
             .read_as_enum(TAC::ENABLE)
             .is_some_and(|v: TAC::ENABLE::Value| v == TAC::ENABLE::Value::Off)
         {
             // Timer is disabled
-            return Interrupts::new();
+            return interrupts;
         }
 
         let clk_sel_bit = self.clk_select_bit();
         let shifted_cur_div = cur_div >> clk_sel_bit;
         let shifted_prev_div = prev_div >> clk_sel_bit;
         let wraparound_val = ((1 << 16) >> clk_sel_bit) as u16;
-        let new_count = if div_overflow {
+        let new_count = if div_reset {
+            // TIMA increments on the falling edge of the DIV bit right below the selected one.
+            // Resetting DIV while that bit is set is also a falling edge.
+            let monitored_bit = 1 << (clk_sel_bit - 1);
+            (prev_div & monitored_bit != 0) as u16
+        } else if div_overflow {
             wraparound_val + shifted_cur_div - shifted_prev_div
         } else {
             shifted_cur_div - shifted_prev_div
         };
 
-        let (new_tima, did_tima_overflow) = self.tima.overflowing_add(new_count as u8);
-        self.tima = new_tima;
-        if did_tima_overflow {
-            Interrupt::Timer.into()
+        let tima = self.tima as usize + new_count as usize;
+        if tima <= u8::MAX as usize {
+            self.tima = tima as u8;
+            return interrupts;
+        }
+
+        // TIMA is reloaded from TMA every time it overflows and keeps counting from there
+        let tma = self.tma as usize;
+        let excess = (tima - (u8::MAX as usize + 1)) % (u8::MAX as usize + 1 - tma);
+        let cycles_since_increment = cur_div & ((1 << clk_sel_bit) - 1);
+        if excess == 0 && cycles_since_increment < TIMA_RELOAD_DELAY {
+            // The last increment overflowed and the reload hasn't happened yet
+            self.tima = 0;
+            self.tima_reload_delay = TIMA_RELOAD_DELAY - cycles_since_increment;
+            interrupts
         } else {
-            Interrupts::new()
+            self.tima = (tma + excess) as u8;
+            Interrupt::Timer.into()
         }
     }
 
@@ -124,7 +171,11 @@ impl Timer {
     pub fn write(&mut self, address: sm83::memory::Address, value: u8) {
         match address {
             0xFF04 => self.request_div_reset = true,
-            0xFF05 => self.tima = value,
+            0xFF05 => {
+                // Writing TIMA before it is reloaded cancels the reload and the interrupt
+                self.tima = value;
+                self.tima_reload_delay = 0;
+            }
             0xFF06 => self.tma = value,
             0xFF07 => self.tac.set(value),
             _ => unreachable!(
@@ -134,3 +185,139 @@ impl Timer {
         };
     }
 }
+
+impl SaveState for Timer {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.div);
+        state.write_u8(self.tima);
+        state.write_u8(self.tma);
+        state.write_u8(self.tac.get());
+        state.write_bool(self.request_div_reset);
+        state.write_u8(self.tima_reload_delay as u8);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.div = state.read_u16()?;
+        self.tima = state.read_u8()?;
+        self.tma = state.read_u8()?;
+        self.tac.set(state.read_u8()?);
+        self.request_div_reset = state.read_bool()?;
+        let tima_reload_delay = state.read_u8()? as u16;
+        if tima_reload_delay > TIMA_RELOAD_DELAY {
+            return Err(StateError::Invalid);
+        }
+        self.tima_reload_delay = tima_reload_delay;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reset_restores_power_on_values() {
+        let mut timer = Timer::new();
+        timer.write(0xFF05, 0x12);
+        timer.write(0xFF06, 0x34);
+        timer.write(0xFF07, 0x05);
+        timer.step(Cycles::new(0x400));
+        assert_ne!(timer.read(0xFF04), 0);
+        timer.write(0xFF04, 0);
+
+        timer.reset();
+        for address in 0xFF04..=0xFF07 {
+            assert_eq!(timer.read(address), 0, "{address:#06x}");
+        }
+
+        // The pending DIV reset is dropped too
+        timer.step(Cycles::new(0x100));
+        assert_eq!(timer.read(0xFF04), 1);
+    }
+
+    #[test]
+    fn tima_overflow_reloads_tma() {
+        let mut timer = Timer::new();
+        timer.write(0xFF06, 0x80);
+        // Enabled, incrementing every 16 cycles
+        timer.write(0xFF07, 0x05);
+
+        // Excess increments are added to TMA
+        timer.write(0xFF05, 0xFE);
+        assert_eq!(timer.step(Cycles::new(48)), Interrupt::Timer.into());
+        assert_eq!(timer.read(0xFF05), 0x81);
+
+        // TIMA reads as 0 until it is reloaded 4 cycles after the overflow
+        timer.write(0xFF05, 0xFF);
+        assert_eq!(timer.step(Cycles::new(16)), Interrupts::new());
+        assert_eq!(timer.read(0xFF05), 0x00);
+        assert_eq!(timer.step(Cycles::new(4)), Interrupt::Timer.into());
+        assert_eq!(timer.read(0xFF05), 0x80);
+
+        // Writing TIMA in the meantime cancels the reload
+        timer.write(0xFF05, 0xFF);
+        assert_eq!(timer.step(Cycles::new(12)), Interrupts::new());
+        assert_eq!(timer.read(0xFF05), 0x00);
+        timer.write(0xFF05, 0x10);
+        assert_eq!(timer.step(Cycles::new(4)), Interrupts::new());
+        assert_eq!(timer.read(0xFF05), 0x10);
+
+        // Overflowing several times in a single step
+        timer.write(0xFF05, 0xFF);
+        assert_eq!(timer.step(Cycles::new(16 * 0x82)), Interrupt::Timer.into());
+        assert_eq!(timer.read(0xFF05), 0x81);
+    }
+
+    #[test]
+    fn tima_reload_delay_spans_steps() {
+        let mut timer = Timer::new();
+        timer.write(0xFF06, 0x80);
+        timer.write(0xFF07, 0x05);
+        timer.write(0xFF05, 0xFF);
+
+        // The overflow happens 2 cycles before the end of the step
+        assert_eq!(timer.step(Cycles::new(18)), Interrupts::new());
+        assert_eq!(timer.read(0xFF05), 0x00);
+
+        // TIMA keeps reading as 0 on the intermediate cycle, and TMA is read on reload
+        assert_eq!(timer.step(Cycles::new(1)), Interrupts::new());
+        assert_eq!(timer.read(0xFF05), 0x00);
+        timer.write(0xFF06, 0x90);
+        assert_eq!(timer.step(Cycles::new(1)), Interrupt::Timer.into());
+        assert_eq!(timer.read(0xFF05), 0x90);
+        assert_eq!(timer.step(Cycles::new(4)), Interrupts::new());
+        assert_eq!(timer.read(0xFF05), 0x90);
+    }
+
+    #[test]
+    fn div_reset_increments_tima_on_falling_edge() {
+        // TAC with the timer enabled, and the cycles until the monitored DIV bit is set
+        let clocks = [(0x04, 512), (0x05, 8), (0x06, 32), (0x07, 128)];
+        for (tac, half_period) in clocks {
+            let mut timer = Timer::new();
+            timer.write(0xFF07, tac);
+
+            timer.step(Cycles::new(half_period));
+            assert_eq!(timer.read(0xFF05), 0, "TAC = {tac:#04x}");
+
+            // The monitored bit is set, so the reset increments TIMA
+            timer.write(0xFF04, 0);
+            timer.step(Cycles::new(4));
+            assert_eq!(timer.read(0xFF05), 1, "TAC = {tac:#04x}");
+            assert_eq!(timer.read(0xFF04), 0);
+
+            // Now it is clear, so the reset doesn't
+            timer.write(0xFF04, 0);
+            timer.step(Cycles::new(4));
+            assert_eq!(timer.read(0xFF05), 1, "TAC = {tac:#04x}");
+        }
+
+        // Nothing happens while the timer is disabled
+        let mut timer = Timer::new();
+        timer.write(0xFF07, 0x01);
+        timer.step(Cycles::new(8));
+        timer.write(0xFF04, 0);
+        timer.step(Cycles::new(4));
+        assert_eq!(timer.read(0xFF05), 0);
+    }
+}